@@ -0,0 +1,170 @@
+//! Foreground latency while compaction runs.
+//!
+//! Runs the same mix of reads and overwrites against a store that never
+//! compacts and one that does, and reports the latency percentiles of both
+//! and how much compaction moves them. Compaction runs inline on the write
+//! that triggers it, so that write's latency includes the whole compaction.
+//!
+//!     cargo bench --bench compaction
+//!
+//! The store's shape comes from the environment:
+//!
+//! - `KVS_BENCH_KEYS`: live keys, default 10000
+//! - `KVS_BENCH_VALUE_LEN`: bytes per value, default 1024
+//! - `KVS_BENCH_OPS`: measured operations, half of them writes, default 50000
+//! - `KVS_BENCH_COMPACTION_MB`: also compact once this many megabytes are
+//!   stale, default only the store's stale ratio
+//! - `KVS_BENCH_COMPACTION_MB_PER_SEC`: compaction I/O cap, default none
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use kvs::{KvStore, KvStoreConfig, KvsReader, KvsWriter, StoreEvent};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+struct Run {
+    latencies: Vec<Duration>,
+    compactions: usize,
+}
+
+fn main() {
+    let keys: u64 = env_or("KVS_BENCH_KEYS", 10_000);
+    let value_len: usize = env_or("KVS_BENCH_VALUE_LEN", 1024);
+    let ops: usize = env_or("KVS_BENCH_OPS", 50_000);
+    let compaction_mb: Option<u64> = env_opt("KVS_BENCH_COMPACTION_MB");
+    let compaction_mb_per_sec: Option<u64> = env_opt("KVS_BENCH_COMPACTION_MB_PER_SEC");
+
+    println!(
+        "{} keys of {} bytes, {} ops{}{}",
+        keys,
+        value_len,
+        ops,
+        match compaction_mb {
+            Some(mb) => format!(", compacting every {} MB stale", mb),
+            None => String::new(),
+        },
+        match compaction_mb_per_sec {
+            Some(mb) => format!(" at up to {} MB/s", mb),
+            None => String::new(),
+        }
+    );
+
+    let baseline = run(
+        KvStoreConfig {
+            compaction_min_stale: u64::MAX,
+            ..KvStoreConfig::default()
+        },
+        keys,
+        value_len,
+        ops,
+    );
+    let compacting = run(
+        KvStoreConfig {
+            compaction_threshold: compaction_mb.map(|mb| mb * 1024 * 1024),
+            compaction_bytes_per_sec: compaction_mb_per_sec.map(|mb| mb * 1024 * 1024),
+            ..KvStoreConfig::default()
+        },
+        keys,
+        value_len,
+        ops,
+    );
+
+    println!(
+        "{:<12} {:>11} {:>12} {:>12} {:>12} {:>12}",
+        "", "compactions", "p50", "p99", "p99.9", "max"
+    );
+    for (name, run) in [("no compact", &baseline), ("compacting", &compacting)] {
+        println!(
+            "{:<12} {:>11} {:>12?} {:>12?} {:>12?} {:>12?}",
+            name,
+            run.compactions,
+            percentile(&run.latencies, 50.0),
+            percentile(&run.latencies, 99.0),
+            percentile(&run.latencies, 99.9),
+            percentile(&run.latencies, 100.0),
+        );
+    }
+
+    let delta = |p: f64| {
+        let (base, with) = (
+            percentile(&baseline.latencies, p),
+            percentile(&compacting.latencies, p),
+        );
+        match with.checked_sub(base) {
+            Some(more) => format!("+{:?}", more),
+            None => format!("-{:?}", base - with),
+        }
+    };
+    println!(
+        "{:<12} {:>11} {:>12} {:>12} {:>12} {:>12}",
+        "delta",
+        "",
+        delta(50.0),
+        delta(99.0),
+        delta(99.9),
+        delta(100.0)
+    );
+}
+
+// Fill a fresh store, then time each operation of a 50/50 mix of random
+// gets and overwrites
+fn run(config: KvStoreConfig, keys: u64, value_len: usize, ops: usize) -> Run {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config).unwrap();
+    let events = store.subscribe();
+    let value = "v".repeat(value_len);
+
+    for key in 0..keys {
+        store
+            .set(format!("key{}", key).into_bytes(), value.clone())
+            .unwrap();
+    }
+    store.flush().unwrap();
+    // Only count compactions during the measured operations
+    events.try_iter().for_each(drop);
+
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut latencies = Vec::with_capacity(ops);
+    for _ in 0..ops {
+        let key = format!("key{}", rng.gen_range(0..keys)).into_bytes();
+        let write = rng.gen_bool(0.5);
+
+        let started = Instant::now();
+        if write {
+            store.set(key, value.clone()).unwrap();
+        } else {
+            store.get(key).unwrap();
+        }
+        latencies.push(started.elapsed());
+    }
+
+    let compactions = events
+        .try_iter()
+        .filter(|event| matches!(event, StoreEvent::CompactionFinished(_)))
+        .count();
+    latencies.sort_unstable();
+
+    Run {
+        latencies,
+        compactions,
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env_opt(name).unwrap_or(default)
+}
+
+fn env_opt<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", name))
+    })
+}
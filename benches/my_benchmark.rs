@@ -19,12 +19,15 @@ use tempfile::tempdir;
 
 pub fn test_writes<Engine: KvsEngine>(b: &mut Bencher, thread_rng: ThreadRng) {
     let mut rng = SmallRng::from_rng(thread_rng).unwrap();
-    let temp_dir = tempfile::TempDir::new().unwrap().into_path();
+    let temp_dir = tempfile::TempDir::new().unwrap().keep();
     let mut store = Engine::open(temp_dir).unwrap();
 
     b.iter(|| {
         store
-            .set(format!("key{}", rng.gen::<u32>()), "value".to_string())
+            .set(
+                format!("key{}", rng.gen::<u32>()).into_bytes(),
+                "value".to_string(),
+            )
             .unwrap();
     });
 }
@@ -43,13 +46,13 @@ pub fn bench_writes(c: &mut Criterion) {
 
 pub fn test_reads<Engine: KvsEngine>(b: &mut Bencher, thread_rng: ThreadRng) {
     let mut rng = SmallRng::from_rng(thread_rng).unwrap();
-    let temp_dir = TempDir::new().unwrap().into_path();
+    let temp_dir = TempDir::new().unwrap().keep();
     let mut store = Engine::open(temp_dir).unwrap();
     let key_count = 1 << 8;
 
     for key_i in 1..key_count {
         store
-            .set(format!("key{}", key_i), "value".to_string())
+            .set(format!("key{}", key_i).into_bytes(), "value".to_string())
             .unwrap();
     }
 
@@ -57,7 +60,7 @@ pub fn test_reads<Engine: KvsEngine>(b: &mut Bencher, thread_rng: ThreadRng) {
 
     b.iter(|| {
         store
-            .get(format!("key{}", rng.gen_range(1..key_count)))
+            .get(format!("key{}", rng.gen_range(1..key_count)).into_bytes())
             .unwrap();
     })
 }
@@ -0,0 +1,5 @@
+//! The C ABI of `kvs::ffi`, declared in `include/kvs.h`, as a shared
+//! library. Build it from this directory with `cargo build --release`,
+//! which leaves `target/release/libkvs_ffi.so` (or `.dylib` / `.dll`).
+
+pub use kvs::ffi::*;
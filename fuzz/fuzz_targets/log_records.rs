@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::index_log(data);
+    kvs::fuzzing::read_record(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::read_messages(data);
+    kvs::fuzzing::read_response(data);
+});
@@ -0,0 +1,77 @@
+/*
+ * C interface to the kvs embedded store. Build the library from ffi/ with
+ *
+ *     cargo build --release
+ *
+ * and link against ffi/target/release/libkvs_ffi.so (or .dylib / .dll).
+ *
+ * Every function but kvs_free_value and kvs_last_error returns a KvsStatus.
+ * On anything but KVS_OK or KVS_NOT_FOUND, kvs_last_error() describes the
+ * failure.
+ *
+ * Ownership:
+ * - A store from kvs_open belongs to the caller until kvs_close, and must
+ *   not be used from two threads at once.
+ * - Keys and values passed in are borrowed for the length of the call.
+ * - Values returned by kvs_get belong to the caller; free them with
+ *   kvs_free_value, not free().
+ * - The string from kvs_last_error belongs to the library and stays valid
+ *   until the next kvs_* call on the same thread.
+ */
+
+#ifndef KVS_H
+#define KVS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum KvsStatus {
+    KVS_OK = 0,
+    /* The key isn't in the store */
+    KVS_NOT_FOUND = 1,
+    /* A null pointer, or a path or value that isn't UTF-8 */
+    KVS_INVALID_ARGUMENT = 2,
+    /* The store's files couldn't be read or written */
+    KVS_IO = 3,
+    /* Any other failure, including a panic inside the library */
+    KVS_ERROR = 4,
+} KvsStatus;
+
+typedef struct KvsStore KvsStore;
+
+/* Open or create the store in the directory `path`. */
+KvsStatus kvs_open(const char *path, KvsStore **out);
+
+/*
+ * Look up a key. On KVS_OK, *value points to the value followed by a NUL
+ * byte and *value_len holds its length without the NUL. On KVS_NOT_FOUND,
+ * *value is NULL.
+ */
+KvsStatus kvs_get(KvsStore *store, const uint8_t *key, size_t key_len,
+                  char **value, size_t *value_len);
+
+/* Free a value returned by kvs_get. Does nothing given NULL. */
+void kvs_free_value(char *value, size_t value_len);
+
+/* Set a key to a UTF-8 value. */
+KvsStatus kvs_set(KvsStore *store, const uint8_t *key, size_t key_len,
+                  const char *value, size_t value_len);
+
+/* Remove a key, returning KVS_NOT_FOUND if it isn't set. */
+KvsStatus kvs_remove(KvsStore *store, const uint8_t *key, size_t key_len);
+
+/* Flush and close the store. Does nothing given NULL. */
+KvsStatus kvs_close(KvsStore *store);
+
+/* The message of the last failed call on this thread, or NULL. */
+const char *kvs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
@@ -0,0 +1,209 @@
+//! Python bindings for `KvStore` and `KvsClient`. Build and install into the
+//! active environment with `maturin develop` from this directory, then:
+//!
+//! ```python
+//! import kvs
+//!
+//! with kvs.KvStore("/path/to/store") as store:
+//!     store.set("key", "value")
+//!     store.get("key")  # "value"
+//!
+//! with kvs.KvsClient("127.0.0.1:4000") as client:
+//!     client.get("key")
+//! ```
+//!
+//! Keys are `str` or `bytes` and values are `str`. Removing a missing key
+//! raises `KeyError`; other failures raise `kvs.KvsError`.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyType;
+
+use kvs::{KvStoreError, KvsEngine, KvsReader, KvsWriter};
+
+create_exception!(kvs, KvsError, PyException);
+
+fn to_py_err(err: KvStoreError) -> PyErr {
+    match err {
+        KvStoreError::UnknownKeyError => PyKeyError::new_err("Key not found"),
+        err => KvsError::new_err(err.to_string()),
+    }
+}
+
+fn closed() -> PyErr {
+    KvsError::new_err("Already closed")
+}
+
+/// A key given as `str` or `bytes`.
+#[derive(FromPyObject)]
+enum Key {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Key {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Key::Str(key) => key.into_bytes(),
+            Key::Bytes(key) => key,
+        }
+    }
+}
+
+/// A store opened in this process. Only one process may have a store open
+/// at a time, so close it, or use it in a `with` block, before a server
+/// opens the same directory.
+#[pyclass(unsendable)]
+struct KvStore {
+    store: Option<kvs::KvStore>,
+}
+
+impl KvStore {
+    fn store(&mut self) -> PyResult<&mut kvs::KvStore> {
+        self.store.as_mut().ok_or_else(closed)
+    }
+}
+
+#[pymethods]
+impl KvStore {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<KvStore> {
+        let store = kvs::KvStore::open(path).map_err(to_py_err)?;
+        Ok(KvStore { store: Some(store) })
+    }
+
+    /// The key's value, or `None` if it isn't set.
+    fn get(&mut self, key: Key) -> PyResult<Option<String>> {
+        self.store()?.get(key.into_bytes()).map_err(to_py_err)
+    }
+
+    fn set(&mut self, key: Key, value: String) -> PyResult<()> {
+        self.store()?
+            .set(key.into_bytes(), value)
+            .map_err(to_py_err)
+    }
+
+    fn remove(&mut self, key: Key) -> PyResult<()> {
+        self.store()?.remove(key.into_bytes()).map_err(to_py_err)
+    }
+
+    /// Every key starting with `prefix` and its value, in key order.
+    #[pyo3(signature = (prefix = Key::Bytes(Vec::new())))]
+    fn scan(&mut self, prefix: Key) -> PyResult<Vec<(Vec<u8>, String)>> {
+        self.store()?
+            .scan(&prefix.into_bytes(), None, usize::MAX)
+            .map_err(to_py_err)
+    }
+
+    fn __contains__(&mut self, key: Key) -> PyResult<bool> {
+        self.store()?
+            .contains(&key.into_bytes())
+            .map_err(to_py_err)
+    }
+
+    /// Flush and close the store. Later calls raise `KvsError`.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(mut store) = self.store.take() {
+            store.flush().map_err(|err| to_py_err(err.into()))?;
+        }
+        Ok(())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<&Bound<'_, PyType>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.close()?;
+        Ok(false)
+    }
+}
+
+/// A connection to a `kvs-server`.
+#[pyclass(unsendable)]
+struct KvsClient {
+    client: Option<kvs::KvsClient>,
+}
+
+impl KvsClient {
+    fn client(&mut self) -> PyResult<&mut kvs::KvsClient> {
+        self.client.as_mut().ok_or_else(closed)
+    }
+}
+
+#[pymethods]
+impl KvsClient {
+    /// Connect to the server at `addr`, e.g. "127.0.0.1:4000", and
+    /// authenticate if `user` and `token` are given.
+    #[new]
+    #[pyo3(signature = (addr, user = None, token = None))]
+    fn new(addr: &str, user: Option<String>, token: Option<String>) -> PyResult<KvsClient> {
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| PyValueError::new_err(format!("Invalid address {}", addr)))?;
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut client =
+            kvs::KvsClient::new(logger, addr).map_err(|err| to_py_err(err.into()))?;
+        if let (Some(user), Some(token)) = (user, token) {
+            client.auth(user, token).map_err(to_py_err)?;
+        }
+
+        Ok(KvsClient {
+            client: Some(client),
+        })
+    }
+
+    /// The key's value, or `None` if it isn't set.
+    fn get(&mut self, key: Key) -> PyResult<Option<String>> {
+        self.client()?.get(key.into_bytes()).map_err(to_py_err)
+    }
+
+    fn set(&mut self, key: Key, value: String) -> PyResult<()> {
+        self.client()?
+            .set(key.into_bytes(), value)
+            .map_err(to_py_err)
+    }
+
+    fn remove(&mut self, key: Key) -> PyResult<()> {
+        self.client()?
+            .remove(key.into_bytes())
+            .map_err(to_py_err)
+    }
+
+    /// Close the connection. Later calls raise `KvsError`.
+    fn close(&mut self) {
+        self.client = None;
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<&Bound<'_, PyType>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> bool {
+        self.close();
+        false
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "kvs")]
+fn kvs_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<KvStore>()?;
+    m.add_class::<KvsClient>()?;
+    m.add("KvsError", m.py().get_type::<KvsError>())?;
+    Ok(())
+}
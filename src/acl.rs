@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Message;
+use crate::{Compare, KvStoreError, Result, TxnOp};
+
+/// Users allowed on a server, read from a JSON file like
+///
+/// ```json
+/// {"users": [
+///     {"name": "app", "token": "s3cret", "permissions": ["read", "write"]},
+///     {"name": "analyst", "token": "t0ken", "permissions": ["read"],
+///      "prefixes": ["reports:"]}
+/// ]}
+/// ```
+///
+/// A connection to a server with an ACL must authenticate as one of the users
+/// before anything else, and may then only send messages the user's
+/// permissions cover.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    path: PathBuf,
+    users: HashMap<String, User>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AclFile {
+    users: Vec<User>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub token: String,
+    pub permissions: Vec<Permission>,
+    /// Key prefixes the user is limited to. `None` allows every key.
+    #[serde(default)]
+    pub prefixes: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Gets, scans and subscriptions
+    Read,
+    /// Writes, including the ones that read back what they replaced
+    Write,
+    /// Server inspection and maintenance, such as the slow log and reloading
+    /// the ACL
+    Admin,
+}
+
+impl Acl {
+    /// Read the ACL file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Acl> {
+        let path = path.as_ref().to_path_buf();
+        let file: AclFile = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        let users = file
+            .users
+            .into_iter()
+            .map(|user| (user.name.clone(), user))
+            .collect();
+
+        Ok(Acl { path, users })
+    }
+
+    /// Read the ACL file again. The current users stay in place if it can't
+    /// be read.
+    pub(crate) fn reload(&mut self) -> Result<()> {
+        *self = Acl::load(&self.path)?;
+        Ok(())
+    }
+
+    pub(crate) fn authenticate(&self, name: &str, token: &str) -> Option<&User> {
+        self.users.get(name).filter(|user| user.token == token)
+    }
+}
+
+impl User {
+    /// Check that the user may send `message`.
+    pub(crate) fn check(&self, message: &Message) -> Result<()> {
+        match message {
+            Message::Batch(messages) => {
+                return messages.iter().try_for_each(|message| self.check(message))
+            }
+            Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+                return self.check(message)
+            }
+            _ => {}
+        }
+
+        for &permission in required_permissions(message) {
+            if !self.permissions.contains(&permission) {
+                return Err(KvStoreError::PermissionDenied(format!(
+                    "{} needs the {:?} permission",
+                    message.operation(),
+                    permission
+                )));
+            }
+        }
+
+        let Some(prefixes) = &self.prefixes else {
+            return Ok(());
+        };
+        let allowed = |key: &[u8]| {
+            prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_bytes()))
+        };
+        match message_keys(message) {
+            Some(keys) if keys.iter().all(|key| allowed(key)) => Ok(()),
+            Some(_) => Err(KvStoreError::PermissionDenied(format!(
+                "{} touches a key outside the user's prefixes",
+                message.operation()
+            ))),
+            None => Err(KvStoreError::PermissionDenied(format!(
+                "{} can touch any key, which a user limited to prefixes can't send",
+                message.operation()
+            ))),
+        }
+    }
+}
+
+fn required_permissions(message: &Message) -> &'static [Permission] {
+    use Permission::*;
+
+    match message {
+        Message::Auth { .. } | Message::Hello { .. } | Message::Batch(_) => &[],
+        Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+            required_permissions(message)
+        }
+        Message::Get { .. }
+        | Message::GetRange { .. }
+        | Message::Ttl { .. }
+        | Message::Scan { .. }
+        | Message::Analyze { .. }
+        | Message::Digest { .. }
+        | Message::MerkleNodes { .. }
+        | Message::Subscribe { .. } => &[Read],
+        Message::Set { .. }
+        | Message::SetChunk { .. }
+        | Message::SetNx { .. }
+        | Message::Remove { .. }
+        | Message::Append { .. }
+        | Message::Expire { .. }
+        | Message::Persist { .. }
+        | Message::Restore { .. }
+        | Message::Enqueue { .. }
+        | Message::Ack { .. }
+        | Message::Acquire { .. }
+        | Message::Renew { .. }
+        | Message::Release { .. }
+        | Message::Publish { .. } => &[Write],
+        Message::GetSet { .. }
+        | Message::GetDel { .. }
+        | Message::Dequeue { .. }
+        | Message::Txn(_)
+        | Message::Eval { .. } => &[Read, Write],
+        Message::SlowLogGet { .. }
+        | Message::SlowLogReset
+        | Message::HotKeys { .. }
+        | Message::Quotas
+        | Message::Metrics
+        | Message::CompactionHistory
+        | Message::RotateLog
+        | Message::Reopen { .. }
+        | Message::ReloadAcl
+        | Message::SetLogLevel { .. } => &[Admin],
+    }
+}
+
+/// Whether the message, or one in its batch, may write to the store.
+pub(crate) fn is_write(message: &Message) -> bool {
+    match message {
+        Message::Batch(messages) => messages.iter().any(is_write),
+        Message::Traced { message, .. } | Message::Idempotent { message, .. } => is_write(message),
+        // Needs the write permission but only reaches subscribers
+        Message::Publish { .. } => false,
+        message => required_permissions(message).contains(&Permission::Write),
+    }
+}
+
+// The keys a message reads or writes, or `None` if it can reach keys that
+// can't be told from the message itself
+pub(crate) fn message_keys(message: &Message) -> Option<Vec<&[u8]>> {
+    match message {
+        Message::Txn(txn) => {
+            let compared = txn.compare.iter().map(|compare| match compare {
+                Compare::Value { key, .. } | Compare::Exists { key } | Compare::Absent { key } => {
+                    key.as_slice()
+                }
+            });
+            let operated = txn.success.iter().chain(&txn.failure).map(|op| match op {
+                TxnOp::Get { key } | TxnOp::Set { key, .. } | TxnOp::Remove { key } => {
+                    key.as_slice()
+                }
+            });
+            Some(compared.chain(operated).collect())
+        }
+        Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+            message_keys(message)
+        }
+        Message::Scan {
+            pattern: Some(_), ..
+        }
+        | Message::Eval { .. }
+        | Message::Analyze { .. }
+        | Message::Digest { .. }
+        | Message::MerkleNodes { .. }
+        | Message::Enqueue { .. }
+        | Message::Dequeue { .. }
+        | Message::Ack { .. }
+        | Message::Acquire { .. }
+        | Message::Renew { .. }
+        | Message::Release { .. } => None,
+        message => Some(message.key().into_iter().collect()),
+    }
+}
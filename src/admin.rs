@@ -0,0 +1,472 @@
+//! A small admin web UI, served on `ServerConfig::admin_addr`: the engine's
+//! counters, a paged key browser with value previews, and buttons to compact
+//! or rotate the logs. `/metrics` serves the counters for Prometheus to
+//! scrape.
+//!
+//! Each connection gets one response and is closed. On a server with an ACL
+//! every route needs HTTP Basic credentials of a user with the admin
+//! permission, the user's name and token. Without one the UI is only served
+//! on loopback addresses, and only to requests that name the server by IP
+//! address or `localhost`, so a web page can't reach it by rebinding a name
+//! of its own to the server. Either way, buttons pressed on pages of another
+//! origin are refused.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+use crate::acl::{Acl, Permission};
+use crate::server::ServerEngine;
+use crate::{KvStoreError, Metrics};
+
+// Keys listed per page of the browser
+const PAGE_LEN: usize = 50;
+// Characters of each value shown in the key list
+const PREVIEW_LEN: usize = 120;
+// Characters of a value shown on its own page
+const VALUE_LEN: usize = 64 * 1024;
+// Largest request line and headers accepted
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+// Browsers open connections they may never use. The server serves one
+// connection at a time, so don't let them hold it up.
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, Vec<u8>>,
+    // Header names are lowercased
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    // Whether a user of `acl` with the admin permission sent the request
+    fn is_admin(&self, acl: &Acl) -> bool {
+        let credentials = self
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let Some((name, token)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+            return false;
+        };
+        acl.authenticate(name, token)
+            .is_some_and(|user| user.permissions.contains(&Permission::Admin))
+    }
+
+    // Whether the request names the server by IP address or `localhost`,
+    // rather than by a name anyone could point at it
+    fn names_local_host(&self) -> bool {
+        let Some(host) = self.header("host") else {
+            return false;
+        };
+        let name = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+        };
+        name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+    }
+
+    // Whether a browser sent the request from a page of another origin.
+    // Requests that don't say where they come from, as from scripts, aren't.
+    fn is_cross_origin(&self) -> bool {
+        if let Some(site) = self.header("sec-fetch-site") {
+            return !matches!(site, "same-origin" | "none");
+        }
+        let Some(source) = self.header("origin").or_else(|| self.header("referer")) else {
+            return false;
+        };
+        let source_host = source
+            .split_once("://")
+            .map(|(_, rest)| rest.split('/').next().unwrap_or_default());
+        source_host.is_none() || source_host != self.header("host")
+    }
+}
+
+/// Answer one HTTP request on `stream`, from a user of `acl` if the server
+/// has one.
+pub(crate) fn serve(
+    stream: TcpStream,
+    engine: &mut ServerEngine,
+    acl: Option<&Acl>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let request = match read_request(BufReader::new(stream.take(MAX_HEAD_LEN))) {
+        Ok(request) => request,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            return respond(&mut writer, "400 Bad Request", &page("Bad request", ""));
+        }
+        Err(err) => return Err(err),
+    };
+
+    match acl {
+        Some(acl) if !request.is_admin(acl) => {
+            return respond_unauthorized(&mut writer);
+        }
+        None if !request.names_local_host() => {
+            return respond(&mut writer, "403 Forbidden", &page("Forbidden", ""));
+        }
+        _ => {}
+    }
+    if request.method == "POST" && request.is_cross_origin() {
+        return respond(&mut writer, "403 Forbidden", &page("Forbidden", ""));
+    }
+
+    let param = |name: &str| request.query.get(name).cloned().unwrap_or_default();
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => {
+            let notice = String::from_utf8_lossy(&param("notice")).into_owned();
+            respond(&mut writer, "200 OK", &overview(engine, &notice))
+        }
+        ("GET", "/keys") => {
+            let after = request.query.get("after").map(Vec::as_slice);
+            respond(
+                &mut writer,
+                "200 OK",
+                &keys(engine, &param("prefix"), after),
+            )
+        }
+        ("GET", "/key") => respond(&mut writer, "200 OK", &value(engine, param("key"))),
+        ("GET", "/metrics") => match engine.engine() {
+            Ok(engine) => respond_with(
+                &mut writer,
+                "200 OK",
+                "text/plain; version=0.0.4; charset=utf-8",
+                &engine.metrics().to_prometheus(),
+            ),
+            Err(err) => respond_with(
+                &mut writer,
+                "503 Service Unavailable",
+                "text/plain; charset=utf-8",
+                &format!("{}\n", err),
+            ),
+        },
+        ("POST", "/compact") => {
+            let notice = match engine.engine().and_then(|engine| engine.compact()) {
+                Ok(true) => "Compacted the logs".to_owned(),
+                Ok(false) => "This engine compacts on its own".to_owned(),
+                Err(err) => format!("Compaction failed: {}", err),
+            };
+            redirect(&mut writer, &notice)
+        }
+        ("POST", "/rotate") => {
+            let notice = match engine.engine().and_then(|engine| engine.rotate_log()) {
+                Ok(Some(log_gen)) => format!("Writing to log {}", log_gen),
+                Ok(None) => "This engine has no logs to rotate".to_owned(),
+                Err(err) => format!("Rotating the log failed: {}", err),
+            };
+            redirect(&mut writer, &notice)
+        }
+        _ => respond(&mut writer, "404 Not Found", &page("Not found", "")),
+    }
+}
+
+fn read_request(mut reader: impl BufRead) -> io::Result<Request> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed HTTP request");
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let (method, target) = (method.to_owned(), target.to_owned());
+
+    // No route needs a body
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8_lossy(&percent_decode(name)).into_owned();
+            (name, percent_decode(value))
+        })
+        .collect();
+
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        headers,
+    })
+}
+
+fn respond_unauthorized(writer: &mut impl Write) -> io::Result<()> {
+    let body = page(
+        "Unauthorized",
+        "<p>Sign in as a user with the admin permission.</p>",
+    );
+    write!(
+        writer,
+        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"kvs\", charset=\"UTF-8\"\r\n\
+         Content-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+fn respond(writer: &mut impl Write, status: &str, body: &str) -> io::Result<()> {
+    respond_with(writer, status, "text/html; charset=utf-8", body)
+}
+
+fn respond_with(
+    writer: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+// Send the browser back to the overview after a button press, so reloading
+// it doesn't press the button again
+fn redirect(writer: &mut impl Write, notice: &str) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 303 See Other\r\nLocation: /?notice={}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        percent_encode(notice.as_bytes())
+    )?;
+    writer.flush()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>kvs: {title}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         td,th{{border:1px solid #ccc;padding:.3em .6em;text-align:left}}\
+         td.value{{font-family:monospace}}form{{display:inline}}</style></head>\n\
+         <body><p><a href=\"/\">Overview</a> | <a href=\"/keys\">Keys</a></p>\
+         <h1>{title}</h1>\n{body}</body></html>\n",
+        title = escape(title),
+        body = body
+    )
+}
+
+fn overview(engine: &mut ServerEngine, notice: &str) -> String {
+    let mut body = String::new();
+    if !notice.is_empty() {
+        let _ = write!(body, "<p><strong>{}</strong></p>", escape(notice));
+    }
+
+    match engine.engine() {
+        Ok(engine) => {
+            body.push_str(&metrics_table(&engine.metrics()));
+            body.push_str(
+                "<p><form method=\"post\" action=\"/compact\"><button>Compact now</button></form> \
+                 <form method=\"post\" action=\"/rotate\"><button>Rotate log</button></form></p>",
+            );
+        }
+        Err(KvStoreError::ReadOnly) => {
+            body.push_str("<p>This server is read-only; its counters and maintenance belong to the primary.</p>");
+        }
+        Err(err) => {
+            let _ = write!(body, "<p>{}</p>", escape(&err.to_string()));
+        }
+    }
+
+    page("Overview", &body)
+}
+
+fn metrics_table(metrics: &Metrics) -> String {
+    let optional = |count: Option<u64>| count.map_or_else(|| "n/a".to_owned(), |n| n.to_string());
+    let largest =
+        |up_to: Option<u64>| up_to.map_or_else(|| "-".to_owned(), |n| format!("≤ {} bytes", n));
+    let rows = [
+        ("Reads", metrics.reads.to_string()),
+        ("Writes", metrics.writes.to_string()),
+        ("Bytes read", metrics.bytes_read.to_string()),
+        ("Bytes written", metrics.bytes_written.to_string()),
+        ("Flushes", metrics.flushes.to_string()),
+        ("Expired keys reaped", metrics.reaped.to_string()),
+        ("Cache hits", optional(metrics.cache_hits)),
+        ("Cache misses", optional(metrics.cache_misses)),
+        ("Largest key set", largest(metrics.key_lens.max())),
+        ("Largest value set", largest(metrics.value_lens.max())),
+        (
+            "Memory used",
+            metrics.memory.map_or_else(
+                || "n/a".to_owned(),
+                |memory| format!("~{} bytes", memory.total_bytes()),
+            ),
+        ),
+        (
+            "Records read from disk",
+            metrics.read_path.map_or_else(
+                || "n/a".to_owned(),
+                |read_path| {
+                    let average = |nanos: u64| nanos / read_path.reads.max(1) / 1000;
+                    format!(
+                        "{} ({} seeks); on average {} µs on disk, {} µs decoding",
+                        read_path.reads,
+                        read_path.seeks,
+                        average(read_path.disk_nanos_total),
+                        average(read_path.decode_nanos_total)
+                    )
+                },
+            ),
+        ),
+    ];
+
+    let mut table = String::from("<table>");
+    for (name, value) in rows {
+        let _ = write!(table, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+    }
+    table.push_str("</table>");
+    table
+}
+
+fn keys(engine: &mut ServerEngine, prefix: &[u8], after: Option<&[u8]>) -> String {
+    let mut body = format!(
+        "<form method=\"get\" action=\"/keys\">Prefix <input name=\"prefix\" value=\"{}\"> \
+         <button>Filter</button></form>",
+        escape(&String::from_utf8_lossy(prefix))
+    );
+
+    let entries = match engine.reader().scan(prefix, after, PAGE_LEN) {
+        Ok(entries) => entries,
+        Err(err) => {
+            let _ = write!(body, "<p>{}</p>", escape(&err.to_string()));
+            return page("Keys", &body);
+        }
+    };
+    if entries.is_empty() {
+        body.push_str("<p>No keys.</p>");
+        return page("Keys", &body);
+    }
+
+    body.push_str("<table><tr><th>Key</th><th>Value</th></tr>");
+    for (key, value) in &entries {
+        let _ = write!(
+            body,
+            "<tr><td><a href=\"/key?key={}\">{}</a></td><td class=\"value\">{}</td></tr>",
+            percent_encode(key),
+            escape(&String::from_utf8_lossy(key)),
+            escape(&truncate(value, PREVIEW_LEN))
+        );
+    }
+    body.push_str("</table>");
+
+    if entries.len() == PAGE_LEN {
+        let (last, _) = &entries[entries.len() - 1];
+        let _ = write!(
+            body,
+            "<p><a href=\"/keys?prefix={}&amp;after={}\">Next page</a></p>",
+            percent_encode(prefix),
+            percent_encode(last)
+        );
+    }
+
+    page("Keys", &body)
+}
+
+fn value(engine: &mut ServerEngine, key: Vec<u8>) -> String {
+    let title = String::from_utf8_lossy(&key).into_owned();
+    let body = match engine.reader().get(key) {
+        Ok(Some(value)) => format!(
+            "<p>{} bytes</p><pre>{}</pre>",
+            value.len(),
+            escape(&truncate(&value, VALUE_LEN))
+        ),
+        Ok(None) => "<p>Key not found.</p>".to_owned(),
+        Err(err) => format!("<p>{}</p>", escape(&err.to_string())),
+    };
+
+    page(&title, &body)
+}
+
+fn truncate(value: &str, len: usize) -> String {
+    match value.char_indices().nth(len) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value.to_owned(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
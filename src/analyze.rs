@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsReader, Result, SizeHistogram};
+
+// Entries read per scan while walking the keyspace
+const ANALYZE_PAGE_LEN: usize = 1024;
+// Prefixes reported in each of the top lists
+const TOP_PREFIXES: usize = 10;
+
+/// Size statistics of a uniform random sample of the live keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyspaceSample {
+    /// Live keys the sample was drawn from
+    pub keys: u64,
+    pub sampled: u64,
+    /// Sampled key lengths in bytes, in power-of-two buckets
+    pub key_lens: Vec<Bucket>,
+    /// Sampled value lengths in bytes, in power-of-two buckets
+    pub value_lens: Vec<Bucket>,
+    /// Sampled prefixes with the most keys, most first
+    pub top_prefixes_by_keys: Vec<PrefixStats>,
+    /// Sampled prefixes with the most key and value bytes, most first
+    pub top_prefixes_by_bytes: Vec<PrefixStats>,
+}
+
+/// Number of sampled lengths no larger than `up_to` and larger than the
+/// previous bucket's bound.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bucket {
+    pub up_to: u64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// The key up to and including its first delimiter, or the whole key if
+    /// it has none
+    #[serde(with = "crate::encoding")]
+    pub prefix: Vec<u8>,
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// Draw `samples` live keys uniformly at random and summarize their key and
+/// value sizes and how they group by prefix up to `delimiter`. Every entry is
+/// read once, but only the sample is kept in memory.
+pub fn analyze(
+    reader: &mut dyn KvsReader,
+    samples: usize,
+    delimiter: u8,
+) -> Result<KeyspaceSample> {
+    let mut rng = SmallRng::from_entropy();
+    // (key length, value length, prefix) of each sampled entry
+    let mut reservoir: Vec<(u64, u64, Vec<u8>)> = Vec::with_capacity(samples);
+    let mut keys = 0u64;
+    let mut start_after: Option<Vec<u8>> = None;
+
+    loop {
+        let page = reader.scan(b"", start_after.as_deref(), ANALYZE_PAGE_LEN)?;
+        let page_len = page.len();
+
+        for (key, value) in page {
+            keys += 1;
+            // Reservoir sampling: the n-th key replaces a sampled one with
+            // probability samples / n
+            let slot = match reservoir.len() < samples {
+                true => reservoir.len() as u64,
+                false => rng.gen_range(0..keys),
+            };
+
+            if slot < samples as u64 {
+                let prefix = match key.iter().position(|&byte| byte == delimiter) {
+                    Some(end) => key[..=end].to_vec(),
+                    None => key.clone(),
+                };
+                let entry = (key.len() as u64, value.len() as u64, prefix);
+                match reservoir.get_mut(slot as usize) {
+                    Some(sampled) => *sampled = entry,
+                    None => reservoir.push(entry),
+                }
+            }
+            start_after = Some(key);
+        }
+
+        if page_len < ANALYZE_PAGE_LEN {
+            break;
+        }
+    }
+
+    let mut prefixes: HashMap<Vec<u8>, PrefixStats> = HashMap::new();
+    for (key_len, value_len, prefix) in &reservoir {
+        let stats = prefixes
+            .entry(prefix.clone())
+            .or_insert_with(|| PrefixStats {
+                prefix: prefix.clone(),
+                keys: 0,
+                bytes: 0,
+            });
+        stats.keys += 1;
+        stats.bytes += key_len + value_len;
+    }
+    let prefixes: Vec<PrefixStats> = prefixes.into_values().collect();
+
+    Ok(KeyspaceSample {
+        keys,
+        sampled: reservoir.len() as u64,
+        key_lens: histogram(reservoir.iter().map(|(key_len, _, _)| *key_len)),
+        value_lens: histogram(reservoir.iter().map(|(_, value_len, _)| *value_len)),
+        top_prefixes_by_keys: top_prefixes(&prefixes, |stats| stats.keys),
+        top_prefixes_by_bytes: top_prefixes(&prefixes, |stats| stats.bytes),
+    })
+}
+
+// Count lengths into power-of-two buckets, leaving out the empty ones
+fn histogram(lens: impl Iterator<Item = u64>) -> Vec<Bucket> {
+    let mut histogram = SizeHistogram::default();
+    for len in lens {
+        histogram.record(len as usize);
+    }
+    histogram.buckets()
+}
+
+fn top_prefixes(prefixes: &[PrefixStats], by: impl Fn(&PrefixStats) -> u64) -> Vec<PrefixStats> {
+    let mut top = prefixes.to_vec();
+    top.sort_by(|a, b| by(b).cmp(&by(a)).then_with(|| a.prefix.cmp(&b.prefix)));
+    top.truncate(TOP_PREFIXES);
+    top
+}
@@ -1,8 +1,12 @@
-use std::net::{Ipv4Addr, SocketAddr};
-use std::{error::Error, net::IpAddr};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{command, Parser, Subcommand};
-use kvs::KvsClient;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use kvs::protocol::Compression;
+use kvs::{KvStore, KvsClient, KvsEngine, SyncPeer};
 use slog::{o, Drain};
 
 #[derive(Parser)]
@@ -10,14 +14,46 @@ use slog::{o, Drain};
 struct Cli {
     /// Address on which to connect to server to
     #[arg(
-		long,
-		global=true,
-		default_value_t=SocketAddr::new(
-			IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-			8080,
-		)
-	)]
-    addr: SocketAddr,
+        long,
+        global = true,
+        value_name = "HOST:PORT",
+        default_value = "127.0.0.1:8080"
+    )]
+    addr: String,
+
+    /// Fall back to this server, e.g. a replica, when the one at --addr is
+    /// unreachable or fails mid-request. Can be given several times; they
+    /// are tried in order
+    #[arg(long, global = true, value_name = "HOST:PORT")]
+    fallback_addr: Vec<String>,
+
+    /// Treat keys as hex-encoded bytes, for keys that aren't valid UTF-8
+    #[arg(long, global = true)]
+    key_hex: bool,
+
+    /// Authenticate as this user of the server's ACL
+    #[arg(long, global = true, requires = "auth_token")]
+    user: Option<String>,
+
+    /// The user's token
+    #[arg(long = "token", global = true, requires = "user")]
+    auth_token: Option<String>,
+
+    /// Tag the request with this id in the server's logs, traces and slow
+    /// log
+    #[arg(long, global = true, value_name = "ID")]
+    trace_id: Option<String>,
+
+    /// Send the command with this idempotency key. Running it again with the
+    /// same key, e.g. after a timeout, gets the first response back instead
+    /// of running it twice, while the server remembers the key.
+    #[arg(long, global = true, value_name = "KEY")]
+    idempotency_key: Option<String>,
+
+    /// Have the server compress responses of at least this many bytes with
+    /// zstd, for a slow link
+    #[arg(long, global = true, value_name = "BYTES")]
+    compress_above: Option<usize>,
 
     /// Command to server
     #[command(subcommand)]
@@ -27,43 +63,698 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 enum CliCommand {
     /// Set a key to a value
+    #[command(after_help = "Examples:
+  kvs-client set user:1 alice
+  kvs-client set session:42 token --ttl 3600
+  kvs-client set config - < config.json")]
     Set {
+        /// The key, or its bytes in hex with --key-hex
         key: String,
+        /// The value, or "-" to read it from stdin
         value: String,
+        /// Expire the key after this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        ttl: Option<u64>,
     },
-    // Get the value to a key
+    /// Print a key's value, or "Key not found"
+    #[command(after_help = "Examples:
+  kvs-client get user:1
+  kvs-client get config --raw > config.json")]
     Get {
+        /// The key, or its bytes in hex with --key-hex
+        key: String,
+        /// Write just the value, without a trailing newline, and fail if the
+        /// key isn't set
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Print LEN bytes of a key's value from byte OFFSET, or "Key not found"
+    #[command(after_help = "Example:
+  kvs-client get-range upload:7 0 512")]
+    GetRange {
+        /// The key, or its bytes in hex with --key-hex
         key: String,
+        offset: usize,
+        len: usize,
     },
+    /// Remove a key. Fails if the key isn't set
+    #[command(after_help = "Example:
+  kvs-client rm user:1")]
     Rm {
+        /// The key, or its bytes in hex with --key-hex
         key: String,
     },
+    /// Append to a key's value, or set it if absent, and print the new
+    /// length in bytes
+    #[command(after_help = "Example:
+  kvs-client append log:today 'line 1;'")]
+    Append { key: String, suffix: String },
+    /// Print the values of several keys, one per line in argument order
+    #[command(after_help = "Example:
+  kvs-client mget user:1 user:2 user:3")]
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Set several keys, given as key=value pairs
+    #[command(after_help = "Example:
+  kvs-client mset user:1=alice user:2=bob")]
+    Mset {
+        #[arg(required = true, value_name = "KEY=VALUE")]
+        entries: Vec<String>,
+    },
+    /// Print the seconds left before a key expires
+    #[command(after_help = "Example:
+  kvs-client ttl session:42")]
+    Ttl { key: String },
+    /// Make a key expire after a number of seconds
+    #[command(after_help = "Example:
+  kvs-client expire session:42 600")]
+    Expire { key: String, seconds: u64 },
+    /// Remove a key's expiry
+    #[command(after_help = "Example:
+  kvs-client persist session:42")]
+    Persist { key: String },
+    /// Bring back a removed key's value, if the server keeps soft deletes
+    #[command(after_help = "Example:
+  kvs-client restore user:1")]
+    Restore { key: String },
+    /// Run a Lua script on the server and print what it returns. The script
+    /// reads its arguments from ARGV and the store through kvs.get, kvs.set
+    /// and kvs.remove
+    #[command(after_help = "Example:
+  kvs-client eval 'return kvs.get(ARGV[1])' user:1")]
+    Eval { script: String, args: Vec<String> },
+    /// Append an item to a queue and print its id
+    #[command(after_help = "Example:
+  kvs-client enqueue jobs 'resize image 7'")]
+    Enqueue { queue: String, item: String },
+    /// Take the oldest available item of a queue and print its id, delivery
+    /// count and item separated by tabs. Prints nothing if none is available
+    #[command(after_help = "Example:
+  kvs-client dequeue jobs --visibility 60")]
+    Dequeue {
+        queue: String,
+        /// Seconds before the item is handed out again unless acknowledged
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        visibility: u64,
+    },
+    /// Remove a dequeued item once it has been processed
+    #[command(after_help = "Example:
+  kvs-client ack jobs 3")]
+    Ack { queue: String, id: u64 },
+    /// Take a lock and print the lease's fencing token. Fails if someone else
+    /// holds the lock
+    #[command(after_help = "Example:
+  kvs-client acquire nightly-backup --ttl 300")]
+    Acquire {
+        lock: String,
+        /// Seconds before the lease expires unless renewed
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        ttl: u64,
+    },
+    /// Extend a lease held with TOKEN
+    #[command(after_help = "Example:
+  kvs-client renew nightly-backup 12 --ttl 300")]
+    Renew {
+        lock: String,
+        token: u64,
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        ttl: u64,
+    },
+    /// Give up a lease held with TOKEN
+    #[command(after_help = "Example:
+  kvs-client release nightly-backup 12")]
+    Release { lock: String, token: u64 },
+    /// Run a transaction given as JSON, with `compare`, `success` and
+    /// `failure` lists, and print the response as JSON
+    #[command(after_help = "Examples:
+  kvs-client txn '{\"compare\": [{\"Absent\": {\"key\": \"user:1\"}}],
+                   \"success\": [{\"Set\": {\"key\": \"user:1\", \"value\": \"alice\"}}]}'")]
+    Txn { txn: String },
+    /// Send a message to a channel's subscribers and print how many got it
+    #[command(after_help = "Example:
+  kvs-client publish news 'hello'")]
+    Publish { channel: String, message: String },
+    /// Print messages published to the channels as they arrive, one per line
+    /// as channel and message separated by a tab
+    #[command(after_help = "Example:
+  kvs-client subscribe news alerts")]
+    Subscribe {
+        #[arg(required = true)]
+        channels: Vec<String>,
+    },
+    /// Inspect the server's log of slow requests
+    #[command(after_help = "Examples:
+  kvs-client slowlog get 20
+  kvs-client slowlog reset")]
+    Slowlog {
+        #[command(subcommand)]
+        command: SlowlogCommand,
+    },
+    /// Print the keys the server has seen read most, one per line: key,
+    /// estimated reads and the time of the last sampled read (Unix ms). The
+    /// server must have been started with --hotkeys-sample-rate
+    #[command(after_help = "Example:
+  kvs-client hotkeys --top 20")]
+    Hotkeys {
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Print the server's key prefix quotas, one per line: prefix, keys,
+    /// key limit, bytes and byte limit, with "-" for no limit
+    #[command(after_help = "Example:
+  kvs-client quotas")]
+    Quotas,
+    /// Print the server engine's work counters, one per line as name and
+    /// value separated by a tab. Cache counters are left out for engines
+    /// whose cache can't be observed
+    #[command(after_help = "Examples:
+  kvs-client metrics
+  kvs-client metrics --prometheus > kvs.prom")]
+    Metrics {
+        /// Print them in Prometheus' text exposition format instead
+        #[arg(long)]
+        prometheus: bool,
+    },
+    /// Print the stats of the server engine's latest compactions, oldest
+    /// first, one per line: finish time (Unix ms), duration in ms, input
+    /// bytes, bytes written, stale bytes, live keys and write amplification
+    #[command(after_help = "Example:
+  kvs-client compactions")]
+    Compactions,
+    /// Seal the server's active log and start a new one, e.g. before a
+    /// backup, and print the new log's generation
+    #[command(after_help = "Example:
+  kvs-client rotate-log")]
+    RotateLog,
+    /// Make the server read its ACL file again
+    #[command(after_help = "Example:
+  kvs-client reload-acl --user admin --token s3cret")]
+    ReloadAcl,
+    /// Change the server's log level until it restarts
+    #[command(after_help = "Example:
+  kvs-client log-level debug")]
+    LogLevel {
+        /// One of error, warn, info, debug and trace
+        level: String,
+    },
+    /// Make the server close its engine and open it again, e.g. after
+    /// restoring a backup into its data directory
+    #[command(after_help = "Examples:
+  kvs-client reopen
+  kvs-client reopen /var/lib/kvs-restored")]
+    Reopen {
+        /// Open this directory, on the server, instead of the one the engine
+        /// was opened from
+        dir: Option<PathBuf>,
+    },
+    /// Sample random live keys and print their key and value size
+    /// histograms and the prefixes with the most keys and bytes
+    #[command(after_help = "Example:
+  kvs-client analyze --samples 10000 --delimiter /")]
+    Analyze {
+        #[arg(long, default_value_t = 1000)]
+        samples: usize,
+        /// Keys are grouped by their text up to and including this byte
+        #[arg(long, default_value_t = ':')]
+        delimiter: char,
+    },
+    /// Make this server hold the same keys and values as another server or
+    /// a stopped store's data directory, copying only the keys that differ.
+    /// Key prefixes are compared by digest first, so only prefixes that
+    /// differ are read in full.
+    #[command(after_help = "Examples:
+  kvs-client sync --from 10.0.0.2:4000 --addr 10.0.0.3:4000
+  kvs-client sync --from-dir /backups/kvs --prefix-len 8")]
+    Sync {
+        /// The server to copy from, reached with the same --user and --token
+        #[arg(long, value_name = "HOST:PORT", required_unless_present = "from_dir")]
+        from: Option<String>,
+        /// The data directory to copy from
+        #[arg(long, value_name = "DIR", conflicts_with = "from")]
+        from_dir: Option<PathBuf>,
+        /// Compare keys grouped by this many leading bytes
+        #[arg(long, default_value_t = 4)]
+        prefix_len: usize,
+    },
+    /// Compare this server's keys with another server's by Merkle tree and
+    /// print the leaves whose keys they disagree on, one per line
+    #[command(after_help = "Example:
+  kvs-client diff --with 10.0.0.2:4000 --depth 16")]
+    Diff {
+        /// The server to compare with, reached with the same --user and --token
+        #[arg(long, value_name = "HOST:PORT")]
+        with: String,
+        /// Levels below the root; the tree has 2^depth leaves
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Print every key starting with a prefix and its value, one per line
+    #[command(after_help = "Examples:
+  kvs-client scan user:
+  kvs-client scan --glob 'user:*:settings'")]
+    Scan {
+        #[arg(default_value = "")]
+        prefix: String,
+        /// Print the keys matching a glob such as "user:*:settings" instead
+        #[arg(long, conflicts_with = "prefix")]
+        glob: Option<String>,
+    },
+    /// Print a completion script for a shell. Nothing is sent to the server
+    #[command(after_help = "Examples:
+  kvs-client completions bash > /etc/bash_completion.d/kvs-client
+  kvs-client completions zsh > \"${fpath[1]}/_kvs-client\"
+  kvs-client completions fish > ~/.config/fish/completions/kvs-client.fish")]
+    Completions { shell: Shell },
+}
+
+#[derive(Debug, Subcommand)]
+enum SlowlogCommand {
+    /// Print the newest entries, one per line: id, timestamp (Unix ms),
+    /// duration (µs), connection, operation, key, request and response bytes,
+    /// and the trace id if the request had one
+    Get {
+        #[arg(default_value_t = 10)]
+        count: usize,
+    },
+    /// Clear the slow log
+    Reset,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let Cli { addr, command, .. } = Cli::parse();
+    let Cli {
+        addr,
+        fallback_addr,
+        key_hex,
+        user,
+        auth_token,
+        trace_id,
+        idempotency_key,
+        compress_above,
+        command,
+    } = Cli::parse();
+
+    if let CliCommand::Completions { shell } = command {
+        clap_complete::generate(shell, &mut Cli::command(), "kvs-client", &mut io::stdout());
+        return Ok(());
+    }
+
+    let encode_key = |key: String| -> Result<Vec<u8>, Box<dyn Error>> {
+        if key_hex {
+            Ok(hex::decode(key)?)
+        } else {
+            Ok(key.into_bytes())
+        }
+    };
 
     let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
 
     let logger = slog::Logger::root(
         drain,
-        o!("address" => addr, "command" => format!("{:?}", command)),
+        o!("address" => addr.clone(), "command" => format!("{:?}", command)),
     );
 
-    let mut client = KvsClient::new(logger, addr)?;
+    let mut client = match fallback_addr.is_empty() {
+        true => KvsClient::new(logger.clone(), addr)?,
+        false => {
+            let addrs = std::iter::once(addr).chain(fallback_addr).collect();
+            KvsClient::builder(logger.clone()).connect_failover(addrs)?
+        }
+    };
+    client.set_trace_id(trace_id);
+    if let Some(compress_above) = compress_above {
+        client.set_compression(vec![Compression::Zstd], compress_above)?;
+    }
+    if let (Some(user), Some(token)) = (&user, &auth_token) {
+        client.auth(user.clone(), token.clone())?;
+    }
+    if let Some(idempotency_key) = idempotency_key {
+        client.set_idempotency_key(idempotency_key);
+    }
 
     match command {
-        CliCommand::Set { key, value } => client.set(key, value)?,
-        CliCommand::Get { key } => {
-            let value = client.get(key)?;
+        CliCommand::Set { key, value, ttl } => {
+            let value = match value.as_str() {
+                "-" => {
+                    let mut value = String::new();
+                    io::stdin().read_to_string(&mut value)?;
+                    value
+                }
+                _ => value,
+            };
+            match ttl {
+                Some(ttl) => {
+                    client.set_with_ttl(encode_key(key)?, value, Duration::from_secs(ttl))?
+                }
+                None => client.set(encode_key(key)?, value)?,
+            }
+        }
+        CliCommand::Get { key, raw } => {
+            let value = client.get(encode_key(key)?)?;
 
-            match value {
-                None => println!("Key not found"),
+            match (value, raw) {
+                (None, false) => println!("Key not found"),
+                (None, true) => return Err("Key not found".into()),
+                (Some(value), false) => println!("{}", value),
+                (Some(value), true) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(value.as_bytes())?;
+                    stdout.flush()?;
+                }
+            }
+        }
+        CliCommand::GetRange { key, offset, len } => {
+            match client.get_range(encode_key(key)?, offset, len)? {
                 Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }
+        }
+        CliCommand::Rm { key } => client.remove(encode_key(key)?)?,
+        CliCommand::Append { key, suffix } => {
+            println!("{}", client.append(encode_key(key)?, suffix)?)
+        }
+        CliCommand::Mget { keys } => {
+            let keys = keys
+                .into_iter()
+                .map(encode_key)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for value in client.get_many(keys)? {
+                match value {
+                    None => println!("Key not found"),
+                    Some(value) => println!("{}", value),
+                }
+            }
+        }
+        CliCommand::Mset { entries } => {
+            let entries = entries
+                .into_iter()
+                .map(|entry| match entry.split_once('=') {
+                    Some((key, value)) => Ok((encode_key(key.to_owned())?, value.to_owned())),
+                    None => Err(format!("Expected KEY=VALUE, got {:?}", entry).into()),
+                })
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+            client.set_many(entries)?
+        }
+        CliCommand::Ttl { key } => match client.ttl(encode_key(key)?)? {
+            // Round up so a key with time left never reports 0
+            Some(ttl) => println!("{}", (ttl.as_millis() as u64).div_ceil(1000)),
+            None => println!("No expiry"),
+        },
+        CliCommand::Expire { key, seconds } => {
+            client.expire(encode_key(key)?, Duration::from_secs(seconds))?
+        }
+        CliCommand::Persist { key } => client.persist(encode_key(key)?)?,
+        CliCommand::Restore { key } => client.restore(encode_key(key)?)?,
+        CliCommand::Enqueue { queue, item } => println!("{}", client.enqueue(queue, item)?),
+        CliCommand::Dequeue { queue, visibility } => {
+            if let Some(item) = client.dequeue(queue, Duration::from_secs(visibility))? {
+                println!("{}\t{}\t{}", item.id, item.deliveries, item.item);
+            }
+        }
+        CliCommand::Ack { queue, id } => client.ack(queue, id)?,
+        CliCommand::Acquire { lock, ttl } => {
+            match client.acquire(lock, Duration::from_secs(ttl))? {
+                Some(token) => println!("{}", token),
+                None => return Err("Lock is held".into()),
+            }
+        }
+        CliCommand::Renew { lock, token, ttl } => {
+            client.renew(lock, token, Duration::from_secs(ttl))?
+        }
+        CliCommand::Release { lock, token } => client.release(lock, token)?,
+        CliCommand::Txn { txn } => {
+            let response = client.txn(serde_json::from_str(&txn)?)?;
+            println!("{}", serde_json::to_string(&response)?);
+        }
+        CliCommand::Publish { channel, message } => {
+            println!("{}", client.publish(channel, message)?);
+        }
+        CliCommand::Subscribe { channels } => {
+            for published in client.subscribe(channels)? {
+                let (channel, message) = published?;
+                println!("{}\t{}", channel, message);
+            }
+        }
+        CliCommand::Eval { script, args } => {
+            if let Some(value) = client.eval(script, args)? {
+                println!("{}", value);
+            }
+        }
+        CliCommand::Metrics { prometheus: true } => print!("{}", client.metrics()?.to_prometheus()),
+        CliCommand::Compactions => {
+            for stats in client.compaction_history()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+                    stats.finished_at_ms,
+                    stats.duration.as_millis(),
+                    stats.input_bytes,
+                    stats.bytes_written,
+                    stats.stale_bytes,
+                    stats.keys,
+                    stats.write_amplification()
+                );
+            }
+        }
+        CliCommand::Metrics { prometheus: false } => {
+            let metrics = client.metrics()?;
+            let mut counters = vec![
+                ("reads", Some(metrics.reads)),
+                ("writes", Some(metrics.writes)),
+                ("bytes_read", Some(metrics.bytes_read)),
+                ("bytes_written", Some(metrics.bytes_written)),
+                ("flushes", Some(metrics.flushes)),
+                ("reaped", Some(metrics.reaped)),
+                ("cache_hits", metrics.cache_hits),
+                ("cache_misses", metrics.cache_misses),
+                ("open_log_hits", metrics.open_log_hits),
+                ("open_log_misses", metrics.open_log_misses),
+            ];
+            if let Some(stats) = metrics.buffer_pool {
+                counters.extend([
+                    ("buffers_taken", Some(stats.taken)),
+                    ("buffers_allocated", Some(stats.allocated)),
+                    ("buffers_discarded", Some(stats.discarded)),
+                    ("buffers_pooled", Some(stats.pooled)),
+                    ("buffers_pooled_bytes", Some(stats.pooled_bytes)),
+                ]);
+            }
+            if let Some(memory) = metrics.memory {
+                counters.extend([
+                    ("keys", Some(memory.keys)),
+                    ("keydir_bytes", Some(memory.keydir_bytes)),
+                    ("bloom_bytes", Some(memory.bloom_bytes)),
+                    ("cache_bytes", Some(memory.cache_bytes)),
+                ]);
+            }
+            if let Some(read_path) = metrics.read_path {
+                counters.extend([
+                    ("record_reads", Some(read_path.reads)),
+                    ("record_seeks", Some(read_path.seeks)),
+                    ("record_disk_nanos", Some(read_path.disk_nanos_total)),
+                    ("record_decode_nanos", Some(read_path.decode_nanos_total)),
+                ]);
+            }
+            for (name, value) in counters {
+                if let Some(value) = value {
+                    println!("{}\t{}", name, value);
+                }
+            }
+            let mut histograms = vec![
+                ("key bytes", "sets", metrics.key_lens),
+                ("value bytes", "sets", metrics.value_lens),
+            ];
+            if let Some(read_path) = metrics.read_path {
+                histograms.extend([
+                    ("record bytes", "reads", read_path.record_lens),
+                    ("disk nanos", "reads", read_path.disk_nanos),
+                    ("decode nanos", "reads", read_path.decode_nanos),
+                ]);
+            }
+            for (title, counted, histogram) in histograms {
+                println!();
+                println!("{}\t{}", title, counted);
+                for bucket in histogram.buckets() {
+                    println!("<={}\t{}", bucket.up_to, bucket.count);
+                }
+            }
+            if let Some(rates) = metrics.rates {
+                println!();
+                println!("operation\tper_sec_1s\tper_sec_10s\tper_sec_60s");
+                for (operation, rates) in rates {
+                    println!(
+                        "{}\t{:.2}\t{:.2}\t{:.2}",
+                        operation, rates.per_sec_1s, rates.per_sec_10s, rates.per_sec_60s
+                    );
+                }
+            }
+        }
+        CliCommand::ReloadAcl => client.reload_acl()?,
+        CliCommand::LogLevel { level } => client.set_log_level(level)?,
+        CliCommand::Reopen { dir } => client.reopen(dir)?,
+        CliCommand::RotateLog => match client.rotate_log()? {
+            Some(log_gen) => println!("{}", log_gen),
+            None => return Err("The server's engine has no logs to rotate".into()),
+        },
+        CliCommand::Analyze { samples, delimiter } => {
+            if !delimiter.is_ascii() {
+                return Err("The delimiter must be an ASCII character".into());
+            }
+            let sample = client.analyze(samples, delimiter as u8)?;
+            println!("keys\t{}", sample.keys);
+            println!("sampled\t{}", sample.sampled);
+            println!();
+            println!("key bytes\tkeys");
+            for bucket in &sample.key_lens {
+                println!("<={}\t{}", bucket.up_to, bucket.count);
+            }
+            println!();
+            println!("value bytes\tkeys");
+            for bucket in &sample.value_lens {
+                println!("<={}\t{}", bucket.up_to, bucket.count);
+            }
+            for (title, prefixes) in [
+                ("by keys", &sample.top_prefixes_by_keys),
+                ("by bytes", &sample.top_prefixes_by_bytes),
+            ] {
+                println!();
+                println!("prefix ({})\tkeys\tbytes", title);
+                for stats in prefixes {
+                    let prefix = if key_hex {
+                        hex::encode(&stats.prefix)
+                    } else {
+                        String::from_utf8_lossy(&stats.prefix).into_owned()
+                    };
+                    println!("{}\t{}\t{}", prefix, stats.keys, stats.bytes);
+                }
+            }
+        }
+        CliCommand::Slowlog {
+            command: SlowlogCommand::Get { count },
+        } => {
+            for entry in client.slowlog_get(count)? {
+                let key = if key_hex {
+                    hex::encode(&entry.key)
+                } else {
+                    String::from_utf8_lossy(&entry.key).into_owned()
+                };
+
+                print!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    entry.id,
+                    entry.timestamp_ms,
+                    entry.duration_us,
+                    entry.connection,
+                    entry.operation,
+                    key,
+                    entry.request_len,
+                    entry.response_len
+                );
+                match entry.trace_id {
+                    Some(trace_id) => println!("\t{}", trace_id),
+                    None => println!(),
+                }
+            }
+        }
+        CliCommand::Hotkeys { top } => {
+            for hot_key in client.hot_keys(top)? {
+                let key = if key_hex {
+                    hex::encode(&hot_key.key)
+                } else {
+                    String::from_utf8_lossy(&hot_key.key).into_owned()
+                };
+                println!("{}\t{}\t{}", key, hot_key.reads, hot_key.last_read_ms);
+            }
+        }
+        CliCommand::Quotas => {
+            let limit =
+                |limit: Option<u64>| limit.map_or("-".to_owned(), |limit| limit.to_string());
+            for usage in client.quotas()? {
+                let prefix = if key_hex {
+                    hex::encode(&usage.quota.prefix)
+                } else {
+                    String::from_utf8_lossy(&usage.quota.prefix).into_owned()
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    prefix,
+                    usage.keys,
+                    limit(usage.quota.max_keys),
+                    usage.bytes,
+                    limit(usage.quota.max_bytes)
+                );
+            }
+        }
+        CliCommand::Slowlog {
+            command: SlowlogCommand::Reset,
+        } => client.slowlog_reset()?,
+        CliCommand::Sync {
+            from,
+            from_dir,
+            prefix_len,
+        } => {
+            let report = match (from, from_dir) {
+                (Some(from), _) => {
+                    let mut source =
+                        KvsClient::new(logger.new(o!("source" => from.clone())), from)?;
+                    if let (Some(user), Some(token)) = (user, auth_token) {
+                        source.auth(user, token)?;
+                    }
+                    kvs::sync(
+                        &mut SyncPeer::Remote(&mut source),
+                        &mut SyncPeer::Remote(&mut client),
+                        prefix_len,
+                    )?
+                }
+                (None, Some(from_dir)) => {
+                    let mut source = KvStore::open(from_dir)?;
+                    kvs::sync(
+                        &mut SyncPeer::Local(&mut source),
+                        &mut SyncPeer::Remote(&mut client),
+                        prefix_len,
+                    )?
+                }
+                (None, None) => return Err("Give --from or --from-dir".into()),
+            };
+            println!("prefixes\t{}", report.prefixes);
+            println!("differing\t{}", report.differing);
+            println!("set\t{}", report.set);
+            println!("removed\t{}", report.removed);
+        }
+        CliCommand::Diff { with, depth } => {
+            let mut other = KvsClient::new(logger.new(o!("with" => with.clone())), with)?;
+            if let (Some(user), Some(token)) = (user, auth_token) {
+                other.auth(user, token)?;
+            }
+            let leaves = kvs::diverging_leaves(
+                &mut SyncPeer::Remote(&mut client),
+                &mut SyncPeer::Remote(&mut other),
+                depth,
+            )?;
+            for leaf in leaves {
+                println!("{}", leaf);
+            }
+        }
+        CliCommand::Completions { .. } => unreachable!("printed before connecting"),
+        CliCommand::Scan { prefix, glob } => {
+            let entries = match glob {
+                Some(pattern) => client.scan_glob(pattern)?,
+                None => client.scan(encode_key(prefix)?)?,
+            };
+
+            for entry in entries {
+                let (key, value) = entry?;
+
+                if key_hex {
+                    println!("{}\t{}", hex::encode(key), value);
+                } else {
+                    println!("{}\t{}", String::from_utf8_lossy(&key), value);
+                }
             }
         }
-        CliCommand::Rm { key } => client.remove(key)?,
     }
 
     Ok(())
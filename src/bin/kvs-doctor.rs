@@ -0,0 +1,266 @@
+use std::env::current_dir;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use kvs::{
+    check_logs, read_log, repair_logs, sync, IndexState, Keyring, KvStore, KvStoreConfig,
+    KvsEngine, LogsCheck, SyncPeer,
+};
+
+/// Inspect and repair a kvs-server data directory while the server is
+/// stopped
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: DoctorCommand,
+    /// The server's --encryption-key-file, to read an encrypted store
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "FILE", global = true)]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum DoctorCommand {
+    /// List the logs and check every record in them. Exits non-zero if the
+    /// server would lose or misread data on its next start.
+    Check {
+        /// The data directory. Default: the current directory
+        dir: Option<PathBuf>,
+        /// The server's --cold-dir, so logs moved there are checked too
+        #[arg(long, value_name = "DIR")]
+        cold_dir: Option<PathBuf>,
+        /// Truncate unreadable log tails, delete leftover temporary files and
+        /// rebuild the keydir index
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Print the records of one log file: command, key, value size, offset
+    /// and length. Exits non-zero at an unreadable record.
+    Dump {
+        /// The log file, e.g. 3.log
+        log: PathBuf,
+        /// Byte offset of the first record to print
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+    },
+    /// Make the target data directory hold the same keys and values as the
+    /// source, copying only the keys that differ. Key prefixes are compared
+    /// by digest first, so only prefixes that differ are read in full.
+    Sync {
+        source: PathBuf,
+        target: PathBuf,
+        /// Compare keys grouped by this many leading bytes
+        #[arg(long, default_value_t = 4)]
+        prefix_len: usize,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    #[cfg(feature = "encryption")]
+    let keys = match &cli.key_file {
+        Some(key_file) => Some(Keyring::from_file(key_file)?),
+        None => None,
+    };
+    #[cfg(not(feature = "encryption"))]
+    let keys: Option<Keyring> = None;
+    let keys = keys.as_ref();
+
+    match cli.command {
+        DoctorCommand::Check { dir, cold_dir, fix } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => current_dir()?,
+            };
+            if is_sled_dir(&dir) {
+                return check_sled(&dir);
+            }
+
+            let cold_dir = cold_dir.as_deref();
+            let check = check_logs(&dir, cold_dir, keys)?;
+            print_check(&check);
+            if !fix {
+                return match check.is_healthy() {
+                    true => Ok(()),
+                    false => Err("Found problems; run with --fix to repair them".into()),
+                };
+            }
+
+            let repaired = repair_logs(&dir, cold_dir, keys)?;
+            println!();
+            println!("Repaired:");
+            print_check(&repaired);
+            Ok(())
+        }
+        DoctorCommand::Dump { log, offset } => dump(&log, offset, keys),
+        DoctorCommand::Sync {
+            source,
+            target,
+            prefix_len,
+        } => {
+            let mut source = open_engine(&source, keys)?;
+            let mut target = open_engine(&target, keys)?;
+            let report = sync(
+                &mut SyncPeer::Local(&mut *source),
+                &mut SyncPeer::Local(&mut *target),
+                prefix_len,
+            )?;
+            println!("prefixes\t{}", report.prefixes);
+            println!("differing\t{}", report.differing);
+            println!("set\t{}", report.set);
+            println!("removed\t{}", report.removed);
+            Ok(())
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn open_engine(dir: &Path, keys: Option<&Keyring>) -> Result<Box<dyn KvsEngine>, Box<dyn Error>> {
+    if is_sled_dir(dir) {
+        return open_sled(dir);
+    }
+    let config = KvStoreConfig {
+        #[cfg(feature = "encryption")]
+        encryption: keys.cloned(),
+        ..KvStoreConfig::default()
+    };
+    Ok(Box::new(KvStore::open_with_config(
+        dir.to_path_buf(),
+        config,
+    )?))
+}
+
+fn dump(log: &Path, offset: u64, keys: Option<&Keyring>) -> Result<(), Box<dyn Error>> {
+    println!(
+        "{:>12} {:>8}  {:<6}  {:>10}  {:<24}  {:>8}  key",
+        "offset", "len", "cmd", "value", "expiry", "seq"
+    );
+
+    let mut end = offset;
+    let mut records = 0;
+    for record in read_log(log, offset, keys)? {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                let message = format!(
+                    "Unreadable record at offset {} after {} records: {}",
+                    end, records, err
+                );
+                return Err(message.into());
+            }
+        };
+
+        let (cmd, value, expiry) = match (record.value_len, record.removed_at) {
+            (Some(value_len), _) => (
+                "set",
+                format!("{} B", value_len),
+                record.expires_at.map(|at| format!("expires {}", at)),
+            ),
+            (None, Some(removed_at)) => {
+                ("rm", "-".to_owned(), Some(format!("soft {}", removed_at)))
+            }
+            (None, None) => ("rm", "-".to_owned(), None),
+        };
+        println!(
+            "{:>12} {:>8}  {:<6}  {:>10}  {:<24}  {:>8}  {}",
+            record.offset,
+            record.len,
+            cmd,
+            value,
+            expiry.unwrap_or_default(),
+            record.seq.map_or("-".to_owned(), |seq| seq.to_string()),
+            display_key(&record.key)
+        );
+        end = record.offset + record.len;
+        records += 1;
+    }
+
+    println!("{} records, ending at offset {}", records, end);
+    Ok(())
+}
+
+// Keys print as quoted UTF-8 with escapes, or as hex if they aren't UTF-8
+fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => format!("{:?}", key),
+        Err(_) => format!("0x{}", hex::encode(key)),
+    }
+}
+
+fn print_check(check: &LogsCheck) {
+    println!(
+        "{:>8} {:>10} {:>12} {:>12} {:>12}  problem",
+        "log", "records", "bytes", "live", "stale"
+    );
+    for log in &check.logs {
+        let problem = match &log.error {
+            Some(error) => format!(
+                "{} unreadable bytes from offset {}: {}",
+                log.torn_len(),
+                log.valid_len,
+                error
+            ),
+            None => String::new(),
+        };
+        println!(
+            "{:>8} {:>10} {:>12} {:>12} {:>12}  {}",
+            log.log_gen,
+            log.records,
+            log.file_len,
+            log.live_len,
+            log.stale_len(),
+            problem
+        );
+    }
+
+    println!("Live keys: {}", check.live_keys);
+    match check.log_encoding {
+        Some(log_encoding) => println!("Encoding: {:?}", log_encoding),
+        None => println!("Encoding: no manifest; the server records one on start"),
+    }
+    println!(
+        "Index: {}",
+        match check.index {
+            IndexState::Missing => "missing; the server replays every log on start",
+            IndexState::Valid => "valid",
+            IndexState::Unusable => "unusable; the server ignores it and replays every log",
+            IndexState::Invalid => "points past the readable records; the server would load it",
+        }
+    );
+    for tmp_file in &check.tmp_files {
+        println!("Leftover temporary file: {}", tmp_file.display());
+    }
+}
+
+// Sled keeps a `conf` and a `db` file in its directory and no logs of ours
+fn is_sled_dir(dir: &Path) -> bool {
+    dir.join("conf").is_file() && dir.join("db").is_file()
+}
+
+#[cfg(feature = "sled")]
+fn check_sled(dir: &Path) -> Result<(), Box<dyn Error>> {
+    use kvs::{KvsEngine, KvsReader, SledKvsEngine};
+
+    // Sled verifies its own files as it opens them
+    let mut engine = SledKvsEngine::open(dir.to_path_buf())?;
+    let keys = engine.scan(&[], None, usize::MAX)?.len();
+    println!("Sled data directory; it opened cleanly with {} keys", keys);
+    Ok(())
+}
+
+#[cfg(feature = "sled")]
+fn open_sled(dir: &Path) -> Result<Box<dyn KvsEngine>, Box<dyn Error>> {
+    Ok(Box::new(kvs::SledKvsEngine::open(dir.to_path_buf())?))
+}
+
+#[cfg(not(feature = "sled"))]
+fn open_sled(_dir: &Path) -> Result<Box<dyn KvsEngine>, Box<dyn Error>> {
+    Err("This is a sled data directory; build with the sled feature to open it".into())
+}
+
+#[cfg(not(feature = "sled"))]
+fn check_sled(_dir: &Path) -> Result<(), Box<dyn Error>> {
+    Err("This is a sled data directory; build with the sled feature to check it".into())
+}
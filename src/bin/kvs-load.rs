@@ -0,0 +1,203 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use kvs::KvsClient;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// Run a workload of gets and sets against a running kvs-server and report
+/// its throughput and latency percentiles. The server serves one connection
+/// at a time, so with more than one client the latencies include the time
+/// spent waiting for the other clients' connections.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Address of the server
+    #[arg(
+		long,
+		default_value_t=SocketAddr::new(
+			IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+			8080,
+		)
+	)]
+    addr: SocketAddr,
+
+    /// Number of distinct keys the workload reads and writes
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+
+    /// Length of the values set, in bytes: a fixed length such as `100`, or
+    /// a range such as `64..4096` to pick lengths from uniformly
+    #[arg(long, value_name = "BYTES|MIN..MAX", default_value = "100")]
+    value_bytes: ValueBytes,
+
+    /// Fraction of requests that are gets, from 0 to 1; the rest are sets
+    #[arg(long, default_value_t = 0.9)]
+    read_ratio: f64,
+
+    /// Number of clients sending requests at once, each on a connection of
+    /// its own
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+
+    /// Stop after this many requests in all, instead of after --duration-secs
+    #[arg(long)]
+    requests: Option<u64>,
+
+    /// How long to run the workload for
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+
+    /// Set every key before the workload starts, so that gets find values
+    #[arg(long)]
+    prefill: bool,
+}
+
+/// Lengths to pick values' lengths from, inclusive.
+#[derive(Debug, Clone, Copy)]
+struct ValueBytes {
+    min: usize,
+    max: usize,
+}
+
+impl FromStr for ValueBytes {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<ValueBytes, String> {
+        let parse = |len: &str| {
+            len.parse::<usize>()
+                .map_err(|err| format!("Invalid length {:?}: {}", len, err))
+        };
+        let (min, max) = match arg.split_once("..") {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(arg)?, parse(arg)?),
+        };
+        if min > max {
+            return Err(format!("{} is longer than {}", min, max));
+        }
+        Ok(ValueBytes { min, max })
+    }
+}
+
+impl ValueBytes {
+    fn pick(&self, rng: &mut SmallRng) -> String {
+        "x".repeat(rng.gen_range(self.min..=self.max))
+    }
+}
+
+// What one client did
+#[derive(Default)]
+struct Report {
+    gets: u64,
+    sets: u64,
+    errors: u64,
+    // Microseconds each request took
+    latencies: Vec<u64>,
+}
+
+fn key(n: u64) -> Vec<u8> {
+    format!("load/{:010}", n).into_bytes()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        return Err("--read-ratio must be from 0 to 1".into());
+    }
+    if args.keys == 0 || args.concurrency == 0 {
+        return Err("--keys and --concurrency must be at least 1".into());
+    }
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+    if args.prefill {
+        let mut client = KvsClient::new(logger.clone(), args.addr)?;
+        let mut rng = SmallRng::from_entropy();
+        for n in 0..args.keys {
+            client.set(key(n), args.value_bytes.pick(&mut rng))?;
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(args.duration_secs);
+    let started = Instant::now();
+    let workers: Vec<_> = (0..args.concurrency as u64)
+        .map(|worker| {
+            // Split the requests as evenly as possible between the clients
+            let requests = args.requests.map(|requests| {
+                requests / args.concurrency as u64
+                    + u64::from(worker < requests % args.concurrency as u64)
+            });
+            let (logger, addr, value_bytes) = (logger.clone(), args.addr, args.value_bytes);
+            let (keys, read_ratio) = (args.keys, args.read_ratio);
+            thread::spawn(move || -> kvs::Result<Report> {
+                let mut client = KvsClient::new(logger, addr)?;
+                let mut rng = SmallRng::from_entropy();
+                let mut report = Report::default();
+                loop {
+                    let sent = report.gets + report.sets;
+                    match requests {
+                        Some(requests) if sent >= requests => break,
+                        None if Instant::now() >= deadline => break,
+                        _ => {}
+                    }
+
+                    let key = key(rng.gen_range(0..keys));
+                    let start = Instant::now();
+                    let result = if rng.gen::<f64>() < read_ratio {
+                        report.gets += 1;
+                        client.get(key).map(drop)
+                    } else {
+                        report.sets += 1;
+                        client.set(key, value_bytes.pick(&mut rng))
+                    };
+                    report.latencies.push(start.elapsed().as_micros() as u64);
+                    if result.is_err() {
+                        report.errors += 1;
+                    }
+                }
+                Ok(report)
+            })
+        })
+        .collect();
+
+    let mut total = Report::default();
+    for worker in workers {
+        let report = worker.join().map_err(|_| "A client panicked")??;
+        total.gets += report.gets;
+        total.sets += report.sets;
+        total.errors += report.errors;
+        total.latencies.extend(report.latencies);
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    total.latencies.sort_unstable();
+
+    let requests = total.gets + total.sets;
+    println!("requests\t{}", requests);
+    println!("gets\t{}", total.gets);
+    println!("sets\t{}", total.sets);
+    println!("errors\t{}", total.errors);
+    println!("seconds\t{:.3}", elapsed);
+    println!("requests_per_sec\t{:.1}", requests as f64 / elapsed);
+    for (name, percentile) in [
+        ("p50_us", 50.0),
+        ("p90_us", 90.0),
+        ("p99_us", 99.0),
+        ("p99.9_us", 99.9),
+        ("max_us", 100.0),
+    ] {
+        if let Some(latency) = percentile_of(&total.latencies, percentile) {
+            println!("{}\t{}", name, latency);
+        }
+    }
+
+    Ok(())
+}
+
+// The `percentile`th of sorted `latencies`, by the nearest-rank method
+fn percentile_of(latencies: &[u64], percentile: f64) -> Option<u64> {
+    let rank = (percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+    latencies.get(rank.max(1) - 1).copied()
+}
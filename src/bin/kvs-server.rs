@@ -1,19 +1,54 @@
 use std::{
     env::current_dir,
     error::Error,
+    fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+    thread,
+    time::Duration,
 };
 
-use clap::{command, Parser, ValueEnum};
-use kvs::{KvStore, KvsEngine, KvsServer, SledKvsEngine};
-use slog::{o, Drain};
+use clap::{Parser, ValueEnum};
+use kvs::{
+    Acl, Chaos, CompactionWindow, KeydirCheck, Keyring, KvStore, KvStoreConfig, KvStoreStandby,
+    KvsEngine, KvsServer, Quota, ServerConfig, StoreEvent,
+};
+#[cfg(feature = "sled")]
+use kvs::{SledConfig, SledKvsEngine};
+#[cfg(unix)]
+use signal_hook::{consts::SIGUSR1, iterator::Signals};
+use slog::{info, o, warn, Drain, Level};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Engine {
     Kvs,
+    #[cfg(feature = "sled")]
     Sled,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LogEncoding {
+    Compact,
+    Lines,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[cfg(feature = "sled")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum SledMode {
+    LowSpace,
+    HighThroughput,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -30,14 +65,322 @@ struct Cli {
     /// What engine to use for the program. Default: kvs
     #[arg(value_enum, long, default_value_t=Engine::Kvs)]
     engine: Engine,
+
+    /// Serve reads from another kvs server's data directory, following its
+    /// writes as they are flushed. Writes are rejected.
+    #[arg(long, value_name = "DIR")]
+    standby_of: Option<PathBuf>,
+
+    /// Largest response, in bytes, sent for a single request. Larger gets and
+    /// scans fail with an error instead.
+    #[arg(long, value_name = "BYTES")]
+    max_response_bytes: Option<usize>,
+
+    /// Largest request frame, in bytes, read from a client. Clients sending
+    /// larger ones are disconnected; clients send larger values in parts.
+    #[arg(long, value_name = "BYTES")]
+    max_frame_bytes: Option<usize>,
+
+    /// Record requests taking at least this many milliseconds in the slow log
+    #[arg(long, value_name = "MS")]
+    slowlog_threshold_ms: Option<u64>,
+
+    /// Number of entries the slow log keeps
+    #[arg(long, value_name = "ENTRIES")]
+    slowlog_len: Option<usize>,
+
+    /// Close connections that send nothing for this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout_secs: Option<u64>,
+
+    /// Send TCP keepalive probes after a connection has been quiet this many
+    /// seconds. 0 turns keepalive off.
+    #[arg(long, value_name = "SECONDS")]
+    keepalive_secs: Option<u64>,
+
+    /// Limit compaction to this many megabytes of disk I/O per second, so it
+    /// does not starve foreground requests. Only applies to the kvs engine.
+    #[arg(long, value_name = "MB")]
+    compaction_mb_per_sec: Option<u64>,
+
+    /// Only compact on the store's own within this daily window, in UTC, such
+    /// as 02:00-05:00. Can be given several times. Only applies to the kvs
+    /// engine.
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    compaction_window: Vec<CompactionWindow>,
+
+    /// Also let the store compact on its own outside --compaction-window
+    /// while it serves no more than this many reads and writes a second.
+    /// Only applies to the kvs engine.
+    #[arg(long, value_name = "OPS")]
+    compaction_max_ops_per_sec: Option<u64>,
+
+    /// Compact once the oldest log is this many days old and there is any
+    /// stale, expired or soft-deleted data to purge. Only applies to the kvs
+    /// engine.
+    #[arg(long, value_name = "DAYS")]
+    compact_after_days: Option<u64>,
+
+    /// Move logs untouched for --cold-after-days to this directory, e.g. on a
+    /// slower, cheaper disk. Only applies to the kvs engine.
+    #[arg(long, value_name = "DIR")]
+    cold_dir: Option<PathBuf>,
+
+    /// Days a log goes unmodified before it moves to --cold-dir
+    #[arg(long, value_name = "DAYS", requires = "cold_dir")]
+    cold_after_days: Option<u64>,
+
+    /// Memory, in megabytes, for values kept after they are read. Only
+    /// applies to the kvs engine.
+    #[arg(long, value_name = "MB")]
+    read_cache_mb: Option<usize>,
+
+    /// Keep values up to this many bytes in memory beside their keys, so
+    /// gets of them skip the disk. Only applies to the kvs engine.
+    #[arg(long, value_name = "BYTES")]
+    inline_value_bytes: Option<usize>,
+
+    /// Keep at most this many log files open, reopening others as they are
+    /// read (256 by default). Only applies to the kvs engine.
+    #[arg(long, value_name = "FILES")]
+    max_open_logs: Option<usize>,
+
+    /// Read the keys listed in this file, one per line, into the read cache
+    /// before accepting connections. Only applies to the kvs engine.
+    #[arg(long, value_name = "FILE")]
+    preload: Option<PathBuf>,
+
+    /// Keep removed values restorable with `kvs-client restore` for this many
+    /// seconds, or until the next compaction. Only applies to the kvs engine.
+    #[arg(long, value_name = "SECONDS")]
+    soft_delete_secs: Option<u64>,
+
+    /// Save the keydir to an index file after this many megabytes of writes,
+    /// so restarts only replay the logs written since. Only applies to the
+    /// kvs engine.
+    #[arg(long, value_name = "MB")]
+    index_interval_mb: Option<u64>,
+
+    /// Start a new log on the first write once the active one has been open
+    /// this many seconds. Only applies to the kvs engine.
+    #[arg(long, value_name = "SECONDS")]
+    rotate_every_secs: Option<u64>,
+
+    /// Refuse writes, telling clients when to retry, while the logs hold more
+    /// than this many megabytes of stale data that compaction can't reclaim.
+    /// Only applies to the kvs engine.
+    #[arg(long, value_name = "MB")]
+    stall_stale_mb: Option<u64>,
+
+    /// How a new store lays out its log records: back to back, or one per
+    /// line for grepping. An existing store keeps the one it was created
+    /// with. Only applies to the kvs engine.
+    #[arg(value_enum, long)]
+    log_encoding: Option<LogEncoding>,
+
+    /// On start, read back this many random keydir entries, or "all" of them,
+    /// from the logs and refuse to start if any don't match. Only applies to
+    /// the kvs engine.
+    #[arg(long, value_name = "N|all", value_parser = parse_keydir_check)]
+    verify_keydir: Option<KeydirCheck>,
+
+    /// Encrypt the log and index records with keys read from this file of
+    /// "<id> <base64 key>" lines; the last one is used for new records.
+    /// Once encrypted, the store only opens with its keys. Only applies to
+    /// the kvs engine.
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Like --encryption-key-file, with the keys read from this environment
+    /// variable, separated by commas
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "VAR", conflicts_with = "encryption_key_file")]
+    encryption_key_env: Option<String>,
+
+    /// Remove up to this many expired keys a second, between requests, so
+    /// they don't linger until read or compacted. 0 turns reaping off. Only
+    /// applies to the kvs engine.
+    #[arg(long, value_name = "KEYS", default_value_t = 1000)]
+    reap_keys_per_sec: u64,
+
+    /// Memory, in megabytes, for sled's page cache. Only applies to the sled
+    /// engine.
+    #[cfg(feature = "sled")]
+    #[arg(long, value_name = "MB")]
+    sled_cache_mb: Option<u64>,
+
+    /// How often sled flushes writes in the background, in milliseconds. 0
+    /// only flushes on shutdown. Only applies to the sled engine.
+    #[cfg(feature = "sled")]
+    #[arg(long, value_name = "MS")]
+    sled_flush_every_ms: Option<u64>,
+
+    /// Whether sled's storage layout favors disk space or write speed. Only
+    /// applies to the sled engine.
+    #[cfg(feature = "sled")]
+    #[arg(value_enum, long)]
+    sled_mode: Option<SledMode>,
+
+    /// Compress sled's data with zstd. Must match the setting the database
+    /// was created with. Only applies to the sled engine.
+    #[cfg(feature = "sled")]
+    #[arg(long)]
+    sled_compression: bool,
+
+    /// Require clients to authenticate as a user listed in this JSON file,
+    /// and limit each to its permissions and key prefixes
+    #[arg(long, value_name = "FILE")]
+    acl: Option<PathBuf>,
+
+    /// Limit the keys and bytes under key prefixes to the quotas listed in
+    /// this JSON file, rejecting writes that would go over
+    #[arg(long, value_name = "FILE")]
+    quotas: Option<PathBuf>,
+
+    /// Test mode: inject the delays, disconnects and errors described in this
+    /// JSON file into client requests, to test clients' retries and timeouts
+    /// against. Never use it in production.
+    #[arg(long, value_name = "FILE")]
+    chaos: Option<PathBuf>,
+
+    /// Log messages at this level and above. Can be changed while the server
+    /// runs with `kvs-client log-level`, or on Unix by sending the server
+    /// SIGUSR1, which steps to the next more verbose level, from trace back
+    /// round to error.
+    #[arg(long, value_enum, default_value = "info")]
+    log_level: LogLevel,
+
+    /// Reject requests naming keys that start with "__kvs" and hide them from
+    /// scans, keeping them for internal metadata
+    #[arg(long)]
+    reserve_internal_keys: bool,
+
+    /// Count one in this many gets towards the per-key read counts
+    /// `kvs-client hotkeys` reports. Default: 0, counting none
+    #[arg(long, value_name = "N")]
+    hotkeys_sample_rate: Option<u32>,
+
+    /// Keys whose read counts are kept for `kvs-client hotkeys`. Default:
+    /// 10000
+    #[arg(long, value_name = "KEYS", requires = "hotkeys_sample_rate")]
+    hotkeys_len: Option<usize>,
+
+    /// Idempotency keys whose responses are kept to answer retried requests
+    /// with. Default: 10000
+    #[arg(long, value_name = "KEYS")]
+    idempotency_window: Option<usize>,
+
+    /// Stop scripts that run longer than this many milliseconds
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "MS")]
+    script_timeout_ms: Option<u64>,
+
+    /// Fail scripts that allocate more than this many megabytes. Default: 64
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "MB")]
+    script_memory_mb: Option<usize>,
+
+    /// Also accept WebSocket connections, e.g. from a browser, on this socket
+    /// address
+    #[cfg(feature = "websocket")]
+    #[arg(long, value_name = "ADDR")]
+    websocket_addr: Option<SocketAddr>,
+
+    /// Serve the admin web UI on this socket address, or on this port of
+    /// 127.0.0.1. With --acl it asks for the name and token of a user with
+    /// the admin permission; without, it has no authentication and is only
+    /// served on loopback addresses.
+    #[cfg(feature = "admin-ui")]
+    #[arg(long, value_name = "ADDR|PORT", value_parser = parse_admin_addr)]
+    admin_addr: Option<SocketAddr>,
+
+    /// Export per-request and engine tracing spans to this OTLP/HTTP endpoint,
+    /// e.g. http://localhost:4318/v1/traces
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+}
+
+impl Cli {
+    /// Whether any option only the kvs engine understands was given
+    fn has_kvs_options(&self) -> bool {
+        self.compaction_mb_per_sec.is_some()
+            || !self.compaction_window.is_empty()
+            || self.compaction_max_ops_per_sec.is_some()
+            || self.compact_after_days.is_some()
+            || self.cold_dir.is_some()
+            || self.read_cache_mb.is_some()
+            || self.inline_value_bytes.is_some()
+            || self.max_open_logs.is_some()
+            || self.preload.is_some()
+            || self.soft_delete_secs.is_some()
+            || self.index_interval_mb.is_some()
+            || self.rotate_every_secs.is_some()
+            || self.log_encoding.is_some()
+            || self.stall_stale_mb.is_some()
+            || self.verify_keydir.is_some()
+            || self.keyring_given()
+    }
+
+    /// Whether keys were given to encrypt the store with
+    fn keyring_given(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.encryption_key_file.is_some() || self.encryption_key_env.is_some();
+        #[cfg(not(feature = "encryption"))]
+        return false;
+    }
+
+    /// The keys given to encrypt the store with, if any
+    #[cfg(not(feature = "encryption"))]
+    fn keyring(&self) -> kvs::Result<Option<Keyring>> {
+        Ok(None)
+    }
+
+    /// The keys given to encrypt the store with, if any
+    #[cfg(feature = "encryption")]
+    fn keyring(&self) -> kvs::Result<Option<Keyring>> {
+        if let Some(key_file) = &self.encryption_key_file {
+            return Keyring::from_file(key_file).map(Some);
+        }
+        match &self.encryption_key_env {
+            Some(var) => Keyring::from_env(var).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether any option only the sled engine understands was given
+    #[cfg(feature = "sled")]
+    fn has_sled_options(&self) -> bool {
+        self.sled_cache_mb.is_some()
+            || self.sled_flush_every_ms.is_some()
+            || self.sled_mode.is_some()
+            || self.sled_compression
+    }
+
+    #[cfg(not(feature = "sled"))]
+    fn has_sled_options(&self) -> bool {
+        false
+    }
 }
 
+// How often a standby checks the primary's logs for new records
+const STANDBY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
     println!("{:#?}", args);
 
     let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let log_level = kvs::LogLevel::new(match args.log_level {
+        LogLevel::Error => Level::Error,
+        LogLevel::Warn => Level::Warning,
+        LogLevel::Info => Level::Info,
+        LogLevel::Debug => Level::Debug,
+        LogLevel::Trace => Level::Trace,
+    });
+    let drain = log_level.filter(drain).fuse();
 
     let log = slog::Logger::root(
         drain,
@@ -46,23 +389,284 @@ fn main() -> Result<(), Box<dyn Error>> {
             "address" => args.addr,
             "engine" => match args.engine {
                 Engine::Kvs => "kvs",
+                #[cfg(feature = "sled")]
                 Engine::Sled => "sled",
             }
         ),
     );
 
+    // Keeps the exporter running for as long as the server does
+    #[cfg(feature = "otlp")]
+    let _tracer_provider = match &args.otlp_endpoint {
+        Some(endpoint) => Some(init_otlp(endpoint)?),
+        None => None,
+    };
+
+    let mut config = ServerConfig::default();
+    if let Some(max_response_bytes) = args.max_response_bytes {
+        config.max_response_len = max_response_bytes;
+    }
+    if let Some(max_frame_bytes) = args.max_frame_bytes {
+        config.max_frame_len = max_frame_bytes;
+    }
+    if let Some(slowlog_threshold_ms) = args.slowlog_threshold_ms {
+        config.slowlog_threshold = Duration::from_millis(slowlog_threshold_ms);
+    }
+    if let Some(slowlog_len) = args.slowlog_len {
+        config.slowlog_len = slowlog_len;
+    }
+    if let Some(idle_timeout_secs) = args.idle_timeout_secs {
+        config.idle_timeout = Some(Duration::from_secs(idle_timeout_secs));
+    }
+    if let Some(keepalive_secs) = args.keepalive_secs {
+        config.keepalive = match keepalive_secs {
+            0 => None,
+            keepalive_secs => Some(Duration::from_secs(keepalive_secs)),
+        };
+    }
+    config.reserve_internal_keys = args.reserve_internal_keys;
+    if let Some(hotkeys_sample_rate) = args.hotkeys_sample_rate {
+        config.hotkeys_sample_rate = hotkeys_sample_rate;
+    }
+    if let Some(hotkeys_len) = args.hotkeys_len {
+        config.hotkeys_len = hotkeys_len;
+    }
+    if let Some(idempotency_window) = args.idempotency_window {
+        config.idempotency_window = idempotency_window;
+    }
+    if let Some(quotas) = &args.quotas {
+        config.quotas = Quota::load(quotas)?;
+    }
+    if let Some(chaos) = &args.chaos {
+        config.chaos = Some(Chaos::load(chaos)?);
+        warn!(
+            log,
+            "Chaos mode is on; requests will be delayed, dropped and failed"
+        );
+    }
+    #[cfg(feature = "websocket")]
+    {
+        config.websocket_addr = args.websocket_addr;
+    }
+    #[cfg(feature = "admin-ui")]
+    {
+        config.admin_addr = args.admin_addr;
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(script_timeout_ms) = args.script_timeout_ms {
+        config.script_timeout = Duration::from_millis(script_timeout_ms);
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(script_memory_mb) = args.script_memory_mb {
+        config.script_memory_limit = script_memory_mb * 1024 * 1024;
+    }
+
+    #[cfg(unix)]
+    {
+        let mut signals = Signals::new([SIGUSR1])?;
+        let (signal_log, log_level) = (log.clone(), log_level.clone());
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                let level = log_level.step();
+                warn!(signal_log, "Log level is now {}", level.as_str());
+            }
+        });
+    }
+
+    let acl = args.acl.as_ref().map(Acl::load).transpose()?;
+    let configure = |server: KvsServer| {
+        let server = server.with_config(config).with_log_level(log_level);
+        match acl {
+            Some(acl) => server.with_acl(acl),
+            None => server,
+        }
+    };
+
+    let keys = args.keyring()?;
+    if let Some(primary_dir) = args.standby_of {
+        if args.engine != Engine::Kvs {
+            return Err("Standby mode only supports the kvs engine".into());
+        }
+
+        let standby = KvStoreStandby::open_with_keys(primary_dir, STANDBY_POLL_INTERVAL, keys)?;
+        let mut server = configure(KvsServer::read_only(log, standby));
+        server.listen(args.addr)?;
+        return Ok(());
+    }
+
     let dir = current_dir()?;
 
     match args.engine {
         Engine::Kvs => {
-            let mut server = KvsServer::new(log, KvStore::open(dir)?);
+            if args.has_sled_options() {
+                return Err("The sled options only apply to the sled engine".into());
+            }
+            let mut store_config = KvStoreConfig {
+                compaction_bytes_per_sec: args.compaction_mb_per_sec.map(|mb| mb * 1024 * 1024),
+                compaction_windows: args.compaction_window,
+                compaction_max_ops_per_sec: args.compaction_max_ops_per_sec,
+                compact_after: args
+                    .compact_after_days
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+                cold_dir: args.cold_dir,
+                rotate_every: args.rotate_every_secs.map(Duration::from_secs),
+                stall_stale_bytes: args.stall_stale_mb.map(|mb| mb * 1024 * 1024),
+                log_encoding: args.log_encoding.map(|encoding| match encoding {
+                    LogEncoding::Compact => kvs::LogEncoding::Compact,
+                    LogEncoding::Lines => kvs::LogEncoding::Lines,
+                }),
+                verify_keydir: args.verify_keydir.unwrap_or_default(),
+                #[cfg(feature = "encryption")]
+                encryption: keys,
+                reap_keys_per_sec: Some(args.reap_keys_per_sec).filter(|&keys| keys > 0),
+                ..KvStoreConfig::default()
+            };
+            if let Some(days) = args.cold_after_days {
+                store_config.cold_after = Duration::from_secs(days * 24 * 60 * 60);
+            }
+            if let Some(soft_delete_secs) = args.soft_delete_secs {
+                store_config.soft_delete_retention = Some(Duration::from_secs(soft_delete_secs));
+            }
+            if let Some(index_interval_mb) = args.index_interval_mb {
+                store_config.index_interval = Some(index_interval_mb * 1024 * 1024);
+            }
+            if let Some(read_cache_mb) = args.read_cache_mb {
+                store_config.read_cache_bytes = read_cache_mb * 1024 * 1024;
+            }
+            if let Some(inline_value_bytes) = args.inline_value_bytes {
+                store_config.inline_value_len = inline_value_bytes;
+            }
+            if let Some(max_open_logs) = args.max_open_logs {
+                store_config.max_open_logs = max_open_logs;
+            }
+            // Runs again whenever a client asks the server to reopen the store
+            let open_log = log.clone();
+            let preload = args.preload;
+            let open = move |dir: &Path| -> kvs::Result<Box<dyn KvsEngine>> {
+                let mut store = KvStore::open_with_config(dir.to_path_buf(), store_config.clone())?;
+                if let Some(preload) = &preload {
+                    let keys = fs::read_to_string(preload)?;
+                    let loaded = store.preload(keys.lines().map(|key| key.as_bytes().to_vec()))?;
+                    info!(open_log, "Preloaded {} keys", loaded);
+                }
+                let events = store.subscribe();
+                let event_log = open_log.clone();
+                thread::spawn(move || log_store_events(event_log, events));
+                Ok(Box::new(store))
+            };
+            let server = KvsServer::reopenable(log, dir, Box::new(open))?;
+            let mut server = configure(server);
             server.listen(args.addr)?;
         }
+        #[cfg(feature = "sled")]
         Engine::Sled => {
-            let mut server = KvsServer::new(log, SledKvsEngine::open(dir)?);
+            if args.has_kvs_options() {
+                return Err("The kvs store options only apply to the kvs engine".into());
+            }
+            let mut sled_config = SledConfig {
+                compression: args.sled_compression,
+                ..SledConfig::default()
+            };
+            if let Some(sled_cache_mb) = args.sled_cache_mb {
+                sled_config.cache_capacity = sled_cache_mb * 1024 * 1024;
+            }
+            if let Some(every_ms) = args.sled_flush_every_ms {
+                sled_config.flush_every = match every_ms {
+                    0 => None,
+                    every_ms => Some(Duration::from_millis(every_ms)),
+                };
+            }
+            if let Some(mode) = args.sled_mode {
+                sled_config.mode = match mode {
+                    SledMode::LowSpace => kvs::SledMode::LowSpace,
+                    SledMode::HighThroughput => kvs::SledMode::HighThroughput,
+                };
+            }
+            let open = move |dir: &Path| -> kvs::Result<Box<dyn KvsEngine>> {
+                let engine =
+                    SledKvsEngine::open_with_config(dir.to_path_buf(), sled_config.clone())?;
+                Ok(Box::new(engine))
+            };
+            let server = KvsServer::reopenable(log, dir, Box::new(open))?;
+            let mut server = configure(server);
             server.listen(args.addr)?;
         }
     };
 
     Ok(())
 }
+
+fn parse_keydir_check(arg: &str) -> Result<KeydirCheck, String> {
+    match arg {
+        "all" => Ok(KeydirCheck::Full),
+        sample => match sample.parse() {
+            Ok(0) => Ok(KeydirCheck::Off),
+            Ok(len) => Ok(KeydirCheck::Sample(len)),
+            Err(_) => Err("expected a number of entries or \"all\"".to_owned()),
+        },
+    }
+}
+
+// A bare port binds the admin UI to loopback only
+#[cfg(feature = "admin-ui")]
+fn parse_admin_addr(arg: &str) -> Result<SocketAddr, String> {
+    match arg.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        Err(_) => arg
+            .parse()
+            .map_err(|_| "expected a socket address or a port".to_owned()),
+    }
+}
+
+/// Log the store's compactions and log file changes as they happen.
+fn log_store_events(log: slog::Logger, events: Receiver<StoreEvent>) {
+    for event in events {
+        match event {
+            StoreEvent::CompactionStarted { stale_bytes } => {
+                info!(log, "Compaction started"; "stale_bytes" => stale_bytes)
+            }
+            StoreEvent::CompactionFinished(stats) => info!(
+                log,
+                "Compaction finished";
+                "keys" => stats.keys,
+                "input_bytes" => stats.input_bytes,
+                "bytes_written" => stats.bytes_written,
+                "stale_bytes" => stats.stale_bytes,
+                "write_amplification" => format!("{:.2}", stats.write_amplification()),
+                "duration_ms" => stats.duration.as_millis() as u64,
+            ),
+            StoreEvent::SegmentCreated { log_gen } => {
+                info!(log, "Log created"; "log_gen" => log_gen)
+            }
+            StoreEvent::SegmentDeleted { log_gen } => {
+                info!(log, "Log deleted"; "log_gen" => log_gen)
+            }
+        }
+    }
+}
+
+/// Send tracing spans to an OTLP collector over HTTP.
+#[cfg(feature = "otlp")]
+fn init_otlp(
+    endpoint: &str,
+) -> Result<opentelemetry_sdk::trace::SdkTracerProvider, Box<dyn Error>> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("kvs-server").build())
+        .build();
+
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("kvs")));
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(provider)
+}
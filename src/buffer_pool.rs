@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+// Buffers that grew past this for one large response are freed on return
+// rather than kept
+#[cfg(feature = "net")]
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+/// Counts of how a server's buffer pool has been used since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferPoolStats {
+    /// Buffers handed out to encode a frame
+    pub taken: u64,
+    /// Buffers allocated because the pool was empty
+    pub allocated: u64,
+    /// Buffers freed on return because the pool was full or they had grown
+    /// too large to keep
+    pub discarded: u64,
+    /// Buffers in the pool now
+    pub pooled: u64,
+    /// Capacity of the buffers in the pool now, in bytes
+    pub pooled_bytes: u64,
+}
+
+/// Buffers the server encodes frames into, kept between requests so that
+/// each one doesn't allocate its own.
+#[cfg(feature = "net")]
+pub(crate) struct BufferPool {
+    free: Vec<Vec<u8>>,
+    max_buffers: usize,
+    stats: BufferPoolStats,
+}
+
+#[cfg(feature = "net")]
+impl BufferPool {
+    /// A pool keeping up to `max_buffers` buffers.
+    pub(crate) fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::new(),
+            max_buffers,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    /// An empty buffer, from the pool if it has one.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        self.stats.taken += 1;
+        match self.free.pop() {
+            Some(buffer) => {
+                self.stats.pooled -= 1;
+                self.stats.pooled_bytes -= buffer.capacity() as u64;
+                buffer
+            }
+            None => {
+                self.stats.allocated += 1;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Return a buffer from `take` to the pool.
+    pub(crate) fn put(&mut self, mut buffer: Vec<u8>) {
+        if self.free.len() >= self.max_buffers || buffer.capacity() > MAX_POOLED_CAPACITY {
+            self.stats.discarded += 1;
+            return;
+        }
+
+        buffer.clear();
+        self.stats.pooled += 1;
+        self.stats.pooled_bytes += buffer.capacity() as u64;
+        self.free.push(buffer);
+    }
+
+    pub(crate) fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStoreError, Result};
+
+/// Faults a server in chaos mode injects into client requests, so that
+/// clients' retry and timeout handling can be tested against a real server.
+/// Read from a JSON file like
+///
+/// ```json
+/// {"delay_probability": 0.5, "delay_ms": [10, 200],
+///  "disconnect_probability": 0.01, "error_probability": 0.05}
+/// ```
+///
+/// Probabilities are from 0 to 1; missing fields are 0. Admin requests,
+/// such as metrics, are left alone so the server can still be inspected.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Chaos {
+    /// Chance that a request is held up before it runs
+    pub delay_probability: f64,
+    /// Shortest and longest hold-up of a delayed request, in milliseconds
+    pub delay_ms: (u64, u64),
+    /// Chance that the server closes the connection instead of running a
+    /// request
+    pub disconnect_probability: f64,
+    /// Chance that a request fails with an error instead of running
+    pub error_probability: f64,
+}
+
+/// What chaos mode does to a request instead of running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    Disconnect,
+    Error,
+}
+
+impl Chaos {
+    /// Read the faults to inject from the file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Chaos> {
+        let chaos: Chaos = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let probabilities = [
+            chaos.delay_probability,
+            chaos.disconnect_probability,
+            chaos.error_probability,
+        ];
+        if !probabilities.iter().all(|p| (0.0..=1.0).contains(p)) {
+            return Err(KvStoreError::StringError(
+                "Chaos probabilities must be from 0 to 1".to_owned(),
+            ));
+        }
+        if chaos.delay_ms.0 > chaos.delay_ms.1 {
+            return Err(KvStoreError::StringError(
+                "The chaos delay range must be [shortest, longest]".to_owned(),
+            ));
+        }
+
+        Ok(chaos)
+    }
+
+    /// How long to hold up the next request, if at all.
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        let mut rng = rand::thread_rng();
+        if rng.gen::<f64>() >= self.delay_probability {
+            return None;
+        }
+
+        let (shortest, longest) = self.delay_ms;
+        Some(Duration::from_millis(
+            rng.gen_range(shortest..=longest.max(shortest)),
+        ))
+    }
+
+    /// The fault to answer the next request with, if any.
+    pub(crate) fn fault(&self) -> Option<Fault> {
+        let roll = rand::thread_rng().gen::<f64>();
+        if roll < self.disconnect_probability {
+            Some(Fault::Disconnect)
+        } else if roll < self.disconnect_probability + self.error_probability {
+            Some(Fault::Error)
+        } else {
+            None
+        }
+    }
+}
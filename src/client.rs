@@ -1,80 +1,1180 @@
-use crate::codec::*;
+use crate::acl::is_write;
 use crate::error::KvStoreError;
-use serde::Deserialize;
-use serde_json::StreamDeserializer;
-use serde_json::{de::IoRead, Deserializer, Serializer};
-use slog::{info, Logger, KV};
+use crate::protocol::*;
+use crate::{
+    CompactionStats, HotKey, KeyspaceSample, LogPosition, Metrics, PrefixDigest, QueueItem,
+    QuotaUsage, SlowLogEntry, Txn, TxnResponse,
+};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use socket2::{SockRef, TcpKeepalive};
 use std::result::Result;
 use std::{
-    io::{self, BufReader, BufWriter, Write},
+    io::{self, BufReader, BufWriter, Read, Write},
     net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
+type ResponseReader = FrameReader<BufReader<Box<dyn Read + Send>>, Response>;
+type MessageWriter = BufWriter<Box<dyn Write + Send>>;
+// Resolves an address a client was given, again each time it connects
+type Resolve = Box<dyn Fn() -> io::Result<Vec<SocketAddr>> + Send>;
+
+// Values longer than this are sent in parts by default, well under the
+// frame limit servers have by default
+const DEFAULT_CHUNK_LEN: usize = 1024 * 1024;
+
 pub struct KvsClient {
     logger: Logger,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: ResponseReader,
+    writer: MessageWriter,
+    // Position of the latest write seen, attached to reads
+    session: Option<LogPosition>,
+    trace_id: Option<String>,
+    // Sent with the next request only
+    idempotency_key: Option<String>,
+    remote: Option<Remote>,
+    // Sent again on each new connection
+    credentials: Option<(String, String)>,
+    compression: Option<(Vec<Compression>, usize)>,
+    chunk_len: usize,
 }
 
-impl KvsClient {
-    pub fn new(logger: Logger, addr: SocketAddr) -> Result<KvsClient, io::Error> {
-        info!(logger, "Connecting...");
+// The addresses a client made with `connect` or `connect_failover` can
+// connect to again
+struct Remote {
+    builder: KvsClientBuilder,
+    addrs: Vec<Resolve>,
+    // Index into `addrs` of the server connected to
+    current: usize,
+    // Whether requests that fail move the client to another of `addrs`
+    failover: bool,
+    last_probe: Instant,
+}
+
+// Encodes like a `Message` wrapped in `Traced` or `Idempotent` without taking
+// ownership of the message
+#[derive(Serialize)]
+enum WrappedMessage<'a> {
+    Traced {
+        trace_id: &'a str,
+        message: &'a WrappedMessage<'a>,
+    },
+    Idempotent {
+        idempotency_key: &'a str,
+        message: &'a WrappedMessage<'a>,
+    },
+    #[serde(untagged)]
+    Message(&'a Message),
+}
+
+/// Connection settings for a `KvsClient`.
+///
+/// ```ignore
+/// let client = KvsClient::builder(logger)
+///     .keepalive(Some(Duration::from_secs(30)))
+///     .timeout(Some(Duration::from_secs(5)))
+///     .connect(addr)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvsClientBuilder {
+    logger: Logger,
+    keepalive: Option<Duration>,
+    timeout: Option<Duration>,
+    probe_interval: Duration,
+    chunk_len: usize,
+}
+
+impl KvsClientBuilder {
+    /// Send TCP keepalive probes after the connection has been quiet this
+    /// long, so a server that vanished is noticed. Defaults to 60 seconds;
+    /// `None` turns keepalive off.
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> KvsClientBuilder {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Fail reads and writes that block for longer than this with a
+    /// `WouldBlock` or `TimedOut` I/O error. Defaults to waiting forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> KvsClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// While a client made with `connect_failover` is connected to a
+    /// fallback, try the primary again before a request once this long has
+    /// passed since the last try. Defaults to 30 seconds.
+    pub fn probe_interval(mut self, probe_interval: Duration) -> KvsClientBuilder {
+        self.probe_interval = probe_interval;
+        self
+    }
+
+    /// Set values longer than this many bytes in parts of about this size,
+    /// so that they fit in the server's frame limit. Defaults to 1 MiB.
+    pub fn chunk_len(mut self, chunk_len: usize) -> KvsClientBuilder {
+        self.chunk_len = chunk_len.max(1);
+        self
+    }
+
+    /// Connect to the server at `addr`, which may be a hostname to resolve,
+    /// trying each address it resolves to in turn. `KvsClient::reconnect`
+    /// resolves it again, so a client follows a name whose address changes.
+    pub fn connect(
+        self,
+        addr: impl ToSocketAddrs + Send + 'static,
+    ) -> Result<KvsClient, io::Error> {
+        self.connect_remote(vec![resolver(addr)], false)
+    }
+
+    /// Connect to the first of `addrs` that accepts: the primary, then its
+    /// fallbacks in order. When a request fails with an I/O error, the client
+    /// moves to the next server that accepts, preferring the primary, and
+    /// sends reads again there. Writes are only sent again if they carry an
+    /// idempotency key, as the failed server may have applied them; otherwise
+    /// the error is returned and the next request goes to the new server.
+    /// While on a fallback, the client keeps trying to get back to the
+    /// primary every `probe_interval`. Each address is resolved again every
+    /// time the client connects to it.
+    pub fn connect_failover<A: ToSocketAddrs + Send + 'static>(
+        self,
+        addrs: Vec<A>,
+    ) -> Result<KvsClient, io::Error> {
+        self.connect_remote(addrs.into_iter().map(resolver).collect(), true)
+    }
+
+    fn connect_remote(self, addrs: Vec<Resolve>, failover: bool) -> Result<KvsClient, io::Error> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "No addresses given");
+        for (current, resolve) in addrs.iter().enumerate() {
+            match self.open(resolve) {
+                Ok((reader, writer)) => {
+                    let mut client = KvsClient::with_streams(self.logger.clone(), reader, writer);
+                    client.chunk_len = self.chunk_len;
+                    client.remote = Some(Remote {
+                        builder: self,
+                        addrs,
+                        current,
+                        failover,
+                        last_probe: Instant::now(),
+                    });
+                    return Ok(client);
+                }
+                Err(err) => {
+                    warn!(self.logger, "Failed to connect: {}", err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // Resolve an address and connect to the first of its addresses that
+    // accepts
+    fn open(&self, resolve: &Resolve) -> Result<(ResponseReader, MessageWriter), io::Error> {
+        let mut last_err = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The address didn't resolve to any addresses",
+        );
+        for addr in resolve()? {
+            match self.open_addr(addr) {
+                Ok(streams) => return Ok(streams),
+                Err(err) => {
+                    warn!(self.logger, "Failed to connect to {}: {}", addr, err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn open_addr(&self, addr: SocketAddr) -> Result<(ResponseReader, MessageWriter), io::Error> {
+        info!(self.logger, "Connecting to {}...", addr);
 
         let reader_stream = TcpStream::connect(addr)?;
+        reader_stream.set_read_timeout(self.timeout)?;
+        reader_stream.set_write_timeout(self.timeout)?;
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(&reader_stream)
+                .set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
         let writer_stream = reader_stream.try_clone()?;
 
-        info!(logger, "Connected.");
+        info!(self.logger, "Connected.");
+
+        Ok(streams(reader_stream, writer_stream))
+    }
+}
+
+fn resolver(addr: impl ToSocketAddrs + Send + 'static) -> Resolve {
+    Box::new(move || Ok(addr.to_socket_addrs()?.collect()))
+}
+
+fn streams(
+    reader: impl Read + Send + 'static,
+    writer: impl Write + Send + 'static,
+) -> (ResponseReader, MessageWriter) {
+    (
+        FrameReader::new(BufReader::new(Box::new(reader))),
+        BufWriter::new(Box::new(writer)),
+    )
+}
+
+impl KvsClient {
+    /// Connect with the default settings.
+    pub fn new(
+        logger: Logger,
+        addr: impl ToSocketAddrs + Send + 'static,
+    ) -> Result<KvsClient, io::Error> {
+        KvsClient::builder(logger).connect(addr)
+    }
 
-        let reader = Deserializer::from_reader(BufReader::new(reader_stream));
-        let writer = BufWriter::new(writer_stream);
+    /// Speak the protocol over any pair of byte streams rather than a TCP
+    /// connection, such as the two halves of a WebSocket. `writer` is flushed
+    /// after every frame, so a message-based transport can send each flush as
+    /// one message.
+    pub fn with_transport(
+        logger: Logger,
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> KvsClient {
+        let (reader, writer) = streams(reader, writer);
+        KvsClient::with_streams(logger, reader, writer)
+    }
 
-        return Ok(KvsClient {
+    fn with_streams(logger: Logger, reader: ResponseReader, writer: MessageWriter) -> KvsClient {
+        KvsClient {
             logger,
             reader,
             writer,
-        });
+            session: None,
+            trace_id: None,
+            idempotency_key: None,
+            remote: None,
+            credentials: None,
+            compression: None,
+            chunk_len: DEFAULT_CHUNK_LEN,
+        }
+    }
+
+    pub fn builder(logger: Logger) -> KvsClientBuilder {
+        KvsClientBuilder {
+            logger,
+            keepalive: Some(Duration::from_secs(60)),
+            timeout: None,
+            probe_interval: Duration::from_secs(30),
+            chunk_len: DEFAULT_CHUNK_LEN,
+        }
+    }
+
+    /// Position of the latest write made in this session, if the server's
+    /// engine hands them out.
+    pub fn session(&self) -> Option<LogPosition> {
+        self.session
+    }
+
+    /// Continue a session started on another connection. Reads then wait
+    /// until the server has applied the session's writes, so a client reading
+    /// from a standby sees what it wrote to the primary.
+    pub fn set_session(&mut self, session: Option<LogPosition>) {
+        self.session = session;
+    }
+
+    /// Send `trace_id` with every following request, until it is set to
+    /// `None`. The server tags its log entries, trace spans and slow log
+    /// entries for those requests with it.
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+
+    /// Send `idempotency_key` with the next request only. While the server
+    /// remembers the key, sending the same request with the same key again
+    /// gets the first response back without running the request twice, so
+    /// a write whose response was lost, e.g. to a dropped connection, can be
+    /// retried safely. The key should be unique to the request, such as a
+    /// random UUID.
+    pub fn set_idempotency_key(&mut self, idempotency_key: String) {
+        self.idempotency_key = Some(idempotency_key);
+    }
+
+    fn record_write(&mut self, position: Option<LogPosition>) {
+        self.session = self.session.max(position);
+    }
+
+    /// Index into the addresses given to `connect_failover` of the server
+    /// the client is connected to, 0 being the primary.
+    pub fn server_index(&self) -> Option<usize> {
+        self.remote
+            .as_ref()
+            .filter(|remote| remote.failover)
+            .map(|remote| remote.current)
+    }
+
+    /// Connect again to the server the client is connected to, resolving
+    /// its address again and authenticating as before, e.g. after an I/O
+    /// error or once a hostname's address has changed. Fails for clients
+    /// made with `with_transport`, which have no address.
+    pub fn reconnect(&mut self) -> Result<(), KvStoreError> {
+        let Some(remote) = &self.remote else {
+            return Err(KvStoreError::StringError(
+                "The client has no address to reconnect to".into(),
+            ));
+        };
+        self.switch_to(remote.current)
     }
 
     fn send(&mut self, message: &Message) -> Result<Response, KvStoreError> {
+        if !self.remote.as_ref().is_some_and(|remote| remote.failover) {
+            self.write_message(message)?;
+            return self.read_response();
+        }
+
+        self.probe_primary();
+        let idempotency_key = self.idempotency_key.clone();
+        let result = self
+            .write_message(message)
+            .and_then(|()| self.read_response());
+        let Err(KvStoreError::IoErr(err)) = result else {
+            return result;
+        };
+
+        warn!(self.logger, "Request failed, failing over: {}", err);
+        if self.fail_over().is_err() || (is_write(message) && idempotency_key.is_none()) {
+            return Err(KvStoreError::IoErr(err));
+        }
+        self.idempotency_key = idempotency_key;
+        self.write_message(message)?;
+        self.read_response()
+    }
+
+    // Move to the first other server that accepts, trying the primary first
+    // and the one just left last
+    fn fail_over(&mut self) -> Result<(), KvStoreError> {
+        let remote = self.remote.as_ref().expect("not a failover client");
+        let current = remote.current;
+        let candidates = (0..remote.addrs.len())
+            .filter(|&index| index != current)
+            .chain([current]);
+
+        let mut last_err = None;
+        for index in candidates {
+            match self.switch_to(index) {
+                Ok(()) => return Ok(()),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.expect("no addresses to fail over to"))
+    }
+
+    // Go back to the primary if the probe interval has passed since it was
+    // last tried
+    fn probe_primary(&mut self) {
+        let Some(remote) = self.remote.as_mut() else {
+            return;
+        };
+        if remote.current == 0 || remote.last_probe.elapsed() < remote.builder.probe_interval {
+            return;
+        }
+
+        remote.last_probe = Instant::now();
+        if let Err(err) = self.switch_to(0) {
+            info!(self.logger, "The primary is still unavailable: {}", err);
+        }
+    }
+
+    // Connect to the `index`th address, asking for compression and
+    // authenticating as before, and send requests there from now on
+    fn switch_to(&mut self, index: usize) -> Result<(), KvStoreError> {
+        let remote = self.remote.as_mut().expect("not connected by address");
+        let (reader, writer) = remote.builder.open(&remote.addrs[index])?;
+        let credentials = self.credentials.clone();
+
+        let compression = self.compression.clone();
+
+        let previous_reader = std::mem::replace(&mut self.reader, reader);
+        let previous_writer = std::mem::replace(&mut self.writer, writer);
+        let greeted = match compression {
+            Some((compression, compress_above)) => self
+                .write_message(&Message::Hello {
+                    compression,
+                    compress_above: compress_above as u64,
+                })
+                .and_then(|()| self.read_response())
+                .and_then(|response| match response {
+                    Response::Hello(result) => result.map_err(KvStoreError::StringError),
+                    _ => Err(KvStoreError::StringError("Unexpected response".into())),
+                })
+                .map(|_| ()),
+            None => Ok(()),
+        };
+        let authed = greeted.and_then(|()| match credentials {
+            Some((user, token)) => self
+                .write_message(&Message::Auth { user, token })
+                .and_then(|()| self.read_response())
+                .and_then(|response| match response {
+                    Response::Auth(result) => result.map_err(KvStoreError::StringError),
+                    _ => Err(KvStoreError::StringError("Unexpected response".into())),
+                }),
+            None => Ok(()),
+        });
+        if let Err(err) = authed {
+            self.reader = previous_reader;
+            self.writer = previous_writer;
+            return Err(err);
+        }
+
+        if let Some(remote) = self.remote.as_mut() {
+            remote.current = index;
+        }
+        Ok(())
+    }
+
+    fn write_message(&mut self, message: &Message) -> Result<(), KvStoreError> {
         info!(self.logger, "Sending message...");
-        self.writer.write(&serde_json::to_vec(message)?)?;
-        self.writer.flush()?;
+        let idempotency_key = self.idempotency_key.take();
+        let message = WrappedMessage::Message(message);
+        let idempotent =
+            idempotency_key
+                .as_deref()
+                .map(|idempotency_key| WrappedMessage::Idempotent {
+                    idempotency_key,
+                    message: &message,
+                });
+        let message = idempotent.as_ref().unwrap_or(&message);
+        let traced = self
+            .trace_id
+            .as_deref()
+            .map(|trace_id| WrappedMessage::Traced { trace_id, message });
+        write_frame(&mut self.writer, traced.as_ref().unwrap_or(message))?;
         info!(self.logger, "Sent.");
 
+        Ok(())
+    }
+
+    fn read_response(&mut self) -> Result<Response, KvStoreError> {
         info!(self.logger, "Waiting for response...");
-        let response = Response::deserialize(&mut self.reader)?;
+        let response = self.reader.next().unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The server closed the connection",
+            ))
+        })?;
         info!(self.logger, "Received response: {:?}", response);
+        let response = match response {
+            Response::Compressed { compression, data } => compression.decompress(&data)?,
+            response => response,
+        };
 
-        return Ok(response);
+        match response {
+            Response::Denied(reason) => Err(KvStoreError::PermissionDenied(reason)),
+            Response::Stalled { retry_after_ms } => Err(KvStoreError::WriteStalled(
+                Duration::from_millis(retry_after_ms),
+            )),
+            response => Ok(response),
+        }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>, KvStoreError> {
-        let message = Message::Get { key };
-        let response = self.send(&message)?;
+    /// Authenticate as a user of the server's ACL. Servers without one
+    /// accept any user.
+    pub fn auth(&mut self, user: String, token: String) -> Result<(), KvStoreError> {
+        let response = self.send(&Message::Auth {
+            user: user.clone(),
+            token: token.clone(),
+        })?;
 
         match response {
-            Response::Get(result) => return result.map_err(KvStoreError::StringError),
+            Response::Auth(result) => result.map_err(KvStoreError::StringError)?,
             _ => return Err(KvStoreError::StringError("Unexpected response".into())),
         }
+        self.credentials = Some((user, token));
+        Ok(())
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<(), KvStoreError> {
-        let message = Message::Set { key, value };
+    /// Ask the server to compress its responses of at least `compress_above`
+    /// bytes with the first of `preferences` it supports, e.g. for a client
+    /// on a slow link, and return the one it chose. Responses are
+    /// decompressed as they arrive. An empty list turns compression back
+    /// off. The choice lasts for the connection, and is asked for again on
+    /// each new one.
+    pub fn set_compression(
+        &mut self,
+        preferences: Vec<Compression>,
+        compress_above: usize,
+    ) -> Result<Option<Compression>, KvStoreError> {
+        let response = self.send(&Message::Hello {
+            compression: preferences.clone(),
+            compress_above: compress_above as u64,
+        })?;
+
+        let chosen = match response {
+            Response::Hello(result) => result.map_err(KvStoreError::StringError)?,
+            _ => return Err(KvStoreError::StringError("Unexpected response".into())),
+        };
+        self.compression = (!preferences.is_empty()).then_some((preferences, compress_above));
+        Ok(chosen)
+    }
+
+    /// Make the server read its ACL file again.
+    pub fn reload_acl(&mut self) -> Result<(), KvStoreError> {
+        let response = self.send(&Message::ReloadAcl)?;
+
+        match response {
+            Response::ReloadAcl(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Change the server's log level to `error`, `warn`, `info`, `debug` or
+    /// `trace`, until it restarts.
+    pub fn set_log_level(&mut self, level: String) -> Result<(), KvStoreError> {
+        let response = self.send(&Message::SetLogLevel { level })?;
+
+        match response {
+            Response::SetLogLevel(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Make the server close its engine and open it again, from `dir` if
+    /// given. `dir` is resolved on the server.
+    pub fn reopen(&mut self, dir: Option<PathBuf>) -> Result<(), KvStoreError> {
+        let response = self.send(&Message::Reopen { dir })?;
+
+        match response {
+            Response::Reopen(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<String>, KvStoreError> {
+        let message = Message::Get {
+            key,
+            after: self.session,
+        };
         let response = self.send(&message)?;
 
         match response {
-            Response::Set(result) => return result.map_err(KvStoreError::StringError),
-            _ => return Err(KvStoreError::StringError("Unexpected response".into())),
+            Response::Get(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Up to `len` bytes of the key's value from byte `offset`, without
+    /// transferring the rest of the value.
+    pub fn get_range(
+        &mut self,
+        key: Vec<u8>,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<String>, KvStoreError> {
+        let message = Message::GetRange {
+            key,
+            offset: offset as u64,
+            len: len as u64,
+            after: self.session,
+        };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::GetRange(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: String) -> Result<(), KvStoreError> {
+        self.set_value(key, value, None)
+    }
+
+    // Set the key, in parts if the value is longer than the chunk length
+    fn set_value(
+        &mut self,
+        key: Vec<u8>,
+        value: String,
+        ttl_ms: Option<u64>,
+    ) -> Result<(), KvStoreError> {
+        if value.len() <= self.chunk_len {
+            let response = self.send(&Message::Set { key, value, ttl_ms })?;
+            return match response {
+                Response::Set(result) => {
+                    self.record_write(result.map_err(KvStoreError::StringError)?);
+                    Ok(())
+                }
+                _ => Err(KvStoreError::StringError("Unexpected response".into())),
+            };
+        }
+
+        // Only the last part writes, so it carries the idempotency key
+        let mut idempotency_key = self.idempotency_key.take();
+        let mut offset = 0;
+        while offset < value.len() {
+            let mut end = (offset + self.chunk_len).min(value.len());
+            while !value.is_char_boundary(end) {
+                end += 1;
+            }
+            let last = end == value.len();
+            if last {
+                self.idempotency_key = idempotency_key.take();
+            }
+
+            let message = Message::SetChunk {
+                key: key.clone(),
+                offset: offset as u64,
+                chunk: value[offset..end].to_owned(),
+                last,
+                ttl_ms,
+            };
+            match self.send(&message)? {
+                Response::SetChunk(result) => {
+                    let position = result.map_err(KvStoreError::StringError)?;
+                    if last {
+                        self.record_write(position);
+                    }
+                }
+                _ => return Err(KvStoreError::StringError("Unexpected response".into())),
+            }
+            offset = end;
         }
+
+        Ok(())
+    }
+
+    /// Get the values of several keys in one round trip. Values are returned
+    /// in the order of `keys`.
+    pub fn get_many(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<String>>, KvStoreError> {
+        let messages = keys
+            .into_iter()
+            .map(|key| Message::Get {
+                key,
+                after: self.session,
+            })
+            .collect();
+
+        self.batch(messages)?
+            .into_iter()
+            .map(|response| match response {
+                Response::Get(result) => result.map_err(KvStoreError::StringError),
+                _ => Err(KvStoreError::StringError("Unexpected response".into())),
+            })
+            .collect()
     }
 
-    pub fn remove(&mut self, key: String) -> Result<(), KvStoreError> {
+    /// Set several keys, in as few round trips as fit the server's frame
+    /// limit: entries are batched up to about the chunk length, and a value
+    /// longer than it is set in parts on its own. Entries are written in
+    /// order, and the first failure is returned.
+    pub fn set_many(&mut self, entries: Vec<(Vec<u8>, String)>) -> Result<(), KvStoreError> {
+        let mut messages = Vec::new();
+        let mut batch_len = 0;
+        for (key, value) in entries {
+            let len = key.len() + value.len();
+            let oversize = value.len() > self.chunk_len;
+            if !messages.is_empty() && (oversize || batch_len + len > self.chunk_len) {
+                self.set_batch(std::mem::take(&mut messages))?;
+                batch_len = 0;
+            }
+            if oversize {
+                self.set_value(key, value, None)?;
+                continue;
+            }
+
+            batch_len += len;
+            messages.push(Message::Set {
+                key,
+                value,
+                ttl_ms: None,
+            });
+        }
+
+        match messages.is_empty() {
+            true => Ok(()),
+            false => self.set_batch(messages),
+        }
+    }
+
+    fn set_batch(&mut self, messages: Vec<Message>) -> Result<(), KvStoreError> {
+        self.batch(messages)?
+            .into_iter()
+            .try_for_each(|response| match response {
+                Response::Set(result) => {
+                    self.record_write(result.map_err(KvStoreError::StringError)?);
+                    Ok(())
+                }
+                _ => Err(KvStoreError::StringError("Unexpected response".into())),
+            })
+    }
+
+    fn batch(&mut self, messages: Vec<Message>) -> Result<Vec<Response>, KvStoreError> {
+        let len = messages.len();
+        let response = self.send(&Message::Batch(messages))?;
+
+        match response {
+            Response::Batch(Ok(responses)) if responses.len() == len => Ok(responses),
+            Response::Batch(Err(err)) => Err(KvStoreError::StringError(err)),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Set the key only if it is absent. Returns whether the value was
+    /// written, so concurrent callers can tell which of them won.
+    pub fn set_nx(&mut self, key: Vec<u8>, value: String) -> Result<bool, KvStoreError> {
+        let message = Message::SetNx { key, value };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::SetNx(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn set_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: String,
+        ttl: Duration,
+    ) -> Result<(), KvStoreError> {
+        self.set_value(key, value, Some(ttl.as_millis() as u64))
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<(), KvStoreError> {
         let message = Message::Remove { key };
         let response = self.send(&message)?;
 
         match response {
-            Response::Remove(result) => return result.map_err(KvStoreError::StringError),
-            _ => return Err(KvStoreError::StringError("Unexpected response".into())),
+            Response::Remove(result) => {
+                self.record_write(result.map_err(KvStoreError::StringError)?);
+                Ok(())
+            }
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Set the key and return the value it replaced, as one operation.
+    pub fn get_set(&mut self, key: Vec<u8>, value: String) -> Result<Option<String>, KvStoreError> {
+        let message = Message::GetSet { key, value };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::GetSet(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Remove the key and return its value, as one operation.
+    pub fn get_del(&mut self, key: Vec<u8>) -> Result<Option<String>, KvStoreError> {
+        let message = Message::GetDel { key };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::GetDel(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
         }
     }
+
+    /// Append `suffix` to the key's value, or set the key to it if absent,
+    /// and return the new length in bytes. The server does the append, so
+    /// the value never travels back.
+    pub fn append(&mut self, key: Vec<u8>, suffix: String) -> Result<usize, KvStoreError> {
+        let message = Message::Append { key, suffix };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Append(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Time left before the key expires, or `None` if it never does.
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>, KvStoreError> {
+        let message = Message::Ttl { key };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Ttl(result) => result
+                .map(|ttl_ms| ttl_ms.map(Duration::from_millis))
+                .map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<(), KvStoreError> {
+        let message = Message::Expire {
+            key,
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Expire(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn persist(&mut self, key: Vec<u8>) -> Result<(), KvStoreError> {
+        let message = Message::Persist { key };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Persist(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Bring back the value of a key the server soft-deleted.
+    pub fn restore(&mut self, key: Vec<u8>) -> Result<(), KvStoreError> {
+        let message = Message::Restore { key };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Restore(result) => {
+                self.record_write(result.map_err(KvStoreError::StringError)?);
+                Ok(())
+            }
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Run a Lua script on the server and return what it returned. The script
+    /// reads `args` from `ARGV` and uses the store through `kvs.get`,
+    /// `kvs.set` and `kvs.remove`; no other request runs while it does.
+    pub fn eval(
+        &mut self,
+        script: String,
+        args: Vec<String>,
+    ) -> Result<Option<String>, KvStoreError> {
+        let message = Message::Eval { script, args };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Eval(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Append an item to a queue, returning its id.
+    pub fn enqueue(&mut self, queue: String, item: String) -> Result<u64, KvStoreError> {
+        let message = Message::Enqueue { queue, item };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Enqueue(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Take the oldest available item of a queue. It is handed out again
+    /// after `visibility_timeout` unless `ack` is called for it first.
+    pub fn dequeue(
+        &mut self,
+        queue: String,
+        visibility_timeout: Duration,
+    ) -> Result<Option<QueueItem>, KvStoreError> {
+        let message = Message::Dequeue {
+            queue,
+            visibility_ms: visibility_timeout.as_millis() as u64,
+        };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Dequeue(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn ack(&mut self, queue: String, id: u64) -> Result<(), KvStoreError> {
+        let message = Message::Ack { queue, id };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Ack(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Take a lock for `ttl`, returning the lease's fencing token, or `None`
+    /// if someone else holds a live lease on it.
+    pub fn acquire(&mut self, lock: String, ttl: Duration) -> Result<Option<u64>, KvStoreError> {
+        let message = Message::Acquire {
+            lock,
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Acquire(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Extend the lease held with `token` to expire `ttl` from now.
+    pub fn renew(&mut self, lock: String, token: u64, ttl: Duration) -> Result<(), KvStoreError> {
+        let message = Message::Renew {
+            lock,
+            token,
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Renew(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn release(&mut self, lock: String, token: u64) -> Result<(), KvStoreError> {
+        let message = Message::Release { lock, token };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Release(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Run a transaction on the server, with no other request in between.
+    pub fn txn(&mut self, txn: Txn) -> Result<TxnResponse, KvStoreError> {
+        let response = self.send(&Message::Txn(txn))?;
+
+        match response {
+            Response::Txn(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Send a message to everyone subscribed to `channel`, returning how many
+    /// subscribers it reached. Messages aren't stored.
+    pub fn publish(&mut self, channel: String, message: String) -> Result<usize, KvStoreError> {
+        let message = Message::Publish { channel, message };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::Publish(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Turn this connection into a subscription to `channels`. The returned
+    /// iterator blocks until the next message published to any of them.
+    pub fn subscribe(mut self, channels: Vec<String>) -> Result<Subscription, KvStoreError> {
+        let response = self.send(&Message::Subscribe { channels })?;
+
+        match response {
+            Response::Subscribed(result) => {
+                result.map_err(KvStoreError::StringError)?;
+                Ok(Subscription { client: self })
+            }
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Up to `count` of the keys the server has seen read most, most first.
+    /// Fails unless the server samples reads.
+    pub fn hot_keys(&mut self, count: usize) -> Result<Vec<HotKey>, KvStoreError> {
+        let response = self.send(&Message::HotKeys { count })?;
+
+        match response {
+            Response::HotKeys(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// The server's key prefix quotas, each with how much of it is used.
+    pub fn quotas(&mut self) -> Result<Vec<QuotaUsage>, KvStoreError> {
+        let response = self.send(&Message::Quotas)?;
+
+        match response {
+            Response::Quotas(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Up to `count` of the server's slow log entries, newest first.
+    pub fn slowlog_get(&mut self, count: usize) -> Result<Vec<SlowLogEntry>, KvStoreError> {
+        let message = Message::SlowLogGet { count };
+        let response = self.send(&message)?;
+
+        match response {
+            Response::SlowLogGet(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    pub fn slowlog_reset(&mut self) -> Result<(), KvStoreError> {
+        let response = self.send(&Message::SlowLogReset)?;
+
+        match response {
+            Response::SlowLogReset(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// The counters of the work the server's engine has done since it opened.
+    pub fn metrics(&mut self) -> Result<Metrics, KvStoreError> {
+        let response = self.send(&Message::Metrics)?;
+
+        match response {
+            Response::Metrics(result) => result
+                .map(|metrics| *metrics)
+                .map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Stats of the server engine's latest compactions, oldest first.
+    pub fn compaction_history(&mut self) -> Result<Vec<CompactionStats>, KvStoreError> {
+        let response = self.send(&Message::CompactionHistory)?;
+
+        match response {
+            Response::CompactionHistory(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Seal the server's active log and start a new one, returning the new
+    /// log's generation, or `None` if its engine has no logs.
+    pub fn rotate_log(&mut self) -> Result<Option<u64>, KvStoreError> {
+        let response = self.send(&Message::RotateLog)?;
+
+        match response {
+            Response::RotateLog(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Summarize the sizes of `samples` live keys picked at random, and how
+    /// they group by prefix up to the first `delimiter`.
+    pub fn analyze(
+        &mut self,
+        samples: usize,
+        delimiter: u8,
+    ) -> Result<KeyspaceSample, KvStoreError> {
+        let response = self.send(&Message::Analyze { samples, delimiter })?;
+
+        match response {
+            Response::Analyze(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Hash the server's live entries grouped by the first `prefix_len`
+    /// bytes of their keys, to compare with another store's.
+    pub fn digest(&mut self, prefix_len: usize) -> Result<Vec<PrefixDigest>, KvStoreError> {
+        let response = self.send(&Message::Digest { prefix_len })?;
+
+        match response {
+            Response::Digest(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// The hashes of the nodes at `nodes` of the server's `MerkleTree` of
+    /// `depth`. The server builds the tree for each call.
+    pub fn merkle_nodes(&mut self, depth: u32, nodes: Vec<u64>) -> Result<Vec<u64>, KvStoreError> {
+        let response = self.send(&Message::MerkleNodes { depth, nodes })?;
+
+        match response {
+            Response::MerkleNodes(result) => result.map_err(KvStoreError::StringError),
+            _ => Err(KvStoreError::StringError("Unexpected response".into())),
+        }
+    }
+
+    /// Stream every entry whose key starts with `prefix`. Entries are read from
+    /// the connection chunk by chunk as the iterator advances.
+    pub fn scan(&mut self, prefix: Vec<u8>) -> Result<Scan<'_>, KvStoreError> {
+        self.write_message(&Message::Scan {
+            prefix,
+            after: self.session,
+            pattern: None,
+        })?;
+
+        Ok(Scan {
+            client: self,
+            chunk: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+
+    /// Stream every entry whose key matches the glob `pattern`, such as
+    /// `user:*:settings`. Keys are matched on the server.
+    pub fn scan_glob(&mut self, pattern: String) -> Result<Scan<'_>, KvStoreError> {
+        self.write_message(&Message::Scan {
+            prefix: Vec::new(),
+            after: self.session,
+            pattern: Some(pattern),
+        })?;
+
+        Ok(Scan {
+            client: self,
+            chunk: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+}
+
+/// Messages published to a subscription's channels, as `(channel, message)`.
+pub struct Subscription {
+    client: KvsClient,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<(String, String), KvStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.read_response() {
+            Ok(Response::Published { channel, message }) => Some(Ok((channel, message))),
+            Ok(_) => Some(Err(KvStoreError::StringError("Unexpected response".into()))),
+            // The server closed the subscription
+            Err(KvStoreError::IoErr(err)) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Iterator over the entries of a scan. Dropping it early drains the rest of
+/// the response so the connection can be reused.
+pub struct Scan<'a> {
+    client: &'a mut KvsClient,
+    chunk: std::vec::IntoIter<Entry>,
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, String), KvStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(Entry { key, value }) = self.chunk.next() {
+                return Some(Ok((key, value)));
+            }
+
+            if self.done {
+                return None;
+            }
+
+            match self.client.read_response() {
+                Ok(Response::ScanChunk(entries)) => self.chunk = entries.into_iter(),
+                Ok(Response::ScanEnd(result)) => {
+                    self.done = true;
+                    if let Err(err) = result {
+                        return Some(Err(KvStoreError::StringError(err)));
+                    }
+                }
+                Ok(_) => {
+                    self.done = true;
+                    return Some(Err(KvStoreError::StringError("Unexpected response".into())));
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Scan<'_> {
+    fn drop(&mut self) {
+        self.chunk = Vec::new().into_iter();
+        for _ in self {}
+    }
 }
@@ -0,0 +1,397 @@
+//! Encryption of log and index records at rest.
+//!
+//! A sealed record is still one JSON value, so the log and index readers
+//! find record boundaries the same way: `[key_id, "<base64>"]`, where the
+//! base64 holds a random nonce followed by the AES-256-GCM ciphertext of the
+//! record's plain JSON. The record's `Location`, e.g. its log generation and
+//! offset, is authenticated along with it, so a sealed record copied or
+//! moved anywhere else fails to open.
+//!
+//! Plain records are JSON objects. A store that turns encryption on keeps
+//! reading the plain records of the logs it had then, until compaction
+//! rewrites them sealed, and rejects plain records anywhere else.
+//!
+//! Sealing and opening records needs the `encryption` feature. Without it
+//! no `Keyring` can be made, so records are only written plain, and a store
+//! whose `MANIFEST` says it is encrypted refuses to open.
+
+#[cfg(feature = "encryption")]
+use std::collections::HashMap;
+#[cfg(feature = "encryption")]
+use std::convert::TryInto;
+use std::fmt;
+#[cfg(feature = "encryption")]
+use std::fs;
+use std::marker::PhantomData;
+#[cfg(feature = "encryption")]
+use std::path::Path;
+#[cfg(feature = "encryption")]
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+#[cfg(feature = "encryption")]
+use base64::engine::general_purpose::STANDARD as BASE64;
+#[cfg(feature = "encryption")]
+use base64::Engine;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{KvStoreError, Result};
+
+// Bytes of the random nonce each sealed record starts with
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+#[cfg(feature = "encryption")]
+type FetchKey = dyn Fn(u32) -> Result<[u8; 32]> + Send + Sync;
+
+/// Where a record is stored, authenticated with it when it is sealed.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub(crate) enum Location {
+    /// The record at `offset` in log `log_gen`
+    Log { log_gen: u64, offset: u64 },
+    /// The `record`th record of the keydir index
+    Index { record: u64 },
+    /// The bloom filter of log `log_gen`
+    Bloom { log_gen: u64 },
+}
+
+impl Location {
+    #[cfg(feature = "encryption")]
+    fn aad(self) -> Vec<u8> {
+        let (kind, numbers) = match self {
+            Location::Log { log_gen, offset } => (b'L', [log_gen, offset]),
+            Location::Index { record } => (b'I', [record, 0]),
+            Location::Bloom { log_gen } => (b'B', [log_gen, 0]),
+        };
+        let mut aad = vec![kind];
+        for number in numbers {
+            aad.extend_from_slice(&number.to_be_bytes());
+        }
+        aad
+    }
+}
+
+/// The AES-256 keys a `KvStore` encrypts its records with, by id.
+///
+/// New records are sealed with the active key. The other keys only open
+/// records sealed before a rotation; compaction rewrites every live record
+/// with the active key, after which the old keys can be retired.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct Keyring {
+    active_key_id: u32,
+    ciphers: Arc<Mutex<HashMap<u32, Aes256Gcm>>>,
+    // Looks up keys not added up front, e.g. from a KMS
+    fetch: Option<Arc<FetchKey>>,
+    // Logs older than this were written before the store was encrypted, and
+    // are the only place a plain record is accepted
+    plain_logs_before: u64,
+}
+
+/// The keys a `KvStore` encrypts its records with. Without the
+/// `encryption` feature there are none: no `Keyring` can be made.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub struct Keyring {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Keyring {
+    /// Id of the key new records are sealed with.
+    pub fn active_key_id(&self) -> u32 {
+        match self.never {}
+    }
+
+    pub(crate) fn with_plain_logs_before(self, _log_gen: u64) -> Keyring {
+        match self.never {}
+    }
+
+    pub(crate) fn accepts_plain(&self, _location: Location) -> bool {
+        match self.never {}
+    }
+
+    fn seal(&self, _plaintext: &[u8], _location: Location) -> Result<SealedRecord> {
+        match self.never {}
+    }
+
+    fn open(&self, _sealed: &SealedRecord, _location: Location) -> Result<Vec<u8>> {
+        match self.never {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Keyring {
+    /// A keyring sealing with `key`, known by `key_id`.
+    pub fn new(key_id: u32, key: [u8; 32]) -> Keyring {
+        Keyring {
+            active_key_id: key_id,
+            ciphers: Arc::new(Mutex::new(HashMap::new())),
+            fetch: None,
+            plain_logs_before: 0,
+        }
+        .with_retired_key(key_id, key)
+    }
+
+    /// Also open records sealed with `key`, e.g. the key active before the
+    /// last rotation.
+    pub fn with_retired_key(self, key_id: u32, key: [u8; 32]) -> Keyring {
+        self.lock().insert(key_id, Aes256Gcm::new(&key.into()));
+        self
+    }
+
+    /// A keyring sealing with the key `active_key_id`, which calls `fetch`
+    /// the first time it needs each key, e.g. to ask a KMS for it.
+    pub fn from_callback(
+        active_key_id: u32,
+        fetch: impl Fn(u32) -> Result<[u8; 32]> + Send + Sync + 'static,
+    ) -> Keyring {
+        Keyring {
+            active_key_id,
+            ciphers: Arc::new(Mutex::new(HashMap::new())),
+            fetch: Some(Arc::new(fetch)),
+            plain_logs_before: 0,
+        }
+    }
+
+    /// Read keys from a file of `<id> <key>` lines, each key 32 bytes in
+    /// base64, e.g. from `openssl rand -base64 32`. The last key listed is
+    /// the active one, so a key is rotated by appending a new line. Blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> Result<Keyring> {
+        Keyring::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read keys from the environment variable `var`, listed as in
+    /// `from_file`, separated by newlines or commas.
+    pub fn from_env(var: &str) -> Result<Keyring> {
+        let keys = std::env::var(var).map_err(|err| {
+            KvStoreError::Encryption(format!("Can't read keys from ${}: {}", var, err))
+        })?;
+        Keyring::parse(&keys.replace(',', "\n"))
+    }
+
+    fn parse(keys: &str) -> Result<Keyring> {
+        let mut parsed = Vec::new();
+        for line in keys.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || KvStoreError::Encryption(format!("Invalid key line {:?}", line));
+            let (key_id, key) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let key_id: u32 = key_id.parse().map_err(|_| invalid())?;
+            let key: [u8; 32] = BASE64
+                .decode(key.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(invalid)?;
+            parsed.push((key_id, key));
+        }
+
+        let (&(active_key_id, active_key), retired) = parsed
+            .split_last()
+            .ok_or_else(|| KvStoreError::Encryption("No keys listed".to_owned()))?;
+        let mut keyring = Keyring::new(active_key_id, active_key);
+        for &(key_id, key) in retired {
+            if key_id != active_key_id {
+                keyring = keyring.with_retired_key(key_id, key);
+            }
+        }
+        Ok(keyring)
+    }
+
+    /// Id of the key new records are sealed with.
+    pub fn active_key_id(&self) -> u32 {
+        self.active_key_id
+    }
+
+    /// Also accept plain records from logs older than `log_gen`, the first
+    /// one the store wrote sealed.
+    pub(crate) fn with_plain_logs_before(mut self, log_gen: u64) -> Keyring {
+        self.plain_logs_before = log_gen;
+        self
+    }
+
+    /// Whether a plain record at `location` can be one written before the
+    /// store was encrypted, rather than one put there since.
+    pub(crate) fn accepts_plain(&self, location: Location) -> bool {
+        match location {
+            Location::Log { log_gen, .. } => log_gen < self.plain_logs_before,
+            // Rewritten whole on every save, so sealed since the first one
+            Location::Index { .. } | Location::Bloom { .. } => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, Aes256Gcm>> {
+        self.ciphers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn cipher(&self, key_id: u32) -> Result<Aes256Gcm> {
+        if let Some(cipher) = self.lock().get(&key_id) {
+            return Ok(cipher.clone());
+        }
+
+        let fetch = self
+            .fetch
+            .as_ref()
+            .ok_or_else(|| KvStoreError::Encryption(format!("No key with id {}", key_id)))?;
+        let cipher = Aes256Gcm::new(&fetch(key_id)?.into());
+        self.lock().insert(key_id, cipher.clone());
+        Ok(cipher)
+    }
+
+    fn seal(&self, plaintext: &[u8], location: Location) -> Result<SealedRecord> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &location.aad(),
+        };
+        let ciphertext = self
+            .cipher(self.active_key_id)?
+            .encrypt(&nonce, payload)
+            .map_err(|_| KvStoreError::Encryption("Failed to seal a record".to_owned()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(SealedRecord(self.active_key_id, BASE64.encode(sealed)))
+    }
+
+    fn open(&self, sealed: &SealedRecord, location: Location) -> Result<Vec<u8>> {
+        let SealedRecord(key_id, data) = sealed;
+        let invalid = || {
+            KvStoreError::Encryption(format!(
+                "A record sealed with key {} failed to decrypt at {:?}",
+                key_id, location
+            ))
+        };
+        let data = BASE64.decode(data).map_err(|_| invalid())?;
+        if data.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &location.aad(),
+        };
+        self.cipher(*key_id)?
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| invalid())
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active_key_id", &self.active_key_id())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A record encrypted with the key `.0`: the nonce and ciphertext in base64.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SealedRecord(u32, String);
+
+/// A record as it is stored: plain, or sealed with one of a keyring's keys.
+pub(crate) enum Stored<T> {
+    Plain(T),
+    Sealed(SealedRecord),
+}
+
+impl<T: DeserializeOwned> Stored<T> {
+    /// The record stored at `location`, decrypted with `keys` if it was
+    /// sealed. With keys, a plain record is only accepted where one could
+    /// have been written before the store was encrypted.
+    pub(crate) fn open(self, keys: Option<&Keyring>, location: Location) -> Result<T> {
+        match self {
+            Stored::Plain(record) => {
+                check_plain(keys, location)?;
+                Ok(record)
+            }
+            Stored::Sealed(sealed) => {
+                let plaintext = keys.ok_or_else(missing_keys)?.open(&sealed, location)?;
+                Ok(serde_json::from_slice(&plaintext)?)
+            }
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Stored<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(StoredVisitor(PhantomData))
+    }
+}
+
+struct StoredVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for StoredVisitor<T> {
+    type Value = Stored<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a record, or a sealed record")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Self::Value, A::Error> {
+        T::deserialize(MapAccessDeserializer::new(map)).map(Stored::Plain)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> std::result::Result<Self::Value, A::Error> {
+        SealedRecord::deserialize(SeqAccessDeserializer::new(seq)).map(Stored::Sealed)
+    }
+}
+
+fn missing_keys() -> KvStoreError {
+    KvStoreError::Encryption("The record is encrypted and no keys were given".to_owned())
+}
+
+/// Fail for a plain record at `location` if there are `keys` and it can't
+/// be one written before the store was encrypted.
+pub(crate) fn check_plain(keys: Option<&Keyring>, location: Location) -> Result<()> {
+    match keys {
+        Some(keys) if !keys.accepts_plain(location) => Err(KvStoreError::Encryption(format!(
+            "Unsealed record at {:?} in an encrypted store",
+            location
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Encode `record` as JSON to be stored at `location`, sealed if there are
+/// `keys`.
+pub(crate) fn seal_json(
+    keys: Option<&Keyring>,
+    record: &impl Serialize,
+    location: Location,
+) -> Result<Vec<u8>> {
+    let plain = serde_json::to_vec(record)?;
+    match keys {
+        Some(keys) => Ok(serde_json::to_vec(&keys.seal(&plain, location)?)?),
+        None => Ok(plain),
+    }
+}
+
+/// The plain JSON of the encoded record `bytes` stored at `location`,
+/// decrypting it if it was sealed.
+pub(crate) fn unseal_bytes(
+    keys: Option<&Keyring>,
+    bytes: Vec<u8>,
+    location: Location,
+) -> Result<Vec<u8>> {
+    let is_sealed = bytes
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|&byte| byte == b'[');
+    if !is_sealed {
+        check_plain(keys, location)?;
+        return Ok(bytes);
+    }
+
+    let sealed: SealedRecord = serde_json::from_slice(&bytes)?;
+    keys.ok_or_else(missing_keys)?.open(&sealed, location)
+}
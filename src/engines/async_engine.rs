@@ -0,0 +1,67 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{KvStoreError, KvsEngine, Result};
+
+/// A store whose operations are futures, for servers running on an async
+/// runtime. Handles are cheap to clone and share one store. Engines that are
+/// natively async implement this directly; sync engines are wrapped in a
+/// `BlockingEngine`.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    fn get(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<String>>> + Send;
+    fn set(&self, key: Vec<u8>, value: String) -> impl Future<Output = Result<()>> + Send;
+    fn remove(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Runs a sync engine's operations on tokio's blocking thread pool, one at
+/// a time, so that disk I/O doesn't block the runtime's worker threads.
+/// Must be used from within a tokio runtime.
+pub struct BlockingEngine<E> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E> Clone for BlockingEngine<E> {
+    fn clone(&self) -> BlockingEngine<E> {
+        BlockingEngine {
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> BlockingEngine<E> {
+    pub fn new(engine: E) -> BlockingEngine<E> {
+        BlockingEngine {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Run `op` on the engine on the blocking thread pool, for operations
+    /// `AsyncKvsEngine` doesn't cover.
+    pub fn run<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&mut E) -> Result<T> + Send + 'static,
+    ) -> impl Future<Output = Result<T>> + Send {
+        let engine = self.engine.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                op(&mut engine.lock().unwrap_or_else(PoisonError::into_inner))
+            })
+            .await
+            .map_err(|err| KvStoreError::StringError(format!("Engine task failed: {}", err)))?
+        }
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> AsyncKvsEngine for BlockingEngine<E> {
+    fn get(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.run(move |engine| engine.get(key))
+    }
+
+    fn set(&self, key: Vec<u8>, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.remove(key))
+    }
+}
@@ -0,0 +1,72 @@
+use super::validate_key;
+use crate::Result;
+
+/// Writes applied to a store as one unit with `KvsWriter::apply`: even if
+/// the process crashes partway through, a reopened store has either all of
+/// them or none.
+///
+/// ```ignore
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set(b"to".to_vec(), "10".to_owned())
+///     .remove(b"from".to_vec());
+/// store.apply(batch)?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// One write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BatchOp {
+    Set {
+        key: Vec<u8>,
+        value: String,
+    },
+    /// Removing an absent key does nothing
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set `key` to `value`, without an expiry.
+    pub fn set(&mut self, key: Vec<u8>, value: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Remove `key`. Unlike `KvsWriter::remove`, an absent key is no error.
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Check every key the batch writes, as engines do before writing any.
+    pub(crate) fn validate(&self) -> Result<()> {
+        for op in &self.ops {
+            let (BatchOp::Set { key, .. } | BatchOp::Remove { key }) = op;
+            validate_key(key)?;
+        }
+        Ok(())
+    }
+
+    /// The writes, in the order they were added.
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
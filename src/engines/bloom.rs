@@ -0,0 +1,159 @@
+use crate::encryption::{self, Keyring, Location, Stored};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// Bits per key and bits set per key, for about a 1% false positive rate
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+
+pub(super) fn bloom_path(dir: &Path, log_gen: u64) -> PathBuf {
+    dir.join(format!("{}.bloom", log_gen))
+}
+
+/// A bloom filter of the keys with records in one log. A key it doesn't
+/// match has no records there; one it matches may.
+#[derive(Debug, Clone)]
+pub(super) struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+// A bloom file, sealed with the store's keys if it has any
+#[derive(Serialize, Deserialize)]
+struct BloomFile {
+    hashes: u32,
+    /// The filter's bits, in hex. Files from before, with the bits in
+    /// base64 under `bits`, don't read and are skipped.
+    bits_hex: String,
+}
+
+impl Bloom {
+    /// A filter of the keys hashed with `hash`.
+    pub(super) fn from_hashes(key_hashes: &[u64]) -> Bloom {
+        let len = (key_hashes.len().max(1) * BITS_PER_KEY).div_ceil(8);
+        let mut bloom = Bloom {
+            bits: vec![0; len],
+            hashes: HASHES,
+        };
+        for &key_hash in key_hashes {
+            for bit in bloom.bits_of(key_hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// Bytes the filter's bits take up.
+    pub(super) fn bytes(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // The bits a key sets, by double hashing
+    fn bits_of(&self, key_hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let step = mix(key_hash) | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (key_hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    /// Write the filter of log `log_gen` to `dir`, replacing any previous
+    /// one only once the new one is complete.
+    pub(super) fn save(&self, dir: &Path, log_gen: u64, keys: Option<&Keyring>) -> Result<()> {
+        let record = BloomFile {
+            hashes: self.hashes,
+            bits_hex: self
+                .bits
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        };
+        let path = bloom_path(dir, log_gen);
+        let tmp_path = path.with_extension("bloom.tmp");
+        let mut file = File::create(&tmp_path)?;
+        let location = Location::Bloom { log_gen };
+        file.write_all(&encryption::seal_json(keys, &record, location)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// Read the filter of log `log_gen` in `dir`, if it has a readable one.
+/// Filters only ever save work, so one that can't be read is skipped.
+pub(super) fn load(dir: &Path, log_gen: u64, keys: Option<&Keyring>) -> Option<Bloom> {
+    let path = bloom_path(dir, log_gen);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!(
+                "Ignoring unreadable bloom filter {}: {}",
+                path.display(),
+                err
+            );
+            return None;
+        }
+    };
+
+    let record = serde_json::from_slice::<Stored<BloomFile>>(&bytes)
+        .map_err(Into::into)
+        .and_then(|record| record.open(keys, Location::Bloom { log_gen }));
+    let bloom = record.ok().and_then(|record| {
+        Some(Bloom {
+            bits: from_hex(&record.bits_hex)?,
+            hashes: record.hashes,
+        })
+    });
+    match bloom {
+        Some(bloom) if !bloom.bits.is_empty() && bloom.hashes > 0 => Some(bloom),
+        _ => {
+            warn!("Ignoring unreadable bloom filter {}", path.display());
+            None
+        }
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Delete the filter of log `log_gen` in `dir`, if it has one.
+pub(super) fn remove(dir: &Path, log_gen: u64) -> Result<()> {
+    match fs::remove_file(bloom_path(dir, log_gen)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Hash of `key` as a filter sees it: FNV-1a, mixed so similar keys spread
+/// out. Unlike std's hashers it never changes, as filters are saved.
+pub(super) fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    mix(hash)
+}
+
+// The finalizer of MurmurHash3
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ (x >> 33)
+}
@@ -0,0 +1,153 @@
+use super::btree_entry_bytes;
+use crate::logs::LogPointer;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+/// Values read from the logs, kept up to a byte budget and evicted least
+/// recently used first. Each value remembers the record it was read from, so
+/// a lookup only hits while the key still points at that record.
+///
+/// Values no longer than `inline_len` are instead kept beside the keydir
+/// entry for as long as the key points at their record, outside the budget,
+/// so that gets of them never touch the disk once written or first read.
+#[derive(Debug)]
+pub(super) struct ReadCache {
+    capacity: usize,
+    len: usize,
+    tick: u64,
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    // Keys by the tick they were last used at
+    recency: BTreeMap<u64, Vec<u8>>,
+    inline_len: usize,
+    inline: HashMap<Vec<u8>, InlineEntry>,
+    inline_bytes: usize,
+}
+
+#[derive(Debug)]
+struct InlineEntry {
+    log_pointer: LogPointer,
+    value: Box<str>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    log_pointer: LogPointer,
+    value: String,
+    last_used: u64,
+}
+
+fn entry_len(key: &[u8], value: &str) -> usize {
+    key.len() + value.len()
+}
+
+impl ReadCache {
+    pub(super) fn new(capacity: usize, inline_len: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            len: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            inline_len,
+            inline: HashMap::new(),
+            inline_bytes: 0,
+        }
+    }
+
+    /// Whether `value` is short enough to keep inline.
+    pub(super) fn is_inline(&self, value: &str) -> bool {
+        value.len() <= self.inline_len && self.inline_len > 0
+    }
+
+    /// The cached value of `key`, if it was read from `log_pointer`.
+    pub(super) fn get(&mut self, key: &[u8], log_pointer: &LogPointer) -> Option<String> {
+        if let Some(entry) = self.inline.get(key) {
+            let hit = (entry.log_pointer.log_gen, entry.log_pointer.pos)
+                == (log_pointer.log_gen, log_pointer.pos);
+            return hit.then(|| entry.value.to_string());
+        }
+
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        if (entry.log_pointer.log_gen, entry.log_pointer.pos)
+            != (log_pointer.log_gen, log_pointer.pos)
+        {
+            return None;
+        }
+
+        let key = self.recency.remove(&entry.last_used)?;
+        entry.last_used = self.tick;
+        self.recency.insert(self.tick, key);
+
+        Some(entry.value.clone())
+    }
+
+    pub(super) fn insert(&mut self, key: Vec<u8>, log_pointer: LogPointer, value: String) {
+        self.remove(&key);
+        let len = entry_len(&key, &value);
+        if self.is_inline(&value) {
+            self.inline_bytes += len;
+            let value = value.into_boxed_str();
+            self.inline.insert(key, InlineEntry { log_pointer, value });
+            return;
+        }
+        if len > self.capacity {
+            return;
+        }
+
+        while self.len + len > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.len -= entry_len(&oldest, &entry.value);
+            }
+        }
+
+        self.tick += 1;
+        self.len += len;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                log_pointer,
+                value,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.inline.remove(key) {
+            self.inline_bytes -= entry_len(key, &entry.value);
+        }
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.len -= entry_len(key, &entry.value);
+        }
+    }
+
+    /// Estimated bytes the cache holds: its values, both copies of their
+    /// keys, and the entries of its two maps. The hash map keeps an eighth of
+    /// its slots free, and a control byte for each.
+    pub(super) fn memory_usage(&self) -> usize {
+        let key_bytes: usize = self.entries.keys().map(Vec::len).sum();
+        let slot_bytes = (size_of::<(Vec<u8>, CacheEntry)>() + 1) * 8 / 7;
+        let inline_slot_bytes = (size_of::<(Vec<u8>, InlineEntry)>() + 1) * 8 / 7;
+        self.len
+            + key_bytes
+            + self.entries.len() * (slot_bytes + btree_entry_bytes::<u64, Vec<u8>>())
+            + self.inline_bytes
+            + self.inline.len() * inline_slot_bytes
+    }
+
+    /// Point a cached value at the record compaction copied it to.
+    pub(super) fn relocate(&mut self, key: &[u8], log_pointer: LogPointer) {
+        if let Some(entry) = self.inline.get_mut(key) {
+            entry.log_pointer = log_pointer;
+        }
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.log_pointer = log_pointer;
+        }
+    }
+}
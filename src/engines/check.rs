@@ -0,0 +1,457 @@
+use super::index::{self, INDEX_FILE};
+use super::kvs::{apply_record, sorted_log_gens, Keydir};
+use super::manifest;
+use super::readers::Readers;
+use crate::encryption::Keyring;
+use crate::logs::{log_path, unix_millis, Command, LogEncoding, LogIterator, LogPointer};
+use crate::{KvStore, KvStoreConfig, KvStoreError, LogPosition, Result};
+use rand::seq::IteratorRandom;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Problems listed in a failed keydir check's error; the rest are counted
+const MAX_LISTED_PROBLEMS: usize = 20;
+
+/// What checking found in one log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogCheck {
+    pub log_gen: u64,
+    pub path: PathBuf,
+    pub file_len: u64,
+    /// Records read before the end of the file or the first unreadable one
+    pub records: u64,
+    /// Bytes up to the end of the last readable record
+    pub valid_len: u64,
+    /// Bytes of records that are still the live value of an unexpired key
+    pub live_len: u64,
+    /// Why reading stopped before the end of the file, if it did
+    pub error: Option<String>,
+}
+
+impl LogCheck {
+    /// Bytes after the last readable record. Opening the store skips them,
+    /// along with any records after them.
+    pub fn torn_len(&self) -> u64 {
+        self.file_len - self.valid_len
+    }
+
+    /// Readable bytes compaction would reclaim.
+    pub fn stale_len(&self) -> u64 {
+        self.valid_len - self.live_len
+    }
+}
+
+/// State of the saved keydir index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexState {
+    Missing,
+    Valid,
+    /// Unreadable, or made before a compaction deleted logs it points into.
+    /// Opening the store ignores it and replays every log.
+    Unusable,
+    /// Points past the readable records of the logs, so opening the store
+    /// would load keys whose records can't be read.
+    Invalid,
+}
+
+/// What `check_logs` found in a `KvStore` directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogsCheck {
+    /// Every log, oldest first
+    pub logs: Vec<LogCheck>,
+    pub live_keys: usize,
+    /// The encoding recorded in the store's manifest, `None` if it has none
+    pub log_encoding: Option<LogEncoding>,
+    pub index: IndexState,
+    /// Temporary files left by an interrupted index save or cold move
+    pub tmp_files: Vec<PathBuf>,
+}
+
+impl LogsCheck {
+    /// Whether opening the store would lose or misread anything.
+    pub fn is_healthy(&self) -> bool {
+        self.logs.iter().all(|log| log.error.is_none()) && self.index != IndexState::Invalid
+    }
+}
+
+/// Check a `KvStore` directory without opening the store: read every record
+/// of every log, and check the saved index against what was read. Pass the
+/// store's `cold_dir`, if it has one, so logs moved there are checked too,
+/// and its keys if it is encrypted.
+pub fn check_logs(
+    path: &Path,
+    cold_dir: Option<&Path>,
+    keys: Option<&Keyring>,
+) -> Result<LogsCheck> {
+    // Without its keys every sealed record would look unreadable
+    let keys = manifest::check_keys(path, keys)?;
+    let keys = keys.as_ref();
+
+    let cold_log_gens: BTreeSet<u64> = match cold_dir {
+        Some(cold_dir) => sorted_log_gens(&cold_dir.to_path_buf())?
+            .into_iter()
+            .collect(),
+        None => BTreeSet::new(),
+    };
+    let mut log_gens = sorted_log_gens(&path.to_path_buf())?;
+    log_gens.extend(&cold_log_gens);
+    log_gens.sort_unstable();
+    log_gens.dedup();
+
+    let mut keydir = Keydir::new();
+    let mut logs = Vec::with_capacity(log_gens.len());
+    for log_gen in log_gens {
+        let dir = match cold_dir {
+            Some(cold_dir) if cold_log_gens.contains(&log_gen) => cold_dir,
+            _ => path,
+        };
+        logs.push(check_log(
+            &mut keydir,
+            log_path(dir, log_gen),
+            log_gen,
+            keys,
+        )?);
+    }
+
+    let now = unix_millis();
+    let mut live_lens: BTreeMap<u64, u64> = BTreeMap::new();
+    for log_pointer in keydir.values().filter(|pointer| !pointer.is_expired(now)) {
+        *live_lens.entry(log_pointer.log_gen).or_default() += log_pointer.len;
+    }
+    for log in &mut logs {
+        log.live_len = live_lens.get(&log.log_gen).copied().unwrap_or(0);
+    }
+
+    let mut tmp_files = tmp_files_in(path)?;
+    if let Some(cold_dir) = cold_dir {
+        tmp_files.extend(tmp_files_in(cold_dir)?);
+    }
+
+    Ok(LogsCheck {
+        log_encoding: manifest::load(path)?.map(|manifest| manifest.log_encoding),
+        index: check_index(path, &logs, keys)?,
+        live_keys: keydir.len(),
+        logs,
+        tmp_files,
+    })
+}
+
+/// Check a `KvStore` directory as `check_logs` does and repair what it
+/// finds: truncate each log after its last readable record, delete leftover
+/// temporary files, and rebuild the index unless it is valid. Returns a
+/// check of the repaired directory.
+///
+/// Truncating only drops records opening the store would skip anyway.
+/// Rebuilding the index opens the store, which starts a new empty log.
+pub fn repair_logs(
+    path: &Path,
+    cold_dir: Option<&Path>,
+    keys: Option<&Keyring>,
+) -> Result<LogsCheck> {
+    let check = check_logs(path, cold_dir, keys)?;
+
+    for log in check.logs.iter().filter(|log| log.torn_len() > 0) {
+        let file = OpenOptions::new().write(true).open(&log.path)?;
+        file.set_len(log.valid_len)?;
+        file.sync_all()?;
+    }
+    for tmp_file in &check.tmp_files {
+        fs::remove_file(tmp_file)?;
+    }
+
+    if check.index != IndexState::Valid {
+        let index_path = path.join(INDEX_FILE);
+        if index_path.exists() {
+            fs::remove_file(index_path)?;
+        }
+
+        let config = KvStoreConfig {
+            cold_dir: cold_dir.map(Path::to_path_buf),
+            // Leave moving logs between tiers to the server
+            cold_after: Duration::MAX,
+            #[cfg(feature = "encryption")]
+            encryption: keys.cloned(),
+            ..KvStoreConfig::default()
+        };
+        KvStore::open_with_config(path.to_path_buf(), config)?.save_index()?;
+    }
+
+    check_logs(path, cold_dir, keys)
+}
+
+/// One record of a log, as `read_log` returns it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Byte offset of the record in the log
+    pub offset: u64,
+    /// Encoded length in bytes
+    pub len: u64,
+    pub key: Vec<u8>,
+    /// Length of the value set, `None` for a remove
+    pub value_len: Option<usize>,
+    /// Unix time in milliseconds a set expires at
+    pub expires_at: Option<u64>,
+    /// Unix time in milliseconds of a soft delete
+    pub removed_at: Option<u64>,
+    /// Sequence number of the mutation, `None` for records written before
+    /// mutations were numbered
+    pub seq: Option<u64>,
+}
+
+/// Read the records of the log file at `path`, a `<gen>.log`, starting at
+/// byte `offset`, which must be the start of a record, decrypting sealed
+/// records with `keys`. Plain records are read as the manifest next to the
+/// log allows. The iterator yields an error at the first record
+/// that can't be read, and should not be resumed after one.
+pub fn read_log(
+    path: &Path,
+    offset: u64,
+    keys: Option<&Keyring>,
+) -> Result<impl Iterator<Item = Result<LogRecord>>> {
+    let log_gen = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".log"))
+        .and_then(|log_gen| log_gen.parse().ok())
+        .ok_or_else(|| {
+            KvStoreError::StringError(format!("{} is not a <gen>.log file", path.display()))
+        })?;
+
+    let keys = match path.parent() {
+        Some(dir) => manifest::check_keys(dir, keys)?,
+        None => keys.cloned(),
+    };
+    let records = LogIterator::open_at(path, log_gen, offset)?.decrypting(keys.as_ref());
+    Ok(records.map(|record| {
+        let (cmd, log_pointer) = record?;
+        let (key, value_len, expires_at, removed_at) = match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                ..
+            } => (key, Some(value.len()), expires_at, None),
+            Command::Remove {
+                key, removed_at, ..
+            } => (key, None, None, removed_at),
+            Command::Batch { .. } => return Err(KvStoreError::UnexpectedCommandType),
+        };
+        Ok(LogRecord {
+            offset: log_pointer.pos,
+            len: log_pointer.len,
+            key,
+            value_len,
+            expires_at,
+            removed_at,
+            seq: log_pointer.seq,
+        })
+    }))
+}
+
+/// How many keydir entries opening a `KvStore` reads back from the logs
+/// before serving anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeydirCheck {
+    /// Trust the keydir
+    #[default]
+    Off,
+    /// Check this many entries, picked at random on each open
+    Sample(usize),
+    /// Check every entry
+    Full,
+}
+
+/// Read back the record each checked keydir entry points to, and fail with
+/// `InconsistentKeydir` listing every entry that isn't a readable set of its
+/// key with the same expiry. `index_position` is where the saved index the
+/// keydir was loaded from ends, if it was loaded from one.
+pub(super) fn verify_keydir(
+    keydir: &Keydir,
+    readers: &mut Readers,
+    check: KeydirCheck,
+    index_position: Option<LogPosition>,
+) -> Result<()> {
+    let entries: Vec<(&Vec<u8>, &LogPointer)> = match check {
+        KeydirCheck::Off => return Ok(()),
+        KeydirCheck::Sample(len) => keydir.iter().choose_multiple(&mut rand::thread_rng(), len),
+        KeydirCheck::Full => keydir.iter().collect(),
+    };
+    let _span = tracing::info_span!("verify_keydir", entries = entries.len()).entered();
+
+    let mut problems = Vec::new();
+    for (key, log_pointer) in &entries {
+        if let Some(problem) = check_entry(key, log_pointer, readers) {
+            problems.push(format!(
+                "key {} -> log {} bytes {}..{}: {}",
+                display_key(key),
+                log_pointer.log_gen,
+                log_pointer.pos,
+                log_pointer.pos + log_pointer.len,
+                problem
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let mut report = format!(
+        "{} of {} entries checked don't match their records; the keydir was {}",
+        problems.len(),
+        entries.len(),
+        match index_position {
+            Some(position) => format!(
+                "loaded from the saved index, which ends at log {} byte {}",
+                position.log_gen, position.offset
+            ),
+            None => "rebuilt by replaying every log".to_owned(),
+        }
+    );
+    for problem in problems.iter().take(MAX_LISTED_PROBLEMS) {
+        report.push_str("\n  ");
+        report.push_str(problem);
+    }
+    if problems.len() > MAX_LISTED_PROBLEMS {
+        report.push_str(&format!(
+            "\n  ... and {} more",
+            problems.len() - MAX_LISTED_PROBLEMS
+        ));
+    }
+    Err(KvStoreError::InconsistentKeydir(report))
+}
+
+// What's wrong with one keydir entry, if anything
+fn check_entry(key: &[u8], log_pointer: &LogPointer, readers: &mut Readers) -> Option<String> {
+    if !readers.contains(log_pointer.log_gen) {
+        return Some("the log doesn't exist".to_owned());
+    }
+    let reader = match readers.get_mut(log_pointer.log_gen) {
+        Ok(reader) => reader,
+        Err(err) => return Some(format!("the log can't be read: {}", err)),
+    };
+    match reader.file_len() {
+        Ok(file_len) if log_pointer.pos + log_pointer.len > file_len => {
+            return Some(format!("the log ends at byte {}", file_len));
+        }
+        Ok(_) => {}
+        Err(err) => return Some(format!("the log can't be read: {}", err)),
+    }
+
+    match reader.read_record(log_pointer) {
+        Ok(Command::Set {
+            key: record_key,
+            expires_at,
+            ..
+        }) => {
+            if record_key != key {
+                Some(format!("the record sets key {}", display_key(&record_key)))
+            } else if expires_at != log_pointer.expires_at {
+                Some(format!(
+                    "the record expires at {:?}, the keydir says {:?}",
+                    expires_at, log_pointer.expires_at
+                ))
+            } else {
+                None
+            }
+        }
+        Ok(Command::Remove {
+            key: record_key, ..
+        }) => Some(format!(
+            "the record removes key {}",
+            display_key(&record_key)
+        )),
+        Ok(Command::Batch { .. }) => Some("the record is a batch header".to_owned()),
+        Err(err) => Some(format!("the bytes aren't one record: {}", err)),
+    }
+}
+
+fn display_key(key: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(key))
+}
+
+fn check_log(
+    keydir: &mut Keydir,
+    path: PathBuf,
+    log_gen: u64,
+    keys: Option<&Keyring>,
+) -> Result<LogCheck> {
+    let file = File::open(&path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut records = 0;
+    let mut valid_len = 0;
+    let mut error = None;
+    for record in LogIterator::from_reader(log_gen, BufReader::new(file)).decrypting(keys) {
+        match record {
+            Ok((cmd, log_pointer)) => {
+                records += 1;
+                valid_len = log_pointer.pos + log_pointer.len;
+                apply_record(keydir, cmd, log_pointer);
+            }
+            Err(err) => {
+                error = Some(err.to_string());
+                break;
+            }
+        }
+    }
+    // The deserializer skips whitespace after the last record, which doesn't
+    // count as unreadable
+    if error.is_none() {
+        valid_len = file_len;
+    }
+
+    Ok(LogCheck {
+        log_gen,
+        path,
+        file_len,
+        records,
+        valid_len,
+        live_len: 0,
+        error,
+    })
+}
+
+fn check_index(path: &Path, logs: &[LogCheck], keys: Option<&Keyring>) -> Result<IndexState> {
+    if !path.join(INDEX_FILE).exists() {
+        return Ok(IndexState::Missing);
+    }
+    let Some(index) = index::load(path, keys)? else {
+        return Ok(IndexState::Unusable);
+    };
+
+    let valid_lens: BTreeMap<u64, u64> = logs
+        .iter()
+        .map(|log| (log.log_gen, log.valid_len))
+        .collect();
+    if !index
+        .log_gens
+        .iter()
+        .all(|log_gen| valid_lens.contains_key(log_gen))
+    {
+        return Ok(IndexState::Unusable);
+    }
+
+    let within = |log_gen: u64, end: u64| valid_lens.get(&log_gen).is_some_and(|&len| end <= len);
+    let pointers_within = index
+        .keydir
+        .values()
+        .chain(index.removed.values().map(|(log_pointer, _)| log_pointer))
+        .all(|pointer| within(pointer.log_gen, pointer.pos + pointer.len));
+    if !within(index.position.log_gen, index.position.offset) || !pointers_within {
+        return Ok(IndexState::Invalid);
+    }
+
+    Ok(IndexState::Valid)
+}
+
+fn tmp_files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut tmp_files: Vec<PathBuf> = fs::read_dir(dir)?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("tmp".as_ref()))
+        .collect();
+    tmp_files.sort();
+    Ok(tmp_files)
+}
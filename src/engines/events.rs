@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+/// Something a `KvStore` did with its logs, delivered to subscribers as it
+/// happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// Compaction began, with the stale bytes that triggered it
+    CompactionStarted {
+        stale_bytes: u64,
+    },
+    CompactionFinished(CompactionStats),
+    /// A new log file was created
+    SegmentCreated {
+        log_gen: u64,
+    },
+    /// A log file was deleted after compaction
+    SegmentDeleted {
+        log_gen: u64,
+    },
+}
+
+/// What one compaction did.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// When the compaction finished, in Unix ms
+    pub finished_at_ms: u64,
+    /// Live keys copied into the compacted logs
+    pub keys: usize,
+    /// Bytes of the logs compaction replaced, the active log included
+    pub input_bytes: u64,
+    /// Bytes written to the compacted logs
+    pub bytes_written: u64,
+    /// Stale bytes in the logs compaction replaced
+    pub stale_bytes: u64,
+    pub duration: Duration,
+}
+
+impl CompactionStats {
+    /// Bytes written for each byte of disk the compaction freed. Above 1 it
+    /// copied more than it reclaimed, a sign the stale thresholds start
+    /// compactions too early; infinite if it freed nothing but wrote
+    /// something.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_written == 0 {
+            return 0.0;
+        }
+        let freed = self.input_bytes.saturating_sub(self.bytes_written);
+        self.bytes_written as f64 / freed as f64
+    }
+}
+
+/// The subscribers of a store's events.
+#[derive(Debug, Default)]
+pub(super) struct Subscribers {
+    senders: Vec<Sender<StoreEvent>>,
+}
+
+impl Subscribers {
+    pub(super) fn subscribe(&mut self) -> Receiver<StoreEvent> {
+        let (sender, receiver) = channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    /// Send `event` to every subscriber, forgetting those that hung up.
+    pub(super) fn emit(&mut self, event: StoreEvent) {
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
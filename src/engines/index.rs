@@ -0,0 +1,178 @@
+use super::kvs::{History, Keydir, Removed};
+use crate::encryption::{self, Keyring, Location, Stored};
+use crate::logs::LogPointer;
+use crate::{LogPosition, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::warn;
+
+pub(super) const INDEX_FILE: &str = "keydir.index";
+
+/// One line of the index file. The header comes first.
+#[derive(Serialize, Deserialize)]
+enum IndexRecord {
+    Header {
+        /// The index reflects every record before this position
+        position: LogPosition,
+        /// Log generations the keydir points into
+        log_gens: Vec<u64>,
+        stale_logs_size: u64,
+    },
+    Key {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        log_pointer: LogPointer,
+    },
+    Removed {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        log_pointer: LogPointer,
+        removed_at: u64,
+    },
+    /// An old version of a key, listed oldest first
+    Version {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        log_pointer: LogPointer,
+        replaced_at: u64,
+    },
+}
+
+/// A keydir saved at a position in the logs.
+pub(super) struct KeydirIndex {
+    pub(super) position: LogPosition,
+    pub(super) log_gens: Vec<u64>,
+    pub(super) stale_logs_size: u64,
+    pub(super) keydir: Keydir,
+    pub(super) removed: Removed,
+    pub(super) history: History,
+}
+
+/// The maps of a store an index is saved from.
+pub(super) struct Indexed<'a> {
+    pub(super) keydir: &'a Keydir,
+    pub(super) removed: &'a Removed,
+    pub(super) history: &'a History,
+}
+
+/// Write an index of the `indexed` maps as of `position` to `dir`,
+/// replacing any previous one only once the new one is complete. Records are
+/// sealed with `keys` if given, like the logs'.
+pub(super) fn save(
+    dir: &Path,
+    keys: Option<&Keyring>,
+    position: LogPosition,
+    log_gens: Vec<u64>,
+    stale_logs_size: u64,
+    indexed: Indexed,
+) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", INDEX_FILE));
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    let mut written = 0;
+    let mut write = |record: &IndexRecord| -> Result<()> {
+        let location = Location::Index { record: written };
+        written += 1;
+        file.write_all(&encryption::seal_json(keys, record, location)?)?;
+        Ok(())
+    };
+
+    write(&IndexRecord::Header {
+        position,
+        log_gens,
+        stale_logs_size,
+    })?;
+    for (key, &log_pointer) in indexed.keydir {
+        write(&IndexRecord::Key {
+            key: key.clone(),
+            log_pointer,
+        })?;
+    }
+    for (key, &(log_pointer, removed_at)) in indexed.removed {
+        write(&IndexRecord::Removed {
+            key: key.clone(),
+            log_pointer,
+            removed_at,
+        })?;
+    }
+    for (key, versions) in indexed.history {
+        for &(log_pointer, replaced_at) in versions {
+            write(&IndexRecord::Version {
+                key: key.clone(),
+                log_pointer,
+                replaced_at,
+            })?;
+        }
+    }
+
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(tmp_path, dir.join(INDEX_FILE))?;
+
+    Ok(())
+}
+
+/// Read the index in `dir`, if there is a readable one.
+pub(super) fn load(dir: &Path, keys: Option<&Keyring>) -> Result<Option<KeydirIndex>> {
+    let file = match File::open(dir.join(INDEX_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = serde_json::Deserializer::from_reader(BufReader::new(file))
+        .into_iter::<Stored<IndexRecord>>()
+        .zip(0..)
+        .map(|(record, n)| record?.open(keys, Location::Index { record: n }));
+
+    let mut index = match records.next() {
+        Some(Ok(IndexRecord::Header {
+            position,
+            log_gens,
+            stale_logs_size,
+        })) => KeydirIndex {
+            position,
+            log_gens,
+            stale_logs_size,
+            keydir: Keydir::new(),
+            removed: Removed::new(),
+            history: History::new(),
+        },
+        _ => {
+            warn!("Ignoring keydir index without a header");
+            return Ok(None);
+        }
+    };
+
+    for record in records {
+        match record {
+            Ok(IndexRecord::Key { key, log_pointer }) => {
+                index.keydir.insert(key, log_pointer);
+            }
+            Ok(IndexRecord::Removed {
+                key,
+                log_pointer,
+                removed_at,
+            }) => {
+                index.removed.insert(key, (log_pointer, removed_at));
+            }
+            Ok(IndexRecord::Version {
+                key,
+                log_pointer,
+                replaced_at,
+            }) => {
+                index
+                    .history
+                    .entry(key)
+                    .or_default()
+                    .push((log_pointer, replaced_at));
+            }
+            Ok(IndexRecord::Header { .. }) | Err(_) => {
+                warn!("Ignoring unreadable keydir index");
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(index))
+}
@@ -1,149 +1,2235 @@
-pub use crate::engines::KvsEngine;
-use crate::logs::{log_path, Command, LogPointer, LogReader, LogWriter};
+use super::bloom::{self, Bloom};
+use super::cache::ReadCache;
+use super::check::{self, KeydirCheck};
+use super::events::{CompactionStats, StoreEvent, Subscribers};
+use super::index;
+use super::manifest;
+use super::readers::Readers;
+use super::remote::{Misses, RemoteTier};
+use super::snapshot::KvStoreSnapshot;
+use crate::encryption::Keyring;
+use crate::engines::{
+    btree_entry_bytes, validate_key, value_range, value_range_from, BatchOp, EngineMetrics,
+    MemoryUsage, Metrics, ReadPathStats, WriteBatch,
+};
+pub use crate::engines::{KvsEngine, KvsReader, KvsWriter, LogPosition};
+use crate::glob::Glob;
+use crate::logs::{
+    expiry_after, log_path, unix_millis, Command, LogEncoding, LogPointer, LogReader, LogWriter,
+    RecordRead,
+};
 pub use crate::{KvStoreError, Result};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{error, info_span, warn};
 
-// Stale byte count size to trigger compaction
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// Bytes compaction writes between checks against its I/O budget
+const COMPACTION_CHUNK_LEN: u64 = 64 * 1024;
+// Records at least this long are copied into `get_json` results without
+// decoding them, and skip the read cache
+const RAW_READ_MIN_LEN: u64 = 64 * 1024;
+// How long a stalled store refuses writes before trying to compact again
+const STALL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tunables for a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// Compact once stale bytes make up at least this fraction of the
+    /// logs, so the work compaction does stays in proportion to what it
+    /// reclaims however large the store grows.
+    pub compaction_stale_ratio: f64,
+    /// Stale bytes there must be more of before the ratio triggers a
+    /// compaction, so small stores don't compact every few writes.
+    pub compaction_min_stale: u64,
+    /// Logs, counting the active one, the store must have before the ratio
+    /// triggers a compaction.
+    pub compaction_min_logs: usize,
+    /// Also compact once there are more stale bytes than this, whatever
+    /// their share of the logs. `None` leaves it to the ratio.
+    pub compaction_threshold: Option<u64>,
+    /// Cap on the bytes per second compaction writes. Compaction sleeps
+    /// between chunks to stay under it, leaving disk bandwidth for other
+    /// work. `None` compacts as fast as the disk allows.
+    pub compaction_bytes_per_sec: Option<u64>,
+    /// Times of day the thresholds above may start a compaction. A
+    /// compaction they call for at other times waits for the next window, or
+    /// for a quiet spell if `compaction_max_ops_per_sec` is also set; it is
+    /// retried when `reap_expired` runs, as the server does between requests
+    /// and while idle. With no windows and no `compaction_max_ops_per_sec`,
+    /// any time will do. `compact` and stalls ignore both.
+    pub compaction_windows: Vec<CompactionWindow>,
+    /// Let the thresholds start a compaction outside `compaction_windows`
+    /// while the store serves no more than this many reads and writes a
+    /// second, measured over the last ten seconds. `None` only goes by the
+    /// windows.
+    pub compaction_max_ops_per_sec: Option<u64>,
+    /// Compact once the oldest log was started this long ago if the logs
+    /// hold any stale records, expired keys or soft-deleted values, however
+    /// few. Overwritten, removed and expired data is then gone from disk
+    /// within this long of going, plus any wait for `compaction_windows`, as
+    /// data retention policies may require. Checked when `reap_expired` runs
+    /// as well as on writes. `None` leaves it to the other thresholds.
+    pub compact_after: Option<Duration>,
+    /// How many of the latest compactions `compaction_history` keeps the
+    /// stats of.
+    pub compaction_history_len: usize,
+    /// Secondary directory, typically on slower and cheaper disk, that logs
+    /// are moved to once they go untouched for `cold_after`. Moved logs stay
+    /// readable, and compaction keeps their live data in this directory,
+    /// except for a key whose versions are in both directories, which it
+    /// moves back to the hot one whole. Standbys only follow the primary's own directory.
+    pub cold_dir: Option<PathBuf>,
+    /// How long a log has to go unmodified before it moves to `cold_dir`.
+    pub cold_after: Duration,
+    /// Bytes of keys and values kept in memory after they are read, so
+    /// repeated gets of hot keys skip the disk. Zero disables the cache.
+    pub read_cache_bytes: usize,
+    /// Values up to this many bytes are kept in memory beside their keydir
+    /// entry, outside the read cache's budget, from when they are written or
+    /// first read after opening. Gets of them then never touch the disk;
+    /// they are still written to the log as usual. Zero keeps none.
+    pub inline_value_len: usize,
+    /// Logs kept open for reading at once, besides the active log's writer.
+    /// Reading any other log opens it, closing the one read longest ago, so
+    /// a store with many logs doesn't run out of file descriptors. At least
+    /// one is kept open.
+    pub max_open_logs: usize,
+    /// Turns on soft deletes: a removed key's value can be brought back with
+    /// `restore` for this long, or until the next compaction discards it.
+    pub soft_delete_retention: Option<Duration>,
+    /// Save the keydir to an index file once this many bytes have been
+    /// written to the logs since the last save, and after each compaction.
+    /// Opening then replays only the logs written after the save. `None`
+    /// saves only when `save_index` is called.
+    pub index_interval: Option<u64>,
+    /// Rotate the active log on the first write once it has been open this
+    /// long, so no log keeps growing while compaction has nothing to do.
+    /// `None` only rotates when `rotate_log` is called or on compaction.
+    pub rotate_every: Option<Duration>,
+    /// Layout of the log records of a new store, recorded in its manifest.
+    /// `None` uses the manifest's for an existing store and `Compact` for a
+    /// new one; opening an existing store with a different one fails.
+    pub log_encoding: Option<LogEncoding>,
+    /// Refuse writes with `WriteStalled` while the logs hold more stale bytes
+    /// than this and compacting can't bring them back under it, e.g. because
+    /// the disk is full, rather than letting the logs grow without bound.
+    /// Going over first compacts right away, whatever the other thresholds
+    /// say. `None` never stalls.
+    pub stall_stale_bytes: Option<u64>,
+    /// Read back keydir entries from the logs once the store is indexed, and
+    /// fail to open with a report of any that don't match, to catch index
+    /// and compaction bugs before the store serves wrong values.
+    pub verify_keydir: KeydirCheck,
+    /// Encrypt log and index records with the keyring's active key. Once a
+    /// store has been opened with keys it fails to open without them.
+    /// Records written before encryption was turned on, or sealed with an
+    /// older key, are rewritten with the active key as compaction copies
+    /// them. Needs the `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Keyring>,
+    /// Cap on the expired keys per second `reap_expired` removes, writing a
+    /// tombstone for each, so expired keys don't take up the keydir and logs
+    /// until they are next read or compacted. `None` leaves them to reads and
+    /// compaction.
+    pub reap_keys_per_sec: Option<u64>,
+    /// Keep values a write replaced or removed, readable with `get_version`,
+    /// for as long as the retention allows. Compaction copies the versions
+    /// kept into the new logs. `None` keeps only the current values.
+    pub keep_versions: Option<VersionRetention>,
+    /// Another system the store caches: keys it doesn't have are looked up
+    /// there, and writes are passed on to it. `None` stands alone.
+    pub remote: Option<RemoteTier>,
+    /// Rewrite or drop each live value as compaction copies it. `None`
+    /// copies values as they are.
+    pub compaction_transform: Option<CompactionTransform>,
+}
+
+/// A daily span of time, in UTC, in which a store may compact on its own.
+/// One that ends before it starts runs past midnight; one that ends when it
+/// starts lasts all day. Parses from `HH:MM-HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    // Minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+impl CompactionWindow {
+    /// The window from `start` to `end`, each as hours and minutes after
+    /// midnight UTC.
+    pub fn new(start: (u32, u32), end: (u32, u32)) -> Result<CompactionWindow> {
+        let minutes = |(hours, minutes): (u32, u32)| match hours < 24 && minutes < 60 {
+            true => Ok(hours * 60 + minutes),
+            false => Err(KvStoreError::StringError(format!(
+                "{:02}:{:02} isn't a time of day",
+                hours, minutes
+            ))),
+        };
+        Ok(CompactionWindow {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+
+    /// Whether the window is open at `unix_millis`.
+    pub fn contains(&self, unix_millis: u64) -> bool {
+        let minute = (unix_millis / 60_000 % MINUTES_PER_DAY) as u32;
+        match self.start.cmp(&self.end) {
+            Ordering::Less => self.start <= minute && minute < self.end,
+            Ordering::Greater => self.start <= minute || minute < self.end,
+            Ordering::Equal => true,
+        }
+    }
+}
+
+impl FromStr for CompactionWindow {
+    type Err = KvStoreError;
+
+    fn from_str(window: &str) -> Result<CompactionWindow> {
+        let invalid = || {
+            KvStoreError::StringError(format!(
+                "Invalid compaction window {:?}; expected HH:MM-HH:MM",
+                window
+            ))
+        };
+        let time = |time: &str| -> Result<(u32, u32)> {
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            Ok((
+                hours.parse().map_err(|_| invalid())?,
+                minutes.parse().map_err(|_| invalid())?,
+            ))
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        CompactionWindow::new(time(start)?, time(end)?)
+    }
+}
+
+type Transform = dyn Fn(&[u8], String) -> Option<String> + Send + Sync;
+
+/// A function compaction passes each live key and value through, e.g. to
+/// migrate values to a new schema lazily, a log at a time, rather than in a
+/// separate pass over the whole store.
+#[derive(Clone)]
+pub struct CompactionTransform(Arc<Transform>);
+
+impl CompactionTransform {
+    /// Pass each live value through `transform`, which returns the value to
+    /// keep, changed or not, or `None` to drop the key. Old versions kept by
+    /// `keep_versions` are copied as they are. The changes are compaction's,
+    /// not writes: they take no sequence number and don't reach a remote
+    /// tier, and a value only changes once a compaction copies it.
+    pub fn new(
+        transform: impl Fn(&[u8], String) -> Option<String> + Send + Sync + 'static,
+    ) -> CompactionTransform {
+        CompactionTransform(Arc::new(transform))
+    }
+}
+
+impl fmt::Debug for CompactionTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CompactionTransform")
+    }
+}
+
+/// How many old values of each key a store keeps, and for how long. An old
+/// value goes once either limit says so.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VersionRetention {
+    /// Old values kept per key, newest first
+    pub max_versions: Option<usize>,
+    /// How long a value is kept after it was replaced or removed
+    pub max_age: Option<Duration>,
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> KvStoreConfig {
+        KvStoreConfig {
+            compaction_stale_ratio: 0.5,
+            compaction_min_stale: 1024 * 1024,
+            compaction_min_logs: 1,
+            compaction_threshold: None,
+            compaction_bytes_per_sec: None,
+            compaction_windows: Vec::new(),
+            compaction_max_ops_per_sec: None,
+            compact_after: None,
+            compaction_history_len: 16,
+            cold_dir: None,
+            cold_after: Duration::from_secs(30 * 24 * 60 * 60),
+            read_cache_bytes: 8 * 1024 * 1024,
+            inline_value_len: 0,
+            max_open_logs: 256,
+            soft_delete_retention: None,
+            index_interval: None,
+            rotate_every: None,
+            log_encoding: None,
+            stall_stale_bytes: None,
+            verify_keydir: KeydirCheck::Off,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            reap_keys_per_sec: None,
+            keep_versions: None,
+            remote: None,
+            compaction_transform: None,
+        }
+    }
+}
+
+impl KvStoreConfig {
+    /// The keys records are sealed with, if the store is encrypted.
+    pub(crate) fn keys(&self) -> Option<&Keyring> {
+        #[cfg(feature = "encryption")]
+        return self.encryption.as_ref();
+        #[cfg(not(feature = "encryption"))]
+        return None;
+    }
+
+    // Read and seal with `keys` from now on, as `manifest::check_keys`
+    // returns them for the store
+    fn set_keys(&mut self, keys: Option<Keyring>) {
+        #[cfg(feature = "encryption")]
+        {
+            self.encryption = keys;
+        }
+        #[cfg(not(feature = "encryption"))]
+        let _ = keys;
+    }
+}
 
 #[derive(Debug)]
 /** A simple key-value store */
 pub struct KvStore {
     path: PathBuf,
-    keydir: Keydir,
-    readers: HashMap<u64, LogReader>,
+    // Shared with snapshot views; cloned on the first write while one is alive
+    keydir: Arc<Keydir>,
+    // The keydir's keys that expire, soonest first
+    expiries: Expiries,
+    readers: Readers,
+    // Readers of the live snapshot views
+    snapshot_readers: Vec<Weak<Mutex<Readers>>>,
+    // Log generations that live in the cold directory
+    cold_log_gens: BTreeSet<u64>,
+    // Soft-deleted keys whose old records compaction hasn't discarded yet
+    removed: Removed,
+    history: History,
+    // Bloom filters of the logs that have one
+    blooms: HashMap<u64, Bloom>,
     writer: LogWriter,
     log_gen: u64,
+    log_encoding: LogEncoding,
+    // When the active log was started
+    log_started: Instant,
+    // When each log was started, in Unix millis, as kept in the manifest
+    log_started_at: BTreeMap<u64, u64>,
+    // Bytes in every log but the active one
+    sealed_logs_size: u64,
     stale_logs_size: u64,
+    cache: ReadCache,
+    subscribers: Subscribers,
+    // Position the last saved keydir index covers
+    indexed_at: LogPosition,
+    // Sequence number of the latest mutation
+    sequence: u64,
+    // Until when writes are refused without retrying compaction
+    stalled_until: Option<Instant>,
+    reaper: Reaper,
+    load: LoadMeter,
+    // Whether the thresholds called for a compaction outside the windows
+    compaction_deferred: bool,
+    // Stats of the latest compactions, oldest first
+    compactions: VecDeque<CompactionStats>,
+    // Keys the remote tier didn't have when last asked
+    remote_misses: Misses,
+    metrics: Metrics,
+    config: KvStoreConfig,
+}
+
+/// Paces a stream of writes to a byte rate.
+struct Throttle {
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    written: u64,
+    unchecked: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: Option<u64>) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            started: Instant::now(),
+            written: 0,
+            unchecked: 0,
+        }
+    }
+
+    /// Account for `len` more bytes, sleeping once a chunk's worth has been
+    /// written faster than the rate allows.
+    fn consume(&mut self, len: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+
+        self.written += len;
+        self.unchecked += len;
+        if self.unchecked < COMPACTION_CHUNK_LEN {
+            return;
+        }
+        self.unchecked = 0;
+
+        let due = Duration::from_secs_f64(self.written as f64 / bytes_per_sec.max(1) as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Paces the removal of expired keys to a rate.
+#[derive(Debug)]
+struct Reaper {
+    keys_per_sec: Option<u64>,
+    since: Instant,
+}
+
+impl Reaper {
+    fn new(keys_per_sec: Option<u64>) -> Reaper {
+        Reaper {
+            keys_per_sec,
+            since: Instant::now(),
+        }
+    }
+
+    /// How many keys may be removed now: those due since the last removal,
+    /// up to a second's worth.
+    fn take(&mut self) -> usize {
+        let Some(keys_per_sec) = self.keys_per_sec else {
+            return 0;
+        };
+
+        let keys_per_sec = keys_per_sec.max(1) as f64;
+        let due = (self.since.elapsed().as_secs_f64() * keys_per_sec).min(keys_per_sec) as usize;
+        if due > 0 {
+            self.since = Instant::now();
+        }
+        due
+    }
+}
+
+// How long the load `compaction_max_ops_per_sec` is compared with is
+// measured over
+const LOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Measures how many reads and writes a second the store serves.
+#[derive(Debug)]
+struct LoadMeter {
+    since: Instant,
+    ops_at: u64,
+    // Rate over the last whole interval
+    last: Option<f64>,
+}
+
+impl LoadMeter {
+    fn new() -> LoadMeter {
+        LoadMeter {
+            since: Instant::now(),
+            ops_at: 0,
+            last: None,
+        }
+    }
+
+    /// Operations per second, given `ops` done since the store opened: over
+    /// the last whole interval, or the time so far before the first ends.
+    fn ops_per_sec(&mut self, ops: u64) -> f64 {
+        let elapsed = self.since.elapsed();
+        let current = (ops - self.ops_at) as f64 / elapsed.as_secs_f64().max(1.0);
+        if elapsed >= LOAD_INTERVAL {
+            self.since = Instant::now();
+            self.ops_at = ops;
+            self.last = Some(current);
+        }
+        self.last.unwrap_or(current)
+    }
+}
+
+pub(super) type Keydir = BTreeMap<Vec<u8>, LogPointer>;
+
+/// The keys that expire, by when they do.
+type Expiries = BTreeSet<(u64, Vec<u8>)>;
+
+fn expiries(keydir: &Keydir) -> Expiries {
+    keydir
+        .iter()
+        .filter_map(|(key, log_pointer)| Some((log_pointer.expires_at?, key.clone())))
+        .collect()
+}
+
+/// The last value of each soft-deleted key, with when it was removed.
+pub(super) type Removed = BTreeMap<Vec<u8>, (LogPointer, u64)>;
+
+/// The old versions kept of each key, oldest first, with when each was
+/// replaced or removed.
+pub(super) type History = BTreeMap<Vec<u8>, Vec<(LogPointer, u64)>>;
+
+// Whether reading a keydir entry failed because it doesn't match the log,
+// rather than because the disk did
+fn is_mismatch(err: &KvStoreError) -> bool {
+    match err {
+        KvStoreError::MissingLogReader(_)
+        | KvStoreError::SerdeErr(_)
+        | KvStoreError::UnexpectedCommandType
+        | KvStoreError::RecordTooLarge => true,
+        KvStoreError::IoErr(err) => err.kind() == std::io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// Drop the oldest of a key's `versions` until they are within `retention`,
+/// returning the bytes of the records dropped.
+fn prune_versions(
+    versions: &mut Vec<(LogPointer, u64)>,
+    retention: &VersionRetention,
+    now: u64,
+) -> u64 {
+    let too_many = versions
+        .len()
+        .saturating_sub(retention.max_versions.unwrap_or(usize::MAX));
+    let too_old = match retention.max_age {
+        Some(max_age) => versions
+            .iter()
+            .take_while(|(_, replaced_at)| {
+                replaced_at.saturating_add(max_age.as_millis() as u64) <= now
+            })
+            .count(),
+        None => 0,
+    };
+
+    versions
+        .drain(..too_many.max(too_old))
+        .map(|(log_pointer, _)| log_pointer.len)
+        .sum()
+}
+
+pub(crate) fn sorted_log_gens(path: &PathBuf) -> Result<Vec<u64>> {
+    let mut log_entries: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+
+    log_entries.sort_unstable();
+    Ok(log_entries)
+}
+
+/// Apply one record read from a log to the keydir, returning how many bytes of
+/// older records it made stale.
+pub(super) fn apply_record(keydir: &mut Keydir, cmd: Command, log_pointer: LogPointer) -> u64 {
+    let replaced = match cmd {
+        Command::Set { key, .. } => keydir.insert(key, log_pointer),
+        Command::Remove { key, .. } => keydir.remove(&key),
+        // The iterators hand out a batch's records, not its header
+        Command::Batch { .. } => None,
+    };
+
+    replaced.map_or(0, |existing_value| existing_value.len)
+}
+
+/// Logs read from the hot and cold directories, with the tier each came from.
+struct IndexedLogs {
+    readers: Readers,
+    removed: Removed,
+    history: History,
+    blooms: HashMap<u64, Bloom>,
+    cold_log_gens: BTreeSet<u64>,
+    last_log_gen: u64,
+    stale_logs_size: u64,
+    // Where the saved index the keydir started from ends, if it did
+    index_position: Option<LogPosition>,
+    // Highest sequence number seen in the index and the records read
+    sequence: u64,
+}
+
+fn index_logs(
+    keydir: &mut Keydir,
+    path: &PathBuf,
+    cold_dir: Option<&PathBuf>,
+    keys: Option<&Keyring>,
+    keep_versions: bool,
+    max_open_logs: usize,
+) -> Result<IndexedLogs> {
+    let _span = info_span!("index_logs", path = %path.display()).entered();
+    let mut readers = Readers::new(max_open_logs, keys);
+    let mut blooms = HashMap::new();
+
+    let cold_log_gens: BTreeSet<u64> = match cold_dir {
+        Some(cold_dir) => sorted_log_gens(cold_dir)?.into_iter().collect(),
+        None => BTreeSet::new(),
+    };
+    let mut log_gens = sorted_log_gens(path)?;
+    log_gens.extend(&cold_log_gens);
+    log_gens.sort_unstable();
+    log_gens.dedup();
+
+    // Start from the saved keydir, unless compaction has since deleted logs
+    // it points into
+    let index = index::load(path, keys)?.filter(|index| {
+        index
+            .log_gens
+            .iter()
+            .all(|log_gen| log_gens.binary_search(log_gen).is_ok())
+    });
+    let (mut stale_logs_size, mut removed, mut history, index_position) = match index {
+        Some(index) => {
+            *keydir = index.keydir;
+            (
+                index.stale_logs_size,
+                index.removed,
+                index.history,
+                Some(index.position),
+            )
+        }
+        None => (0, Removed::new(), History::new(), None),
+    };
+    // Versions kept by an earlier run are stale once versions aren't kept
+    if !keep_versions {
+        for (_, versions) in std::mem::take(&mut history) {
+            stale_logs_size += versions
+                .iter()
+                .map(|(log_pointer, _)| log_pointer.len)
+                .sum::<u64>();
+        }
+    }
+    let now = unix_millis();
+    let mut sequence = index_position
+        .and_then(|position| position.seq)
+        .unwrap_or(0);
+
+    for &log_gen in &log_gens {
+        let dir = match cold_dir {
+            Some(cold_dir) if cold_log_gens.contains(&log_gen) => {
+                // A move to the cold tier only removes the hot copy once the
+                // cold one is complete, so a leftover hot copy is a duplicate
+                let hot_path = log_path(path, log_gen);
+                if hot_path.exists() {
+                    fs::remove_file(hot_path)?;
+                    bloom::remove(path, log_gen)?;
+                }
+                cold_dir
+            }
+            _ => path,
+        };
+        let mut reader = LogReader::new(dir, log_gen, keys)?;
+        if let Some(bloom) = bloom::load(dir, log_gen, keys) {
+            blooms.insert(log_gen, bloom);
+        }
+        let offset = match index_position {
+            Some(position) if log_gen < position.log_gen => None,
+            Some(position) if log_gen == position.log_gen => Some(position.offset),
+            _ => Some(0),
+        };
+
+        if let Some(offset) = offset {
+            for record in reader.iter_from(offset)? {
+                let (cmd, log_pointer) = match record {
+                    Ok(record) => record,
+                    // A wrong key would otherwise pass for a torn tail
+                    Err(err @ KvStoreError::Encryption(_)) => return Err(err),
+                    Err(_) => break,
+                };
+                sequence = sequence.max(log_pointer.seq.unwrap_or(0));
+                match &cmd {
+                    Command::Set { key, .. } => {
+                        removed.remove(key);
+                    }
+                    Command::Remove {
+                        key,
+                        removed_at: Some(removed_at),
+                        ..
+                    } => {
+                        if let Some(&previous) = keydir.get(key) {
+                            removed.insert(key.clone(), (previous, *removed_at));
+                        }
+                    }
+                    Command::Remove { .. } | Command::Batch { .. } => {}
+                }
+
+                let (key, replaced_at) = match &cmd {
+                    Command::Set { key, version, .. } => (key, version.unwrap_or(now)),
+                    Command::Remove { key, .. } => (key, now),
+                    Command::Batch { .. } => continue,
+                };
+                let kept = keydir
+                    .get(key)
+                    .filter(|previous| keep_versions && previous.version.is_some())
+                    .map(|&previous| (key.clone(), previous));
+                let stale = apply_record(keydir, cmd, log_pointer);
+                match kept {
+                    Some((key, previous)) => history
+                        .entry(key)
+                        .or_default()
+                        .push((previous, replaced_at)),
+                    None => stale_logs_size += stale,
+                }
+            }
+        }
+
+        readers.insert(log_gen, dir, reader);
+    }
+
+    let last_log_gen = *log_gens.last().unwrap_or(&0);
+
+    Ok(IndexedLogs {
+        readers,
+        removed,
+        history,
+        blooms,
+        cold_log_gens,
+        last_log_gen,
+        stale_logs_size,
+        index_position,
+        sequence,
+    })
+}
+
+/// A log written start to finish in one go, by compaction or a bulk load,
+/// with a bloom filter of its keys. It is written under a temporary name,
+/// which opening a store ignores, until it is installed.
+struct SegmentWriter {
+    dir: PathBuf,
+    log_gen: u64,
+    encoding: LogEncoding,
+    keys: Option<Keyring>,
+    file: BufWriter<File>,
+    pos: u64,
+    key_hashes: Vec<u64>,
+}
+
+impl SegmentWriter {
+    fn create(
+        dir: &Path,
+        log_gen: u64,
+        encoding: LogEncoding,
+        keys: Option<&Keyring>,
+    ) -> Result<SegmentWriter> {
+        Ok(SegmentWriter {
+            dir: dir.to_path_buf(),
+            log_gen,
+            encoding,
+            keys: keys.cloned(),
+            file: BufWriter::new(File::create(tmp_log_path(dir, log_gen))?),
+            pos: 0,
+            key_hashes: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, cmd: &Command) -> Result<LogPointer> {
+        let bytes = self
+            .encoding
+            .encode(cmd, self.keys.as_ref(), self.log_gen, self.pos)?;
+        self.file.write_all(&bytes)?;
+        if let Command::Set { key, .. } | Command::Remove { key, .. } = cmd {
+            self.key_hashes.push(bloom::hash(key));
+        }
+
+        let log_pointer = cmd.pointer(self.log_gen, self.pos, bytes.len() as u64);
+        self.pos += log_pointer.len;
+
+        Ok(log_pointer)
+    }
+
+    /// Flush the log and save the bloom filter of the keys written to it
+    /// next to it, returning the filter.
+    fn finish(&mut self) -> Result<Bloom> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        let bloom = Bloom::from_hashes(&self.key_hashes);
+        bloom.save(&self.dir, self.log_gen, self.keys.as_ref())?;
+        Ok(bloom)
+    }
+
+    /// Give the finished log its real name, making it part of the store.
+    fn install(&self) -> Result<()> {
+        fs::rename(
+            tmp_log_path(&self.dir, self.log_gen),
+            log_path(&self.dir, self.log_gen),
+        )?;
+        Ok(())
+    }
+
+    /// Delete the log, which was never installed.
+    fn discard(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(tmp_log_path(&self.dir, self.log_gen))?;
+        Ok(())
+    }
+}
+
+// Where a log is written before it is complete
+fn tmp_log_path(dir: &Path, log_gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", log_gen))
+}
+
+// Finish a compaction that was committed before a crash, deleting the logs
+// it replaced, and throw away the logs of any compaction or bulk load that
+// wasn't, along with half-copied cold logs
+fn recover_compaction(path: &Path, cold_dir: Option<&PathBuf>) -> Result<()> {
+    let mut dirs = vec![path];
+    dirs.extend(cold_dir.map(PathBuf::as_path));
+
+    if let Some(commit) = manifest::compaction(path)? {
+        warn!(
+            written = ?commit.written,
+            retired = ?commit.retired,
+            "finishing a compaction interrupted by a crash"
+        );
+        for &log_gen in &commit.written {
+            for dir in &dirs {
+                let tmp_path = tmp_log_path(dir, log_gen);
+                if tmp_path.exists() {
+                    fs::rename(tmp_path, log_path(dir, log_gen))?;
+                }
+            }
+        }
+        for &log_gen in &commit.retired {
+            for dir in &dirs {
+                let old_path = log_path(dir, log_gen);
+                if old_path.exists() {
+                    fs::remove_file(old_path)?;
+                }
+                bloom::remove(dir, log_gen)?;
+            }
+        }
+        manifest::record_compaction(path, None)?;
+    }
+
+    for dir in &dirs {
+        for entry in fs::read_dir(dir)? {
+            let tmp_path = entry?.path();
+            let log_gen = tmp_path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| name.strip_suffix(".log.tmp"))
+                .and_then(|log_gen| log_gen.parse::<u64>().ok());
+            let Some(log_gen) = log_gen else {
+                continue;
+            };
+            fs::remove_file(&tmp_path)?;
+            // Its bloom filter is saved before it is installed
+            if !log_path(dir, log_gen).exists() {
+                bloom::remove(dir, log_gen)?;
+            }
+        }
+    }
+    Ok(())
 }
 
-type Keydir = HashMap<String, LogPointer>;
+impl KvStore {
+    /// Build a store from an already indexed directory, starting a new active
+    /// log after `last_log_gen`.
+    pub(super) fn from_index(
+        path: PathBuf,
+        keydir: Keydir,
+        mut readers: Readers,
+        cold_log_gens: BTreeSet<u64>,
+        last_log_gen: u64,
+        stale_logs_size: u64,
+        config: KvStoreConfig,
+    ) -> Result<KvStore> {
+        let log_encoding = manifest::log_encoding(&path, config.log_encoding)?;
+        let current_log_gen = last_log_gen + 1;
+        let keys = config.keys();
+        let writer = LogWriter::new(&path, current_log_gen, log_encoding, keys)?;
+        let sealed_logs_size = readers.files_len()?;
+
+        let current_reader = LogReader::new(&path, current_log_gen, keys)?;
+        readers.insert(current_log_gen, &path, current_reader);
+        let log_started_at = manifest::log_started_at(&path)?;
+        let sequence = manifest::sequence(&path)?;
+
+        let mut store = KvStore {
+            path,
+            readers,
+            snapshot_readers: Vec::new(),
+            cold_log_gens,
+            removed: Removed::new(),
+            history: History::new(),
+            blooms: HashMap::new(),
+            writer,
+            expiries: expiries(&keydir),
+            keydir: Arc::new(keydir),
+            log_gen: current_log_gen,
+            log_encoding,
+            log_started: Instant::now(),
+            log_started_at,
+            sealed_logs_size,
+            stale_logs_size,
+            cache: ReadCache::new(config.read_cache_bytes, config.inline_value_len),
+            subscribers: Subscribers::default(),
+            indexed_at: LogPosition {
+                log_gen: current_log_gen,
+                offset: 0,
+                seq: None,
+            },
+            sequence,
+            stalled_until: None,
+            reaper: Reaper::new(config.reap_keys_per_sec),
+            load: LoadMeter::new(),
+            compaction_deferred: false,
+            compactions: VecDeque::new(),
+            remote_misses: Misses::default(),
+            metrics: Metrics {
+                cache_hits: Some(0),
+                cache_misses: Some(0),
+                read_path: Some(ReadPathStats::default()),
+                ..Metrics::default()
+            },
+            config,
+        };
+        store.record_log_starts()?;
+
+        Ok(store)
+    }
+
+    /// Open the store at `path` with non-default tunables.
+    pub fn open_with_config(path: PathBuf, mut config: KvStoreConfig) -> Result<KvStore> {
+        fs::create_dir_all(&path)?;
+        if let Some(cold_dir) = &config.cold_dir {
+            fs::create_dir_all(cold_dir)?;
+        }
+
+        // Check the store's keys before reading any of its records
+        manifest::log_encoding(&path, config.log_encoding)?;
+        manifest::check_keys(&path, config.keys())?;
+        recover_compaction(&path, config.cold_dir.as_ref())?;
+        // The next log is the first a store opened with keys for the first
+        // time seals
+        let mut last_log_gen = sorted_log_gens(&path)?.last().copied().unwrap_or(0);
+        if let Some(cold_dir) = &config.cold_dir {
+            let last_cold_log_gen = sorted_log_gens(cold_dir)?.last().copied().unwrap_or(0);
+            last_log_gen = last_log_gen.max(last_cold_log_gen);
+        }
+        let keys = manifest::record_keys(&path, last_log_gen + 1, config.keys())?;
+        config.set_keys(keys);
+
+        let mut keydir: Keydir = BTreeMap::new();
+        let mut logs = index_logs(
+            &mut keydir,
+            &path,
+            config.cold_dir.as_ref(),
+            config.keys(),
+            config.keep_versions.is_some(),
+            config.max_open_logs,
+        )?;
+        check::verify_keydir(
+            &keydir,
+            &mut logs.readers,
+            config.verify_keydir,
+            logs.index_position,
+        )?;
+
+        let mut store = KvStore::from_index(
+            path,
+            keydir,
+            logs.readers,
+            logs.cold_log_gens,
+            logs.last_log_gen,
+            logs.stale_logs_size,
+            config,
+        )?;
+        store.resume_sequence(logs.sequence);
+        store.removed = logs.removed;
+        store.history = logs.history;
+        store.blooms = logs.blooms;
+        store.prune_history();
+        store.move_cold_logs()?;
+
+        Ok(store)
+    }
+
+    /// Sequence number of the store's latest mutation, 0 if it has none.
+    /// Every set and remove, including those in a batch or a bulk load and
+    /// the removes of expired keys, takes the next number, and the numbers
+    /// carry on across reopens and compactions.
+    pub fn latest_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // Carry on numbering mutations after `sequence`, if it is past the
+    // latest one
+    pub(super) fn resume_sequence(&mut self, sequence: u64) {
+        self.sequence = self.sequence.max(sequence);
+    }
+
+    /// Receive the store's compaction and log file events from now on. The
+    /// store never blocks on a receiver; drop it to unsubscribe.
+    pub fn subscribe(&mut self) -> Receiver<StoreEvent> {
+        self.subscribers.subscribe()
+    }
+
+    // The directory a log generation is stored in
+    fn log_dir(&self, log_gen: u64) -> &Path {
+        match &self.config.cold_dir {
+            Some(cold_dir) if self.cold_log_gens.contains(&log_gen) => cold_dir,
+            _ => &self.path,
+        }
+    }
+
+    /// When each log generation was started, in milliseconds since the Unix
+    /// epoch. Compaction copies the live data into new logs, so no log is
+    /// older than the last compaction.
+    pub fn log_started_at(&self) -> &BTreeMap<u64, u64> {
+        &self.log_started_at
+    }
+
+    // Record a start time for the logs that don't have one yet, going by
+    // when their files were created, and forget the logs that are gone.
+    // Logs written before start times were recorded get theirs on open.
+    fn record_log_starts(&mut self) -> Result<()> {
+        let readers = &self.readers;
+        let recorded = self.log_started_at.len();
+        self.log_started_at
+            .retain(|&log_gen, _| readers.contains(log_gen));
+        let mut changed = self.log_started_at.len() != recorded;
+
+        for log_gen in self.readers.log_gens() {
+            if self.log_started_at.contains_key(&log_gen) {
+                continue;
+            }
+            let metadata = fs::metadata(log_path(self.log_dir(log_gen), log_gen))?;
+            let created = metadata.created().or_else(|_| metadata.modified())?;
+            let started_at = created
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+            self.log_started_at.insert(log_gen, started_at);
+            changed = true;
+        }
+
+        if changed {
+            manifest::record_log_started_at(&self.path, &self.log_started_at)?;
+        }
+        Ok(())
+    }
+
+    /// Move the logs that have gone unmodified for the configured
+    /// `cold_after` to the cold directory, returning how many were moved.
+    /// This runs on open and after each compaction; long-running callers can
+    /// also call it periodically. Does nothing without a cold directory.
+    pub fn move_cold_logs(&mut self) -> Result<usize> {
+        let Some(cold_dir) = self.config.cold_dir.clone() else {
+            return Ok(0);
+        };
+        let _span = info_span!("move_cold_logs", cold_dir = %cold_dir.display()).entered();
+
+        let hot_log_gens: Vec<u64> = self
+            .readers
+            .log_gens()
+            .filter(|&log_gen| log_gen != self.log_gen && !self.cold_log_gens.contains(&log_gen))
+            .collect();
+
+        let mut moved = 0;
+        for log_gen in hot_log_gens {
+            let hot_path = log_path(&self.path, log_gen);
+            let age = fs::metadata(&hot_path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age < self.config.cold_after {
+                continue;
+            }
+
+            if let Some(bloom) = self.blooms.get(&log_gen) {
+                bloom.save(&cold_dir, log_gen, self.config.keys())?;
+            }
+            // Copy under a temporary name so a crash never leaves a partial
+            // log that indexing would pick up
+            let tmp_path = tmp_log_path(&cold_dir, log_gen);
+            fs::copy(&hot_path, &tmp_path)?;
+            File::open(&tmp_path)?.sync_all()?;
+            fs::rename(&tmp_path, log_path(&cold_dir, log_gen))?;
+
+            self.readers.insert(
+                log_gen,
+                &cold_dir,
+                LogReader::new(&cold_dir, log_gen, self.config.keys())?,
+            );
+            self.cold_log_gens.insert(log_gen);
+            self.keep_open_for_snapshots(log_gen)?;
+            fs::remove_file(hot_path)?;
+            bloom::remove(&self.path, log_gen)?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+
+    // The key's log pointer like `live_pointer`, first fetching a key the
+    // store doesn't have from the remote tier, if there is one
+    fn live_or_fetched(&mut self, key: &[u8]) -> Result<Option<LogPointer>> {
+        if let Some(log_pointer) = self.live_pointer(key) {
+            return Ok(Some(log_pointer));
+        }
+        let Some(remote) = &self.config.remote else {
+            return Ok(None);
+        };
+        let Some(fetch) = remote.read_through.clone() else {
+            return Ok(None);
+        };
+        let negative_caching = remote.negative_caching;
+        if validate_key(key).is_err() || self.remote_misses.contains(key) {
+            return Ok(None);
+        }
+
+        match fetch(key)? {
+            Some(value) => {
+                self.write_set_local(key.to_vec(), value, None)?;
+                Ok(self.live_pointer(key))
+            }
+            None => {
+                if let Some(policy) = negative_caching {
+                    self.remote_misses.insert(key.to_vec(), policy);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // Pass a write of `key` on to the remote tier, if there is one
+    fn write_through(
+        &mut self,
+        key: &[u8],
+        value: Option<&str>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.remote_misses.remove(key);
+        match self
+            .config
+            .remote
+            .as_ref()
+            .and_then(|remote| remote.write_through.as_ref())
+        {
+            Some(store) => store(key, value, expires_at),
+            None => Ok(()),
+        }
+    }
+
+    // The key's log pointer, unless it is missing or has expired
+    fn live_pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        let now = unix_millis();
+        self.keydir
+            .get(key)
+            .filter(|log_pointer| !log_pointer.is_expired(now))
+            .copied()
+    }
+
+    fn write_set(&mut self, key: Vec<u8>, value: String, expires_at: Option<u64>) -> Result<()> {
+        validate_key(&key)?;
+        self.check_stall()?;
+        self.write_through(&key, Some(&value), expires_at)?;
+        self.write_set_local(key, value, expires_at)
+    }
+
+    // Write a set the remote tier already has, or needn't have
+    fn write_set_local(
+        &mut self,
+        key: Vec<u8>,
+        value: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.metrics.key_lens.record(key.len());
+        self.metrics.value_lens.record(value.len());
+        let version = self.config.keep_versions.map(|_| self.next_version(&key));
+        let inline_value = self.cache.is_inline(&value).then(|| value.clone());
+        let seq = self.sequence + 1;
+        let log_pointer =
+            self.writer
+                .write_set_cmd(key.clone(), value, expires_at, version, seq)?;
+        self.sequence = seq;
+        self.metrics.writes += 1;
+        self.metrics.bytes_written += log_pointer.len;
+
+        self.index_set(key.clone(), log_pointer);
+        if let Some(value) = inline_value {
+            self.cache.insert(key, log_pointer, value);
+        }
+        self.maybe_compact()?;
+        self.maybe_rotate_log()?;
+        self.maybe_save_index()
+    }
+
+    // Point `key` at the value just written at `log_pointer`, retiring the
+    // one it replaces
+    fn index_set(&mut self, key: Vec<u8>, log_pointer: LogPointer) {
+        if let Some(existing_value) = self.keydir.get(&key).copied() {
+            if let Some(expires_at) = existing_value.expires_at {
+                self.expiries.remove(&(expires_at, key.clone()));
+            }
+            let replaced_at = log_pointer.version.unwrap_or_else(unix_millis);
+            self.supersede(&key, existing_value, replaced_at);
+        }
+        if let Some(expires_at) = log_pointer.expires_at {
+            self.expiries.insert((expires_at, key.clone()));
+        }
+
+        self.cache.remove(&key);
+        self.removed.remove(&key);
+        Arc::make_mut(&mut self.keydir).insert(key, log_pointer);
+    }
+
+    // Drop `key` from the keydir after its remove was written, keeping the
+    // value for a restore if the remove was soft
+    fn index_remove(&mut self, key: &[u8], removed_at: Option<u64>) {
+        let Some(log_pointer) = Arc::make_mut(&mut self.keydir).remove(key) else {
+            return;
+        };
+        if let Some(removed_at) = removed_at {
+            if !log_pointer.is_expired(removed_at) {
+                self.removed.insert(key.to_vec(), (log_pointer, removed_at));
+            }
+        }
+        self.supersede(key, log_pointer, unix_millis());
+        if let Some(expires_at) = log_pointer.expires_at {
+            self.expiries.remove(&(expires_at, key.to_vec()));
+        }
+
+        self.cache.remove(key);
+    }
+
+    // Write the remove of `key` and drop it from the keydir, leaving the
+    // compaction, rotation and index save to the caller
+    fn write_remove(&mut self, key: Vec<u8>, removed_at: Option<u64>) -> Result<()> {
+        self.write_through(&key, None, None)?;
+
+        let start = self.writer.pos();
+        self.writer
+            .write_rm_cmd(key.clone(), removed_at, self.sequence + 1)?;
+        self.sequence += 1;
+        self.metrics.writes += 1;
+        self.metrics.bytes_written += self.writer.pos() - start;
+
+        self.index_remove(&key, removed_at);
+        Ok(())
+    }
+
+    // Write `batch` as one batch in the log, which a reopened store replays
+    // either whole or, if a crash tore it, not at all
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        batch.validate()?;
+        self.check_stall()?;
+        let ops = batch.into_ops();
+        for op in &ops {
+            match op {
+                BatchOp::Set { key, value } => self.write_through(key, Some(value), None)?,
+                BatchOp::Remove { key } => self.write_through(key, None, None)?,
+            }
+        }
+
+        // A key set twice in the batch needs a later version the second time
+        let mut versions: HashMap<&[u8], u64> = HashMap::new();
+        let removed_at = self.config.soft_delete_retention.map(|_| unix_millis());
+        let first_seq = self.sequence + 1;
+        let cmds: Vec<Command> = ops
+            .iter()
+            .zip(first_seq..)
+            .map(|(op, seq)| match op {
+                BatchOp::Set { key, value } => {
+                    let version = self.config.keep_versions.map(|_| {
+                        let version = match versions.get(&key[..]) {
+                            Some(&previous) => self.next_version(key).max(previous + 1),
+                            None => self.next_version(key),
+                        };
+                        versions.insert(key, version);
+                        version
+                    });
+                    Command::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        expires_at: None,
+                        version,
+                        seq: Some(seq),
+                    }
+                }
+                BatchOp::Remove { key } => Command::Remove {
+                    key: key.clone(),
+                    removed_at,
+                    seq: Some(seq),
+                },
+            })
+            .collect();
+
+        let start = self.writer.pos();
+        let log_pointers = self.writer.write_batch(&cmds)?;
+        self.sequence += cmds.len() as u64;
+        self.metrics.writes += cmds.len() as u64;
+        self.metrics.bytes_written += self.writer.pos() - start;
+
+        for (cmd, log_pointer) in cmds.into_iter().zip(log_pointers) {
+            match cmd {
+                Command::Set { key, value, .. } => {
+                    self.metrics.key_lens.record(key.len());
+                    self.metrics.value_lens.record(value.len());
+                    self.index_set(key.clone(), log_pointer);
+                    if self.cache.is_inline(&value) {
+                        self.cache.insert(key, log_pointer, value);
+                    }
+                }
+                Command::Remove {
+                    key, removed_at, ..
+                } => self.index_remove(&key, removed_at),
+                Command::Batch { .. } => {}
+            }
+        }
+        self.maybe_compact()?;
+        self.maybe_rotate_log()?;
+        self.maybe_save_index()
+    }
+
+    // The version to give a new value of `key`: the time now, or just past
+    // the key's latest version if that is later
+    fn next_version(&self, key: &[u8]) -> u64 {
+        let current = self
+            .keydir
+            .get(key)
+            .and_then(|log_pointer| log_pointer.version);
+        let kept = self
+            .history
+            .get(key)
+            .and_then(|versions| versions.last())
+            .and_then(|(log_pointer, _)| log_pointer.version);
+
+        match current.max(kept) {
+            Some(latest) => unix_millis().max(latest + 1),
+            None => unix_millis(),
+        }
+    }
+
+    // Keep `log_pointer`, a value of `key` replaced or removed at
+    // `replaced_at`, as an old version if the store keeps them, otherwise
+    // count it as stale. Expired and unversioned values aren't kept.
+    fn supersede(&mut self, key: &[u8], log_pointer: LogPointer, replaced_at: u64) {
+        let Some(retention) = self.config.keep_versions else {
+            self.stale_logs_size += log_pointer.len;
+            return;
+        };
+        let now = unix_millis();
+        if log_pointer.version.is_none() || log_pointer.is_expired(now) {
+            self.stale_logs_size += log_pointer.len;
+            return;
+        }
+
+        let versions = self.history.entry(key.to_vec()).or_default();
+        versions.push((log_pointer, replaced_at));
+        self.stale_logs_size += prune_versions(versions, &retention, now);
+        if versions.is_empty() {
+            self.history.remove(key);
+        }
+    }
+
+    // Drop the old versions the retention no longer allows, e.g. after a
+    // restart with a shorter one
+    fn prune_history(&mut self) {
+        let retention = self.config.keep_versions.unwrap_or_default();
+        let now = unix_millis();
+        let mut pruned = 0;
+        self.history.retain(|_, versions| {
+            pruned += prune_versions(versions, &retention, now);
+            !versions.is_empty()
+        });
+        self.stale_logs_size += pruned;
+    }
+
+    /// The value `key` had at `version`, if that version is current or still
+    /// kept, whether or not it has expired since.
+    pub fn get_version(&mut self, key: &[u8], version: u64) -> Result<Option<String>> {
+        self.metrics.reads += 1;
+        let current = self
+            .keydir
+            .get(key)
+            .filter(|log_pointer| log_pointer.version == Some(version));
+        let kept = self.history.get(key).and_then(|versions| {
+            versions
+                .iter()
+                .map(|(log_pointer, _)| log_pointer)
+                .find(|log_pointer| log_pointer.version == Some(version))
+        });
+
+        match current.or(kept).copied() {
+            Some(log_pointer) => self.read_value(&log_pointer),
+            None => Ok(None),
+        }
+    }
+
+    /// The generations of the logs that may hold records of `key`, oldest
+    /// first. Logs written by compaction or a bulk load are listed only if
+    /// their bloom filter, saved next to them as `<gen>.bloom`, matches the
+    /// key; other logs always are.
+    pub fn candidate_logs(&self, key: &[u8]) -> Vec<u64> {
+        self.readers
+            .log_gens()
+            .filter(|log_gen| {
+                self.blooms
+                    .get(log_gen)
+                    .is_none_or(|bloom| bloom.may_contain(key))
+            })
+            .collect()
+    }
+
+    /// Estimate the memory the store's keydir, the indexes beside it, its
+    /// bloom filters and its read cache take up, for sizing the machine a
+    /// store of many keys runs on. This walks the whole keydir, so it takes
+    /// time in proportion to the number of keys.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let key_bytes = |key: &Vec<u8>| key.capacity();
+        let keydir_bytes = self.keydir.keys().map(key_bytes).sum::<usize>()
+            + self.keydir.len() * btree_entry_bytes::<Vec<u8>, LogPointer>();
+        let expiry_bytes = self
+            .expiries
+            .iter()
+            .map(|(_, key)| key_bytes(key))
+            .sum::<usize>()
+            + self.expiries.len() * btree_entry_bytes::<(u64, Vec<u8>), ()>();
+        let removed_bytes = self.removed.keys().map(key_bytes).sum::<usize>()
+            + self.removed.len() * btree_entry_bytes::<Vec<u8>, (LogPointer, u64)>();
+        let history_bytes = self
+            .history
+            .iter()
+            .map(|(key, versions)| {
+                key_bytes(key) + versions.capacity() * size_of::<(LogPointer, u64)>()
+            })
+            .sum::<usize>()
+            + self.history.len() * btree_entry_bytes::<Vec<u8>, Vec<(LogPointer, u64)>>();
+
+        MemoryUsage {
+            keys: self.keydir.len() as u64,
+            keydir_bytes: (keydir_bytes + expiry_bytes + removed_bytes + history_bytes) as u64,
+            bloom_bytes: self.blooms.values().map(Bloom::bytes).sum::<usize>() as u64,
+            cache_bytes: self.cache.memory_usage() as u64,
+        }
+    }
+
+    /// The versions of `key` that can be read with `get_version`, oldest
+    /// first. The last is the current value's if the key is present.
+    pub fn versions(&self, key: &[u8]) -> Vec<u64> {
+        let kept = self
+            .history
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|(log_pointer, _)| log_pointer);
+        let current = self.live_pointer(key);
+
+        kept.chain(current.as_ref())
+            .filter_map(|log_pointer| log_pointer.version)
+            .collect()
+    }
+
+    // Rewrite a live key's value with a different expiry
+    fn reset_expiry(&mut self, key: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let log_pointer = self
+            .live_pointer(&key)
+            .ok_or(KvStoreError::UnknownKeyError)?;
+        let value = self
+            .live_value(&key, log_pointer)?
+            .ok_or(KvStoreError::UnknownKeyError)?;
+
+        self.write_set(key, value, expires_at)
+    }
+
+    /// Scan the keys under `prefix` that also satisfy `matches`.
+    fn scan_matching(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        let lower = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+
+        self.metrics.reads += 1;
+        let now = unix_millis();
+        let log_pointers: Vec<(Vec<u8>, LogPointer)> = self
+            .keydir
+            .range((lower, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| matches(key))
+            .filter(|(_, log_pointer)| !log_pointer.is_expired(now))
+            .take(limit)
+            .map(|(key, &log_pointer)| (key.clone(), log_pointer))
+            .collect();
+
+        let mut entries = Vec::with_capacity(log_pointers.len());
+        for (key, log_pointer) in log_pointers {
+            // Scans use cached values but don't fill the cache, so one large
+            // scan doesn't evict the hot keys
+            let value = match self.cache_get(&key, &log_pointer) {
+                Some(value) => Some(value),
+                None => self.live_value(&key, log_pointer)?,
+            };
+            if let Some(value) = value {
+                entries.push((key, value));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Look a value up in the read cache, counting the hit or miss
+    fn cache_get(&mut self, key: &[u8], log_pointer: &LogPointer) -> Option<String> {
+        let value = self.cache.get(key, log_pointer);
+        let counter = match value {
+            Some(_) => &mut self.metrics.cache_hits,
+            None => &mut self.metrics.cache_misses,
+        };
+        *counter = counter.map(|count| count + 1);
+
+        value
+    }
+
+    // Read a key's value through the read cache
+    fn cached_value(&mut self, key: Vec<u8>, log_pointer: LogPointer) -> Result<Option<String>> {
+        if let Some(value) = self.cache_get(&key, &log_pointer) {
+            return Ok(Some(value));
+        }
 
-fn sorted_log_gens(path: &PathBuf) -> Result<Vec<u64>> {
-    let mut log_entries: Vec<u64> = fs::read_dir(path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
+        let value = self.live_value(&key, log_pointer)?;
+        if let Some(value) = &value {
+            self.cache.insert(key, log_pointer, value.clone());
+        }
 
-    log_entries.sort_unstable();
-    Ok(log_entries)
-}
+        Ok(value)
+    }
 
-fn index_logs(keydir: &mut Keydir, path: &PathBuf) -> Result<(HashMap<u64, LogReader>, u64, u64)> {
-    let mut readers: HashMap<u64, LogReader> = HashMap::new();
+    /// Read the values of `keys` into the read cache, so the first requests
+    /// for them after a restart don't wait on the disk. Missing keys are
+    /// skipped. Returns how many values were loaded.
+    pub fn preload(&mut self, keys: impl IntoIterator<Item = Vec<u8>>) -> Result<usize> {
+        let _span = info_span!("preload").entered();
+        let mut loaded = 0;
+        for key in keys {
+            if let Some(log_pointer) = self.live_pointer(&key) {
+                if self.cached_value(key, log_pointer)?.is_some() {
+                    loaded += 1;
+                }
+            }
+        }
 
-    let log_gens = sorted_log_gens(&path)?;
+        Ok(loaded)
+    }
 
-    let mut stale_logs_size: u64 = 0;
+    /// Read the values of every key starting with `prefix` into the read
+    /// cache. Returns how many values were loaded.
+    pub fn preload_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        let keys: Vec<Vec<u8>> = self
+            .keydir
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
 
-    for &log_gen in &log_gens {
-        let mut reader = LogReader::new(&path, log_gen)?;
-        let mut commands = reader.iter();
+        self.preload(keys)
+    }
 
-        while let Some(Ok((cmd, log_pointer))) = commands.next() {
-            match cmd {
-                Command::Set { key, .. } => {
-                    if let Some(existing_value) = keydir.get(&key) {
-                        stale_logs_size += existing_value.len;
-                    }
-                    keydir.insert(key, log_pointer);
+    /// Write `records` straight into a new log and index them in one step,
+    /// for initial imports. Unlike `set`, nothing is compacted per record;
+    /// pre-sorted input keeps the log in key order. A later record for the
+    /// same key wins. Returns how many records were loaded, each counted in
+    /// the metrics as a write. An invalid key, or the remote tier failing to
+    /// take a record, fails the whole load, leaving the store as it was. The
+    /// remote tier isn't rolled back: it keeps the records handed to it
+    /// before the failure.
+    pub fn bulk_load(
+        &mut self,
+        records: impl IntoIterator<Item = (Vec<u8>, String)>,
+    ) -> Result<usize> {
+        let _span = info_span!("bulk_load", log_gen = self.log_gen + 1).entered();
+        self.check_stall()?;
+        self.writer.flush()?;
+
+        let load_log_gen = self.log_gen + 1;
+        let mut load_log = SegmentWriter::create(
+            &self.path,
+            load_log_gen,
+            self.log_encoding,
+            self.config.keys(),
+        )?;
+        let mut loaded = Vec::new();
+        let mut seq = self.sequence;
+        for (key, value) in records {
+            let written =
+                validate_key(&key).and_then(|()| self.write_through(&key, Some(&value), None));
+            if let Err(err) = written {
+                load_log.discard()?;
+                return Err(err);
+            }
+            seq += 1;
+            let value_len = value.len();
+            let cmd = Command::Set {
+                key,
+                value,
+                expires_at: None,
+                version: None,
+                seq: Some(seq),
+            };
+            let log_pointer = load_log.write(&cmd)?;
+            if let Command::Set { key, .. } = cmd {
+                loaded.push((key, value_len, log_pointer));
+            }
+        }
+        let bloom = load_log.finish()?;
+        load_log.install()?;
+        self.sequence = seq;
+        self.blooms.insert(load_log_gen, bloom);
+
+        // Seal the active log behind the new one so later writes win
+        let new_log_gen = load_log_gen + 1;
+        self.sealed_logs_size += self.writer.pos() + load_log.pos;
+        self.readers.insert(
+            load_log_gen,
+            &self.path,
+            LogReader::new(&self.path, load_log_gen, self.config.keys())?,
+        );
+        self.writer = LogWriter::new(
+            &self.path,
+            new_log_gen,
+            self.log_encoding,
+            self.config.keys(),
+        )?;
+        self.readers.insert(
+            new_log_gen,
+            &self.path,
+            LogReader::new(&self.path, new_log_gen, self.config.keys())?,
+        );
+        self.log_gen = new_log_gen;
+        self.log_started = Instant::now();
+        self.record_log_starts()?;
+        self.subscribers.emit(StoreEvent::SegmentCreated {
+            log_gen: load_log_gen,
+        });
+        self.subscribers.emit(StoreEvent::SegmentCreated {
+            log_gen: new_log_gen,
+        });
+
+        let count = loaded.len();
+        let now = unix_millis();
+        for (key, value_len, log_pointer) in loaded {
+            self.metrics.key_lens.record(key.len());
+            self.metrics.value_lens.record(value_len);
+            self.metrics.writes += 1;
+            self.metrics.bytes_written += log_pointer.len;
+            self.cache.remove(&key);
+            self.removed.remove(&key);
+            if let Some(existing_value) =
+                Arc::make_mut(&mut self.keydir).insert(key.clone(), log_pointer)
+            {
+                if let Some(expires_at) = existing_value.expires_at {
+                    self.expiries.remove(&(expires_at, key.clone()));
                 }
-                Command::Remove { key } => {
-                    if let Some(existing_value) = keydir.get(&key) {
-                        stale_logs_size += existing_value.len;
-                    }
-                    keydir.remove(&key);
+                self.supersede(&key, existing_value, now);
+            }
+        }
+
+        self.maybe_compact()?;
+        self.maybe_save_index()?;
+        Ok(count)
+    }
+
+    fn read_value(&mut self, log_pointer: &LogPointer) -> Result<Option<String>> {
+        let reader = self.log_reader(log_pointer)?;
+        let value = reader.read_pointer(log_pointer);
+        let read = reader.take_last_read();
+        self.record_read(read);
+        value
+    }
+
+    fn record_read(&mut self, read: Option<RecordRead>) {
+        if let (Some(read), Some(read_path)) = (read, &mut self.metrics.read_path) {
+            read_path.record(&read);
+        }
+    }
+
+    // Read the value of `key`, which the keydir points at `log_pointer`. If
+    // the pointer doesn't lead to a set record, the log is rescanned for the
+    // key's real record and the keydir repaired before reading again.
+    fn live_value(&mut self, key: &[u8], log_pointer: LogPointer) -> Result<Option<String>> {
+        match self.read_value(&log_pointer) {
+            Err(err) if is_mismatch(&err) => match self.reindex_key(key, log_pointer, &err)? {
+                Some(log_pointer) => self.read_value(&log_pointer),
+                None => Err(err),
+            },
+            result => result,
+        }
+    }
+
+    // Rescan the log `log_pointer` points into for the last record of `key`
+    // and point the keydir at it, after reading at `log_pointer` failed with
+    // `err`. A key with no set record there, or whose log is gone, is dropped
+    // from the keydir as unreadable and `None` returned.
+    fn reindex_key(
+        &mut self,
+        key: &[u8],
+        log_pointer: LogPointer,
+        err: &KvStoreError,
+    ) -> Result<Option<LogPointer>> {
+        let _span = info_span!("reindex_key", log_gen = log_pointer.log_gen).entered();
+        if log_pointer.log_gen == self.log_gen {
+            self.writer.flush()?;
+        }
+
+        let mut found = None;
+        if self.readers.contains(log_pointer.log_gen) {
+            let reader = self.readers.get_mut(log_pointer.log_gen)?;
+            for record in reader.iter_from(0)? {
+                let Ok((cmd, record_pointer)) = record else {
+                    break;
+                };
+                match cmd {
+                    Command::Set {
+                        key: record_key, ..
+                    } if record_key == key => found = Some(record_pointer),
+                    Command::Remove {
+                        key: record_key, ..
+                    } if record_key == key => found = None,
+                    _ => {}
                 }
-            };
+            }
         }
+        // The keydir has no record to point at, so stop handing out one that
+        // can't be read
+        let found =
+            found.filter(|found| (found.pos, found.len) != (log_pointer.pos, log_pointer.len));
 
-        readers.insert(log_gen, reader);
+        self.cache.remove(key);
+        if let Some(expires_at) = log_pointer.expires_at {
+            self.expiries.remove(&(expires_at, key.to_vec()));
+        }
+        let keydir = Arc::make_mut(&mut self.keydir);
+        match found {
+            Some(found) => {
+                warn!(
+                    key = %String::from_utf8_lossy(key),
+                    pos = log_pointer.pos,
+                    found = found.pos,
+                    %err,
+                    "Keydir entry didn't match its log; repointed it"
+                );
+                if let Some(expires_at) = found.expires_at {
+                    self.expiries.insert((expires_at, key.to_vec()));
+                }
+                keydir.insert(key.to_vec(), found);
+            }
+            None => {
+                error!(
+                    key = %String::from_utf8_lossy(key),
+                    pos = log_pointer.pos,
+                    %err,
+                    "Keydir entry didn't match its log and no record of the key was found; \
+                     dropped it"
+                );
+                keydir.remove(key);
+            }
+        }
+
+        Ok(found)
     }
 
-    let current_log_gen = log_gens.last().unwrap_or(&0) + 1;
+    // The reader of the log `log_pointer` points into, counting the read
+    fn log_reader(&mut self, log_pointer: &LogPointer) -> Result<&mut LogReader> {
+        // Writes to the active log are buffered, so make them readable first
+        if log_pointer.log_gen == self.log_gen {
+            self.writer.flush()?;
+        }
 
-    Ok((readers, current_log_gen, stale_logs_size))
-}
+        self.metrics.bytes_read += log_pointer.len;
+        self.readers.get_mut(log_pointer.log_gen)
+    }
+
+    /// Open a read-only view of the store as it is now. Later writes and
+    /// compactions don't show up in the view. It opens the log files it
+    /// reads as the store does, at most `max_open_logs` at a time, and
+    /// holds on to a log's file before compaction deletes it or it moves to
+    /// the cold tier, so it stays readable until the view is dropped.
+    pub fn snapshot_view(&mut self) -> Result<KvStoreSnapshot> {
+        self.writer.flush()?;
+
+        let mut readers = Readers::new(self.config.max_open_logs, self.config.keys());
+        for log_gen in self.readers.log_gens() {
+            readers.add(log_gen, self.log_dir(log_gen));
+        }
+        let readers = Arc::new(Mutex::new(readers));
+        self.snapshot_readers
+            .retain(|readers| readers.strong_count() > 0);
+        self.snapshot_readers.push(Arc::downgrade(&readers));
+
+        Ok(KvStoreSnapshot::new(self.keydir.clone(), readers))
+    }
+
+    // Keep the log open in every live snapshot view, before its file is
+    // deleted or moved
+    fn keep_open_for_snapshots(&mut self, log_gen: u64) -> Result<()> {
+        self.snapshot_readers
+            .retain(|readers| readers.strong_count() > 0);
+        for readers in self.snapshot_readers.iter().filter_map(Weak::upgrade) {
+            readers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .keep_open(log_gen)?;
+        }
+        Ok(())
+    }
+
+    /// How long writes should wait before being tried again, if the logs are
+    /// over `stall_stale_bytes` and compacting now doesn't fix that. A failed
+    /// compaction is only retried once the wait has passed.
+    fn stall(&mut self) -> Option<Duration> {
+        let limit = self.config.stall_stale_bytes?;
+        if self.stale_logs_size <= limit {
+            self.stalled_until = None;
+            return None;
+        }
+
+        let now = Instant::now();
+        if let Some(stalled_until) = self.stalled_until.filter(|&until| now < until) {
+            return Some(stalled_until - now);
+        }
+        // A failed compaction leaves the stale bytes in place, which is what
+        // stalls the writes
+        let _ = self.compact_logs();
+        if self.stale_logs_size <= limit {
+            self.stalled_until = None;
+            return None;
+        }
+
+        self.stalled_until = Some(now + STALL_RETRY_INTERVAL);
+        Some(STALL_RETRY_INTERVAL)
+    }
+
+    fn check_stall(&mut self) -> Result<()> {
+        match self.stall() {
+            Some(retry_after) => Err(KvStoreError::WriteStalled(retry_after)),
+            None => Ok(()),
+        }
+    }
 
-impl KvStore {
     fn maybe_compact(&mut self) -> Result<()> {
-        if self.stale_logs_size > COMPACTION_THRESHOLD {
-            self.compact()?;
+        let config = &self.config;
+        let stale = self.stale_logs_size;
+        let logs_size = self.sealed_logs_size + self.writer.pos();
+
+        let by_ratio = stale > config.compaction_min_stale
+            && self.readers.len() >= config.compaction_min_logs
+            && stale as f64 >= config.compaction_stale_ratio * logs_size as f64;
+        let by_threshold =
+            matches!(config.compaction_threshold, Some(threshold) if stale > threshold);
+        let by_age = self.is_due_by_age();
+        self.compaction_deferred = false;
+        if !(by_ratio || by_threshold || by_age) {
+            return Ok(());
+        }
+
+        if !self.may_compact_now() {
+            self.compaction_deferred = true;
+            return Ok(());
+        }
+        self.compact_logs()
+    }
+
+    // Whether the oldest log is past `compact_after` and the logs hold
+    // anything a compaction would purge
+    fn is_due_by_age(&self) -> bool {
+        let Some(compact_after) = self.config.compact_after else {
+            return false;
+        };
+        let now = unix_millis();
+        let oldest = self.log_started_at.values().min().copied().unwrap_or(now);
+        if now.saturating_sub(oldest) < compact_after.as_millis() as u64 {
+            return false;
+        }
+
+        let expired = matches!(self.expiries.first(), Some(&(expires_at, _)) if expires_at <= now);
+        self.stale_logs_size > 0 || expired || !self.removed.is_empty()
+    }
+
+    // Whether the compaction windows or a light enough load let a compaction
+    // start now
+    fn may_compact_now(&mut self) -> bool {
+        let config = &self.config;
+        if config.compaction_windows.is_empty() && config.compaction_max_ops_per_sec.is_none() {
+            return true;
         }
+
+        let now = unix_millis();
+        if config
+            .compaction_windows
+            .iter()
+            .any(|window| window.contains(now))
+        {
+            return true;
+        }
+        let Some(max_ops_per_sec) = config.compaction_max_ops_per_sec else {
+            return false;
+        };
+        let ops = self.metrics.reads + self.metrics.writes;
+        self.load.ops_per_sec(ops) <= max_ops_per_sec as f64
+    }
+
+    fn maybe_rotate_log(&mut self) -> Result<()> {
+        let Some(rotate_every) = self.config.rotate_every else {
+            return Ok(());
+        };
+
+        if self.log_started.elapsed() >= rotate_every {
+            self.rotate_log()?;
+        }
+        Ok(())
+    }
+
+    fn maybe_save_index(&mut self) -> Result<()> {
+        let Some(index_interval) = self.config.index_interval else {
+            return Ok(());
+        };
+
+        let written = match self.indexed_at.log_gen == self.log_gen {
+            true => self.writer.pos() - self.indexed_at.offset,
+            false => self.writer.pos(),
+        };
+        if written >= index_interval {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    /// Save the keydir to the index file, so the next open only replays the
+    /// records written after this point.
+    pub fn save_index(&mut self) -> Result<()> {
+        let _span = info_span!("save_index", keys = self.keydir.len()).entered();
+        self.writer.flush()?;
+
+        let log_gens: Vec<u64> = self.readers.log_gens().collect();
+        let position = LogPosition {
+            log_gen: self.log_gen,
+            offset: self.writer.pos(),
+            seq: Some(self.sequence),
+        };
+
+        index::save(
+            &self.path,
+            self.config.keys(),
+            position,
+            log_gens,
+            self.stale_logs_size,
+            index::Indexed {
+                keydir: &self.keydir,
+                removed: &self.removed,
+                history: &self.history,
+            },
+        )?;
+        self.indexed_at = position;
+
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
+    fn compact_logs(&mut self) -> Result<()> {
+        let _span =
+            info_span!("compact", log_gen = self.log_gen, keys = self.keydir.len()).entered();
+        let started = Instant::now();
+        let stale_bytes = self.stale_logs_size;
+        self.subscribers
+            .emit(StoreEvent::CompactionStarted { stale_bytes });
         self.writer.flush()?;
+        let input_bytes = self.sealed_logs_size + self.writer.pos();
+
+        // Write the live keys into a new log per tier, so compaction never
+        // pulls cold data back onto the hot disk
+        let cold_dir = self
+            .config
+            .cold_dir
+            .clone()
+            .filter(|_| !self.cold_log_gens.is_empty());
+        let mut cold_log = match &cold_dir {
+            Some(cold_dir) => Some(SegmentWriter::create(
+                cold_dir,
+                self.log_gen + 1,
+                self.log_encoding,
+                self.config.keys(),
+            )?),
+            None => None,
+        };
+        let hot_log_gen = self.log_gen + 1 + cold_log.is_some() as u64;
+        let mut hot_log = SegmentWriter::create(
+            &self.path,
+            hot_log_gen,
+            self.log_encoding,
+            self.config.keys(),
+        )?;
+        let mut new_keydir: Keydir = BTreeMap::new();
+        let mut new_history = History::new();
+
+        let now = unix_millis();
+        let mut throttle = Throttle::new(self.config.compaction_bytes_per_sec);
 
-        // Write the current keydir into one new log file
-        let compact_log_gen = self.log_gen + 1;
-        let mut new_keydir: Keydir = HashMap::new();
+        // Old versions go first so a replay finds each key's current value
+        // last, followed by a remove if the key is gone
+        self.prune_history();
+        // A key with records in both tiers is written to the hot log whole,
+        // since a replay reads the cold log first and would put its versions
+        // out of order
+        let split_keys: HashSet<Vec<u8>> = match &cold_log {
+            Some(_) => self
+                .history
+                .iter()
+                .filter(|(key, versions)| {
+                    let mut in_cold = versions
+                        .iter()
+                        .map(|(log_pointer, _)| log_pointer)
+                        .chain(self.keydir.get(*key))
+                        .map(|log_pointer| self.cold_log_gens.contains(&log_pointer.log_gen));
+                    let first = in_cold.next();
+                    in_cold.any(|cold| Some(cold) != first)
+                })
+                .map(|(key, _)| key.clone())
+                .collect(),
+            None => HashSet::new(),
+        };
+        for (key, versions) in self.history.iter() {
+            let mut kept = Vec::with_capacity(versions.len());
+            for &(log_pointer, replaced_at) in versions {
+                let reader = self.readers.get_mut(log_pointer.log_gen)?;
+                let Some(value) = reader.read_pointer(&log_pointer)? else {
+                    continue;
+                };
+                let cmd = Command::Set {
+                    key: key.clone(),
+                    value,
+                    expires_at: log_pointer.expires_at,
+                    version: log_pointer.version,
+                    seq: log_pointer.seq,
+                };
 
-        let compact_log_path = log_path(&self.path, compact_log_gen);
-        let mut compact_log = BufWriter::new(File::create(&compact_log_path)?);
+                let compact_log = match &mut cold_log {
+                    Some(cold_log)
+                        if self.cold_log_gens.contains(&log_pointer.log_gen)
+                            && !split_keys.contains(key) =>
+                    {
+                        cold_log
+                    }
+                    _ => &mut hot_log,
+                };
+                let new_log_pointer = compact_log.write(&cmd)?;
+                kept.push((new_log_pointer, replaced_at));
+                throttle.consume(new_log_pointer.len);
+            }
 
-        let mut pos = 0;
+            let is_live = self
+                .keydir
+                .get(key)
+                .is_some_and(|log_pointer| !log_pointer.is_expired(now));
+            if !is_live {
+                let cmd = Command::Remove {
+                    key: key.clone(),
+                    removed_at: None,
+                    seq: None,
+                };
+                throttle.consume(hot_log.write(&cmd)?.len);
+            }
+            if !kept.is_empty() {
+                new_history.insert(key.clone(), kept);
+            }
+        }
 
+        // Expired keys are dropped here rather than carried into the new log
         for (key, log_pointer) in self.keydir.iter() {
-            let reader = self
-                .readers
-                .get_mut(&log_pointer.log_gen)
-                .expect(&format!("Could not find reader {}", log_pointer.log_gen));
+            if log_pointer.is_expired(now) {
+                continue;
+            }
+
+            let reader = self.readers.get_mut(log_pointer.log_gen)?;
 
             if let Some(value) = reader.read_pointer(log_pointer)? {
+                let value = match &self.config.compaction_transform {
+                    Some(CompactionTransform(transform)) => {
+                        // The cached value may be the one replaced
+                        self.cache.remove(key);
+                        match transform(key, value) {
+                            Some(value) => value,
+                            None => {
+                                // Shadow the old versions just copied
+                                if new_history.contains_key(key) {
+                                    let cmd = Command::Remove {
+                                        key: key.clone(),
+                                        removed_at: None,
+                                        seq: None,
+                                    };
+                                    throttle.consume(hot_log.write(&cmd)?.len);
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    None => value,
+                };
+
                 // Write to new file
                 let cmd = Command::Set {
                     key: key.clone(),
                     value,
+                    expires_at: log_pointer.expires_at,
+                    version: log_pointer.version,
+                    seq: log_pointer.seq,
                 };
 
-                let len = compact_log.write(&serde_json::to_vec(&cmd)?)? as u64;
-
-                let new_log_pointer = LogPointer {
-                    len,
-                    log_gen: compact_log_gen,
-                    pos,
+                let compact_log = match &mut cold_log {
+                    Some(cold_log)
+                        if self.cold_log_gens.contains(&log_pointer.log_gen)
+                            && !split_keys.contains(key) =>
+                    {
+                        cold_log
+                    }
+                    _ => &mut hot_log,
                 };
+                let new_log_pointer = compact_log.write(&cmd)?;
 
                 // Remake the keydir with the new log pointer
+                self.cache.relocate(key, new_log_pointer);
                 new_keydir.insert(key.clone(), new_log_pointer);
-                pos += len;
+                throttle.consume(new_log_pointer.len);
+            }
+        }
+
+        // Make the compact logs durable and commit them in the manifest. From
+        // then on a crash leaves the next open to finish the compaction,
+        // where before it the compact logs are thrown away.
+        let cold_log = match cold_log {
+            Some(mut cold_log) if cold_log.pos > 0 => Some((cold_log.finish()?, cold_log)),
+            Some(cold_log) => {
+                cold_log.discard()?;
+                None
             }
+            None => None,
+        };
+        let hot_bloom = hot_log.finish()?;
+        let mut written: Vec<u64> = cold_log.iter().map(|(_, log)| log.log_gen).collect();
+        written.push(hot_log_gen);
+        let commit = manifest::CompactionCommit {
+            written,
+            retired: self.readers.log_gens().collect(),
+        };
+        // The records that carried the latest sequence numbers may not
+        // survive, so keep the count where a reopen will find it
+        manifest::record_sequence(&self.path, self.sequence)?;
+        manifest::record_compaction(&self.path, Some(&commit))?;
+        if let Some((_, cold_log)) = &cold_log {
+            cold_log.install()?;
         }
+        hot_log.install()?;
 
-        compact_log.flush()?;
+        // Set up the readers to the compact logs and the writer to the new log file
+        let old_readers = self.readers.take();
+        let old_cold_log_gens = std::mem::take(&mut self.cold_log_gens);
+        self.blooms.clear();
+        let mut bytes_written = hot_log.pos;
 
-        // Set up the reader to the compact log and the writer to the new log file
-        self.readers = HashMap::new();
-        let current_reader = LogReader::new(&self.path, compact_log_gen)?;
-        self.readers.insert(compact_log_gen, current_reader);
+        if let (Some(cold_dir), Some((bloom, cold_log))) = (&cold_dir, cold_log) {
+            self.blooms.insert(cold_log.log_gen, bloom);
+            let cold_reader = LogReader::new(cold_dir, cold_log.log_gen, self.config.keys())?;
+            self.readers.insert(cold_log.log_gen, cold_dir, cold_reader);
+            self.cold_log_gens.insert(cold_log.log_gen);
+            bytes_written += cold_log.pos;
+            self.subscribers.emit(StoreEvent::SegmentCreated {
+                log_gen: cold_log.log_gen,
+            });
+        }
+
+        self.blooms.insert(hot_log_gen, hot_bloom);
+        let hot_reader = LogReader::new(&self.path, hot_log_gen, self.config.keys())?;
+        self.readers.insert(hot_log_gen, &self.path, hot_reader);
 
-        let new_log_gen = compact_log_gen + 1;
-        self.writer = LogWriter::new(&self.path, new_log_gen)?;
+        let new_log_gen = hot_log_gen + 1;
+        self.writer = LogWriter::new(
+            &self.path,
+            new_log_gen,
+            self.log_encoding,
+            self.config.keys(),
+        )?;
+        self.readers.insert(
+            new_log_gen,
+            &self.path,
+            LogReader::new(&self.path, new_log_gen, self.config.keys())?,
+        );
+        self.subscribers.emit(StoreEvent::SegmentCreated {
+            log_gen: hot_log_gen,
+        });
+        self.subscribers.emit(StoreEvent::SegmentCreated {
+            log_gen: new_log_gen,
+        });
 
         // Delete the old log files
-        for &old_log_gen in self.readers.keys() {
-            if old_log_gen == compact_log_gen {
-                continue;
-            }
-            fs::remove_file(log_path(&self.path, old_log_gen))?;
+        for old_log_gen in old_readers.log_gens() {
+            let dir = match &self.config.cold_dir {
+                Some(cold_dir) if old_cold_log_gens.contains(&old_log_gen) => cold_dir.clone(),
+                _ => self.path.clone(),
+            };
+            self.keep_open_for_snapshots(old_log_gen)?;
+            fs::remove_file(log_path(&dir, old_log_gen))?;
+            bloom::remove(&dir, old_log_gen)?;
+            self.subscribers.emit(StoreEvent::SegmentDeleted {
+                log_gen: old_log_gen,
+            });
         }
+        manifest::record_compaction(&self.path, None)?;
 
-        self.keydir = new_keydir;
+        let stats = CompactionStats {
+            finished_at_ms: unix_millis(),
+            keys: new_keydir.len(),
+            input_bytes,
+            bytes_written,
+            stale_bytes,
+            duration: started.elapsed(),
+        };
+        if self.compactions.len() == self.config.compaction_history_len {
+            self.compactions.pop_front();
+        }
+        if self.config.compaction_history_len > 0 {
+            self.compactions.push_back(stats.clone());
+        }
+        self.subscribers.emit(StoreEvent::CompactionFinished(stats));
+        self.expiries = expiries(&new_keydir);
+        self.keydir = Arc::new(new_keydir);
+        self.history = new_history;
         self.log_gen = new_log_gen;
+        self.log_started = Instant::now();
+        self.sealed_logs_size = bytes_written;
         self.stale_logs_size = 0;
+        // The records soft-deleted values were read from are gone
+        self.removed.clear();
+        self.record_log_starts()?;
+        self.move_cold_logs()?;
+        // A saved index points into the logs just deleted
+        if self.config.index_interval.is_some() {
+            self.save_index()?;
+        }
 
         // println!("Compacting finished: {:#?}", self);
         // println!("Compacting finished: new log gen: {}", new_log_gen);
@@ -155,80 +2241,370 @@ impl KvStore {
 impl KvsEngine for KvStore {
     /** Create a simple key-value store */
     fn open(path: PathBuf) -> Result<KvStore> {
-        fs::create_dir_all(&path)?;
-
-        let mut keydir: Keydir = HashMap::new();
-        let (mut readers, current_log_gen, stale_logs_size) = index_logs(&mut keydir, &path)?;
-
-        let writer = LogWriter::new(&path, current_log_gen)?;
+        KvStore::open_with_config(path, KvStoreConfig::default())
+    }
 
-        let current_reader = LogReader::new(&path, current_log_gen)?;
-        readers.insert(current_log_gen, current_reader);
+    /** Append to the key's value, keeping its expiry */
+    fn append(&mut self, key: Vec<u8>, suffix: &str) -> Result<usize> {
+        let (mut value, expires_at) = match self.live_or_fetched(&key)? {
+            Some(log_pointer) => (
+                self.live_value(&key, log_pointer)?.unwrap_or_default(),
+                log_pointer.expires_at,
+            ),
+            None => (String::new(), None),
+        };
+        value.push_str(suffix);
+        let len = value.len();
 
-        return Ok(KvStore {
-            path,
-            readers,
-            writer,
-            keydir,
-            log_gen: current_log_gen,
-            stale_logs_size,
-        });
+        self.write_set(key, value, expires_at)?;
+        Ok(len)
     }
+}
 
+impl KvsWriter for KvStore {
     /** Set a key to the given value */
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<()> {
         // println!("Setting key: {} to value: {}", &key, &value);
-        let log_pointer = self.writer.write_set_cmd(key.clone(), value)?;
-
-        // println!("log pointer: {:#?}", log_pointer);
+        self.write_set(key, value, None)
+    }
 
-        if let Some(existing_value) = self.keydir.get(&key) {
-            self.stale_logs_size += existing_value.len;
+    /** Set a key to the given value unless it already exists */
+    fn set_nx(&mut self, key: Vec<u8>, value: String) -> Result<bool> {
+        if self.live_or_fetched(&key)?.is_some() {
+            return Ok(false);
         }
 
-        self.keydir.insert(key, log_pointer);
-        self.maybe_compact()?;
+        self.write_set(key, value, None)?;
+        Ok(true)
+    }
 
-        Ok(())
+    /** Set a key to the given value until the ttl has passed */
+    fn set_with_ttl(&mut self, key: Vec<u8>, value: String, ttl: Duration) -> Result<()> {
+        self.write_set(key, value, Some(expiry_after(ttl)))
     }
 
     /** Remove the key from the store */
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
         // println!("Removing key: {}", &key);
-        if !self.keydir.contains_key(&key) {
+        if self.live_or_fetched(&key)?.is_none() {
             return Err(KvStoreError::UnknownKeyError);
         }
+        self.check_stall()?;
+        let removed_at = self.config.soft_delete_retention.map(|_| unix_millis());
+        self.write_remove(key, removed_at)?;
+        self.maybe_compact()?;
+        self.maybe_rotate_log()?;
+        self.maybe_save_index()?;
 
-        self.writer.write_rm_cmd(key.clone())?;
+        Ok(())
+    }
+
+    /** Apply the batch's writes together */
+    fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch)
+    }
 
-        if let Some(existing_value) = self.keydir.get(&key) {
-            self.stale_logs_size += existing_value.len;
+    /** Set a key to the given value, returning its previous value */
+    fn get_set(&mut self, key: Vec<u8>, value: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        self.write_set(key, value, None)?;
+
+        Ok(previous)
+    }
+
+    /** Remove the key from the store, returning its value */
+    fn get_del(&mut self, key: Vec<u8>) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        if previous.is_some() {
+            self.remove(key)?;
         }
 
-        self.keydir.remove(&key);
-        self.maybe_compact()?;
+        Ok(previous)
+    }
+
+    /** Make an existing key expire after the ttl */
+    fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.reset_expiry(key, Some(expiry_after(ttl)))
+    }
+
+    /** Keep an existing key until it is removed */
+    fn persist(&mut self, key: Vec<u8>) -> Result<()> {
+        self.reset_expiry(key, None)
+    }
+
+    /** Write back the value a soft delete removed */
+    fn restore(&mut self, key: Vec<u8>) -> Result<()> {
+        let retention = self
+            .config
+            .soft_delete_retention
+            .ok_or(KvStoreError::NothingToRestore)?;
+        if self.live_pointer(&key).is_some() {
+            return Err(KvStoreError::NothingToRestore);
+        }
+
+        let (log_pointer, removed_at) = self
+            .removed
+            .remove(&key)
+            .ok_or(KvStoreError::NothingToRestore)?;
+        if unix_millis() >= removed_at.saturating_add(retention.as_millis() as u64) {
+            return Err(KvStoreError::NothingToRestore);
+        }
+
+        let value = self
+            .read_value(&log_pointer)?
+            .ok_or(KvStoreError::NothingToRestore)?;
+        self.write_set(key, value, log_pointer.expires_at)
+    }
 
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        self.metrics.flushes += 1;
         Ok(())
     }
 
+    fn compact(&mut self) -> Result<bool> {
+        self.compact_logs()?;
+        Ok(true)
+    }
+
+    fn write_stall(&mut self) -> Option<Duration> {
+        self.stall()
+    }
+
+    /** Remove expired keys, oldest expiry first, at `reap_keys_per_sec` */
+    fn reap_expired(&mut self) -> Result<usize> {
+        // Logs age without any writes to trigger a compaction
+        if self.compaction_deferred || self.config.compact_after.is_some() {
+            self.maybe_compact()?;
+        }
+
+        let now = unix_millis();
+        let expired = match self.expiries.first() {
+            Some(&(expires_at, _)) => expires_at <= now,
+            None => false,
+        };
+        // A stalled store can't take the tombstones
+        if !expired || self.stall().is_some() {
+            return Ok(0);
+        }
+
+        let mut reaped = 0;
+        let mut failed = None;
+        for _ in 0..self.reaper.take() {
+            let Some((expires_at, key)) = self.expiries.pop_first() else {
+                break;
+            };
+            if expires_at > now {
+                self.expiries.insert((expires_at, key));
+                break;
+            }
+            if !self.keydir.contains_key(&key) {
+                continue;
+            }
+            // An expired key isn't worth keeping for a restore
+            if let Err(err) = self.write_remove(key.clone(), None) {
+                // Try again on a later call
+                self.expiries.insert((expires_at, key));
+                failed = Some(err);
+                break;
+            }
+            reaped += 1;
+        }
+        self.metrics.reaped += reaped as u64;
+
+        if reaped > 0 {
+            self.maybe_compact()?;
+            self.maybe_rotate_log()?;
+            self.maybe_save_index()?;
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(reaped),
+        }
+    }
+
+    /** Seal the active log and start a new one, unless it is still empty */
+    fn rotate_log(&mut self) -> Result<Option<u64>> {
+        if self.writer.pos() == 0 {
+            return Ok(Some(self.log_gen));
+        }
+
+        let _span = info_span!("rotate_log", log_gen = self.log_gen).entered();
+        self.writer.flush()?;
+
+        let new_log_gen = self.log_gen + 1;
+        self.sealed_logs_size += self.writer.pos();
+        self.writer = LogWriter::new(
+            &self.path,
+            new_log_gen,
+            self.log_encoding,
+            self.config.keys(),
+        )?;
+        self.readers.insert(
+            new_log_gen,
+            &self.path,
+            LogReader::new(&self.path, new_log_gen, self.config.keys())?,
+        );
+        self.log_gen = new_log_gen;
+        self.log_started = Instant::now();
+        self.record_log_starts()?;
+        self.subscribers.emit(StoreEvent::SegmentCreated {
+            log_gen: new_log_gen,
+        });
+
+        Ok(Some(new_log_gen))
+    }
+
+    fn position(&self) -> Option<LogPosition> {
+        Some(LogPosition {
+            log_gen: self.log_gen,
+            offset: self.writer.pos(),
+            seq: Some(self.sequence),
+        })
+    }
+}
+
+impl EngineMetrics for KvStore {
+    fn metrics(&self) -> Metrics {
+        let (open_log_hits, open_log_misses) = self.readers.hits_and_misses();
+        Metrics {
+            memory: Some(self.memory_usage()),
+            open_log_hits: Some(open_log_hits),
+            open_log_misses: Some(open_log_misses),
+            ..self.metrics.clone()
+        }
+    }
+
+    fn compaction_history(&self) -> Vec<CompactionStats> {
+        self.compactions.iter().cloned().collect()
+    }
+}
+
+impl KvsReader for KvStore {
     /** Retrieve this key's value from the store */
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>> {
         // println!("Getting key: {}", &key);
         // println!("keydir: {:#?}", &self.keydir);
 
-        if let Some(log_pointer) = self.keydir.get(&key) {
+        self.metrics.reads += 1;
+        if let Some(log_pointer) = self.live_or_fetched(&key)? {
             // println!("log_pointer: {:#?}", log_pointer);
-            self.readers
-                .get_mut(&log_pointer.log_gen)
-                .expect("Expected log reader")
-                .read_pointer(log_pointer)
+            self.cached_value(key, log_pointer)
         } else {
             Ok(None)
         }
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()?;
-        Ok(())
+    /** Retrieve the value of a key as a JSON string */
+    fn get_json(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.metrics.reads += 1;
+        let Some(log_pointer) = self.live_or_fetched(&key)? else {
+            return Ok(None);
+        };
+
+        if log_pointer.len < RAW_READ_MIN_LEN {
+            let value = self.cached_value(key, log_pointer)?;
+            return Ok(value.map(|value| serde_json::to_vec(&value)).transpose()?);
+        }
+        if let Some(value) = self.cache_get(&key, &log_pointer) {
+            return Ok(Some(serde_json::to_vec(&value)?));
+        }
+
+        let raw_value = match self.log_reader(&log_pointer) {
+            Ok(reader) => {
+                let raw_value = reader.read_raw_value(&log_pointer);
+                let read = reader.take_last_read();
+                self.record_read(read);
+                raw_value
+            }
+            Err(err) => Err(err),
+        };
+        match raw_value {
+            Err(err) if is_mismatch(&err) => {
+                let value = self.live_value(&key, log_pointer)?;
+                Ok(value.map(|value| serde_json::to_vec(&value)).transpose()?)
+            }
+            raw_value => Ok(Some(raw_value?)),
+        }
+    }
+
+    /** Retrieve part of the value of a key, reading a large one only as far as the range */
+    fn get_range(&mut self, key: Vec<u8>, offset: usize, len: usize) -> Result<Option<String>> {
+        self.metrics.reads += 1;
+        let Some(log_pointer) = self.live_or_fetched(&key)? else {
+            return Ok(None);
+        };
+
+        if log_pointer.len < RAW_READ_MIN_LEN {
+            let value = self.cached_value(key, log_pointer)?;
+            return value
+                .map(|value| value_range(value, offset, len))
+                .transpose();
+        }
+        if let Some(value) = self.cache_get(&key, &log_pointer) {
+            return Ok(Some(value_range(value, offset, len)?));
+        }
+
+        if log_pointer.log_gen == self.log_gen {
+            self.writer.flush()?;
+        }
+        // One byte more shows whether the range ends inside a character
+        let read = self
+            .readers
+            .get_mut(log_pointer.log_gen)
+            .and_then(|reader| {
+                reader.read_value_range(&log_pointer, &key, offset, len.saturating_add(1))
+            });
+        let range = match read {
+            Ok((range, read)) => {
+                self.metrics.bytes_read += read;
+                range
+            }
+            Err(err) if is_mismatch(&err) => None,
+            Err(err) => return Err(err),
+        };
+        match range {
+            Some(range) => Ok(Some(value_range_from(range, offset, len)?)),
+            // Sealed, or not where the pointer says: read it whole
+            None => {
+                let value = self.live_value(&key, log_pointer)?;
+                value
+                    .map(|value| value_range(value, offset, len))
+                    .transpose()
+            }
+        }
+    }
+
+    /** Retrieve a page of entries whose keys start with the prefix */
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(prefix, start_after, limit, |_| true)
+    }
+
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(pattern.literal_prefix(), start_after, limit, |key| {
+            pattern.matches(key)
+        })
+    }
+
+    /** Check whether the key is in the store */
+    fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.live_pointer(key).is_some())
+    }
+
+    /** Time left before the key expires */
+    fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.live_pointer(key)
+            .map(|log_pointer| log_pointer.ttl(unix_millis()))
+            .ok_or(KvStoreError::UnknownKeyError)
     }
 }
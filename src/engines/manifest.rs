@@ -0,0 +1,185 @@
+use crate::encryption::Keyring;
+use crate::logs::LogEncoding;
+use crate::{KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub(super) const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Choices a store is created with, which every later open keeps to, and
+/// when each of its logs was started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub(super) log_encoding: LogEncoding,
+    /// First log generation written sealed, set once the store is opened
+    /// with a keyring. From then on it only opens with one, and plain
+    /// records are only read from older logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) encrypted_from: Option<u64>,
+    /// Milliseconds since the Unix epoch each log generation was started at
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(super) log_started_at: BTreeMap<u64, u64>,
+    /// A compaction whose logs are complete but whose old logs may not all
+    /// be deleted yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) compaction: Option<CompactionCommit>,
+    /// Lowest sequence number the store may go back to, kept for when
+    /// compaction drops the records that carried the latest ones
+    #[serde(default)]
+    pub(super) sequence: u64,
+}
+
+/// The logs a compaction wrote, some maybe still under their temporary
+/// names, and the logs they replace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct CompactionCommit {
+    pub(super) written: Vec<u64>,
+    pub(super) retired: Vec<u64>,
+}
+
+/// Read the manifest in `dir`, if it has one.
+pub(super) fn load(dir: &Path) -> Result<Option<Manifest>> {
+    match fs::read(dir.join(MANIFEST_FILE)) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write `manifest` to `dir`, replacing any previous one only once the new
+/// one is complete.
+pub(super) fn save(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&mut file, manifest)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(MANIFEST_FILE))?;
+
+    Ok(())
+}
+
+/// The encoding to write the logs in `dir` with. A store without a manifest,
+/// new or written before manifests existed, gets one recording `wanted`, or
+/// `Compact`, the only encoding older stores used. Fails if `wanted` isn't
+/// the encoding the store was created with.
+pub(super) fn log_encoding(dir: &Path, wanted: Option<LogEncoding>) -> Result<LogEncoding> {
+    match load(dir)? {
+        Some(manifest) => match wanted {
+            Some(wanted) if wanted != manifest.log_encoding => {
+                Err(KvStoreError::StringError(format!(
+                    "The store's logs are {:?}-encoded, not {:?}",
+                    manifest.log_encoding, wanted
+                )))
+            }
+            _ => Ok(manifest.log_encoding),
+        },
+        None => {
+            let log_encoding = wanted.unwrap_or_default();
+            save(
+                dir,
+                &Manifest {
+                    log_encoding,
+                    encrypted_from: None,
+                    log_started_at: BTreeMap::new(),
+                    compaction: None,
+                    sequence: 0,
+                },
+            )?;
+            Ok(log_encoding)
+        }
+    }
+}
+
+/// Fail if the store in `dir` is encrypted and there are no `keys` to read
+/// it with. Returns the keys to read it with, which accept plain records
+/// only from the logs written before it was encrypted.
+pub(crate) fn check_keys(dir: &Path, keys: Option<&Keyring>) -> Result<Option<Keyring>> {
+    let encrypted_from = load(dir)?.and_then(|manifest| manifest.encrypted_from);
+    match (keys, encrypted_from) {
+        (None, Some(_)) => Err(KvStoreError::Encryption(
+            "The store is encrypted; open it with its keys".to_owned(),
+        )),
+        (Some(keys), Some(log_gen)) => Ok(Some(keys.clone().with_plain_logs_before(log_gen))),
+        // Not encrypted yet, so every record so far is plain
+        (Some(keys), None) => Ok(Some(keys.clone().with_plain_logs_before(u64::MAX))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Check the store in `dir` can be opened with `keys`, and record that it is
+/// encrypted from log `next_log_gen`, the first one it will write, once it
+/// is opened with some. Returns the keys to read it with, as `check_keys`
+/// does. Call after `log_encoding`, which creates the manifest.
+pub(super) fn record_keys(
+    dir: &Path,
+    next_log_gen: u64,
+    keys: Option<&Keyring>,
+) -> Result<Option<Keyring>> {
+    check_keys(dir, keys)?;
+    match load(dir)? {
+        Some(mut manifest) if keys.is_some() && manifest.encrypted_from.is_none() => {
+            manifest.encrypted_from = Some(next_log_gen);
+            save(dir, &manifest)?;
+        }
+        _ => {}
+    }
+    check_keys(dir, keys)
+}
+
+/// When each log generation in `dir` was started, as last recorded.
+pub(super) fn log_started_at(dir: &Path) -> Result<BTreeMap<u64, u64>> {
+    Ok(load(dir)?
+        .map(|manifest| manifest.log_started_at)
+        .unwrap_or_default())
+}
+
+/// Record when each of the store's logs was started, replacing what was
+/// recorded for logs that are gone. Call after `log_encoding`, which creates
+/// the manifest.
+pub(super) fn record_log_started_at(dir: &Path, log_started_at: &BTreeMap<u64, u64>) -> Result<()> {
+    match load(dir)? {
+        Some(mut manifest) => {
+            manifest.log_started_at = log_started_at.clone();
+            save(dir, &manifest)
+        }
+        None => Ok(()),
+    }
+}
+
+/// The compaction recorded in `dir` as committed but not yet cleaned up.
+pub(super) fn compaction(dir: &Path) -> Result<Option<CompactionCommit>> {
+    Ok(load(dir)?.and_then(|manifest| manifest.compaction))
+}
+
+/// Commit a compaction, or with `None` record that it is done. Call after
+/// `log_encoding`, which creates the manifest.
+pub(super) fn record_compaction(dir: &Path, commit: Option<&CompactionCommit>) -> Result<()> {
+    match load(dir)? {
+        Some(mut manifest) => {
+            manifest.compaction = commit.cloned();
+            save(dir, &manifest)
+        }
+        None => Ok(()),
+    }
+}
+
+/// The sequence number recorded in `dir` as the store's floor.
+pub(super) fn sequence(dir: &Path) -> Result<u64> {
+    Ok(load(dir)?.map_or(0, |manifest| manifest.sequence))
+}
+
+/// Record `sequence` as the floor the store's sequence numbers continue
+/// from. Call after `log_encoding`, which creates the manifest.
+pub(super) fn record_sequence(dir: &Path, sequence: u64) -> Result<()> {
+    match load(dir)? {
+        Some(mut manifest) if manifest.sequence < sequence => {
+            manifest.sequence = sequence;
+            save(dir, &manifest)
+        }
+        _ => Ok(()),
+    }
+}
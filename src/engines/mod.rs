@@ -1,17 +1,526 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::glob::Glob;
+use crate::logs::RecordRead;
+use crate::{Bucket, BufferPoolStats, KvStoreError, OperationRates, Result};
+#[cfg(feature = "async")]
+mod async_engine;
+mod batch;
+mod bloom;
+mod cache;
+mod check;
+mod events;
+mod index;
 mod kvs;
+mod manifest;
+mod readers;
+mod remote;
+#[cfg(feature = "sled")]
 mod sled;
-pub use self::sled::SledKvsEngine;
-pub use kvs::KvStore;
+mod snapshot;
+mod standby;
+#[cfg(feature = "sled")]
+pub use self::sled::{SledConfig, SledKvsEngine, SledMode};
+#[cfg(feature = "async")]
+pub use async_engine::{AsyncKvsEngine, BlockingEngine};
+pub(crate) use batch::BatchOp;
+pub use batch::WriteBatch;
+pub use check::{
+    check_logs, read_log, repair_logs, IndexState, KeydirCheck, LogCheck, LogRecord, LogsCheck,
+};
+pub use events::{CompactionStats, StoreEvent};
+pub(crate) use kvs::sorted_log_gens;
+pub use kvs::{CompactionTransform, CompactionWindow, KvStore, KvStoreConfig, VersionRetention};
+pub(crate) use manifest::check_keys;
+pub use remote::{NegativeCaching, RemoteTier};
+pub use snapshot::KvStoreSnapshot;
+pub use standby::KvStoreStandby;
+
+/// Longest key, in bytes, an engine accepts.
+pub const MAX_KEY_LEN: usize = 64 * 1024;
+
+/// Prefix of keys a server started with `reserve_internal_keys` keeps for its
+/// own metadata, rejecting them from clients.
+pub const RESERVED_KEY_PREFIX: &[u8] = b"__kvs";
+
+/// Check that `key` may be written: it must be non-empty and at most
+/// `MAX_KEY_LEN` bytes. Engines check every key they write; reads of an
+/// invalid key simply find nothing.
+pub fn validate_key(key: &[u8]) -> Result<()> {
+    if key.is_empty() {
+        return Err(KvStoreError::EmptyKey);
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(KvStoreError::KeyTooLong(key.len()));
+    }
+
+    Ok(())
+}
+
+/// The bytes of `value` from `offset`, up to `len` of them.
+pub(crate) fn value_range(mut value: String, offset: usize, len: usize) -> Result<String> {
+    let start = offset.min(value.len());
+    let end = start.saturating_add(len).min(value.len());
+    if !value.is_char_boundary(start) || !value.is_char_boundary(end) {
+        return Err(split_character(start, end));
+    }
+
+    value.truncate(end);
+    value.drain(..start);
+    Ok(value)
+}
+
+/// `value_range` of a value whose bytes were read only from byte `offset`,
+/// as far as one byte past the range if the value goes on that far.
+pub(crate) fn value_range_from(mut bytes: Vec<u8>, offset: usize, len: usize) -> Result<String> {
+    let inside_character = |byte: Option<&u8>| byte.is_some_and(|&byte| byte & 0xC0 == 0x80);
+    let splits = inside_character(bytes.first()) || inside_character(bytes.get(len));
+    bytes.truncate(len);
+    if splits {
+        return Err(split_character(offset, offset + bytes.len()));
+    }
+
+    String::from_utf8(bytes).map_err(|err| KvStoreError::StringError(err.to_string()))
+}
+
+fn split_character(start: usize, end: usize) -> KvStoreError {
+    KvStoreError::StringError(format!(
+        "Bytes {}..{} of the value split a character",
+        start, end
+    ))
+}
 
-pub trait KvsEngine {
+/// A point in a store's write history, handed out after a write so that a
+/// later read from a standby can wait until it has seen that write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LogPosition {
+    pub(crate) log_gen: u64,
+    pub(crate) offset: u64,
+    /// Sequence number of the latest mutation at this point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seq: Option<u64>,
+}
+
+impl LogPosition {
+    /// Sequence number of the store's latest mutation as of this position,
+    /// if the store numbers its mutations.
+    pub fn sequence(&self) -> Option<u64> {
+        self.seq
+    }
+}
+
+/// Counts of the work an engine has done since it was opened.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    /// Gets and scans
+    pub reads: u64,
+    /// Writes of a key, including removes and expiry changes
+    pub writes: u64,
+    /// Bytes read from storage, not counting cache hits
+    pub bytes_read: u64,
+    /// Bytes written to storage by writes, not counting compaction
+    pub bytes_written: u64,
+    pub flushes: u64,
+    /// Expired keys removed by the engine's reaper
+    #[serde(default)]
+    pub reaped: u64,
+    /// Reads answered from the engine's own cache, `None` for engines whose
+    /// cache can't be observed
+    pub cache_hits: Option<u64>,
+    pub cache_misses: Option<u64>,
+    /// Reads of a log that found its file already open, and reads that had
+    /// to open it again, `None` for engines without log files
+    #[serde(default)]
+    pub open_log_hits: Option<u64>,
+    #[serde(default)]
+    pub open_log_misses: Option<u64>,
+    /// Key lengths of the sets written, in bytes
+    #[serde(default)]
+    pub key_lens: SizeHistogram,
+    /// Value lengths of the sets written, in bytes
+    #[serde(default)]
+    pub value_lens: SizeHistogram,
+    /// Use of the server's response buffers, filled in by the server rather
+    /// than the engine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_pool: Option<BufferPoolStats>,
+    /// Recent requests per second of each operation, filled in by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rates: Option<BTreeMap<String, OperationRates>>,
+    /// Memory the engine's in-memory structures use, `None` for engines that
+    /// can't estimate it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryUsage>,
+    /// Where reads of records from storage spend their time, `None` for
+    /// engines that don't track it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_path: Option<ReadPathStats>,
+}
+
+impl Metrics {
+    /// The metrics in Prometheus' text exposition format, named `kvs_*`.
+    /// Counters the engine doesn't keep are left out.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let counters = [
+            ("reads_total", "Gets and scans", Some(self.reads)),
+            ("writes_total", "Writes of a key", Some(self.writes)),
+            (
+                "read_bytes_total",
+                "Bytes read from storage",
+                Some(self.bytes_read),
+            ),
+            (
+                "written_bytes_total",
+                "Bytes written to storage",
+                Some(self.bytes_written),
+            ),
+            (
+                "flushes_total",
+                "Flushes of written data",
+                Some(self.flushes),
+            ),
+            ("reaped_total", "Expired keys reaped", Some(self.reaped)),
+            (
+                "cache_hits_total",
+                "Reads answered from the cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses_total",
+                "Reads that missed the cache",
+                self.cache_misses,
+            ),
+            (
+                "open_log_hits_total",
+                "Log reads that found the log's file open",
+                self.open_log_hits,
+            ),
+            (
+                "open_log_misses_total",
+                "Log reads that had to open the log's file",
+                self.open_log_misses,
+            ),
+            (
+                "record_reads_total",
+                "Records read from the logs",
+                self.read_path.map(|read_path| read_path.reads),
+            ),
+            (
+                "record_seeks_total",
+                "Record reads that didn't start where the last read of the log ended",
+                self.read_path.map(|read_path| read_path.seeks),
+            ),
+        ];
+        for (name, help, value) in counters {
+            if let Some(value) = value {
+                prometheus_header(&mut text, name, help, "counter");
+                let _ = writeln!(text, "kvs_{} {}", name, value);
+            }
+        }
+
+        if let Some(memory) = self.memory {
+            prometheus_header(&mut text, "keys", "Live keys", "gauge");
+            let _ = writeln!(text, "kvs_keys {}", memory.keys);
+            let help = "Estimated memory held in the engine's in-memory structures";
+            prometheus_header(&mut text, "memory_bytes", help, "gauge");
+            let _ = writeln!(text, "kvs_memory_bytes {}", memory.total_bytes());
+        }
+
+        if let Some(read_path) = &self.read_path {
+            let histograms = [
+                (
+                    "record_read_bytes",
+                    "Lengths of the records read from the logs",
+                    &read_path.record_lens,
+                    1.0,
+                    read_path.record_bytes,
+                ),
+                (
+                    "record_read_disk_seconds",
+                    "Time record reads spent seeking and reading from disk",
+                    &read_path.disk_nanos,
+                    1e-9,
+                    read_path.disk_nanos_total,
+                ),
+                (
+                    "record_read_decode_seconds",
+                    "Time record reads spent decrypting and decoding",
+                    &read_path.decode_nanos,
+                    1e-9,
+                    read_path.decode_nanos_total,
+                ),
+            ];
+            for (name, help, histogram, scale, sum) in histograms {
+                prometheus_header(&mut text, name, help, "histogram");
+                let mut count = 0;
+                for (bucket, bucket_count) in
+                    histogram.counts[..SIZE_BUCKETS - 1].iter().enumerate()
+                {
+                    count += bucket_count;
+                    let up_to = (1u64 << bucket) as f64 * scale;
+                    let _ = writeln!(text, "kvs_{}_bucket{{le=\"{}\"}} {}", name, up_to, count);
+                }
+                count += histogram.counts[SIZE_BUCKETS - 1];
+                let _ = writeln!(text, "kvs_{}_bucket{{le=\"+Inf\"}} {}", name, count);
+                let _ = writeln!(text, "kvs_{}_sum {}", name, sum as f64 * scale);
+                let _ = writeln!(text, "kvs_{}_count {}", name, count);
+            }
+        }
+
+        text
+    }
+}
+
+fn prometheus_header(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP kvs_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE kvs_{} {}", name, kind);
+}
+
+/// An estimate of the memory a store holds in its in-memory structures, in
+/// bytes. Allocator overhead isn't counted, so the real use is somewhat more.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// Live keys in the keydir
+    pub keys: u64,
+    /// The keydir's keys, log pointers and map nodes, along with the expiry,
+    /// removed and history indexes kept beside it
+    pub keydir_bytes: u64,
+    /// Bloom filters of the logs
+    pub bloom_bytes: u64,
+    /// Cached and inline values, their keys and the cache's own maps
+    pub cache_bytes: u64,
+}
+
+impl MemoryUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.keydir_bytes + self.bloom_bytes + self.cache_bytes
+    }
+}
+
+/// Where the time goes when a store reads records from its logs, to tell
+/// reads held up by the disk from reads held up by decoding. Cache hits and
+/// compaction's reads aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadPathStats {
+    /// Records read
+    pub reads: u64,
+    /// Reads that didn't start where the previous read of the same log
+    /// ended
+    pub seeks: u64,
+    /// Lengths of the records read, in bytes
+    pub record_lens: SizeHistogram,
+    pub record_bytes: u64,
+    /// Nanoseconds each read spent seeking to and reading its record
+    pub disk_nanos: SizeHistogram,
+    pub disk_nanos_total: u64,
+    /// Nanoseconds each read spent decrypting and decoding its record
+    pub decode_nanos: SizeHistogram,
+    pub decode_nanos_total: u64,
+}
+
+impl ReadPathStats {
+    pub(crate) fn record(&mut self, read: &RecordRead) {
+        let disk_nanos = read.disk.as_nanos() as u64;
+        let decode_nanos = read.decode.as_nanos() as u64;
+        self.reads += 1;
+        self.seeks += u64::from(read.seeked);
+        self.record_lens.record(read.len as usize);
+        self.record_bytes += read.len;
+        self.disk_nanos.record(disk_nanos as usize);
+        self.disk_nanos_total += disk_nanos;
+        self.decode_nanos.record(decode_nanos as usize);
+        self.decode_nanos_total += decode_nanos;
+    }
+}
+
+// Estimated bytes a `BTreeMap<K, V>` spends on each entry, not counting what
+// the key and value point to: nodes hold up to eleven entries and are about
+// two thirds full, and carry a few words of bookkeeping besides.
+fn btree_entry_bytes<K, V>() -> usize {
+    (std::mem::size_of::<K>() + std::mem::size_of::<V>()) * 3 / 2 + 8
+}
+
+// Power-of-two buckets a `SizeHistogram` counts lengths in
+const SIZE_BUCKETS: usize = 32;
+
+/// Counts of lengths in power-of-two buckets: bucket `i` counts the lengths
+/// no larger than `2^i` and larger than the previous bucket's bound. The last
+/// bucket also counts anything longer, though no record is that long.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeHistogram {
+    counts: [u64; SIZE_BUCKETS],
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, len: usize) {
+        let bucket = len.next_power_of_two().trailing_zeros() as usize;
+        self.counts[bucket.min(SIZE_BUCKETS - 1)] += 1;
+    }
+
+    /// The non-empty buckets, smallest first.
+    pub fn buckets(&self) -> Vec<Bucket> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| Bucket {
+                up_to: 1 << bucket,
+                count,
+            })
+            .collect()
+    }
+
+    /// Bound of the largest non-empty bucket, `None` if nothing was recorded.
+    pub fn max(&self) -> Option<u64> {
+        self.buckets().last().map(|bucket| bucket.up_to)
+    }
+}
+
+/// An engine that counts its work, so it can be reported the same way
+/// whichever engine a server runs.
+pub trait EngineMetrics {
+    fn metrics(&self) -> Metrics;
+
+    /// Stats of the engine's latest compactions, oldest first. Engines that
+    /// manage their own space keep none.
+    fn compaction_history(&self) -> Vec<CompactionStats> {
+        Vec::new()
+    }
+}
+
+/// Read access to a store. A `&mut dyn KvsReader` can be handed to code that
+/// must not be able to modify the store.
+pub trait KvsReader {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>>;
+    /// The key's value encoded as a JSON string, ready to be written into a
+    /// response. Engines that keep values JSON-encoded can hand them over
+    /// without decoding and re-encoding them.
+    fn get_json(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::to_vec(&value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Up to `len` bytes of the key's value, starting at byte `offset`, so
+    /// the head of a large value can be read without the rest. A range past
+    /// the end of the value is cut short. Fails if either end of the range
+    /// falls inside a character.
+    fn get_range(&mut self, key: Vec<u8>, offset: usize, len: usize) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(value_range(value, offset, len)?)),
+            None => Ok(None),
+        }
+    }
+    /// Return up to `limit` entries whose keys start with `prefix`, in key
+    /// order, resuming after `start_after` when it is given.
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>>;
+    /// Like `scan`, but return the entries whose keys match `pattern`.
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>>;
+    fn contains(&mut self, key: &[u8]) -> Result<bool>;
+    /// Time left before the key expires, or `None` if it never does. Fails
+    /// with `UnknownKeyError` if the key is absent.
+    fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>>;
+    /// Whether reads reflect every write up to `position`. A store that serves
+    /// its own writes always has.
+    fn has_applied(&mut self, _position: LogPosition) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Write access to a store.
+pub trait KvsWriter {
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<()>;
+    /// Set a key only if it is absent, returning whether the value was
+    /// written.
+    fn set_nx(&mut self, key: Vec<u8>, value: String) -> Result<bool>;
+    /// Set a key that reads as absent once `ttl` has passed.
+    fn set_with_ttl(&mut self, key: Vec<u8>, value: String, ttl: Duration) -> Result<()>;
+    fn remove(&mut self, key: Vec<u8>) -> Result<()>;
+    /// Apply every write of `batch`, in order, as one unit: if it fails or
+    /// the process crashes, none of them take effect.
+    fn apply(&mut self, batch: WriteBatch) -> Result<()>;
+    /// Set a key and return the value it replaced, if any.
+    fn get_set(&mut self, key: Vec<u8>, value: String) -> Result<Option<String>>;
+    /// Remove a key and return its value, or `None` if it was absent.
+    fn get_del(&mut self, key: Vec<u8>) -> Result<Option<String>>;
+    /// Make an existing key expire after `ttl`.
+    fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Remove a key's expiry so it is kept until removed.
+    fn persist(&mut self, key: Vec<u8>) -> Result<()>;
+    /// Bring back the value of a key removed in soft-delete mode. Engines
+    /// without soft deletes have nothing to restore.
+    fn restore(&mut self, _key: Vec<u8>) -> Result<()> {
+        Err(KvStoreError::NothingToRestore)
+    }
+    fn flush(&mut self) -> std::result::Result<(), std::io::Error>;
+    /// Seal the active log so it is never written again, e.g. before copying
+    /// the logs for a backup, and write to a new one from now on. Returns the
+    /// new log's generation, or `None` for engines without logs.
+    fn rotate_log(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Compact now rather than waiting for the engine's own thresholds.
+    /// Returns whether anything ran; engines that manage their own space
+    /// return `false`.
+    fn compact(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    /// How long to wait before writing again, if the engine is refusing
+    /// writes with `WriteStalled` until it has caught up with its own work.
+    fn write_stall(&mut self) -> Option<Duration> {
+        None
+    }
+    /// Remove keys whose ttl has passed, as many as the engine's reaping
+    /// rate allows since the last call, so they don't linger until read.
+    /// Returns how many were removed. Meant to be called often, e.g. between
+    /// requests; engines without a reaper remove none.
+    fn reap_expired(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+    /// Position just past the last write, for engines whose logs can be
+    /// followed by a standby.
+    fn position(&self) -> Option<LogPosition> {
+        None
+    }
+}
+
+/// A store that can be opened from a directory and both read and written.
+pub trait KvsEngine: KvsReader + KvsWriter + EngineMetrics {
     fn open(path_buf: PathBuf) -> Result<Self>
     where
         Self: Sized;
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
-    fn flush(&mut self) -> std::result::Result<(), std::io::Error>;
+
+    /// Append `suffix` to a key's value, setting the key to `suffix` if it is
+    /// absent, and return the new value's length in bytes. The key keeps its
+    /// expiry.
+    fn append(&mut self, key: Vec<u8>, suffix: &str) -> Result<usize> {
+        let Some(mut value) = self.get(key.clone())? else {
+            self.set(key, suffix.to_owned())?;
+            return Ok(suffix.len());
+        };
+        value.push_str(suffix);
+        let len = value.len();
+
+        match self.ttl(&key)? {
+            Some(ttl) => self.set_with_ttl(key, value, ttl)?,
+            None => self.set(key, value)?,
+        }
+        Ok(len)
+    }
 }
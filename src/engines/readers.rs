@@ -0,0 +1,148 @@
+use crate::encryption::Keyring;
+use crate::logs::{log_path, LogReader};
+use crate::{KvStoreError, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A store's logs, with a file kept open for at most `max_open` of them.
+/// Reading a log whose file isn't open opens it again, first closing the
+/// file read longest ago if `max_open` are already open, so that a store
+/// with many logs stays under the process's file descriptor limit.
+#[derive(Debug)]
+pub(super) struct Readers {
+    // Directory each log lives in, by generation
+    dirs: BTreeMap<u64, PathBuf>,
+    // Open logs, with the tick they were last read at
+    open: HashMap<u64, (LogReader, u64)>,
+    // Logs never closed again, since their files are deleted or moved
+    kept: HashSet<u64>,
+    ticks: u64,
+    max_open: usize,
+    keys: Option<Keyring>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Readers {
+    pub(super) fn new(max_open: usize, keys: Option<&Keyring>) -> Readers {
+        Readers {
+            dirs: BTreeMap::new(),
+            open: HashMap::new(),
+            kept: HashSet::new(),
+            ticks: 0,
+            max_open: max_open.max(1),
+            keys: keys.cloned(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Add the log `log_gen` in `dir`, already opened as `reader`, or move
+    /// it there if it was in another directory.
+    pub(super) fn insert(&mut self, log_gen: u64, dir: &Path, reader: LogReader) {
+        self.dirs.insert(log_gen, dir.to_owned());
+        self.open.remove(&log_gen);
+        self.close_until(self.max_open - 1);
+        self.ticks += 1;
+        self.open.insert(log_gen, (reader, self.ticks));
+    }
+
+    /// Add the log `log_gen` in `dir` without opening its file until it is
+    /// read.
+    pub(super) fn add(&mut self, log_gen: u64, dir: &Path) {
+        self.dirs.insert(log_gen, dir.to_owned());
+    }
+
+    /// Open the log `log_gen` if its file isn't open, and keep it open from
+    /// now on whatever `max_open`, so it stays readable once the file is
+    /// deleted or moved.
+    pub(super) fn keep_open(&mut self, log_gen: u64) -> Result<()> {
+        if self.contains(log_gen) {
+            self.get_mut(log_gen)?;
+            self.kept.insert(log_gen);
+        }
+        Ok(())
+    }
+
+    /// The reader of the log `log_gen`, opened again if its file was closed.
+    pub(super) fn get_mut(&mut self, log_gen: u64) -> Result<&mut LogReader> {
+        let dir = self
+            .dirs
+            .get(&log_gen)
+            .ok_or(KvStoreError::MissingLogReader(log_gen))?;
+        if self.open.contains_key(&log_gen) {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let reader = LogReader::new(dir, log_gen, self.keys.as_ref())?;
+            self.close_until(self.max_open - 1);
+            self.open.insert(log_gen, (reader, 0));
+        }
+
+        self.ticks += 1;
+        let (reader, last_read) = self
+            .open
+            .get_mut(&log_gen)
+            .ok_or(KvStoreError::MissingLogReader(log_gen))?;
+        *last_read = self.ticks;
+        Ok(reader)
+    }
+
+    // Close the files read longest ago until no more than `len` are open
+    fn close_until(&mut self, len: usize) {
+        while self.open.len() > len {
+            let oldest = self
+                .open
+                .iter()
+                .filter(|(log_gen, _)| !self.kept.contains(log_gen))
+                .min_by_key(|(_, (_, last_read))| *last_read)
+                .map(|(&log_gen, _)| log_gen);
+            match oldest {
+                Some(log_gen) => self.open.remove(&log_gen),
+                None => break,
+            };
+        }
+    }
+
+    pub(super) fn contains(&self, log_gen: u64) -> bool {
+        self.dirs.contains_key(&log_gen)
+    }
+
+    /// The generations of the logs, oldest first.
+    pub(super) fn log_gens(&self) -> impl Iterator<Item = u64> + '_ {
+        self.dirs.keys().copied()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    /// Bytes in all the logs, read from their files' metadata.
+    pub(super) fn files_len(&self) -> Result<u64> {
+        let mut len = 0;
+        for (&log_gen, dir) in &self.dirs {
+            len += fs::metadata(log_path(dir, log_gen))?.len();
+        }
+        Ok(len)
+    }
+
+    /// Forget every log, handing them over to the returned readers.
+    pub(super) fn take(&mut self) -> Readers {
+        Readers {
+            dirs: std::mem::take(&mut self.dirs),
+            open: std::mem::take(&mut self.open),
+            kept: std::mem::take(&mut self.kept),
+            ticks: self.ticks,
+            max_open: self.max_open,
+            keys: self.keys.clone(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Reads that found their log's file open, and reads that had to open it.
+    pub(super) fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+
+type ReadThrough = dyn Fn(&[u8]) -> Result<Option<String>> + Send + Sync;
+type WriteThrough = dyn Fn(&[u8], Option<&str>, Option<u64>) -> Result<()> + Send + Sync;
+
+/// Hooks that put a `KvStore` in front of another system, e.g. a REST API or
+/// another kvs cluster, making the store a local cache of it that persists
+/// across restarts.
+#[derive(Clone, Default)]
+pub struct RemoteTier {
+    pub(super) read_through: Option<Arc<ReadThrough>>,
+    pub(super) write_through: Option<Arc<WriteThrough>>,
+    pub(super) negative_caching: Option<NegativeCaching>,
+}
+
+/// How long a store remembers that the remote tier doesn't have a key, so
+/// repeated gets of a missing key don't each go to the remote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeCaching {
+    /// How long a miss is remembered
+    pub ttl: Duration,
+    /// Misses remembered at once. Further misses aren't remembered until
+    /// some of these expire.
+    pub max_keys: usize,
+}
+
+impl RemoteTier {
+    pub fn new() -> RemoteTier {
+        RemoteTier::default()
+    }
+
+    /// Look up keys the store doesn't have with `fetch`, on gets and on the
+    /// writes that depend on a key's current value. A value found is written
+    /// to the store, without going through `write_through`; `None` means the
+    /// remote doesn't have the key either.
+    pub fn read_through(
+        self,
+        fetch: impl Fn(&[u8]) -> Result<Option<String>> + Send + Sync + 'static,
+    ) -> RemoteTier {
+        RemoteTier {
+            read_through: Some(Arc::new(fetch)),
+            ..self
+        }
+    }
+
+    /// Hand each write to `store` before the store applies it: the key's new
+    /// value, or `None` for a remove, and when the value expires, in
+    /// milliseconds since the Unix epoch. Changing a key's expiry hands over
+    /// its value again, and expired keys the store reaps are handed over as
+    /// removes. If `store` fails, so does the write, and the store is left
+    /// as it was. A batch's or bulk load's writes are handed over one at a
+    /// time, so a failure can leave the remote with part of one.
+    pub fn write_through(
+        self,
+        store: impl Fn(&[u8], Option<&str>, Option<u64>) -> Result<()> + Send + Sync + 'static,
+    ) -> RemoteTier {
+        RemoteTier {
+            write_through: Some(Arc::new(store)),
+            ..self
+        }
+    }
+
+    /// Remember keys `read_through` didn't find, per `policy`. A write of
+    /// the key forgets it at once.
+    pub fn negative_caching(self, policy: NegativeCaching) -> RemoteTier {
+        RemoteTier {
+            negative_caching: Some(policy),
+            ..self
+        }
+    }
+}
+
+impl fmt::Debug for RemoteTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteTier")
+            .field("read_through", &self.read_through.is_some())
+            .field("write_through", &self.write_through.is_some())
+            .field("negative_caching", &self.negative_caching)
+            .finish()
+    }
+}
+
+/// Keys the remote tier recently didn't have, with when to ask it again.
+#[derive(Debug, Default)]
+pub(super) struct Misses {
+    until: HashMap<Vec<u8>, Instant>,
+}
+
+impl Misses {
+    pub(super) fn contains(&self, key: &[u8]) -> bool {
+        self.until
+            .get(key)
+            .is_some_and(|&until| until > Instant::now())
+    }
+
+    pub(super) fn insert(&mut self, key: Vec<u8>, policy: NegativeCaching) {
+        let now = Instant::now();
+        if self.until.len() >= policy.max_keys {
+            self.until.retain(|_, until| *until > now);
+        }
+        if self.until.len() < policy.max_keys {
+            self.until.insert(key, now + policy.ttl);
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        self.until.remove(key);
+    }
+}
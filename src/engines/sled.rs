@@ -1,9 +1,54 @@
-use crate::{KvStoreError, KvsEngine};
+use crate::engines::BatchOp;
+use crate::glob::Glob;
+use crate::logs::{expiry_after, unix_millis};
+use crate::{
+    validate_key, EngineMetrics, KvStoreError, KvsEngine, KvsReader, KvsWriter, Metrics, WriteBatch,
+};
+use sled::transaction::{TransactionError, TransactionResult, Transactional};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::time::Duration;
+use tracing::warn;
+
+/// Tunables for a `SledKvsEngine`, passed through to sled.
+#[derive(Debug, Clone)]
+pub struct SledConfig {
+    /// Bytes of pages sled keeps in memory.
+    pub cache_capacity: u64,
+    /// How often sled flushes writes to disk in the background. `None` only
+    /// flushes when `flush` is called or the engine is dropped.
+    pub flush_every: Option<Duration>,
+    pub mode: SledMode,
+    /// Compress stored data with zstd. A database keeps the setting it was
+    /// created with; opening it with the other one fails.
+    pub compression: bool,
+}
+
+/// What sled's storage layout favors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SledMode {
+    /// Less space on disk, at the cost of more write amplification
+    LowSpace,
+    /// Faster writes, at the cost of more space on disk
+    HighThroughput,
+}
+
+impl Default for SledConfig {
+    fn default() -> SledConfig {
+        SledConfig {
+            cache_capacity: 1024 * 1024 * 1024,
+            flush_every: Some(Duration::from_millis(500)),
+            mode: SledMode::LowSpace,
+            compression: false,
+        }
+    }
+}
 
 pub struct SledKvsEngine {
     db: sled::Db,
-    dirty_count: u64,
+    // Expiry timestamps (big-endian Unix millis) of keys that have one
+    expiries: sled::Tree,
+    metrics: Metrics,
 }
 
 impl From<sled::Error> for KvStoreError {
@@ -12,61 +57,305 @@ impl From<sled::Error> for KvStoreError {
     }
 }
 
-const FLUSH_LIMIT: u64 = 1 << 12;
+fn decode_value(value: &[u8]) -> crate::Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|err| KvStoreError::StringError(err.to_string()))
+}
 
 impl SledKvsEngine {
-    fn maybe_flush(&mut self) -> crate::Result<()> {
-        if self.dirty_count > FLUSH_LIMIT {
-            self.db.flush()?;
+    /// Open the database at `path` with non-default tunables.
+    pub fn open_with_config(path: PathBuf, config: SledConfig) -> crate::Result<SledKvsEngine> {
+        let mode = match config.mode {
+            SledMode::LowSpace => sled::Mode::LowSpace,
+            SledMode::HighThroughput => sled::Mode::HighThroughput,
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(config.cache_capacity)
+            .flush_every_ms(config.flush_every.map(|every| every.as_millis() as u64))
+            .mode(mode)
+            .use_compression(config.compression)
+            .open()?;
+        let expiries = db.open_tree("expiries")?;
+
+        Ok(SledKvsEngine {
+            db,
+            expiries,
+            metrics: Metrics::default(),
+        })
+    }
+
+    fn expires_at(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        let expires_at = self.expiries.get(key)?;
+
+        Ok(expires_at.map(|bytes| {
+            let mut millis = [0; 8];
+            millis.copy_from_slice(&bytes[..8]);
+            u64::from_be_bytes(millis)
+        }))
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> crate::Result<bool> {
+        Ok(self
+            .expires_at(key)?
+            .is_some_and(|expires_at| expires_at <= now))
+    }
+
+    fn is_live(&self, key: &[u8]) -> crate::Result<bool> {
+        Ok(self.db.contains_key(key)? && !self.is_expired(key, unix_millis())?)
+    }
+
+    /// Scan the keys under `prefix` that also satisfy `matches`.
+    fn scan_matching(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> crate::Result<Vec<(Vec<u8>, String)>> {
+        let lower = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let now = unix_millis();
+        self.metrics.reads += 1;
+
+        let mut entries = Vec::new();
+        for entry in self.db.range::<&[u8], _>((lower, Bound::Unbounded)) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) || entries.len() >= limit {
+                break;
+            }
+            if !matches(&key) || self.is_expired(&key, now)? {
+                continue;
+            }
+
+            self.metrics.bytes_read += value.len() as u64;
+            entries.push((key.to_vec(), decode_value(&value)?));
         }
 
+        Ok(entries)
+    }
+
+    fn expire_unchecked(&mut self, key: &[u8], ttl: Duration) -> crate::Result<()> {
+        let expires_at = expiry_after(ttl);
+        self.expiries.insert(key, &expires_at.to_be_bytes())?;
+
         Ok(())
     }
+
+    // Count a write of `len` bytes of key and value
+    fn count_write(&mut self, len: usize) {
+        self.metrics.writes += 1;
+        self.metrics.bytes_written += len as u64;
+    }
+
+    // Count a set of `key` to `value`
+    fn count_set(&mut self, key: &[u8], value: &str) {
+        self.count_write(key.len() + value.len());
+        self.metrics.key_lens.record(key.len());
+        self.metrics.value_lens.record(value.len());
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn open(path: PathBuf) -> Result<SledKvsEngine, KvStoreError> {
-        let db = sled::open(path)?;
+        SledKvsEngine::open_with_config(path, SledConfig::default())
+    }
+}
+
+impl KvsWriter for SledKvsEngine {
+    fn set(&mut self, key: Vec<u8>, value: String) -> crate::Result<()> {
+        validate_key(&key)?;
+        self.count_set(&key, &value);
+        self.expiries.remove(&key)?;
+        self.db.insert(key, value.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn set_nx(&mut self, key: Vec<u8>, value: String) -> crate::Result<bool> {
+        if self.is_live(&key)? {
+            return Ok(false);
+        }
 
-        Ok(SledKvsEngine { db, dirty_count: 0 })
+        self.set(key, value)?;
+        Ok(true)
     }
 
-    fn set(&mut self, key: String, value: String) -> crate::Result<()> {
+    fn set_with_ttl(&mut self, key: Vec<u8>, value: String, ttl: Duration) -> crate::Result<()> {
+        validate_key(&key)?;
+        self.count_set(&key, &value);
+        self.expire_unchecked(&key, ttl)?;
         self.db.insert(key, value.as_bytes())?;
-        // self.maybe_flush()?;
 
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> crate::Result<Option<String>> {
-        let value = self.db.get(key)?;
+    fn remove(&mut self, key: Vec<u8>) -> crate::Result<()> {
+        if !self.is_live(&key)? {
+            return Err(KvStoreError::UnknownKeyError);
+        }
 
-        match value {
-            Some(value) => {
-                let value_as_string = String::from_utf8(value.to_vec())
-                    .map_err(|err| KvStoreError::StringError(err.to_string()))?;
+        self.count_write(key.len());
+        self.expiries.remove(&key)?;
+        self.db.remove(key)?;
+
+        Ok(())
+    }
 
-                Ok(Some(value_as_string))
+    fn apply(&mut self, batch: WriteBatch) -> crate::Result<()> {
+        batch.validate()?;
+
+        let mut data = sled::Batch::default();
+        let mut expiries = sled::Batch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.count_set(&key, &value);
+                    expiries.remove(key.as_slice());
+                    data.insert(key, value.as_bytes());
+                }
+                BatchOp::Remove { key } => {
+                    self.count_write(key.len());
+                    expiries.remove(key.as_slice());
+                    data.remove(key);
+                }
             }
-            None => Ok(None),
+        }
+
+        // Both trees change in one transaction, so a value never outlives
+        // its cleared expiry or the other way round
+        let result: TransactionResult<()> =
+            (&*self.db, &self.expiries).transaction(|(db, db_expiries)| {
+                db.apply_batch(&data)?;
+                db_expiries.apply_batch(&expiries)?;
+                Ok(())
+            });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Storage(err)) => Err(err.into()),
+            Err(TransactionError::Abort(())) => unreachable!("the transaction never aborts"),
+        }
+    }
+
+    fn get_set(&mut self, key: Vec<u8>, value: String) -> crate::Result<Option<String>> {
+        validate_key(&key)?;
+        self.count_set(&key, &value);
+        let expired = self.is_expired(&key, unix_millis())?;
+        self.expiries.remove(&key)?;
+        let previous = self.db.insert(key, value.as_bytes())?;
+
+        match previous {
+            Some(previous) if !expired => Ok(Some(decode_value(&previous)?)),
+            _ => Ok(None),
         }
     }
 
-    fn remove(&mut self, key: String) -> crate::Result<()> {
-        let contains_key = self.db.contains_key(key.clone())?;
+    fn get_del(&mut self, key: Vec<u8>) -> crate::Result<Option<String>> {
+        self.count_write(key.len());
+        let expired = self.is_expired(&key, unix_millis())?;
+        self.expiries.remove(&key)?;
+        let previous = self.db.remove(key)?;
+
+        match previous {
+            Some(previous) if !expired => Ok(Some(decode_value(&previous)?)),
+            _ => Ok(None),
+        }
+    }
 
-        if !contains_key {
+    fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        if !self.is_live(&key)? {
             return Err(KvStoreError::UnknownKeyError);
         }
 
-        self.db.remove(key)?;
-        // self.maybe_flush()?;
+        self.count_write(key.len());
+        self.expire_unchecked(&key, ttl)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> crate::Result<()> {
+        if !self.is_live(&key)? {
+            return Err(KvStoreError::UnknownKeyError);
+        }
+
+        self.count_write(key.len());
+        self.expiries.remove(key)?;
 
         Ok(())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         self.db.flush()?;
+        self.metrics.flushes += 1;
         Ok(())
     }
 }
+
+// Make sure the last writes are on disk when the engine is dropped, instead
+// of relying on the background flush having run
+impl Drop for SledKvsEngine {
+    fn drop(&mut self) {
+        if let Err(err) = self.db.flush() {
+            warn!("Failed to flush sled on drop: {}", err);
+        }
+    }
+}
+
+impl EngineMetrics for SledKvsEngine {
+    fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+impl KvsReader for SledKvsEngine {
+    fn get(&mut self, key: Vec<u8>) -> crate::Result<Option<String>> {
+        self.metrics.reads += 1;
+        if self.is_expired(&key, unix_millis())? {
+            return Ok(None);
+        }
+
+        let value = self.db.get(key)?;
+
+        match value {
+            Some(value) => {
+                self.metrics.bytes_read += value.len() as u64;
+                Ok(Some(decode_value(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> crate::Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(prefix, start_after, limit, |_| true)
+    }
+
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> crate::Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(pattern.literal_prefix(), start_after, limit, |key| {
+            pattern.matches(key)
+        })
+    }
+
+    fn contains(&mut self, key: &[u8]) -> crate::Result<bool> {
+        self.is_live(key)
+    }
+
+    fn ttl(&mut self, key: &[u8]) -> crate::Result<Option<Duration>> {
+        if !self.is_live(key)? {
+            return Err(KvStoreError::UnknownKeyError);
+        }
+
+        let now = unix_millis();
+        Ok(self
+            .expires_at(key)?
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+    }
+}
@@ -0,0 +1,109 @@
+use super::kvs::Keydir;
+use super::readers::Readers;
+use crate::glob::Glob;
+use crate::logs::{unix_millis, LogPointer};
+use crate::{KvStoreError, KvsReader, Result};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// A read-only view of a `KvStore` pinned to the moment it was taken, so a
+/// long scan or export sees one consistent state while writes continue.
+pub struct KvStoreSnapshot {
+    keydir: Arc<Keydir>,
+    // Shared with the store, which keeps a log open here before deleting or
+    // moving its file
+    readers: Arc<Mutex<Readers>>,
+}
+
+impl KvStoreSnapshot {
+    pub(super) fn new(keydir: Arc<Keydir>, readers: Arc<Mutex<Readers>>) -> KvStoreSnapshot {
+        KvStoreSnapshot { keydir, readers }
+    }
+
+    fn live_pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        let now = unix_millis();
+        self.keydir
+            .get(key)
+            .filter(|log_pointer| !log_pointer.is_expired(now))
+            .copied()
+    }
+
+    /// Scan the keys under `prefix` that also satisfy `matches`.
+    fn scan_matching(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        let lower = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+
+        let now = unix_millis();
+        let keydir = self.keydir.clone();
+        let mut entries = Vec::new();
+        for (key, log_pointer) in keydir
+            .range((lower, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| matches(key))
+            .filter(|(_, log_pointer)| !log_pointer.is_expired(now))
+            .take(limit)
+        {
+            if let Some(value) = self.read_value(log_pointer)? {
+                entries.push((key.clone(), value));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_value(&mut self, log_pointer: &LogPointer) -> Result<Option<String>> {
+        self.readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(log_pointer.log_gen)?
+            .read_pointer(log_pointer)
+    }
+}
+
+impl KvsReader for KvStoreSnapshot {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>> {
+        match self.live_pointer(&key) {
+            Some(log_pointer) => self.read_value(&log_pointer),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(prefix, start_after, limit, |_| true)
+    }
+
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(pattern.literal_prefix(), start_after, limit, |key| {
+            pattern.matches(key)
+        })
+    }
+
+    fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.live_pointer(key).is_some())
+    }
+
+    fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.live_pointer(key)
+            .map(|log_pointer| log_pointer.ttl(unix_millis()))
+            .ok_or(KvStoreError::UnknownKeyError)
+    }
+}
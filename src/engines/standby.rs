@@ -0,0 +1,309 @@
+use super::kvs::{apply_record, sorted_log_gens, Keydir};
+use super::manifest;
+use super::readers::Readers;
+use crate::encryption::Keyring;
+use crate::engines::LogPosition;
+use crate::glob::Glob;
+use crate::logs::{unix_millis, LogPointer, LogReader};
+use crate::{KvStore, KvStoreConfig, KvStoreError, KvsReader, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+use tracing::debug_span;
+
+/// A read-only follower of another process's `KvStore` directory.
+///
+/// The standby never creates or modifies files. A background thread polls the
+/// directory and applies newly flushed log records to an in-memory keydir, so
+/// that `promote` can take over as the primary without re-reading the logs.
+pub struct KvStoreStandby {
+    state: Arc<Mutex<Option<Standby>>>,
+}
+
+struct Standby {
+    path: PathBuf,
+    keydir: Keydir,
+    readers: HashMap<u64, LogReader>,
+    // How far into each log generation records have been applied
+    indexed: BTreeMap<u64, u64>,
+    stale_logs_size: u64,
+    // Highest sequence number among the records applied
+    sequence: u64,
+    keys: Option<Keyring>,
+}
+
+impl Standby {
+    fn new(path: PathBuf, keys: Option<Keyring>) -> Standby {
+        Standby {
+            path,
+            keys,
+            keydir: BTreeMap::new(),
+            readers: HashMap::new(),
+            indexed: BTreeMap::new(),
+            stale_logs_size: 0,
+            sequence: 0,
+        }
+    }
+
+    fn catch_up(&mut self) -> Result<()> {
+        // Runs on every poll, so keep it out of info-level traces
+        let _span = debug_span!("standby_catch_up").entered();
+        let log_gens = sorted_log_gens(&self.path)?;
+
+        // Compaction replaced generations we were following. Removes recorded
+        // in them may not have been read yet, so index the new set from scratch.
+        if self
+            .indexed
+            .keys()
+            .any(|log_gen| !log_gens.contains(log_gen))
+        {
+            *self = Standby::new(self.path.clone(), self.keys.clone());
+        }
+
+        for log_gen in log_gens {
+            if !self.readers.contains_key(&log_gen) {
+                match LogReader::new(&self.path, log_gen, self.keys.as_ref()) {
+                    Ok(reader) => {
+                        self.readers.insert(log_gen, reader);
+                    }
+                    // Deleted by a compaction since the directory was listed
+                    Err(KvStoreError::IoErr(err)) if err.kind() == io::ErrorKind::NotFound => {
+                        return Ok(())
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let reader = self
+                .readers
+                .get_mut(&log_gen)
+                .ok_or(KvStoreError::MissingLogReader(log_gen))?;
+            let mut indexed = *self.indexed.get(&log_gen).unwrap_or(&0);
+
+            // A record that fails to parse is still being written; pick it up
+            // on the next poll
+            for record in reader.iter_from(indexed)? {
+                let (cmd, log_pointer) = match record {
+                    Ok(record) => record,
+                    Err(err @ KvStoreError::Encryption(_)) => return Err(err),
+                    Err(_) => break,
+                };
+
+                indexed = log_pointer.pos + log_pointer.len;
+                self.sequence = self.sequence.max(log_pointer.seq.unwrap_or(0));
+                self.stale_logs_size += apply_record(&mut self.keydir, cmd, log_pointer);
+            }
+
+            self.indexed.insert(log_gen, indexed);
+        }
+
+        Ok(())
+    }
+
+    fn has_applied(&self, position: LogPosition) -> bool {
+        // A later generation only appears once the primary has moved past
+        // every record of the earlier ones
+        match self.indexed.range(position.log_gen..).next_back() {
+            Some((&log_gen, _)) if log_gen > position.log_gen => true,
+            Some((_, &indexed)) => indexed >= position.offset,
+            None => false,
+        }
+    }
+
+    fn live_pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        let now = unix_millis();
+        self.keydir
+            .get(key)
+            .filter(|log_pointer| !log_pointer.is_expired(now))
+            .copied()
+    }
+}
+
+impl KvStoreStandby {
+    /// Index the store at `path` and keep following it, checking for new
+    /// records every `poll_interval`.
+    pub fn open(path: PathBuf, poll_interval: Duration) -> Result<KvStoreStandby> {
+        KvStoreStandby::open_with_keys(path, poll_interval, None)
+    }
+
+    /// Follow the store at `path` as `open` does, decrypting its records
+    /// with `keys`. Promoting the standby keeps encrypting with them.
+    pub fn open_with_keys(
+        path: PathBuf,
+        poll_interval: Duration,
+        keys: Option<Keyring>,
+    ) -> Result<KvStoreStandby> {
+        let keys = manifest::check_keys(&path, keys.as_ref())?;
+        let mut standby = Standby::new(path, keys);
+        standby.catch_up()?;
+
+        let state = Arc::new(Mutex::new(Some(standby)));
+        let follower = Arc::downgrade(&state);
+
+        thread::spawn(move || loop {
+            thread::sleep(poll_interval);
+
+            let Some(state) = follower.upgrade() else {
+                return;
+            };
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            let Some(standby) = state.as_mut() else {
+                return;
+            };
+
+            // Errors are transient (e.g. a compaction in progress); retry on
+            // the next poll
+            let _ = standby.catch_up();
+        });
+
+        Ok(KvStoreStandby { state })
+    }
+
+    /// Apply any records flushed since the last poll.
+    pub fn catch_up(&mut self) -> Result<()> {
+        self.with_standby(Standby::catch_up)
+    }
+
+    /// Stop following and open the directory as a writable store, reusing the
+    /// already built keydir. Only call this once the primary has stopped.
+    pub fn promote(self) -> Result<KvStore> {
+        let mut standby = self
+            .lock()
+            .take()
+            .ok_or_else(|| KvStoreError::StringError("Standby was already promoted".into()))?;
+        standby.catch_up()?;
+
+        let last_log_gen = standby.indexed.keys().next_back().copied().unwrap_or(0);
+        let config = KvStoreConfig {
+            #[cfg(feature = "encryption")]
+            encryption: standby.keys,
+            ..KvStoreConfig::default()
+        };
+        let mut readers = Readers::new(config.max_open_logs, config.keys());
+        for (log_gen, reader) in standby.readers {
+            readers.insert(log_gen, &standby.path, reader);
+        }
+        let mut store = KvStore::from_index(
+            standby.path,
+            standby.keydir,
+            readers,
+            BTreeSet::new(),
+            last_log_gen,
+            standby.stale_logs_size,
+            config,
+        )?;
+        store.resume_sequence(standby.sequence);
+        Ok(store)
+    }
+
+    /// Scan the keys under `prefix` that also satisfy `matches`.
+    fn scan_matching(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        let lower = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+
+        let now = unix_millis();
+        self.with_standby(|standby| {
+            let mut entries = Vec::new();
+            for (key, log_pointer) in standby
+                .keydir
+                .range((lower, Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .filter(|(key, _)| matches(key))
+                .filter(|(_, log_pointer)| !log_pointer.is_expired(now))
+                .take(limit)
+            {
+                let value = standby
+                    .readers
+                    .get_mut(&log_pointer.log_gen)
+                    .ok_or(KvStoreError::MissingLogReader(log_pointer.log_gen))?
+                    .read_pointer(log_pointer)?;
+
+                if let Some(value) = value {
+                    entries.push((key.clone(), value));
+                }
+            }
+
+            Ok(entries)
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Standby>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn with_standby<T>(&mut self, f: impl FnOnce(&mut Standby) -> Result<T>) -> Result<T> {
+        match self.lock().as_mut() {
+            Some(standby) => f(standby),
+            None => Err(KvStoreError::StringError(
+                "Standby was already promoted".into(),
+            )),
+        }
+    }
+}
+
+impl KvsReader for KvStoreStandby {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>> {
+        self.with_standby(|standby| match standby.live_pointer(&key) {
+            Some(log_pointer) => standby
+                .readers
+                .get_mut(&log_pointer.log_gen)
+                .ok_or(KvStoreError::MissingLogReader(log_pointer.log_gen))?
+                .read_pointer(&log_pointer),
+            None => Ok(None),
+        })
+    }
+
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(prefix, start_after, limit, |_| true)
+    }
+
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.scan_matching(pattern.literal_prefix(), start_after, limit, |key| {
+            pattern.matches(key)
+        })
+    }
+
+    fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        self.with_standby(|standby| Ok(standby.live_pointer(key).is_some()))
+    }
+
+    fn has_applied(&mut self, position: LogPosition) -> Result<bool> {
+        self.with_standby(|standby| {
+            if !standby.has_applied(position) {
+                standby.catch_up()?;
+            }
+            Ok(standby.has_applied(position))
+        })
+    }
+
+    fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.with_standby(|standby| {
+            standby
+                .live_pointer(key)
+                .map(|log_pointer| log_pointer.ttl(unix_millis()))
+                .ok_or(KvStoreError::UnknownKeyError)
+        })
+    }
+}
@@ -1,9 +1,8 @@
-use std;
-
 use std::error::Error;
 use std::fmt;
 
 use std::io;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum KvStoreError {
@@ -14,6 +13,47 @@ pub enum KvStoreError {
     UnknownKeyError,
     /// An unexpected command in the store
     UnexpectedCommandType,
+    /// A write was attempted through a read-only handle
+    ReadOnly,
+    /// A log record is larger than the log format accepts
+    RecordTooLarge,
+    /// The keydir points into a log generation that has no open reader
+    MissingLogReader(u64),
+    /// A response would exceed the server's per-response byte limit
+    ResponseTooLarge(usize),
+    /// A standby has not caught up with the write a read was made after
+    ReplicaBehind,
+    /// A scan pattern could not be parsed
+    InvalidPattern(String),
+    /// A key has no soft-deleted value left to restore
+    NothingToRestore,
+    /// A server-side script failed to load or run
+    ScriptError(String),
+    /// A lock was renewed or released with a token whose lease has ended
+    LockNotHeld,
+    /// The connection's user may not send a message
+    PermissionDenied(String),
+    /// A key was written with no bytes
+    EmptyKey,
+    /// A key is longer than `MAX_KEY_LEN` bytes; holds its length
+    KeyTooLong(usize),
+    /// A client key starts with `RESERVED_KEY_PREFIX` on a server that keeps
+    /// the prefix for internal metadata
+    ReservedKey,
+    /// The engine is refusing writes until compaction catches up; holds how
+    /// long to wait before trying again
+    WriteStalled(Duration),
+    /// Opening a store with a keydir check found entries that don't match
+    /// the log records they point to; holds a report of them
+    InconsistentKeydir(String),
+    /// A server closed its engine to reopen it and couldn't open any
+    EngineClosed,
+    /// A write would take the keys under a prefix over their quota; holds
+    /// which quota
+    QuotaExceeded(String),
+    /// A store's keys are missing or invalid, or a sealed record failed to
+    /// decrypt
+    Encryption(String),
 }
 
 impl Error for KvStoreError {
@@ -46,6 +86,49 @@ impl fmt::Display for KvStoreError {
             Self::StringError(ref err) => err.fmt(f),
             Self::UnknownKeyError => write!(f, "Key not found"),
             Self::UnexpectedCommandType => write!(f, "Unexpected command"),
+            Self::ReadOnly => write!(f, "Store is read-only"),
+            Self::RecordTooLarge => write!(f, "Record exceeds the maximum log record size"),
+            Self::MissingLogReader(log_gen) => {
+                write!(f, "No reader for log generation {}", log_gen)
+            }
+            Self::ResponseTooLarge(limit) => {
+                write!(f, "Response exceeds the limit of {} bytes", limit)
+            }
+            Self::ReplicaBehind => write!(
+                f,
+                "Replica has not caught up with this session; read from the primary"
+            ),
+            Self::InvalidPattern(reason) => write!(f, "Invalid pattern {}", reason),
+            Self::NothingToRestore => write!(f, "No deleted value to restore"),
+            Self::ScriptError(err) => write!(f, "Script failed: {}", err),
+            Self::LockNotHeld => write!(f, "Lock is not held with this token"),
+            Self::PermissionDenied(reason) => write!(f, "Permission denied: {}", reason),
+            Self::EmptyKey => write!(f, "Keys must not be empty"),
+            Self::KeyTooLong(len) => write!(
+                f,
+                "Key of {} bytes exceeds the limit of {} bytes",
+                len,
+                crate::MAX_KEY_LEN
+            ),
+            Self::ReservedKey => write!(
+                f,
+                "Keys starting with {:?} are reserved for internal metadata",
+                String::from_utf8_lossy(crate::RESERVED_KEY_PREFIX)
+            ),
+            Self::WriteStalled(retry_after) => write!(
+                f,
+                "Writes are stalled until compaction catches up; retry in {} ms",
+                retry_after.as_millis()
+            ),
+            Self::EngineClosed => write!(
+                f,
+                "The engine is closed after a failed reopen; reopen it to serve again"
+            ),
+            Self::InconsistentKeydir(report) => {
+                write!(f, "Keydir disagrees with the logs: {}", report)
+            }
+            Self::QuotaExceeded(quota) => write!(f, "Quota exceeded: {}", quota),
+            Self::Encryption(reason) => write!(f, "Encryption error: {}", reason),
         }
     }
 }
@@ -0,0 +1,256 @@
+//! A C ABI for embedding `KvStore`, declared in `include/kvs.h`.
+//!
+//! Every function returns a `KvsStatus` code. On anything but `KVS_OK` or
+//! `KVS_NOT_FOUND`, `kvs_last_error` describes what went wrong.
+//!
+//! Ownership:
+//!
+//! - A store opened with `kvs_open` belongs to the caller until it is passed
+//!   to `kvs_close`, and must not be used from two threads at once.
+//! - Keys and values passed in are borrowed for the length of the call.
+//! - Values returned by `kvs_get` belong to the caller and must be freed with
+//!   `kvs_free_value`, not `free`.
+//! - The string returned by `kvs_last_error` belongs to the library and stays
+//!   valid until the next call on the same thread.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+use crate::{KvStore, KvStoreError, KvsEngine, KvsReader, KvsWriter};
+
+/// Outcome of a call through the C ABI.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvsStatus {
+    Ok = 0,
+    /// The key isn't in the store
+    NotFound = 1,
+    /// A null pointer, or a path or value that isn't UTF-8
+    InvalidArgument = 2,
+    /// The store's files couldn't be read or written
+    Io = 3,
+    /// Any other failure, including a panic inside the library
+    Error = 4,
+}
+
+/// An open store, opaque to C.
+pub struct KvsStore {
+    store: KvStore,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Messages can't carry NUL bytes into C
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn status_of(err: KvStoreError) -> KvsStatus {
+    let status = match err {
+        KvStoreError::UnknownKeyError => return KvsStatus::NotFound,
+        KvStoreError::IoErr(_) => KvsStatus::Io,
+        _ => KvsStatus::Error,
+    };
+    set_last_error(err.to_string());
+    status
+}
+
+fn invalid(message: &str) -> KvsStatus {
+    set_last_error(message.to_owned());
+    KvsStatus::InvalidArgument
+}
+
+// Run `f`, turning a panic into `KvsStatus::Error` rather than unwinding into
+// C
+fn guard(f: impl FnOnce() -> KvsStatus) -> KvsStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(_) => {
+            set_last_error("Panicked inside kvs".to_owned());
+            KvsStatus::Error
+        }
+    }
+}
+
+unsafe fn key_slice<'a>(key: *const u8, key_len: usize) -> Option<&'a [u8]> {
+    match key.is_null() {
+        true if key_len == 0 => Some(&[]),
+        true => None,
+        false => Some(slice::from_raw_parts(key, key_len)),
+    }
+}
+
+/// Open or create the store in the directory `path`, writing it to `*out`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `out` must be valid for a
+/// write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char, out: *mut *mut KvsStore) -> KvsStatus {
+    guard(|| {
+        if path.is_null() || out.is_null() {
+            return invalid("path and out must not be null");
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            return invalid("path must be UTF-8");
+        };
+
+        match KvStore::open(PathBuf::from(path)) {
+            Ok(store) => {
+                *out = Box::into_raw(Box::new(KvsStore { store }));
+                KvsStatus::Ok
+            }
+            Err(err) => status_of(err),
+        }
+    })
+}
+
+/// Look up `key`. On `KVS_OK`, `*value` points to the value followed by a
+/// NUL byte and `*value_len` holds its length without the NUL. On
+/// `KVS_NOT_FOUND`, `*value` is set to null.
+///
+/// # Safety
+///
+/// `store` must come from `kvs_open`, `key` must be valid for `key_len`
+/// bytes, and `value` and `value_len` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    store: *mut KvsStore,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut c_char,
+    value_len: *mut usize,
+) -> KvsStatus {
+    guard(|| {
+        let (Some(store), Some(key)) = (store.as_mut(), key_slice(key, key_len)) else {
+            return invalid("store and key must not be null");
+        };
+        if value.is_null() || value_len.is_null() {
+            return invalid("value and value_len must not be null");
+        }
+        *value = ptr::null_mut();
+
+        match store.store.get(key.to_vec()) {
+            Ok(Some(found)) => {
+                let len = found.len();
+                let mut bytes = found.into_bytes();
+                bytes.push(0);
+                *value = Box::into_raw(bytes.into_boxed_slice()) as *mut c_char;
+                *value_len = len;
+                KvsStatus::Ok
+            }
+            Ok(None) => KvsStatus::NotFound,
+            Err(err) => status_of(err),
+        }
+    })
+}
+
+/// Free a value returned by `kvs_get`. Does nothing given null.
+///
+/// # Safety
+///
+/// `value` and `value_len` must be exactly what `kvs_get` returned, and the
+/// value must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free_value(value: *mut c_char, value_len: usize) {
+    if !value.is_null() {
+        let bytes = ptr::slice_from_raw_parts_mut(value as *mut u8, value_len + 1);
+        drop(Box::from_raw(bytes));
+    }
+}
+
+/// Set `key` to the UTF-8 `value`.
+///
+/// # Safety
+///
+/// `store` must come from `kvs_open`, and `key` and `value` must be valid for
+/// `key_len` and `value_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    store: *mut KvsStore,
+    key: *const u8,
+    key_len: usize,
+    value: *const c_char,
+    value_len: usize,
+) -> KvsStatus {
+    guard(|| {
+        let (Some(store), Some(key), Some(value)) = (
+            store.as_mut(),
+            key_slice(key, key_len),
+            key_slice(value as *const u8, value_len),
+        ) else {
+            return invalid("store, key and value must not be null");
+        };
+        let Ok(value) = std::str::from_utf8(value) else {
+            return invalid("value must be UTF-8");
+        };
+
+        match store.store.set(key.to_vec(), value.to_owned()) {
+            Ok(()) => KvsStatus::Ok,
+            Err(err) => status_of(err),
+        }
+    })
+}
+
+/// Remove `key`, returning `KVS_NOT_FOUND` if it isn't set.
+///
+/// # Safety
+///
+/// `store` must come from `kvs_open` and `key` must be valid for `key_len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(
+    store: *mut KvsStore,
+    key: *const u8,
+    key_len: usize,
+) -> KvsStatus {
+    guard(|| {
+        let (Some(store), Some(key)) = (store.as_mut(), key_slice(key, key_len)) else {
+            return invalid("store and key must not be null");
+        };
+
+        match store.store.remove(key.to_vec()) {
+            Ok(()) => KvsStatus::Ok,
+            Err(err) => status_of(err),
+        }
+    })
+}
+
+/// Flush and close the store. Does nothing given null.
+///
+/// # Safety
+///
+/// `store` must come from `kvs_open` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(store: *mut KvsStore) -> KvsStatus {
+    guard(|| {
+        if store.is_null() {
+            return KvsStatus::Ok;
+        }
+        let mut store = Box::from_raw(store);
+
+        match store.store.flush() {
+            Ok(()) => KvsStatus::Ok,
+            Err(err) => status_of(err.into()),
+        }
+    })
+}
+
+/// The message of the last failed call on this thread, or null.
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
@@ -0,0 +1,37 @@
+//! Entry points for the targets in `fuzz/`. Each one feeds arbitrary bytes
+//! through a parsing path exactly as the engine or server would and must
+//! return without panicking. Not part of the stable API.
+
+use crate::logs::{read_set_value, LogIterator};
+
+/// Index `data` as if it were the contents of a log file.
+pub fn index_log(data: &[u8]) {
+    for record in LogIterator::from_reader(0, data) {
+        if record.is_err() {
+            break;
+        }
+    }
+}
+
+/// Decode `data` as the record behind a keydir pointer.
+pub fn read_record(data: &[u8]) {
+    let _ = read_set_value(data);
+}
+
+/// Decode `data` as a stream of client messages, as the server does.
+#[cfg(feature = "net")]
+pub fn read_messages(data: &[u8]) {
+    let messages =
+        serde_json::Deserializer::from_slice(data).into_iter::<crate::protocol::Message>();
+    for message in messages {
+        if message.is_err() {
+            break;
+        }
+    }
+}
+
+/// Decode `data` as one server response, as the client does.
+#[cfg(feature = "net")]
+pub fn read_response(data: &[u8]) {
+    let _ = serde_json::from_slice::<crate::protocol::Response>(data);
+}
@@ -0,0 +1,134 @@
+use crate::{KvStoreError, Result};
+
+/// A glob pattern over keys, in the style of Redis's `KEYS`/`SCAN MATCH`:
+/// `*` matches any run of bytes, `?` any single byte, `[abc]`, `[a-z]` and
+/// `[^a]` match a byte in or out of a set, and `\` makes the next byte
+/// literal.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    prefix: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Byte(u8),
+    AnyByte,
+    AnyRun,
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob> {
+        let invalid =
+            |reason: &str| KvStoreError::InvalidPattern(format!("{:?}: {}", pattern, reason));
+        let mut bytes = pattern.bytes();
+        let mut tokens = Vec::new();
+
+        while let Some(byte) = bytes.next() {
+            let token = match byte {
+                b'*' => Token::AnyRun,
+                b'?' => Token::AnyByte,
+                b'\\' => Token::Byte(bytes.next().ok_or_else(|| invalid("trailing escape"))?),
+                b'[' => {
+                    let mut negated = false;
+                    let mut ranges = Vec::new();
+                    let mut closed = false;
+
+                    while let Some(byte) = bytes.next() {
+                        let low = match byte {
+                            b'^' | b'!' if ranges.is_empty() && !negated => {
+                                negated = true;
+                                continue;
+                            }
+                            b']' if !ranges.is_empty() => {
+                                closed = true;
+                                break;
+                            }
+                            b'\\' => bytes.next().ok_or_else(|| invalid("trailing escape"))?,
+                            byte => byte,
+                        };
+
+                        // A range, unless the `-` is the last byte of the class
+                        let mut lookahead = bytes.clone();
+                        let high = match (lookahead.next(), lookahead.next()) {
+                            (Some(b'-'), Some(high)) if high != b']' => {
+                                bytes = lookahead;
+                                high
+                            }
+                            _ => low,
+                        };
+                        ranges.push((low.min(high), low.max(high)));
+                    }
+
+                    if !closed {
+                        return Err(invalid("unclosed ["));
+                    }
+                    Token::Class { negated, ranges }
+                }
+                byte => Token::Byte(byte),
+            };
+            tokens.push(token);
+        }
+
+        let prefix = tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Byte(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Glob { tokens, prefix })
+    }
+
+    /// The literal bytes every matching key starts with, so a scan only has
+    /// to visit that range of the keyspace.
+    pub fn literal_prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    pub fn matches(&self, key: &[u8]) -> bool {
+        let (mut token, mut pos) = (0, 0);
+        // Where to resume if the bytes after the last `*` stop matching
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while pos < key.len() {
+            let matched = match self.tokens.get(token) {
+                Some(Token::AnyRun) => {
+                    backtrack = Some((token, pos));
+                    token += 1;
+                    continue;
+                }
+                Some(Token::Byte(byte)) => *byte == key[pos],
+                Some(Token::AnyByte) => true,
+                Some(Token::Class { negated, ranges }) => {
+                    let in_class = ranges
+                        .iter()
+                        .any(|&(low, high)| low <= key[pos] && key[pos] <= high);
+                    in_class != *negated
+                }
+                None => false,
+            };
+
+            if matched {
+                token += 1;
+                pos += 1;
+            } else if let Some((star, star_pos)) = backtrack {
+                // Let the `*` swallow one more byte and retry
+                token = star + 1;
+                pos = star_pos + 1;
+                backtrack = Some((star, star_pos + 1));
+            } else {
+                return false;
+            }
+        }
+
+        self.tokens[token..]
+            .iter()
+            .all(|token| matches!(token, Token::AnyRun))
+    }
+}
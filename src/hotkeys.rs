@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::logs::unix_millis;
+
+/// How often a key was read, as estimated from the sampled reads.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    #[serde(with = "crate::encoding")]
+    pub key: Vec<u8>,
+    /// Estimated reads since the key was first sampled: the sampled reads
+    /// times the sampling rate
+    pub reads: u64,
+    /// Unix time in milliseconds of the key's last sampled read
+    pub last_read_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeyStats {
+    sampled: u64,
+    last_read_ms: u64,
+}
+
+/// Read counts of the keys read most across all connections, from one read
+/// in `sample_rate`, kept for at most `max_len` keys.
+pub(crate) struct HotKeys {
+    sample_rate: u32,
+    max_len: usize,
+    keys: HashMap<Vec<u8>, KeyStats>,
+}
+
+impl HotKeys {
+    pub fn new(sample_rate: u32, max_len: usize) -> HotKeys {
+        HotKeys {
+            sample_rate,
+            max_len,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample_rate > 0 && self.max_len > 0
+    }
+
+    /// Count a read of `key` if it is sampled. A new key replaces the least
+    /// read one once `max_len` keys are tracked, inheriting its count, so a
+    /// key that turns hot later still rises to the top.
+    pub fn record(&mut self, key: &[u8]) {
+        if !self.is_enabled() || !rand::thread_rng().gen_ratio(1, self.sample_rate) {
+            return;
+        }
+
+        let last_read_ms = unix_millis();
+        if let Some(stats) = self.keys.get_mut(key) {
+            stats.sampled += 1;
+            stats.last_read_ms = last_read_ms;
+            return;
+        }
+
+        let mut sampled = 1;
+        if self.keys.len() == self.max_len {
+            let coldest = self
+                .keys
+                .iter()
+                .min_by_key(|(_, stats)| stats.sampled)
+                .map(|(key, stats)| (key.clone(), stats.sampled));
+            if let Some((coldest, coldest_sampled)) = coldest {
+                self.keys.remove(&coldest);
+                sampled += coldest_sampled;
+            }
+        }
+        self.keys.insert(
+            key.to_vec(),
+            KeyStats {
+                sampled,
+                last_read_ms,
+            },
+        );
+    }
+
+    /// Up to `count` of the most read keys, most first.
+    pub fn top(&self, count: usize) -> Vec<HotKey> {
+        let mut top: Vec<HotKey> = self
+            .keys
+            .iter()
+            .map(|(key, stats)| HotKey {
+                key: key.clone(),
+                reads: stats.sampled * self.sample_rate as u64,
+                last_read_ms: stats.last_read_ms,
+            })
+            .collect();
+        top.sort_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.key.cmp(&b.key)));
+        top.truncate(count);
+        top
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::protocol::Response;
+
+// An idempotency key, scoped to the user that sent it so one user can't be
+// answered with another's responses
+type ScopedKey = (Option<String>, String);
+
+/// Responses to the most recent idempotent messages across all connections,
+/// so a retry of one can be answered without running it again.
+pub(crate) struct IdempotencyWindow {
+    max_len: usize,
+    responses: HashMap<ScopedKey, Response>,
+    // Keys in the order they were recorded, oldest first
+    order: VecDeque<ScopedKey>,
+}
+
+impl IdempotencyWindow {
+    pub fn new(max_len: usize) -> IdempotencyWindow {
+        IdempotencyWindow {
+            max_len,
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The response recorded for `key` sent by `user`, if it is still
+    /// remembered.
+    pub fn get(&self, user: Option<&str>, key: &str) -> Option<&Response> {
+        self.responses
+            .get(&(user.map(str::to_owned), key.to_owned()))
+    }
+
+    /// Remember `response` for `key` sent by `user`, forgetting the oldest
+    /// key when the window is full.
+    pub fn record(&mut self, user: Option<String>, key: String, response: Response) {
+        if self.max_len == 0 {
+            return;
+        }
+
+        if self.order.len() == self.max_len {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+
+        let key = (user, key);
+        self.order.push_back(key.clone());
+        self.responses.insert(key, response);
+    }
+}
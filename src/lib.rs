@@ -1,13 +1,106 @@
 // #![deny(missing_docs)]
 //! This is documentation for the `kv` crate.
+//!
+//! Only the log-structured `KvStore` engine is always built. The `sled`
+//! feature adds `SledKvsEngine`, `net` adds `KvsClient`/`KvsServer` and their
+//! wire `protocol`, and `cli` builds the `kvs-client` and `kvs-server`
+//! binaries. All are on by default. Engine and server work is recorded as
+//! `tracing` spans; the opt-in `otlp` feature lets `kvs-server` export them,
+//! and the opt-in `scripting` feature lets it run Lua scripts. The opt-in
+//! `encryption` feature encrypts records at rest. The `test-util` feature
+//! adds `test_util`, a conformance suite for `KvsEngine` implementations.
 
+#[cfg(feature = "net")]
+mod acl;
+#[cfg(feature = "admin-ui")]
+mod admin;
+mod analyze;
+mod buffer_pool;
+#[cfg(feature = "net")]
+mod chaos;
+#[cfg(feature = "net")]
 mod client;
-mod codec;
+mod encoding;
+mod encryption;
 mod engines;
 mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod glob;
+#[cfg(feature = "net")]
+mod hotkeys;
+#[cfg(feature = "net")]
+mod idempotency;
+mod lock;
+pub mod log_inspect;
+#[cfg(feature = "net")]
+mod log_level;
 mod logs;
+#[cfg(feature = "net")]
+pub mod protocol;
+#[cfg(feature = "net")]
+mod pubsub;
+mod queue;
+#[cfg(feature = "net")]
+mod quota;
+mod rates;
+#[cfg(feature = "scripting")]
+mod scripting;
+#[cfg(feature = "net")]
 mod server;
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+#[cfg(feature = "net")]
+mod slowlog;
+mod sync;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod txn;
+#[cfg(feature = "net")]
+mod typed;
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "net")]
+pub use acl::{Acl, Permission, User};
+pub use analyze::{analyze, Bucket, KeyspaceSample, PrefixStats};
+pub use buffer_pool::BufferPoolStats;
+#[cfg(feature = "net")]
+pub use chaos::Chaos;
+#[cfg(feature = "net")]
+pub use client::{KvsClient, KvsClientBuilder, Scan, Subscription};
+pub use encryption::Keyring;
+pub use engines::{
+    check_logs, read_log, repair_logs, validate_key, CompactionStats, CompactionTransform,
+    CompactionWindow, EngineMetrics, IndexState, KeydirCheck, KvStore, KvStoreConfig,
+    KvStoreSnapshot, KvStoreStandby, KvsEngine, KvsReader, KvsWriter, LogCheck, LogPosition,
+    LogRecord, LogsCheck, MemoryUsage, Metrics, NegativeCaching, ReadPathStats, RemoteTier,
+    SizeHistogram, StoreEvent, VersionRetention, WriteBatch, MAX_KEY_LEN, RESERVED_KEY_PREFIX,
+};
+#[cfg(feature = "async")]
+pub use engines::{AsyncKvsEngine, BlockingEngine};
+#[cfg(feature = "sled")]
+pub use engines::{SledConfig, SledKvsEngine, SledMode};
 pub use error::{KvStoreError, Result};
-pub use server::KvsServer;
+pub use glob::Glob;
+#[cfg(feature = "net")]
+pub use hotkeys::HotKey;
+pub use lock::Lock;
+#[cfg(feature = "net")]
+pub use log_level::{LevelFilter, LogLevel};
+pub use logs::LogEncoding;
+pub use queue::{Queue, QueueItem};
+#[cfg(feature = "net")]
+pub use quota::{Quota, QuotaUsage};
+pub use rates::OperationRates;
+#[cfg(feature = "net")]
+pub use server::{EngineOpener, KvsServer, ServerConfig, StopHandle};
+#[cfg(feature = "net")]
+pub use slowlog::SlowLogEntry;
+pub use sync::{
+    digest, diverging_leaves, sync, MerkleTree, PrefixDigest, SyncPeer, SyncReport,
+    MAX_MERKLE_DEPTH,
+};
+pub use txn::{Compare, Txn, TxnOp, TxnResponse, TxnResult};
+#[cfg(feature = "net")]
+pub use typed::TypedClient;
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use crate::{KvStoreError, KvsEngine, Result, RESERVED_KEY_PREFIX};
+
+/// A lease on a name, stored as ordinary keys of an engine. At most one
+/// holder has a live lease at a time; a lease that isn't renewed expires
+/// after its TTL, so a holder that dies doesn't keep the lock forever.
+///
+/// Each acquire hands out a fencing token larger than any before it for the
+/// same lock. A holder can't know for sure its lease hasn't lapsed, so
+/// whatever the lock protects should reject writes carrying a token lower
+/// than one it has already seen.
+///
+/// A lock's keys start with `RESERVED_KEY_PREFIX`, so on a server started
+/// with `reserve_internal_keys` only its lock messages can write them, and
+/// scans leave them out.
+#[derive(Debug, Clone)]
+pub struct Lock {
+    /// Holds the token of the live lease, expiring with it
+    lease_key: Vec<u8>,
+    /// Holds the last token handed out, so tokens keep growing across leases
+    fence_key: Vec<u8>,
+}
+
+impl Lock {
+    pub fn new(name: &str) -> Lock {
+        let mut prefix = RESERVED_KEY_PREFIX.to_vec();
+        prefix.extend_from_slice(b"\0lock\0");
+        prefix.extend_from_slice(name.as_bytes());
+        prefix.push(0);
+
+        let mut lease_key = prefix.clone();
+        lease_key.extend_from_slice(b"lease");
+        let mut fence_key = prefix;
+        fence_key.extend_from_slice(b"fence");
+
+        Lock {
+            lease_key,
+            fence_key,
+        }
+    }
+
+    /// Take the lock for `ttl`, returning its fencing token, or `None` if
+    /// someone else holds a live lease.
+    pub fn acquire(&self, engine: &mut dyn KvsEngine, ttl: Duration) -> Result<Option<u64>> {
+        if engine.contains(&self.lease_key)? {
+            return Ok(None);
+        }
+
+        let fence = match engine.get(self.fence_key.clone())? {
+            Some(fence) => fence
+                .parse()
+                .map_err(|_| KvStoreError::StringError("Corrupt lock fence".to_owned()))?,
+            None => 0u64,
+        };
+        let token = fence
+            .checked_add(1)
+            .ok_or_else(|| KvStoreError::StringError("Lock fence exhausted".to_owned()))?;
+        engine.set(self.fence_key.clone(), token.to_string())?;
+        engine.set_with_ttl(self.lease_key.clone(), token.to_string(), ttl)?;
+
+        Ok(Some(token))
+    }
+
+    /// Extend the lease held with `token` to expire `ttl` from now.
+    pub fn renew(&self, engine: &mut dyn KvsEngine, token: u64, ttl: Duration) -> Result<()> {
+        self.check_held(engine, token)?;
+        engine.expire(self.lease_key.clone(), ttl)
+    }
+
+    /// Give up the lease held with `token`.
+    pub fn release(&self, engine: &mut dyn KvsEngine, token: u64) -> Result<()> {
+        self.check_held(engine, token)?;
+        engine.remove(self.lease_key.clone())
+    }
+
+    fn check_held(&self, engine: &mut dyn KvsEngine, token: u64) -> Result<()> {
+        match engine.get(self.lease_key.clone())? {
+            Some(holder) if holder == token.to_string() => Ok(()),
+            _ => Err(KvStoreError::LockNotHeld),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Read-only access to the raw records of a `KvStore`'s logs, for tools
+//! such as ETL jobs and audits that read a data directory without opening
+//! the store.
+//!
+//! `open` lists the logs of a directory, oldest first, and iterates over
+//! every record in them as stored: overwritten and removed keys, old
+//! versions and batch headers included. Nothing in the directory is
+//! written or locked, so a running server can keep using it; records it
+//! appends after a log has been read past are missed, and the end of a log
+//! it is writing may read as a torn record. Compaction deleting a log
+//! before it is reached fails the iteration.
+//!
+//! Records carry no checksum of their own. A record whose bytes can't be
+//! decoded, whose seal fails to authenticate, or that is plain where an
+//! encrypted store only has sealed records, has `checksum_ok` false and no
+//! command. Plain records are found by parsing, so the first one
+//! that can't be parsed ends its log: its `len` covers the rest of the
+//! file.
+//!
+//! ```no_run
+//! use kvs::log_inspect::{self, Command};
+//! use std::path::Path;
+//!
+//! # fn main() -> kvs::Result<()> {
+//! for record in log_inspect::open(Path::new("data"), None, None)? {
+//!     let record = record?;
+//!     if let Some(Command::Set { key, value, .. }) = &record.command {
+//!         println!("{}@{}: {:?} = {:?}", record.log_gen, record.offset, key, value);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::encryption::Location;
+use crate::engines::{check_keys, sorted_log_gens};
+pub use crate::logs::Command;
+use crate::logs::{log_path, LogIterator};
+use crate::{Keyring, KvStoreError, Result};
+
+/// One record of a log, as it is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawRecord {
+    /// Generation of the log the record is in
+    pub log_gen: u64,
+    /// Byte offset of the record in its log
+    pub offset: u64,
+    /// Encoded length in bytes
+    pub len: u64,
+    /// What the record does, `None` if it couldn't be read
+    pub command: Option<Command>,
+    /// Whether the record decoded, and authenticated if it was sealed
+    pub checksum_ok: bool,
+}
+
+/// The records of a directory's logs, oldest log first, from `open`.
+pub struct LogRecords {
+    // Logs not reached yet, with the path of each
+    logs: VecDeque<(u64, PathBuf)>,
+    log: Option<OpenLog>,
+    keys: Option<Keyring>,
+}
+
+struct OpenLog {
+    log_gen: u64,
+    // Length of the file when it was opened
+    len: u64,
+    records: LogIterator<BufReader<File>>,
+}
+
+/// List the logs of the `KvStore` directory at `path` to read their records.
+/// Pass the store's `cold_dir`, if it has one, so logs moved there are read
+/// too, and its keys if it is encrypted.
+pub fn open(path: &Path, cold_dir: Option<&Path>, keys: Option<&Keyring>) -> Result<LogRecords> {
+    // Without its keys every sealed record would look corrupt
+    let keys = check_keys(path, keys)?;
+
+    let mut logs: Vec<(u64, PathBuf)> = Vec::new();
+    for dir in std::iter::once(path).chain(cold_dir) {
+        for log_gen in sorted_log_gens(&dir.to_path_buf())? {
+            logs.push((log_gen, log_path(dir, log_gen)));
+        }
+    }
+    logs.sort_unstable();
+    logs.dedup_by_key(|(log_gen, _)| *log_gen);
+
+    Ok(LogRecords {
+        logs: logs.into(),
+        log: None,
+        keys,
+    })
+}
+
+impl Iterator for LogRecords {
+    type Item = Result<RawRecord>;
+
+    /// The next record. An error reading the disk is returned as an error,
+    /// after which the rest of that log is skipped.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let log = match &mut self.log {
+                Some(log) => log,
+                None => {
+                    let (log_gen, path) = self.logs.pop_front()?;
+                    let log = File::open(&path).and_then(|file| {
+                        Ok(OpenLog {
+                            log_gen,
+                            len: file.metadata()?.len(),
+                            records: LogIterator::from_reader(log_gen, BufReader::new(file)),
+                        })
+                    });
+                    match log {
+                        Ok(log) => self.log.insert(log),
+                        Err(err) => return Some(Err(KvStoreError::IoErr(err))),
+                    }
+                }
+            };
+
+            let log_gen = log.log_gen;
+            let Some((offset, len, stored)) = log.records.read_stored() else {
+                self.log = None;
+                continue;
+            };
+            let record = match stored {
+                Ok(stored) => {
+                    let location = Location::Log { log_gen, offset };
+                    let command = stored.open(self.keys.as_ref(), location).ok();
+                    RawRecord {
+                        log_gen,
+                        offset,
+                        len,
+                        checksum_ok: command.is_some(),
+                        command,
+                    }
+                }
+                Err(err) if err.is_io() => {
+                    self.log = None;
+                    return Some(Err(KvStoreError::SerdeErr(err)));
+                }
+                Err(_) => {
+                    let len = log.len.saturating_sub(offset);
+                    self.log = None;
+                    RawRecord {
+                        log_gen,
+                        offset,
+                        len,
+                        command: None,
+                        checksum_ok: false,
+                    }
+                }
+            };
+            return Some(Ok(record));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use slog::{Drain, Level, OwnedKVList, Record};
+
+/// A log level that can be changed while the server runs. Clones share the
+/// level, so a `KvsServer` given one with `with_log_level` changes what the
+/// drain made by `filter` lets through.
+#[derive(Debug, Clone)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+/// A drain passing on only the records at or above a `LogLevel`.
+#[derive(Debug)]
+pub struct LevelFilter<D> {
+    drain: D,
+    level: LogLevel,
+}
+
+impl LogLevel {
+    pub fn new(level: Level) -> LogLevel {
+        LogLevel(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    /// Move to the next more verbose level, from trace back round to error,
+    /// and return it.
+    pub fn step(&self) -> Level {
+        let level = match self.get() {
+            Level::Critical | Level::Error => Level::Warning,
+            Level::Warning => Level::Info,
+            Level::Info => Level::Debug,
+            Level::Debug => Level::Trace,
+            Level::Trace => Level::Error,
+        };
+        self.set(level);
+        level
+    }
+
+    /// Filter `drain` by this level.
+    pub fn filter<D: Drain>(&self, drain: D) -> LevelFilter<D> {
+        LevelFilter {
+            drain,
+            level: self.clone(),
+        }
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -1,30 +1,175 @@
+use serde_json::value::RawValue;
 use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
 
+use crate::encryption::{self, Keyring, Location, Stored};
 use crate::{KvStoreError, Result};
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, SeekFrom, Write};
 use std::io::{Read, Seek};
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Largest encoded record accepted in a log, so a corrupt pointer or a garbage
+// file can't make the parser buffer without bound
+pub const MAX_RECORD_LEN: u64 = 64 * 1024 * 1024;
+
+/// How a store lays out its log records. Both are JSON, and either reads
+/// back the same way; a store sticks to the one it was created with, as
+/// recorded in its manifest, so its files stay uniform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogEncoding {
+    /// Records back to back, without whitespace
+    #[default]
+    Compact,
+    /// Each record on a line of its own, for grepping while debugging
+    Lines,
+}
+
+impl LogEncoding {
+    /// Encode one record to be written at `offset` in log `log_gen`, sealed
+    /// if there are `keys`. In `Lines` mode the newline comes before the
+    /// record, so each record's bytes run from one record's end to the next
+    /// and pointers read back the same way in either mode.
+    pub(crate) fn encode(
+        self,
+        cmd: &Command,
+        keys: Option<&Keyring>,
+        log_gen: u64,
+        offset: u64,
+    ) -> Result<Vec<u8>> {
+        let location = Location::Log { log_gen, offset };
+        let mut bytes = encryption::seal_json(keys, cmd, location)?;
+        if self == LogEncoding::Lines {
+            bytes.insert(0, b'\n');
+        }
+        if bytes.len() as u64 > MAX_RECORD_LEN {
+            return Err(KvStoreError::RecordTooLarge);
+        }
+        Ok(bytes)
+    }
+}
+
+/// A record of a log, as `log_inspect` reads it. More kinds of record, and
+/// more fields, may be added.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum Command {
     /// Set a key to a value
+    #[non_exhaustive]
     Set {
-        key: String,
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
         value: String,
+        /// Unix time in milliseconds after which the key reads as absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// Unix time in milliseconds the value was written, moved past the
+        /// key's previous version if needed so each is unique. Only written
+        /// while the store keeps old versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+        /// Sequence number of the mutation, one higher than the store's
+        /// previous one. Missing from records older than sequence numbers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
+    /// Remove a key
+    #[non_exhaustive]
     Remove {
-        key: String,
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// Unix time in milliseconds of a soft delete, after which the
+        /// previous value can still be restored for a while
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        removed_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
+    /// Header of a batch: the next `ops` records were written together, and
+    /// are read back only if all of them made it to the log
+    Batch { ops: usize },
 }
 
-#[derive(Debug)]
+// A record read only as far as the encoded value of a set
+#[derive(Deserialize)]
+enum RawCommand<'a> {
+    Set {
+        #[serde(borrow)]
+        value: &'a RawValue,
+    },
+    Remove(IgnoredAny),
+    Batch(IgnoredAny),
+}
+
+impl Command {
+    /// Pointer to this record, written at `pos` in log `log_gen`.
+    pub(crate) fn pointer(&self, log_gen: u64, pos: u64, len: u64) -> LogPointer {
+        let (expires_at, version, seq) = match *self {
+            Command::Set {
+                expires_at,
+                version,
+                seq,
+                ..
+            } => (expires_at, version, seq),
+            Command::Remove { seq, .. } => (None, None, seq),
+            Command::Batch { .. } => (None, None, None),
+        };
+
+        LogPointer {
+            log_gen,
+            pos,
+            len,
+            expires_at,
+            version,
+            seq,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct LogPointer {
     pub log_gen: u64,
     pub pos: u64,
     pub len: u64,
+    /// Expiry of the set record pointed to, kept here so lookups can skip
+    /// expired keys without reading the log
+    pub expires_at: Option<u64>,
+    /// Version of the set record pointed to, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Sequence number of the record pointed to, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl LogPointer {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Time left before the key expires, if it has an expiry.
+    pub fn ttl(&self, now: u64) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now)))
+    }
+}
+
+/// Current Unix time in milliseconds, the clock used for key expiry.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Expiry timestamp for a key given `ttl` from now.
+pub fn expiry_after(ttl: Duration) -> u64 {
+    unix_millis().saturating_add(ttl.as_millis() as u64)
 }
 
 pub fn log_path(dir: &Path, gen: u64) -> PathBuf {
@@ -35,117 +180,508 @@ pub fn log_path(dir: &Path, gen: u64) -> PathBuf {
 pub struct LogReader {
     log_gen: u64,
     reader: BufReader<File>,
+    keys: Option<Keyring>,
+    // Where the last record read ended, if nothing has moved the file since
+    read_end: Option<u64>,
+    last_read: Option<RecordRead>,
+}
+
+/// What reading the last record took, split between the disk and decoding.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordRead {
+    pub(crate) len: u64,
+    /// Whether the read started somewhere other than where the last one ended
+    pub(crate) seeked: bool,
+    pub(crate) disk: Duration,
+    /// Decrypting as well as decoding
+    pub(crate) decode: Duration,
 }
 
 impl LogReader {
-    pub fn new(path: &Path, log_gen: u64) -> Result<LogReader> {
-        let log_file_path = log_path(&path, log_gen);
+    /// Open a log to read, decrypting sealed records with `keys`.
+    pub fn new(path: &Path, log_gen: u64, keys: Option<&Keyring>) -> Result<LogReader> {
+        let log_file_path = log_path(path, log_gen);
         let file = File::open(log_file_path)?;
 
-        return Ok(LogReader {
+        Ok(LogReader {
             log_gen,
             reader: BufReader::new(file),
-        });
+            keys: keys.cloned(),
+            read_end: None,
+            last_read: None,
+        })
     }
 
     pub fn read_pointer(&mut self, log_pointer: &LogPointer) -> Result<Option<String>> {
-        let pos = log_pointer.pos;
-        let len = log_pointer.len;
+        if log_pointer.len > MAX_RECORD_LEN {
+            return Err(KvStoreError::RecordTooLarge);
+        }
+
+        let record = self.read_plain(log_pointer)?;
+        self.decoding(|| read_set_value(&record[..]))
+    }
+
+    /// How long the last record read took, if it was read in full.
+    pub(crate) fn take_last_read(&mut self) -> Option<RecordRead> {
+        self.last_read.take()
+    }
+
+    // The record at `log_pointer` as plain JSON, decrypted if it was sealed
+    fn read_plain(&mut self, log_pointer: &LogPointer) -> Result<Vec<u8>> {
+        self.last_read = None;
+        let started = Instant::now();
+        let seeked = self.read_end != Some(log_pointer.pos);
+        self.read_end = None;
+        let mut record = vec![0; log_pointer.len as usize];
+        self.reader.seek(SeekFrom::Start(log_pointer.pos))?;
+        self.reader.read_exact(&mut record)?;
+        self.read_end = Some(log_pointer.pos + log_pointer.len);
+        let disk = started.elapsed();
+
+        let started = Instant::now();
+        let location = Location::Log {
+            log_gen: self.log_gen,
+            offset: log_pointer.pos,
+        };
+        let record = encryption::unseal_bytes(self.keys.as_ref(), record, location)?;
+        self.last_read = Some(RecordRead {
+            len: log_pointer.len,
+            seeked,
+            disk,
+            decode: started.elapsed(),
+        });
+        Ok(record)
+    }
+
+    // Run `decode` on the record just read, counting the time it takes
+    fn decoding<T>(&mut self, decode: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let decoded = decode();
+        if let Some(read) = &mut self.last_read {
+            read.decode += started.elapsed();
+        }
+        decoded
+    }
+
+    /// Read the record at `log_pointer`, which must be a set, and return its
+    /// value still encoded as a JSON string, without decoding it.
+    pub fn read_raw_value(&mut self, log_pointer: &LogPointer) -> Result<Vec<u8>> {
+        if log_pointer.len > MAX_RECORD_LEN {
+            return Err(KvStoreError::RecordTooLarge);
+        }
+
+        let mut record = self.read_plain(log_pointer)?;
+        let range = self.decoding(|| match serde_json::from_slice(&record)? {
+            RawCommand::Set { value } => {
+                let start = value.get().as_ptr() as usize - record.as_ptr() as usize;
+                Ok(start..start + value.get().len())
+            }
+            RawCommand::Remove(_) | RawCommand::Batch(_) => {
+                Err(KvStoreError::UnexpectedCommandType)
+            }
+        })?;
+        // Cut the value out in place rather than copying it
+        record.truncate(range.end);
+        record.drain(..range.start);
+
+        Ok(record)
+    }
+
+    /// Read the decoded bytes of the value of the set of `key` at
+    /// `log_pointer` from byte `offset`, stopping once `len` bytes are in
+    /// hand, so the head of a large value is read without the rest. Returns
+    /// `None` if the record isn't one written in plain JSON for `key`, such
+    /// as a sealed one, which must be read whole instead, and the number of
+    /// bytes of the log read either way.
+    pub(crate) fn read_value_range(
+        &mut self,
+        log_pointer: &LogPointer,
+        key: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        self.last_read = None;
+        self.read_end = None;
+        let location = Location::Log {
+            log_gen: self.log_gen,
+            offset: log_pointer.pos,
+        };
+        if encryption::check_plain(self.keys.as_ref(), location).is_err() {
+            return Ok((None, 0));
+        }
+        self.reader.seek(SeekFrom::Start(log_pointer.pos))?;
+        let mut record = (&mut self.reader).take(log_pointer.len);
+
+        // Everything up to the value's opening quote, as this key's set
+        // record would be written
+        let mut head = serde_json::to_vec(&Command::Set {
+            key: key.to_vec(),
+            value: String::new(),
+            expires_at: None,
+            version: None,
+            seq: None,
+        })?;
+        head.truncate(head.len() - br#""}}"#.len());
+        let mut found = Vec::with_capacity(head.len());
+        for byte in record.by_ref().bytes() {
+            let byte = byte?;
+            // `Lines` puts a newline before the record
+            if found.is_empty() && byte.is_ascii_whitespace() {
+                continue;
+            }
+            found.push(byte);
+            if found.len() == head.len() {
+                break;
+            }
+        }
+        if found != head {
+            return Ok((None, log_pointer.len - record.limit()));
+        }
+
+        let mut range = Vec::new();
+        let mut at = 0;
+        let decoded = decode_json_str(&mut record, |byte| {
+            if at >= offset {
+                range.push(byte);
+            }
+            at += 1;
+            range.len() < len
+        })?;
+        let read = log_pointer.len - record.limit();
+        Ok((decoded.then_some(range), read))
+    }
+
+    /// Read and decode the whole record at `log_pointer`, failing if its
+    /// bytes don't hold exactly one record.
+    pub fn read_record(&mut self, log_pointer: &LogPointer) -> Result<Command> {
+        if log_pointer.len > MAX_RECORD_LEN {
+            return Err(KvStoreError::RecordTooLarge);
+        }
 
-        let reader = &mut self.reader;
-        reader.seek(SeekFrom::Start(pos))?;
+        let record = self.read_plain(log_pointer)?;
+        self.decoding(|| Ok(serde_json::from_slice(&record)?))
+    }
+
+    /// Size of the log file in bytes.
+    pub fn file_len(&self) -> Result<u64> {
+        Ok(self.reader.get_ref().metadata()?.len())
+    }
 
-        let cmd_reader = reader.take(len);
+    /// Iterate over the records that start at or after byte `offset`.
+    pub fn iter_from(&mut self, offset: u64) -> Result<LogIterator<&mut BufReader<File>>> {
+        self.read_end = None;
+        self.reader.seek(SeekFrom::Start(offset))?;
 
-        if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-            Ok(Some(value))
-        } else {
-            Err(KvStoreError::UnexpectedCommandType)
+        let mut iter =
+            LogIterator::from_reader(self.log_gen, &mut self.reader).decrypting(self.keys.as_ref());
+        iter.start = offset;
+        Ok(iter)
+    }
+}
+
+// Decode the JSON string `reader` is inside of, passing each byte of it to
+// `byte` until the closing quote or until `byte` returns false. Returns
+// whether the string decoded that far.
+fn decode_json_str(reader: impl BufRead, mut byte: impl FnMut(u8) -> bool) -> Result<bool> {
+    let mut bytes = reader.bytes();
+    let mut next = move || bytes.next().transpose();
+    loop {
+        let ch = match next()? {
+            None => return Ok(false),
+            Some(b'"') => return Ok(true),
+            Some(b'\\') => match next()? {
+                Some(b'"') => '"',
+                Some(b'\\') => '\\',
+                Some(b'/') => '/',
+                Some(b'b') => '\u{8}',
+                Some(b'f') => '\u{c}',
+                Some(b'n') => '\n',
+                Some(b'r') => '\r',
+                Some(b't') => '\t',
+                Some(b'u') => {
+                    let Some(mut code) = hex4(&mut next)? else {
+                        return Ok(false);
+                    };
+                    // A character outside the basic plane comes as a pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if next()? != Some(b'\\') || next()? != Some(b'u') {
+                            return Ok(false);
+                        }
+                        match hex4(&mut next)? {
+                            Some(low @ 0xDC00..=0xDFFF) => {
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            _ => return Ok(false),
+                        }
+                    }
+                    match char::from_u32(code) {
+                        Some(ch) => ch,
+                        None => return Ok(false),
+                    }
+                }
+                _ => return Ok(false),
+            },
+            // Anything else is the string's own UTF-8
+            Some(raw) => {
+                if !byte(raw) {
+                    return Ok(true);
+                }
+                continue;
+            }
+        };
+
+        for &raw in ch.encode_utf8(&mut [0; 4]).as_bytes() {
+            if !byte(raw) {
+                return Ok(true);
+            }
         }
     }
+}
 
-    pub fn iter(&mut self) -> LogIterator {
-        return LogIterator::from_reader(self.log_gen, &mut self.reader);
+// Four hex digits, as in a \u escape
+fn hex4(next: &mut impl FnMut() -> io::Result<Option<u8>>) -> io::Result<Option<u32>> {
+    let mut code = 0;
+    for _ in 0..4 {
+        match next()?.and_then(|digit| (digit as char).to_digit(16)) {
+            Some(digit) => code = code * 16 + digit,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(code))
+}
+
+/// Decode one record, which must be a set, and return its value.
+pub fn read_set_value(reader: impl Read) -> Result<Option<String>> {
+    if let Command::Set { value, .. } = serde_json::from_reader(reader)? {
+        Ok(Some(value))
+    } else {
+        Err(KvStoreError::UnexpectedCommandType)
     }
 }
 
-pub struct LogIterator<'a> {
+/// Reader that fails once more than the remaining budget has been read, so a
+/// single oversized record is rejected instead of buffered.
+struct RecordLimit<R> {
+    inner: R,
+    remaining: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for RecordLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining.get();
+        if remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "log record exceeds maximum length",
+            ));
+        }
+
+        let max = (buf.len() as u64).min(remaining) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        self.remaining.set(remaining - read as u64);
+
+        Ok(read)
+    }
+}
+
+pub struct LogIterator<R: Read> {
     log_gen: u64,
-    deserializer: StreamDeserializer<'a, IoRead<&'a mut BufReader<File>>, Command>,
+    // File offset the reader was positioned at when iteration began
+    start: u64,
+    remaining: Rc<Cell<u64>>,
+    deserializer: StreamDeserializer<'static, IoRead<RecordLimit<R>>, Stored<Command>>,
+    keys: Option<Keyring>,
+    // Records of the last batch read, not yet returned
+    batch: VecDeque<(Command, LogPointer)>,
 }
 
-impl LogIterator<'_> {
-    pub fn from_reader<'a>(log_gen: u64, reader: &'a mut BufReader<File>) -> LogIterator<'a> {
-        let deserializer = Deserializer::from_reader(reader).into_iter::<Command>();
-        return LogIterator {
+impl<R: Read> LogIterator<R> {
+    pub fn from_reader(log_gen: u64, reader: R) -> LogIterator<R> {
+        let remaining = Rc::new(Cell::new(MAX_RECORD_LEN));
+        let reader = RecordLimit {
+            inner: reader,
+            remaining: remaining.clone(),
+        };
+
+        let deserializer = Deserializer::from_reader(reader).into_iter::<Stored<Command>>();
+        LogIterator {
             log_gen,
+            start: 0,
+            remaining,
             deserializer,
-        };
+            keys: None,
+            batch: VecDeque::new(),
+        }
     }
-}
 
-impl Iterator for LogIterator<'_> {
-    type Item = Result<(Command, LogPointer)>;
+    /// Decrypt sealed records with `keys`. Without keys a sealed record is
+    /// an error.
+    pub fn decrypting(mut self, keys: Option<&Keyring>) -> LogIterator<R> {
+        self.keys = keys.cloned();
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let pos = self.deserializer.byte_offset() as u64;
+    /// The next record in the log as it is stored, batch header or not, with
+    /// its offset and length. After an error the length is meaningless and
+    /// nothing more can be read.
+    pub(crate) fn read_stored(
+        &mut self,
+    ) -> Option<(u64, u64, serde_json::Result<Stored<Command>>)> {
+        self.remaining.set(MAX_RECORD_LEN);
+
+        let pos = self.start + self.deserializer.byte_offset() as u64;
         let next = self.deserializer.next()?;
-        let next_pos = self.deserializer.byte_offset() as u64;
+        let next_pos = self.start + self.deserializer.byte_offset() as u64;
 
-        let len = next_pos - pos;
+        Some((pos, next_pos - pos, next))
+    }
 
-        let log_pointer = LogPointer {
-            len,
+    // The next record in the log, batch header or not
+    fn read_record(&mut self) -> Option<Result<(Command, LogPointer)>> {
+        let (pos, len, next) = self.read_stored()?;
+        let location = Location::Log {
             log_gen: self.log_gen,
-            pos,
+            offset: pos,
         };
 
         Some(
-            next.map(|cmd| (cmd, log_pointer))
-                .map_err(KvStoreError::SerdeErr),
+            next.map_err(KvStoreError::SerdeErr)
+                .and_then(|record| record.open(self.keys.as_ref(), location))
+                .map(|cmd| {
+                    let log_pointer = cmd.pointer(self.log_gen, pos, len);
+                    (cmd, log_pointer)
+                }),
         )
     }
+
+    // The `ops` records after a batch header, failing if the log ends before
+    // all of them, as it does when a crash tore the batch
+    fn read_batch(&mut self, ops: usize) -> Result<VecDeque<(Command, LogPointer)>> {
+        let mut batch = VecDeque::new();
+        while batch.len() < ops {
+            match self.read_record() {
+                Some(Ok((Command::Batch { .. }, _))) => {
+                    return Err(KvStoreError::UnexpectedCommandType)
+                }
+                Some(record) => batch.push_back(record?),
+                None => {
+                    return Err(KvStoreError::IoErr(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the log ends partway through a batch",
+                    )))
+                }
+            }
+        }
+
+        Ok(batch)
+    }
+}
+
+impl LogIterator<BufReader<File>> {
+    /// Iterate over the records of the log file at `path` that start at or
+    /// after byte `offset`.
+    pub fn open_at(path: &Path, log_gen: u64, offset: u64) -> Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut iter = LogIterator::from_reader(log_gen, BufReader::new(file));
+        iter.start = offset;
+        Ok(iter)
+    }
+}
+
+impl<R: Read> Iterator for LogIterator<R> {
+    type Item = Result<(Command, LogPointer)>;
+
+    /// The next set or remove. The records of a batch come out one by one
+    /// once the whole batch has been read; batch headers never do.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.batch.pop_front() {
+                return Some(Ok(record));
+            }
+
+            match self.read_record()? {
+                Ok((Command::Batch { ops }, _)) => match self.read_batch(ops) {
+                    Ok(batch) => self.batch = batch,
+                    Err(err) => return Some(Err(err)),
+                },
+                record => return Some(record),
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct LogWriter {
     log_pos: u64,
     log_gen: u64,
+    encoding: LogEncoding,
     writer: BufWriter<File>,
+    keys: Option<Keyring>,
 }
 
 impl LogWriter {
-    pub fn new(path: &Path, log_gen: u64) -> Result<LogWriter> {
-        let log_file_path = log_path(&path, log_gen);
+    /// Start a log, sealing its records with `keys`' active key if given.
+    pub fn new(
+        path: &Path,
+        log_gen: u64,
+        encoding: LogEncoding,
+        keys: Option<&Keyring>,
+    ) -> Result<LogWriter> {
+        let log_file_path = log_path(path, log_gen);
         let file = File::create(log_file_path)?;
 
-        return Ok(LogWriter {
+        Ok(LogWriter {
             log_pos: 0,
             log_gen,
+            encoding,
             writer: BufWriter::new(file),
-        });
+            keys: keys.cloned(),
+        })
     }
 
-    pub fn write_set_cmd(&mut self, key: String, value: String) -> Result<LogPointer> {
-        let cmd = Command::Set { key, value };
+    pub fn write_set_cmd(
+        &mut self,
+        key: Vec<u8>,
+        value: String,
+        expires_at: Option<u64>,
+        version: Option<u64>,
+        seq: u64,
+    ) -> Result<LogPointer> {
+        let cmd = Command::Set {
+            key,
+            value,
+            expires_at,
+            version,
+            seq: Some(seq),
+        };
         let pos = self.log_pos;
 
-        let len = self.writer.write(&serde_json::to_vec(&cmd)?)? as u64;
+        let bytes = self
+            .encoding
+            .encode(&cmd, self.keys.as_ref(), self.log_gen, pos)?;
+        self.writer.write_all(&bytes)?;
+        let len = bytes.len() as u64;
         // self.writer.flush()?;
 
         self.log_pos += len;
 
-        Ok(LogPointer {
-            log_gen: self.log_gen,
-            pos,
-            len,
-        })
+        Ok(cmd.pointer(self.log_gen, pos, len))
     }
 
-    pub fn write_rm_cmd(&mut self, key: String) -> Result<()> {
-        let cmd = Command::Remove { key };
+    pub fn write_rm_cmd(&mut self, key: Vec<u8>, removed_at: Option<u64>, seq: u64) -> Result<()> {
+        let cmd = Command::Remove {
+            key,
+            removed_at,
+            seq: Some(seq),
+        };
 
-        let len = self.writer.write(&serde_json::to_vec(&cmd)?)? as u64;
+        let bytes = self
+            .encoding
+            .encode(&cmd, self.keys.as_ref(), self.log_gen, self.log_pos)?;
+        self.writer.write_all(&bytes)?;
+        let len = bytes.len() as u64;
         // self.writer.flush()?;
 
         self.log_pos += len;
@@ -153,7 +689,36 @@ impl LogWriter {
         Ok(())
     }
 
+    /// Write `ops` as one batch, after a header saying how many records it
+    /// holds, so a crash partway through leaves a torn batch that readers
+    /// drop whole. Returns a pointer to each record.
+    pub fn write_batch(&mut self, ops: &[Command]) -> Result<Vec<LogPointer>> {
+        let header = Command::Batch { ops: ops.len() };
+        let mut bytes =
+            self.encoding
+                .encode(&header, self.keys.as_ref(), self.log_gen, self.log_pos)?;
+        let mut log_pointers = Vec::with_capacity(ops.len());
+        for cmd in ops {
+            let pos = self.log_pos + bytes.len() as u64;
+            let record = self
+                .encoding
+                .encode(cmd, self.keys.as_ref(), self.log_gen, pos)?;
+            log_pointers.push(cmd.pointer(self.log_gen, pos, record.len() as u64));
+            bytes.extend_from_slice(&record);
+        }
+
+        self.writer.write_all(&bytes)?;
+        self.log_pos += bytes.len() as u64;
+
+        Ok(log_pointers)
+    }
+
     pub fn flush(&mut self) -> io::Result<()> {
-        return self.writer.flush();
+        self.writer.flush()
+    }
+
+    /// Offset the next record will be written at.
+    pub fn pos(&self) -> u64 {
+        self.log_pos
     }
 }
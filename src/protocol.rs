@@ -0,0 +1,756 @@
+//! The wire protocol between `KvsClient` and `KvsServer`, for clients
+//! written outside this crate.
+//!
+//! A connection carries JSON frames back to back, with no delimiter or
+//! length prefix: the client writes `Message`s and the server answers each
+//! with one `Response`, in order. Two messages answer differently:
+//!
+//! - `Scan` is answered with any number of `ScanChunk`s and then a `ScanEnd`.
+//! - `Subscribe` is answered with `Subscribed`, after which the server only
+//!   sends `Published` frames and reads nothing more from the connection.
+//!
+//! A server with an ACL answers every message but `Auth` with `Denied` until
+//! the connection authenticates. A server whose engine is refusing writes
+//! answers messages that write with `Stalled` instead of running them.
+//!
+//! A server limits the length of a frame and closes connections sending
+//! longer ones. Values too long for one frame are sent in parts with
+//! `SetChunk`.
+//!
+//! Responses are plain JSON unless the connection asks for compression with
+//! `Hello`. From then on the server may send any response, other than the
+//! `Published` frames of a subscription, as a `Compressed` frame holding
+//! it.
+//!
+//! The encoding of existing variants only changes with a breaking release.
+//! New messages, responses and optional fields can come in any release, so
+//! the enums are `#[non_exhaustive]` and servers ignore unknown fields.
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::de::{IoRead, StreamDeserializer};
+use serde_json::Deserializer;
+
+use crate::{
+    CompactionStats, HotKey, KeyspaceSample, LogPosition, Metrics, PrefixDigest, QueueItem,
+    QuotaUsage, SlowLogEntry, Txn, TxnResponse,
+};
+
+/// Write one frame and flush it.
+pub fn write_frame<W: Write + ?Sized, T: Serialize>(writer: &mut W, frame: &T) -> io::Result<()> {
+    writer.write_all(&serde_json::to_vec(frame)?)?;
+    writer.flush()
+}
+
+/// The frames of a stream, in order: `Message`s on the server side and
+/// `Response`s on the client side. Ends when the stream ends between frames.
+pub struct FrameReader<R: Read, T> {
+    frames: StreamDeserializer<'static, IoRead<FrameLimit<R>>, T>,
+    max_len: u64,
+    remaining: Arc<AtomicU64>,
+    frame: PhantomData<T>,
+}
+
+impl<R: Read, T: DeserializeOwned> FrameReader<R, T> {
+    pub fn new(reader: R) -> FrameReader<R, T> {
+        FrameReader::with_max_len(reader, u64::MAX)
+    }
+
+    /// Fail with `InvalidData` on a frame longer than `max_len` bytes,
+    /// before it is buffered. The stream can't be read any further after
+    /// that, as the rest of the frame is still in it.
+    pub fn with_max_len(reader: R, max_len: u64) -> FrameReader<R, T> {
+        let remaining = Arc::new(AtomicU64::new(max_len));
+        let reader = FrameLimit {
+            inner: reader,
+            max_len,
+            remaining: remaining.clone(),
+        };
+        FrameReader {
+            frames: Deserializer::from_reader(reader).into_iter(),
+            max_len,
+            remaining,
+            frame: PhantomData,
+        }
+    }
+
+    /// Bytes of the stream read so far.
+    pub fn byte_offset(&self) -> usize {
+        self.frames.byte_offset()
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for FrameReader<R, T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<io::Result<T>> {
+        self.remaining.store(self.max_len, Ordering::Relaxed);
+        self.frames
+            .next()
+            .map(|frame| frame.map_err(io::Error::from))
+    }
+}
+
+// Reader that fails once a frame has used up its budget, which the frame
+// reader refills before each frame. Reads through it are a byte at a time,
+// so the budget counts the frame's bytes exactly.
+struct FrameLimit<R> {
+    inner: R,
+    max_len: u64,
+    remaining: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for FrameLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining.load(Ordering::Relaxed);
+        if remaining == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame exceeds the maximum length of {} bytes", self.max_len),
+            ));
+        }
+
+        let max = (buf.len() as u64).min(remaining) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        self.remaining
+            .store(remaining - read as u64, Ordering::Relaxed);
+
+        Ok(read)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[non_exhaustive]
+pub enum Message {
+    /// Identify the connection as a user of the server's ACL
+    Auth {
+        user: String,
+        token: String,
+    },
+    Set {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        value: String,
+        /// Expire the key after this many milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    /// One part of a value too long to `Set` in a single frame. Parts go in
+    /// order, each at the byte offset where the previous one ended, and the
+    /// server keeps them for the connection until the `last` one sets the
+    /// key as `Set` would. A part at offset 0 starts a new value.
+    SetChunk {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        offset: u64,
+        chunk: String,
+        #[serde(default)]
+        last: bool,
+        /// Expire the key after this many milliseconds, read from the last
+        /// part
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    /// Set the key only if it is absent
+    SetNx {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        value: String,
+    },
+    Get {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// Only answer once the store has applied writes up to this position
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<LogPosition>,
+    },
+    /// Read `len` bytes of the key's value from byte `offset`
+    GetRange {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        offset: u64,
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<LogPosition>,
+    },
+    Remove {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Set the key and answer with the value it replaced
+    GetSet {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        value: String,
+    },
+    /// Remove the key and answer with its value
+    GetDel {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Append to the key's value, or set it if absent, and answer with the
+    /// new length
+    Append {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        suffix: String,
+    },
+    Ttl {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    Expire {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Persist {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Bring back a soft-deleted key's value
+    Restore {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Run a Lua script on the server with `args` as its `ARGV`, atomically
+    /// with respect to other requests. Needs the `scripting` feature on the
+    /// server.
+    Eval {
+        script: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Append an item to a queue
+    Enqueue {
+        queue: String,
+        item: String,
+    },
+    /// Take the oldest available item of a queue, hiding it from other
+    /// consumers for `visibility_ms` unless it is acknowledged first
+    Dequeue {
+        queue: String,
+        visibility_ms: u64,
+    },
+    /// Remove a dequeued item once it has been processed
+    Ack {
+        queue: String,
+        id: u64,
+    },
+    /// Take a lock unless someone else holds a live lease on it
+    Acquire {
+        lock: String,
+        ttl_ms: u64,
+    },
+    /// Extend a lease to expire `ttl_ms` from now
+    Renew {
+        lock: String,
+        token: u64,
+        ttl_ms: u64,
+    },
+    Release {
+        lock: String,
+        token: u64,
+    },
+    /// Check conditions and run one of two operation lists depending on
+    /// whether they all hold
+    Txn(Txn),
+    /// Turn this connection into a subscription: it is answered with
+    /// `Subscribed`, then a `Published` frame for every message later
+    /// published to one of `channels`, and reads no further messages.
+    Subscribe {
+        channels: Vec<String>,
+    },
+    /// Send a message to the current subscribers of a channel
+    Publish {
+        channel: String,
+        message: String,
+    },
+    /// Run each message in order and answer with all responses at once, so a
+    /// client pays one round trip for many keys. Scans can't be batched.
+    Batch(Vec<Message>),
+    /// Run `message`, tagging the server's log entries, trace spans and slow
+    /// log entry for it with `trace_id`, so they can be matched up with the
+    /// client's own logs. Answered as `message` is.
+    Traced {
+        trace_id: String,
+        message: Box<Message>,
+    },
+    /// Run `message` once per `idempotency_key`. While the server remembers
+    /// the key, a later message from the same user carrying it is answered
+    /// with the first one's response instead of running again, which makes
+    /// retrying a write whose response was lost safe. Failed messages aren't
+    /// remembered, so retrying one runs it again.
+    Idempotent {
+        idempotency_key: String,
+        message: Box<Message>,
+    },
+    /// Return up to `count` of the newest slow log entries
+    SlowLogGet {
+        count: usize,
+    },
+    SlowLogReset,
+    /// Return up to `count` of the keys read most, with estimated read
+    /// counts, if the server samples reads
+    HotKeys {
+        count: usize,
+    },
+    /// Return each key prefix quota with its usage, counted again
+    Quotas,
+    /// Return the engine's work counters
+    Metrics,
+    /// Return the stats of the engine's latest compactions
+    CompactionHistory,
+    /// Seal the active log and start a new one
+    RotateLog,
+    /// Close the engine and open it again, from `dir` if given, e.g. after
+    /// restoring a backup into its directory. Only servers made with
+    /// `KvsServer::reopenable` can.
+    Reopen {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<PathBuf>,
+    },
+    /// Read the server's ACL file again
+    ReloadAcl,
+    /// Change the server's log level to `error`, `warn`, `info`, `debug` or
+    /// `trace`
+    SetLogLevel {
+        level: String,
+    },
+    /// Summarize the sizes and prefixes of `samples` random live keys
+    Analyze {
+        samples: usize,
+        delimiter: u8,
+    },
+    /// Hash the live entries grouped by the first `prefix_len` bytes of
+    /// their keys, for `kvs::sync`
+    Digest {
+        prefix_len: usize,
+    },
+    /// The hashes of the nodes at `nodes` of the store's `MerkleTree` of
+    /// `depth`, for `kvs::diverging_leaves`
+    MerkleNodes {
+        depth: u32,
+        nodes: Vec<u64>,
+    },
+    /// Set how the server answers this connection: responses encoding to at
+    /// least `compress_above` bytes are compressed with the first of
+    /// `compression` the server supports, if any. Answered with the one
+    /// chosen. An empty list turns compression off.
+    Hello {
+        compression: Vec<Compression>,
+        compress_above: u64,
+    },
+    /// Stream every entry whose key starts with the prefix. Answered with any
+    /// number of `ScanChunk` frames followed by one `ScanEnd`.
+    Scan {
+        #[serde(with = "crate::encoding")]
+        prefix: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<LogPosition>,
+        /// Return the keys matching this glob instead of those under `prefix`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+    },
+}
+
+impl Message {
+    /// Name of the operation, for logs and traces.
+    pub(crate) fn operation(&self) -> &'static str {
+        match self {
+            Message::Auth { .. } => "auth",
+            Message::Set { .. } => "set",
+            Message::SetChunk { .. } => "set_chunk",
+            Message::SetNx { .. } => "set_nx",
+            Message::Get { .. } => "get",
+            Message::GetRange { .. } => "get_range",
+            Message::Remove { .. } => "remove",
+            Message::GetSet { .. } => "get_set",
+            Message::GetDel { .. } => "get_del",
+            Message::Append { .. } => "append",
+            Message::Ttl { .. } => "ttl",
+            Message::Expire { .. } => "expire",
+            Message::Persist { .. } => "persist",
+            Message::Restore { .. } => "restore",
+            Message::Eval { .. } => "eval",
+            Message::Enqueue { .. } => "enqueue",
+            Message::Dequeue { .. } => "dequeue",
+            Message::Ack { .. } => "ack",
+            Message::Acquire { .. } => "acquire",
+            Message::Renew { .. } => "renew",
+            Message::Release { .. } => "release",
+            Message::Txn(_) => "txn",
+            Message::Subscribe { .. } => "subscribe",
+            Message::Publish { .. } => "publish",
+            Message::Batch(_) => "batch",
+            Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+                message.operation()
+            }
+            Message::SlowLogGet { .. } => "slowlog_get",
+            Message::SlowLogReset => "slowlog_reset",
+            Message::HotKeys { .. } => "hotkeys",
+            Message::Quotas => "quotas",
+            Message::Metrics => "metrics",
+            Message::CompactionHistory => "compaction_history",
+            Message::RotateLog => "rotate_log",
+            Message::Reopen { .. } => "reopen",
+            Message::ReloadAcl => "reload_acl",
+            Message::SetLogLevel { .. } => "set_log_level",
+            Message::Analyze { .. } => "analyze",
+            Message::Digest { .. } => "digest",
+            Message::MerkleNodes { .. } => "merkle_nodes",
+            Message::Hello { .. } => "hello",
+            Message::Scan { .. } => "scan",
+        }
+    }
+
+    /// Whether the message inspects the server rather than the store.
+    pub(crate) fn is_admin(&self) -> bool {
+        match self {
+            Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+                message.is_admin()
+            }
+            message => matches!(
+                message,
+                Message::Auth { .. }
+                    | Message::Hello { .. }
+                    | Message::SlowLogGet { .. }
+                    | Message::SlowLogReset
+                    | Message::HotKeys { .. }
+                    | Message::Quotas
+                    | Message::Metrics
+                    | Message::CompactionHistory
+                    | Message::ReloadAcl
+                    | Message::SetLogLevel { .. }
+            ),
+        }
+    }
+
+    /// The key the message operates on, or the prefix of a scan.
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        match self {
+            Message::Set { key, .. }
+            | Message::SetChunk { key, .. }
+            | Message::SetNx { key, .. }
+            | Message::Get { key, .. }
+            | Message::GetRange { key, .. }
+            | Message::Remove { key }
+            | Message::GetSet { key, .. }
+            | Message::GetDel { key }
+            | Message::Append { key, .. }
+            | Message::Ttl { key }
+            | Message::Expire { key, .. }
+            | Message::Persist { key }
+            | Message::Restore { key } => Some(key),
+            Message::Scan { prefix, .. } => Some(prefix),
+            Message::Traced { message, .. } | Message::Idempotent { message, .. } => message.key(),
+            Message::Auth { .. }
+            | Message::Eval { .. }
+            | Message::Enqueue { .. }
+            | Message::Dequeue { .. }
+            | Message::Ack { .. }
+            | Message::Txn(_)
+            | Message::Acquire { .. }
+            | Message::Renew { .. }
+            | Message::Release { .. }
+            | Message::Subscribe { .. }
+            | Message::Publish { .. }
+            | Message::Batch(_)
+            | Message::SlowLogGet { .. }
+            | Message::SlowLogReset
+            | Message::HotKeys { .. }
+            | Message::Quotas
+            | Message::Metrics
+            | Message::CompactionHistory
+            | Message::RotateLog
+            | Message::Reopen { .. }
+            | Message::ReloadAcl
+            | Message::SetLogLevel { .. }
+            | Message::Analyze { .. }
+            | Message::Digest { .. }
+            | Message::MerkleNodes { .. }
+            | Message::Hello { .. } => None,
+        }
+    }
+
+    /// The failed response a message gets when it is rejected before it
+    /// runs, carrying `err`.
+    pub(crate) fn failed(&self, err: String) -> Response {
+        match self {
+            Message::Auth { .. } => Response::Auth(Err(err)),
+            Message::Set { .. } => Response::Set(Err(err)),
+            Message::SetChunk { .. } => Response::SetChunk(Err(err)),
+            Message::SetNx { .. } => Response::SetNx(Err(err)),
+            Message::Get { .. } => Response::Get(Err(err)),
+            Message::GetRange { .. } => Response::GetRange(Err(err)),
+            Message::Remove { .. } => Response::Remove(Err(err)),
+            Message::GetSet { .. } => Response::GetSet(Err(err)),
+            Message::GetDel { .. } => Response::GetDel(Err(err)),
+            Message::Append { .. } => Response::Append(Err(err)),
+            Message::Ttl { .. } => Response::Ttl(Err(err)),
+            Message::Expire { .. } => Response::Expire(Err(err)),
+            Message::Persist { .. } => Response::Persist(Err(err)),
+            Message::Restore { .. } => Response::Restore(Err(err)),
+            Message::Eval { .. } => Response::Eval(Err(err)),
+            Message::Enqueue { .. } => Response::Enqueue(Err(err)),
+            Message::Dequeue { .. } => Response::Dequeue(Err(err)),
+            Message::Ack { .. } => Response::Ack(Err(err)),
+            Message::Acquire { .. } => Response::Acquire(Err(err)),
+            Message::Renew { .. } => Response::Renew(Err(err)),
+            Message::Release { .. } => Response::Release(Err(err)),
+            Message::Txn(_) => Response::Txn(Err(err)),
+            Message::Subscribe { .. } => Response::Subscribed(Err(err)),
+            Message::Publish { .. } => Response::Publish(Err(err)),
+            Message::Batch(_) => Response::Batch(Err(err)),
+            Message::Traced { message, .. } | Message::Idempotent { message, .. } => {
+                message.failed(err)
+            }
+            Message::SlowLogGet { .. } => Response::SlowLogGet(Err(err)),
+            Message::SlowLogReset => Response::SlowLogReset(Err(err)),
+            Message::HotKeys { .. } => Response::HotKeys(Err(err)),
+            Message::Quotas => Response::Quotas(Err(err)),
+            Message::Metrics => Response::Metrics(Err(err)),
+            Message::CompactionHistory => Response::CompactionHistory(Err(err)),
+            Message::RotateLog => Response::RotateLog(Err(err)),
+            Message::Reopen { .. } => Response::Reopen(Err(err)),
+            Message::ReloadAcl => Response::ReloadAcl(Err(err)),
+            Message::SetLogLevel { .. } => Response::SetLogLevel(Err(err)),
+            Message::Analyze { .. } => Response::Analyze(Err(err)),
+            Message::Digest { .. } => Response::Digest(Err(err)),
+            Message::MerkleNodes { .. } => Response::MerkleNodes(Err(err)),
+            Message::Hello { .. } => Response::Hello(Err(err)),
+            Message::Scan { .. } => Response::ScanEnd(Err(err)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    #[serde(with = "crate::encoding")]
+    pub key: Vec<u8>,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub enum Response {
+    Auth(Result<(), String>),
+    /// The connection's user may not send the message this answers
+    Denied(String),
+    /// The engine is refusing writes until compaction catches up; send the
+    /// message again after this many milliseconds
+    Stalled {
+        retry_after_ms: u64,
+    },
+    Get(Result<Option<String>, String>),
+    GetRange(Result<Option<String>, String>),
+    /// Position of the write, for engines whose logs a standby can follow
+    Set(Result<Option<LogPosition>, String>),
+    /// Position of the write once the last part is in, `None` before
+    SetChunk(Result<Option<LogPosition>, String>),
+    Remove(Result<Option<LogPosition>, String>),
+    /// Whether the value was written
+    SetNx(Result<bool, String>),
+    GetSet(Result<Option<String>, String>),
+    GetDel(Result<Option<String>, String>),
+    /// Length in bytes of the value after the append
+    Append(Result<usize, String>),
+    /// Milliseconds left before the key expires, `None` if it never does
+    Ttl(Result<Option<u64>, String>),
+    Expire(Result<(), String>),
+    Persist(Result<(), String>),
+    Restore(Result<Option<LogPosition>, String>),
+    /// What the script returned
+    Eval(Result<Option<String>, String>),
+    /// Id of the enqueued item
+    Enqueue(Result<u64, String>),
+    Dequeue(Result<Option<QueueItem>, String>),
+    Ack(Result<(), String>),
+    /// Fencing token of the new lease, `None` if the lock is held
+    Acquire(Result<Option<u64>, String>),
+    Renew(Result<(), String>),
+    Release(Result<(), String>),
+    Txn(Result<TxnResponse, String>),
+    Subscribed(Result<(), String>),
+    /// A message published to a subscribed channel
+    Published {
+        channel: String,
+        message: String,
+    },
+    /// How many subscribers the message was sent to
+    Publish(Result<usize, String>),
+    /// One response per batched message, in the same order
+    Batch(Result<Vec<Response>, String>),
+    SlowLogGet(Result<Vec<SlowLogEntry>, String>),
+    SlowLogReset(Result<(), String>),
+    /// Most read first
+    HotKeys(Result<Vec<HotKey>, String>),
+    Quotas(Result<Vec<QuotaUsage>, String>),
+    Metrics(Result<Box<Metrics>, String>),
+    /// Oldest first
+    CompactionHistory(Result<Vec<CompactionStats>, String>),
+    /// Generation of the new active log, `None` if the engine has no logs
+    RotateLog(Result<Option<u64>, String>),
+    Reopen(Result<(), String>),
+    ReloadAcl(Result<(), String>),
+    SetLogLevel(Result<(), String>),
+    Analyze(Result<KeyspaceSample, String>),
+    Digest(Result<Vec<PrefixDigest>, String>),
+    MerkleNodes(Result<Vec<u64>, String>),
+    /// The compression chosen for the connection's responses, if any
+    Hello(Result<Option<Compression>, String>),
+    ScanChunk(Vec<Entry>),
+    ScanEnd(Result<(), String>),
+    /// Another response, encoded and then compressed, in base64
+    Compressed {
+        compression: Compression,
+        data: String,
+    },
+}
+
+/// A way to compress responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    Zstd,
+}
+
+// Zstd's default level: fast enough to be worth it on all but the quickest
+// links
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    /// Wrap an encoded response in a `Compressed` frame.
+    pub fn compress(self, frame: &[u8]) -> io::Result<Response> {
+        let compressed = match self {
+            Compression::Zstd => zstd::stream::encode_all(frame, ZSTD_LEVEL)?,
+        };
+        Ok(Response::Compressed {
+            compression: self,
+            data: BASE64.encode(compressed),
+        })
+    }
+
+    /// The response the data of a `Compressed` frame holds.
+    pub fn decompress(self, data: &str) -> io::Result<Response> {
+        let compressed = BASE64
+            .decode(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let frame = match self {
+            Compression::Zstd => zstd::stream::decode_all(&compressed[..])?,
+        };
+        Ok(serde_json::from_slice(&frame)?)
+    }
+}
+
+impl Response {
+    /// Whether the message this answers failed, or never ran.
+    pub(crate) fn is_err(&self) -> bool {
+        match self {
+            Response::Auth(result)
+            | Response::Expire(result)
+            | Response::Persist(result)
+            | Response::Ack(result)
+            | Response::Renew(result)
+            | Response::Release(result)
+            | Response::Subscribed(result)
+            | Response::SlowLogReset(result)
+            | Response::ReloadAcl(result)
+            | Response::SetLogLevel(result)
+            | Response::Reopen(result)
+            | Response::ScanEnd(result) => result.is_err(),
+            Response::Get(result)
+            | Response::GetRange(result)
+            | Response::GetSet(result)
+            | Response::GetDel(result)
+            | Response::Eval(result) => result.is_err(),
+            Response::Set(result)
+            | Response::SetChunk(result)
+            | Response::Remove(result)
+            | Response::Restore(result) => result.is_err(),
+            Response::SetNx(result) => result.is_err(),
+            Response::Append(result) | Response::Publish(result) => result.is_err(),
+            Response::Ttl(result) | Response::Acquire(result) | Response::RotateLog(result) => {
+                result.is_err()
+            }
+            Response::Enqueue(result) => result.is_err(),
+            Response::Dequeue(result) => result.is_err(),
+            Response::Txn(result) => result.is_err(),
+            Response::Batch(result) => result.is_err(),
+            Response::SlowLogGet(result) => result.is_err(),
+            Response::HotKeys(result) => result.is_err(),
+            Response::Quotas(result) => result.is_err(),
+            Response::Metrics(result) => result.is_err(),
+            Response::CompactionHistory(result) => result.is_err(),
+            Response::Analyze(result) => result.is_err(),
+            Response::Digest(result) => result.is_err(),
+            Response::MerkleNodes(result) => result.is_err(),
+            Response::Hello(result) => result.is_err(),
+            Response::Compressed { compression, data } => compression
+                .decompress(data)
+                .map_or(true, |response| response.is_err()),
+            Response::Denied(_) | Response::Stalled { .. } => true,
+            Response::Published { .. } | Response::ScanChunk(_) => false,
+        }
+    }
+
+    /// The failed response of the same kind, carrying `err`.
+    pub(crate) fn into_error(self, err: String) -> Response {
+        match self {
+            Response::Get(_) => Response::Get(Err(err)),
+            Response::GetRange(_) => Response::GetRange(Err(err)),
+            Response::Set(_) => Response::Set(Err(err)),
+            Response::SetChunk(_) => Response::SetChunk(Err(err)),
+            Response::Remove(_) => Response::Remove(Err(err)),
+            Response::SetNx(_) => Response::SetNx(Err(err)),
+            Response::GetSet(_) => Response::GetSet(Err(err)),
+            Response::GetDel(_) => Response::GetDel(Err(err)),
+            Response::Append(_) => Response::Append(Err(err)),
+            Response::Ttl(_) => Response::Ttl(Err(err)),
+            Response::Expire(_) => Response::Expire(Err(err)),
+            Response::Persist(_) => Response::Persist(Err(err)),
+            Response::Restore(_) => Response::Restore(Err(err)),
+            Response::Eval(_) => Response::Eval(Err(err)),
+            Response::Enqueue(_) => Response::Enqueue(Err(err)),
+            Response::Dequeue(_) => Response::Dequeue(Err(err)),
+            Response::Ack(_) => Response::Ack(Err(err)),
+            Response::Acquire(_) => Response::Acquire(Err(err)),
+            Response::Renew(_) => Response::Renew(Err(err)),
+            Response::Release(_) => Response::Release(Err(err)),
+            Response::Txn(_) => Response::Txn(Err(err)),
+            Response::Subscribed(_) | Response::Published { .. } => Response::Subscribed(Err(err)),
+            Response::Publish(_) => Response::Publish(Err(err)),
+            Response::Batch(_) => Response::Batch(Err(err)),
+            Response::SlowLogGet(_) => Response::SlowLogGet(Err(err)),
+            Response::SlowLogReset(_) => Response::SlowLogReset(Err(err)),
+            Response::HotKeys(_) => Response::HotKeys(Err(err)),
+            Response::Quotas(_) => Response::Quotas(Err(err)),
+            Response::Metrics(_) => Response::Metrics(Err(err)),
+            Response::CompactionHistory(_) => Response::CompactionHistory(Err(err)),
+            Response::RotateLog(_) => Response::RotateLog(Err(err)),
+            Response::Reopen(_) => Response::Reopen(Err(err)),
+            Response::ReloadAcl(_) => Response::ReloadAcl(Err(err)),
+            Response::SetLogLevel(_) => Response::SetLogLevel(Err(err)),
+            Response::Auth(_) => Response::Auth(Err(err)),
+            Response::Denied(_) => Response::Denied(err),
+            Response::Stalled { retry_after_ms } => Response::Stalled { retry_after_ms },
+            Response::Analyze(_) => Response::Analyze(Err(err)),
+            Response::Digest(_) => Response::Digest(Err(err)),
+            Response::MerkleNodes(_) => Response::MerkleNodes(Err(err)),
+            Response::Hello(_) => Response::Hello(Err(err)),
+            Response::Compressed { compression, data } => match compression.decompress(&data) {
+                Ok(response) => response.into_error(err),
+                Err(_) => Response::Denied(err),
+            },
+            Response::ScanChunk(_) | Response::ScanEnd(_) => Response::ScanEnd(Err(err)),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::protocol::{write_frame, Response};
+
+// How long a publish waits on a subscriber that isn't reading before giving
+// up on it
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A subscribed connection's writer, shared by each channel it subscribed to.
+type Subscriber = Arc<Mutex<dyn Write + Send>>;
+
+/// Connections subscribed to named channels. Nothing is stored: a message
+/// reaches the subscribers connected when it is published, and no one else.
+#[derive(Default)]
+pub(crate) struct Channels {
+    subscribers: HashMap<String, Vec<Subscriber>>,
+}
+
+impl Channels {
+    /// Hand the connection's `writer` over to receive every message later
+    /// published to `channels`. `stream` is the socket under it.
+    pub(crate) fn subscribe(
+        &mut self,
+        channels: Vec<String>,
+        stream: &TcpStream,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<()> {
+        stream.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT))?;
+        let subscriber: Subscriber = Arc::new(Mutex::new(writer));
+        for channel in channels {
+            self.subscribers
+                .entry(channel)
+                .or_default()
+                .push(subscriber.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Send `message` to the channel's subscribers, returning how many got it.
+    /// Subscribers that can't be written to are dropped.
+    pub(crate) fn publish(&mut self, channel: String, message: String) -> io::Result<usize> {
+        let Some(subscribers) = self.subscribers.get_mut(&channel) else {
+            return Ok(0);
+        };
+
+        let frame = Response::Published {
+            channel: channel.clone(),
+            message,
+        };
+        subscribers.retain(|subscriber| match subscriber.lock() {
+            Ok(mut subscriber) => write_frame(&mut *subscriber, &frame).is_ok(),
+            Err(_) => false,
+        });
+
+        let delivered = subscribers.len();
+        if delivered == 0 {
+            self.subscribers.remove(&channel);
+        }
+        Ok(delivered)
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::logs::{expiry_after, unix_millis};
+use crate::{KvStoreError, KvsEngine, Result, WriteBatch, RESERVED_KEY_PREFIX};
+
+// Items a dequeue reads per scan while looking for a visible one
+const DEQUEUE_PAGE_LEN: usize = 64;
+
+/// A durable FIFO queue stored as ordinary keys of an engine. Items are kept
+/// until acknowledged: `dequeue` hides an item for a visibility timeout
+/// rather than removing it, so an item whose consumer dies before `ack` is
+/// delivered again (at-least-once delivery).
+///
+/// A queue's keys start with `RESERVED_KEY_PREFIX`, so on a server started
+/// with `reserve_internal_keys` only its queue messages can write them, and
+/// scans leave them out.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    tail_key: Vec<u8>,
+    item_prefix: Vec<u8>,
+}
+
+/// An item handed out by `Queue::dequeue`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueueItem {
+    /// Identifies the item to `Queue::ack`
+    pub id: u64,
+    pub item: String,
+    /// How many times the item has been dequeued, including this one
+    pub deliveries: u32,
+}
+
+/// How an item is stored under its key.
+#[derive(Serialize, Deserialize)]
+struct StoredItem {
+    item: String,
+    /// Unix time in milliseconds before which the item isn't handed out
+    visible_at: u64,
+    deliveries: u32,
+}
+
+impl Queue {
+    pub fn new(name: &str) -> Queue {
+        let mut prefix = RESERVED_KEY_PREFIX.to_vec();
+        prefix.extend_from_slice(b"\0queue\0");
+        prefix.extend_from_slice(name.as_bytes());
+        prefix.push(0);
+
+        let mut tail_key = prefix.clone();
+        tail_key.extend_from_slice(b"tail");
+        let mut item_prefix = prefix;
+        item_prefix.extend_from_slice(b"item\0");
+
+        Queue {
+            tail_key,
+            item_prefix,
+        }
+    }
+
+    // Zero-padded so items sort in the order they were enqueued
+    fn item_key(&self, id: u64) -> Vec<u8> {
+        let mut key = self.item_prefix.clone();
+        key.extend_from_slice(format!("{:020}", id).as_bytes());
+        key
+    }
+
+    fn item_id(&self, key: &[u8]) -> Result<u64> {
+        std::str::from_utf8(&key[self.item_prefix.len()..])
+            .ok()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| KvStoreError::StringError("Corrupt queue item key".to_owned()))
+    }
+
+    /// Append an item to the back of the queue, returning its id.
+    pub fn enqueue(&self, engine: &mut dyn KvsEngine, item: String) -> Result<u64> {
+        let id = match engine.get(self.tail_key.clone())? {
+            Some(tail) => tail
+                .parse()
+                .map_err(|_| KvStoreError::StringError("Corrupt queue tail".to_owned()))?,
+            None => 0,
+        };
+
+        let stored = StoredItem {
+            item,
+            visible_at: 0,
+            deliveries: 0,
+        };
+        // Together, so a crash can't leave an item the tail hands out again
+        let mut batch = WriteBatch::new();
+        batch
+            .set(self.item_key(id), serde_json::to_string(&stored)?)
+            .set(self.tail_key.clone(), (id + 1).to_string());
+        engine.apply(batch)?;
+
+        Ok(id)
+    }
+
+    /// Hand out the oldest item that isn't already out with a consumer, and
+    /// hide it from other dequeues for `visibility_timeout`. Returns `None`
+    /// if every item is out or the queue is empty.
+    pub fn dequeue(
+        &self,
+        engine: &mut dyn KvsEngine,
+        visibility_timeout: Duration,
+    ) -> Result<Option<QueueItem>> {
+        let now = unix_millis();
+        let mut start_after: Option<Vec<u8>> = None;
+
+        loop {
+            let page = engine.scan(&self.item_prefix, start_after.as_deref(), DEQUEUE_PAGE_LEN)?;
+            let page_len = page.len();
+
+            for (key, value) in page {
+                let mut stored: StoredItem = serde_json::from_str(&value)?;
+                if stored.visible_at > now {
+                    start_after = Some(key);
+                    continue;
+                }
+
+                stored.visible_at = expiry_after(visibility_timeout);
+                stored.deliveries += 1;
+                engine.set(key.clone(), serde_json::to_string(&stored)?)?;
+
+                return Ok(Some(QueueItem {
+                    id: self.item_id(&key)?,
+                    item: stored.item,
+                    deliveries: stored.deliveries,
+                }));
+            }
+
+            if page_len < DEQUEUE_PAGE_LEN {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Remove a dequeued item for good once it has been processed.
+    pub fn ack(&self, engine: &mut dyn KvsEngine, id: u64) -> Result<()> {
+        engine.remove(self.item_key(id))
+    }
+}
@@ -0,0 +1,277 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::acl::message_keys;
+use crate::protocol::Message;
+use crate::{KvStoreError, KvsReader, Result, TxnOp};
+
+// Keys read per scan page while counting a prefix's usage
+const COUNT_PAGE_LEN: usize = 1024;
+
+/// Limits on the keys under a prefix, read from a JSON file like
+///
+/// ```json
+/// {"quotas": [
+///     {"prefix": "team-a/", "max_keys": 100000, "max_bytes": 1073741824},
+///     {"prefix": "team-b/", "max_bytes": 104857600}
+/// ]}
+/// ```
+///
+/// A key's bytes are its length plus its value's. Keys under several
+/// prefixes count towards each.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    #[serde(with = "crate::encoding")]
+    pub prefix: Vec<u8>,
+    #[serde(default)]
+    pub max_keys: Option<u64>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct QuotaFile {
+    quotas: Vec<Quota>,
+}
+
+impl Quota {
+    /// Read the quotas listed in the file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Quota>> {
+        let file: QuotaFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(file.quotas)
+    }
+}
+
+/// How much of a prefix's quota is used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// The server's quotas and their usage, kept up to date by sizing the keys
+/// each write touches before and after it runs.
+pub(crate) struct Quotas {
+    // `None` until counted, and after a write whose keys can't be told
+    usage: Option<Vec<QuotaUsage>>,
+    quotas: Vec<Quota>,
+}
+
+/// Sizes of the quota-limited keys a write touches, from before it ran.
+pub(crate) struct Touched {
+    keys: Vec<(Vec<u8>, Option<u64>)>,
+}
+
+impl Quotas {
+    pub fn new(quotas: Vec<Quota>) -> Quotas {
+        Quotas {
+            usage: None,
+            quotas,
+        }
+    }
+
+    /// Forget the usage, to count it again from the engine when next needed.
+    pub fn reset(&mut self) {
+        self.usage = None;
+    }
+
+    /// The usage of every quota, counted again from the engine so keys that
+    /// expired since are left out.
+    pub fn usage(&mut self, reader: &mut dyn KvsReader) -> Result<Vec<QuotaUsage>> {
+        self.reset();
+        Ok(self.counted(reader)?.to_vec())
+    }
+
+    fn counted(&mut self, reader: &mut dyn KvsReader) -> Result<&mut Vec<QuotaUsage>> {
+        let usage = match self.usage.take() {
+            Some(usage) => usage,
+            None => {
+                let mut usage = Vec::with_capacity(self.quotas.len());
+                for quota in &self.quotas {
+                    let (keys, bytes) = count_prefix(reader, &quota.prefix)?;
+                    usage.push(QuotaUsage {
+                        quota: quota.clone(),
+                        keys,
+                        bytes,
+                    });
+                }
+                usage
+            }
+        };
+
+        Ok(self.usage.insert(usage))
+    }
+
+    /// Check `message`, a write, against the quotas before it runs, failing
+    /// with `QuotaExceeded` if the values it sets would take a prefix over
+    /// its limits. Writes that don't grow a prefix always pass, so a prefix
+    /// over its quota can still be cleaned up. Returns what to pass to
+    /// `update` once the write has run.
+    pub fn check(&mut self, reader: &mut dyn KvsReader, message: &Message) -> Result<Touched> {
+        if self.quotas.is_empty() {
+            return Ok(Touched { keys: Vec::new() });
+        }
+        // Scripts, queues and locks reach keys the message doesn't name
+        let Some(keys) = message_keys(message) else {
+            self.reset();
+            return Ok(Touched { keys: Vec::new() });
+        };
+
+        let mut touched: Vec<(Vec<u8>, Option<u64>)> = Vec::new();
+        for key in keys {
+            let limited = self
+                .quotas
+                .iter()
+                .any(|quota| key.starts_with(&quota.prefix));
+            if limited && !touched.iter().any(|(touched, _)| touched == key) {
+                touched.push((key.to_vec(), key_size(reader, key)?));
+            }
+        }
+        if touched.is_empty() {
+            return Ok(Touched { keys: touched });
+        }
+
+        let written = written_sizes(message, &touched);
+        for usage in self.counted(reader)?.iter() {
+            let prefix = &usage.quota.prefix;
+            let (mut keys, mut bytes) = (usage.keys, usage.bytes);
+            for (key, size) in written.iter().filter(|(key, _)| key.starts_with(prefix)) {
+                let before = touched
+                    .iter()
+                    .find(|(touched, _)| touched == key)
+                    .and_then(|(_, before)| *before);
+                match before {
+                    Some(before) => bytes = bytes.saturating_sub(before),
+                    None => keys += 1,
+                }
+                bytes += size;
+            }
+
+            let over_keys = usage
+                .quota
+                .max_keys
+                .filter(|&max| keys > max && keys > usage.keys);
+            let over_bytes = usage
+                .quota
+                .max_bytes
+                .filter(|&max| bytes > max && bytes > usage.bytes);
+            let limit = match (over_keys, over_bytes) {
+                (Some(max_keys), _) => format!("{} keys", max_keys),
+                (None, Some(max_bytes)) => format!("{} bytes", max_bytes),
+                (None, None) => continue,
+            };
+            return Err(KvStoreError::QuotaExceeded(format!(
+                "keys under {:?} are limited to {}",
+                String::from_utf8_lossy(prefix),
+                limit
+            )));
+        }
+
+        Ok(Touched { keys: touched })
+    }
+
+    /// Account for the write `touched` was returned for, whether or not it
+    /// succeeded, by sizing its keys again.
+    pub fn update(&mut self, reader: &mut dyn KvsReader, touched: Touched) -> Result<()> {
+        if touched.keys.is_empty() {
+            return Ok(());
+        }
+        let Some(usage) = self.usage.as_mut() else {
+            return Ok(());
+        };
+
+        for (key, before) in touched.keys {
+            let after = key_size(reader, &key)?;
+            for usage in usage
+                .iter_mut()
+                .filter(|usage| key.starts_with(&usage.quota.prefix))
+            {
+                if let Some(before) = before {
+                    usage.keys = usage.keys.saturating_sub(1);
+                    usage.bytes = usage.bytes.saturating_sub(before);
+                }
+                if let Some(after) = after {
+                    usage.keys += 1;
+                    usage.bytes += after;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Bytes a key and its value take up, if the key is there
+fn key_size(reader: &mut dyn KvsReader, key: &[u8]) -> Result<Option<u64>> {
+    let value = reader.get(key.to_vec())?;
+    Ok(value.map(|value| (key.len() + value.len()) as u64))
+}
+
+fn count_prefix(reader: &mut dyn KvsReader, prefix: &[u8]) -> Result<(u64, u64)> {
+    let (mut keys, mut bytes) = (0, 0);
+    let mut start_after: Option<Vec<u8>> = None;
+    loop {
+        let page = reader.scan(prefix, start_after.as_deref(), COUNT_PAGE_LEN)?;
+        for (key, value) in &page {
+            keys += 1;
+            bytes += (key.len() + value.len()) as u64;
+        }
+        if page.len() < COUNT_PAGE_LEN {
+            return Ok((keys, bytes));
+        }
+        start_after = page.last().map(|(key, _)| key.clone());
+    }
+}
+
+// The sizes of the keys `message` sets, as far as they can be told before it
+// runs, each key once. A transaction counts as setting every key either
+// branch sets, to the last value it sets.
+fn written_sizes(message: &Message, touched: &[(Vec<u8>, Option<u64>)]) -> Vec<(Vec<u8>, u64)> {
+    let size = |key: &[u8], value: &str| (key.to_vec(), (key.len() + value.len()) as u64);
+    let mut written = match message {
+        Message::Set { key, value, .. } | Message::GetSet { key, value } => {
+            vec![size(key, value)]
+        }
+        Message::SetNx { key, value } => {
+            let exists = touched
+                .iter()
+                .any(|(touched, before)| touched == key && before.is_some());
+            match exists {
+                true => Vec::new(),
+                false => vec![size(key, value)],
+            }
+        }
+        Message::Append { key, suffix } => {
+            let before = touched
+                .iter()
+                .find(|(touched, _)| touched == key)
+                .and_then(|(_, before)| *before)
+                .unwrap_or(key.len() as u64);
+            vec![(key.clone(), before + suffix.len() as u64)]
+        }
+        Message::Txn(txn) => txn
+            .success
+            .iter()
+            .chain(&txn.failure)
+            .filter_map(|op| match op {
+                TxnOp::Set { key, value } => Some(size(key, value)),
+                TxnOp::Get { .. } | TxnOp::Remove { .. } => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    written.reverse();
+    let mut seen: Vec<Vec<u8>> = Vec::new();
+    written.retain(|(key, _)| match seen.contains(key) {
+        true => false,
+        false => {
+            seen.push(key.clone());
+            true
+        }
+    });
+    written
+}
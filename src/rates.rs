@@ -0,0 +1,96 @@
+#[cfg(feature = "net")]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "net")]
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+// Seconds of requests kept, the longest window rates are reported over
+#[cfg(feature = "net")]
+const KEPT_SECS: u64 = 60;
+
+/// Requests per second of one operation, averaged over the last 1, 10 and 60
+/// seconds, or over the time since the server started if that is shorter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperationRates {
+    pub per_sec_1s: f64,
+    pub per_sec_10s: f64,
+    pub per_sec_60s: f64,
+}
+
+/// Counts of the requests of each operation in each of the last minute's
+/// seconds, so that recent rates can be read without diffing counters.
+#[cfg(feature = "net")]
+pub(crate) struct Rates {
+    started: Instant,
+    // Seconds since `started`, oldest first, with the requests made in each
+    seconds: VecDeque<(u64, BTreeMap<&'static str, u64>)>,
+}
+
+#[cfg(feature = "net")]
+impl Rates {
+    pub(crate) fn new() -> Rates {
+        Rates {
+            started: Instant::now(),
+            seconds: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, operation: &'static str) {
+        let second = self.started.elapsed().as_secs();
+        if self.seconds.back().map(|(last, _)| *last) != Some(second) {
+            self.seconds.push_back((second, BTreeMap::new()));
+        }
+        while let Some((oldest, _)) = self.seconds.front() {
+            if oldest + KEPT_SECS >= second {
+                break;
+            }
+            self.seconds.pop_front();
+        }
+
+        if let Some((_, counts)) = self.seconds.back_mut() {
+            *counts.entry(operation).or_default() += 1;
+        }
+    }
+
+    /// The rates of each operation made in the last minute.
+    pub(crate) fn get(&self) -> BTreeMap<String, OperationRates> {
+        let now = self.started.elapsed().as_secs_f64();
+        let last_1s = self.per_sec(now, 1.0);
+        let last_10s = self.per_sec(now, 10.0);
+        // Covers every second kept, so has every operation
+        let last_60s = self.per_sec(now, KEPT_SECS as f64);
+
+        last_60s
+            .into_iter()
+            .map(|(operation, per_sec_60s)| {
+                let rates = OperationRates {
+                    per_sec_1s: last_1s.get(operation).copied().unwrap_or_default(),
+                    per_sec_10s: last_10s.get(operation).copied().unwrap_or_default(),
+                    per_sec_60s,
+                };
+                (operation.to_owned(), rates)
+            })
+            .collect()
+    }
+
+    // Requests per second of each operation over the `window` seconds up to
+    // `now`. The second the window starts partway through counts in
+    // proportion to its overlap.
+    fn per_sec(&self, now: f64, window: f64) -> BTreeMap<&'static str, f64> {
+        let start = now - window;
+        let elapsed = window.min(now).max(1.0);
+        let mut rates = BTreeMap::new();
+        for (second, counts) in &self.seconds {
+            let overlap = (*second as f64 + 1.0 - start).min(1.0);
+            if overlap <= 0.0 {
+                continue;
+            }
+            for (&operation, &count) in counts {
+                *rates.entry(operation).or_default() += count as f64 * overlap / elapsed;
+            }
+        }
+
+        rates
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{KvStoreError, Result};
+use mlua::{HookTriggers, Lua, LuaOptions, LuaString, StdLib, Value, VmState};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+// Instructions a script runs between checks against its time limit
+const TIMEOUT_CHECK_INTERVAL: u32 = 10_000;
+
+impl From<mlua::Error> for KvStoreError {
+    fn from(err: mlua::Error) -> Self {
+        match err {
+            mlua::Error::MemoryError(_) => {
+                KvStoreError::ScriptError("script ran out of memory".to_owned())
+            }
+            err => KvStoreError::ScriptError(err.to_string()),
+        }
+    }
+}
+
+/// What a script's `kvs` table reaches. The server checks each write as it
+/// would a `Set` or `Remove` sent on its own, so a script can't get around
+/// key validation, quotas or a write stall.
+pub(crate) trait ScriptHost {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>>;
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<()>;
+    fn remove(&mut self, key: Vec<u8>) -> Result<()>;
+}
+
+/// Run a Lua script against `host`, in the style of Redis's `EVAL`. The
+/// script sees its arguments as the `ARGV` table and the store as `kvs.get`,
+/// `kvs.set` and `kvs.remove`, and may return a string, a number or nil.
+///
+/// Nothing else runs on the engine while the script does, so its steps are
+/// atomic with respect to other requests. Writes made before a script fails
+/// are kept. Only the string, table, math and utf8 libraries are loaded, a
+/// script running longer than `timeout` is stopped, and one allocating more
+/// than `memory_limit` bytes fails rather than exhausting the server's memory.
+pub(crate) fn eval(
+    host: &mut dyn ScriptHost,
+    script: &str,
+    args: Vec<String>,
+    timeout: Duration,
+    memory_limit: usize,
+) -> Result<Option<String>> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    lua.set_memory_limit(memory_limit)?;
+
+    let started = Instant::now();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(TIMEOUT_CHECK_INTERVAL),
+        move |_, _| match started.elapsed() < timeout {
+            true => Ok(VmState::Continue),
+            false => Err(mlua::Error::runtime("script timed out")),
+        },
+    )?;
+    lua.globals().set("ARGV", args)?;
+
+    let host = RefCell::new(host);
+    let result = lua.scope(|scope| {
+        let kvs = lua.create_table()?;
+        kvs.set(
+            "get",
+            scope.create_function(|_, key: LuaString| {
+                let value = host.borrow_mut().get(key.as_bytes().to_vec());
+                value.map_err(mlua::Error::external)
+            })?,
+        )?;
+        kvs.set(
+            "set",
+            scope.create_function(|_, (key, value): (LuaString, String)| {
+                let result = host.borrow_mut().set(key.as_bytes().to_vec(), value);
+                result.map_err(mlua::Error::external)
+            })?,
+        )?;
+        // Returns whether the key was there to remove
+        kvs.set(
+            "remove",
+            scope.create_function(|_, key: LuaString| {
+                match host.borrow_mut().remove(key.as_bytes().to_vec()) {
+                    Ok(()) => Ok(true),
+                    Err(KvStoreError::UnknownKeyError) => Ok(false),
+                    Err(err) => Err(mlua::Error::external(err)),
+                }
+            })?,
+        )?;
+        lua.globals().set("kvs", kvs)?;
+
+        lua.load(script).set_name("script").eval::<Value>()
+    })?;
+
+    match result {
+        Value::Nil => Ok(None),
+        Value::String(value) => Ok(Some(value.to_str()?.to_string())),
+        Value::Integer(value) => Ok(Some(value.to_string())),
+        Value::Number(value) => Ok(Some(value.to_string())),
+        other => Err(KvStoreError::ScriptError(format!(
+            "scripts must return a string, a number or nil, not {}",
+            other.type_name()
+        ))),
+    }
+}
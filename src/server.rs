@@ -1,85 +1,1497 @@
 use std::{
-    io::{self, BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use serde_json::Deserializer;
+use socket2::{SockRef, TcpKeepalive};
 
 use crate::{
-    codec::{Message, Response},
-    KvsEngine,
+    acl::{is_write, message_keys, Acl, User},
+    buffer_pool::BufferPool,
+    chaos::{Chaos, Fault},
+    hotkeys::HotKeys,
+    idempotency::IdempotencyWindow,
+    log_level::LogLevel,
+    protocol::{write_frame, Compression, Entry, FrameReader, Message, Response},
+    pubsub::Channels,
+    quota::{Quota, Quotas},
+    rates::Rates,
+    slowlog::{Request, SlowLog},
+    validate_key, Glob, KvStoreError, KvsEngine, KvsReader, KvsWriter, Lock, LogPosition,
+    MerkleTree, Metrics, Queue, RESERVED_KEY_PREFIX,
 };
 
-use slog::{error, info, Logger};
+#[cfg(feature = "admin-ui")]
+use crate::admin;
+#[cfg(feature = "websocket")]
+use crate::websocket;
 
-pub struct KvsServer<Engine: KvsEngine> {
+use slog::{error, info, o, warn, Level, Logger};
+use tracing::info_span;
+
+/// The protocol a listener's connections speak.
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Tcp,
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    #[cfg(feature = "admin-ui")]
+    Admin,
+}
+
+// A listener accepting connections in the background
+struct Acceptor {
+    addr: SocketAddr,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Accept connections on `listener` in the background, handing each to the
+/// serving loop through `sender`.
+fn accept_on(
+    listener: TcpListener,
+    transport: Transport,
+    sender: Sender<(Transport, io::Result<TcpStream>)>,
+) -> io::Result<Acceptor> {
+    let addr = listener.local_addr()?;
+    let thread = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if sender.send((transport, stream)).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Acceptor { addr, thread })
+}
+
+impl Acceptor {
+    /// Close the listener once the serving loop has dropped its receiver,
+    /// by waking the accepting thread with a connection of its own.
+    fn close(self) {
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if TcpStream::connect(addr).is_ok() {
+            let _ = self.thread.join();
+        }
+    }
+}
+
+#[cfg(not(all(feature = "websocket", feature = "admin-ui")))]
+fn missing_feature(what: &str, addr: SocketAddr, feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Can't serve {} on {} without the {} feature",
+            what, addr, feature
+        ),
+    )
+}
+
+// How often a server waiting for connections lets its engine remove
+// expired keys
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Max number of entries sent in one scan frame
+const SCAN_CHUNK_LEN: usize = 256;
+
+// How often a read waiting on a session position rechecks the store
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// A `Response::Get(Ok(Some(value)))` frame is these around the value's JSON
+// string, as serde encodes it
+const GET_VALUE_PREFIX: &[u8] = br#"{"Get":{"Ok":"#;
+const GET_VALUE_SUFFIX: &[u8] = b"}}";
+
+/// Tunable limits for a `KvsServer`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Largest encoded response, in bytes, the server sends for one request.
+    /// A streamed scan counts all of its frames. Requests whose response
+    /// would be larger fail with `ResponseTooLarge` instead.
+    pub max_response_len: usize,
+    /// Largest frame, in bytes, the server reads from a client. A client
+    /// sending a longer one is disconnected before the frame is buffered;
+    /// longer values can be sent in parts with `Message::SetChunk`.
+    pub max_frame_len: usize,
+    /// Largest value, in bytes, a client may send in `SetChunk` parts.
+    pub max_chunked_value_len: usize,
+    /// Number of response buffers kept for reuse between requests, reported
+    /// by `Message::Metrics`. Buffers beyond this are freed once used.
+    pub buffer_pool_len: usize,
+    /// How long a read carrying a session position waits for a standby to
+    /// catch up before failing with `ReplicaBehind`.
+    pub replica_wait: Duration,
+    /// Requests taking at least this long are recorded in the slow log.
+    pub slowlog_threshold: Duration,
+    /// Number of slow log entries kept; older ones are dropped first.
+    pub slowlog_len: usize,
+    /// How long a script may run before it is stopped, with the `scripting`
+    /// feature.
+    pub script_timeout: Duration,
+    /// Bytes a script may allocate before it fails, with the `scripting`
+    /// feature.
+    pub script_memory_limit: usize,
+    /// Close connections that send nothing for this long. The server serves
+    /// one connection at a time, so an idle client otherwise holds up every
+    /// other one. `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// Send TCP keepalive probes after a connection has been quiet this
+    /// long, so connections to peers that vanished get closed. `None` turns
+    /// keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Also accept connections speaking the protocol over WebSocket, such as
+    /// from a browser, on this address, with the `websocket` feature. Each
+    /// text or binary message holds one or more frames, and each response
+    /// frame comes back as a text message.
+    pub websocket_addr: Option<SocketAddr>,
+    /// Serve the admin web UI, with counters, a key browser and maintenance
+    /// buttons, on this address, with the `admin-ui` feature. On a server
+    /// with an ACL it asks for the name and token of a user with the admin
+    /// permission. Without one it has no authentication, and the address must
+    /// be a loopback one.
+    pub admin_addr: Option<SocketAddr>,
+    /// Reject client requests naming a key that starts with
+    /// `RESERVED_KEY_PREFIX`, and leave such keys out of scans, keeping them
+    /// for internal metadata.
+    pub reserve_internal_keys: bool,
+    /// Number of idempotency keys, across all connections, whose responses
+    /// are kept to answer retries of `Message::Idempotent` with. Older ones
+    /// are forgotten first, after which a retry runs again. Zero remembers
+    /// none.
+    pub idempotency_window: usize,
+    /// Count one in this many gets towards per-key read counts, reported by
+    /// `Message::HotKeys`. Zero counts none.
+    pub hotkeys_sample_rate: u32,
+    /// Keys whose read counts are kept; a newly read key replaces the least
+    /// read one once there are this many.
+    pub hotkeys_len: usize,
+    /// Limits on the keys and bytes under key prefixes. Writes that would
+    /// take a prefix over its limits fail with `QuotaExceeded`. Usage is
+    /// counted from the engine on the first write to a limited prefix, and
+    /// again after a script, queue or lock operation, which can reach keys
+    /// the message doesn't name.
+    pub quotas: Vec<Quota>,
+    /// Inject delays, disconnects and errors into client requests, to test
+    /// clients against. Never set outside of tests.
+    pub chaos: Option<Chaos>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            max_response_len: 256 * 1024 * 1024,
+            max_frame_len: 16 * 1024 * 1024,
+            max_chunked_value_len: 256 * 1024 * 1024,
+            buffer_pool_len: 16,
+            replica_wait: Duration::from_secs(1),
+            slowlog_threshold: Duration::from_millis(10),
+            slowlog_len: 128,
+            script_timeout: Duration::from_secs(5),
+            script_memory_limit: 64 * 1024 * 1024,
+            idle_timeout: None,
+            keepalive: Some(Duration::from_secs(60)),
+            websocket_addr: None,
+            admin_addr: None,
+            reserve_internal_keys: false,
+            idempotency_window: 10_000,
+            hotkeys_sample_rate: 0,
+            hotkeys_len: 10_000,
+            quotas: Vec::new(),
+            chaos: None,
+        }
+    }
+}
+
+/// Opens the engine a server serves from a data directory. A server made
+/// with `KvsServer::reopenable` keeps it to open the engine again on
+/// `Message::Reopen`.
+pub type EngineOpener = Box<dyn FnMut(&Path) -> Result<Box<dyn KvsEngine>, KvStoreError>>;
+
+// How long a reopen keeps trying to open an engine, as one just closed may
+// not have let go of its directory yet
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(2);
+const REOPEN_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The engine a server was given, in the role it may be used in.
+pub(crate) enum ServerEngine {
+    ReadWrite(Box<dyn KvsEngine>),
+    ReadOnly(Box<dyn KvsReader>),
+    /// A reopen closed the engine and couldn't open any
+    Closed(ClosedEngine),
+}
+
+impl ServerEngine {
+    pub(crate) fn reader(&mut self) -> &mut dyn KvsReader {
+        match self {
+            ServerEngine::ReadWrite(engine) => engine.as_mut(),
+            ServerEngine::ReadOnly(reader) => reader.as_mut(),
+            ServerEngine::Closed(closed) => closed,
+        }
+    }
+
+    pub(crate) fn engine(&mut self) -> Result<&mut dyn KvsEngine, KvStoreError> {
+        match self {
+            ServerEngine::ReadWrite(engine) => Ok(engine.as_mut()),
+            ServerEngine::ReadOnly(_) => Err(KvStoreError::ReadOnly),
+            ServerEngine::Closed(_) => Err(KvStoreError::EngineClosed),
+        }
+    }
+}
+
+/// Fails every read, in place of an engine that couldn't be reopened.
+pub(crate) struct ClosedEngine;
+
+impl KvsReader for ClosedEngine {
+    fn get(&mut self, _key: Vec<u8>) -> Result<Option<String>, KvStoreError> {
+        Err(KvStoreError::EngineClosed)
+    }
+
+    fn scan(
+        &mut self,
+        _prefix: &[u8],
+        _start_after: Option<&[u8]>,
+        _limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>, KvStoreError> {
+        Err(KvStoreError::EngineClosed)
+    }
+
+    fn scan_glob(
+        &mut self,
+        _pattern: &Glob,
+        _start_after: Option<&[u8]>,
+        _limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>, KvStoreError> {
+        Err(KvStoreError::EngineClosed)
+    }
+
+    fn contains(&mut self, _key: &[u8]) -> Result<bool, KvStoreError> {
+        Err(KvStoreError::EngineClosed)
+    }
+
+    fn ttl(&mut self, _key: &[u8]) -> Result<Option<Duration>, KvStoreError> {
+        Err(KvStoreError::EngineClosed)
+    }
+}
+
+// A buffer that refuses writes past `limit` bytes, noting that it did
+struct CappedBuffer<'a> {
+    bytes: &'a mut Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl Write for CappedBuffer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.bytes.len() + buf.len() > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("response over its size limit"));
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A value a connection is sending in `SetChunk` parts, as far as it has come
+struct PendingValue {
+    key: Vec<u8>,
+    value: String,
+}
+
+// The directory a reopenable server's engine was opened from, and how to
+// open it again
+struct Reopener {
+    dir: PathBuf,
+    open: EngineOpener,
+}
+
+pub struct KvsServer {
     logger: Logger,
-    engine: Engine,
+    engine: ServerEngine,
+    config: ServerConfig,
+    slow_log: SlowLog,
+    idempotency: IdempotencyWindow,
+    hot_keys: HotKeys,
+    quotas: Quotas,
+    channels: Channels,
+    buffers: BufferPool,
+    rates: Rates,
+    acl: Option<Acl>,
+    log_level: Option<LogLevel>,
+    // The user the current connection authenticated as
+    user: Option<User>,
+    // The value the current connection is sending in parts
+    pending_value: Option<PendingValue>,
+    // How the current connection wants responses of at least so many bytes
+    // compressed
+    compression: Option<(Compression, usize)>,
+    reopener: Option<Reopener>,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Stops a `KvsServer` from another thread: `listen` or `serve` returns once
+/// the connection being served, if any, has closed, and the server can be
+/// dropped to close its engine.
+#[derive(Debug, Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 }
 
-impl<Engine: KvsEngine> KvsServer<Engine> {
-    pub fn new(logger: Logger, engine: Engine) -> KvsServer<Engine> {
-        return KvsServer { logger, engine };
+impl KvsServer {
+    pub fn new(logger: Logger, engine: impl KvsEngine + 'static) -> KvsServer {
+        KvsServer::with_engine(logger, ServerEngine::ReadWrite(Box::new(engine)))
+    }
+
+    /// Create a server that answers reads and rejects every write.
+    pub fn read_only(logger: Logger, reader: impl KvsReader + 'static) -> KvsServer {
+        KvsServer::with_engine(logger, ServerEngine::ReadOnly(Box::new(reader)))
+    }
+
+    /// Create a server whose engine `open` opens from `dir`, and which can
+    /// close it and open it again on `Message::Reopen`, e.g. after a backup
+    /// was restored into the directory, without dropping its listeners.
+    pub fn reopenable(
+        logger: Logger,
+        dir: PathBuf,
+        mut open: EngineOpener,
+    ) -> Result<KvsServer, KvStoreError> {
+        let engine = open(&dir)?;
+        let mut server = KvsServer::with_engine(logger, ServerEngine::ReadWrite(engine));
+        server.reopener = Some(Reopener { dir, open });
+        Ok(server)
+    }
+
+    fn with_engine(logger: Logger, engine: ServerEngine) -> KvsServer {
+        let config = ServerConfig::default();
+
+        KvsServer {
+            logger,
+            engine,
+            slow_log: SlowLog::new(config.slowlog_threshold, config.slowlog_len),
+            idempotency: IdempotencyWindow::new(config.idempotency_window),
+            hot_keys: HotKeys::new(config.hotkeys_sample_rate, config.hotkeys_len),
+            quotas: Quotas::new(config.quotas.clone()),
+            channels: Channels::default(),
+            buffers: BufferPool::new(config.buffer_pool_len),
+            rates: Rates::new(),
+            acl: None,
+            log_level: None,
+            user: None,
+            pending_value: None,
+            compression: None,
+            reopener: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            config,
+        }
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> KvsServer {
+        self.slow_log = SlowLog::new(config.slowlog_threshold, config.slowlog_len);
+        self.idempotency = IdempotencyWindow::new(config.idempotency_window);
+        self.hot_keys = HotKeys::new(config.hotkeys_sample_rate, config.hotkeys_len);
+        self.quotas = Quotas::new(config.quotas.clone());
+        self.buffers = BufferPool::new(config.buffer_pool_len);
+        self.config = config;
+        self
+    }
+
+    /// A handle that stops the server from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stopped.clone())
+    }
+
+    /// Require every connection to authenticate as one of the ACL's users,
+    /// and limit it to what that user's permissions cover.
+    pub fn with_acl(mut self, acl: Acl) -> KvsServer {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Let `Message::SetLogLevel` change `log_level`, the level of the drain
+    /// the server's logger writes to.
+    pub fn with_log_level(mut self, log_level: LogLevel) -> KvsServer {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    /// Check that the connection may send `message`.
+    fn authorize(&self, message: &Message) -> Result<(), KvStoreError> {
+        if self.acl.is_none() || matches!(message, Message::Auth { .. } | Message::Hello { .. }) {
+            return Ok(());
+        }
+
+        match &self.user {
+            Some(user) => user.check(message),
+            None => Err(KvStoreError::PermissionDenied(
+                "authenticate first".to_owned(),
+            )),
+        }
+    }
+
+    /// Check the keys `message` names before it reaches the engine. Scan
+    /// prefixes are exempt, as the empty prefix scans everything.
+    fn check_keys(&self, message: &Message) -> Result<(), KvStoreError> {
+        if matches!(message, Message::Scan { .. }) {
+            return Ok(());
+        }
+
+        for key in message_keys(message).unwrap_or_default() {
+            validate_key(key)?;
+            if self.config.reserve_internal_keys && key.starts_with(RESERVED_KEY_PREFIX) {
+                return Err(KvStoreError::ReservedKey);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How long a client should wait before sending `message` again, if it
+    /// writes and the engine is refusing writes.
+    fn write_stall(&mut self, message: &Message) -> Option<Duration> {
+        if !is_write(message) {
+            return None;
+        }
+
+        self.engine().ok()?.write_stall()
     }
 
+    /// Make a write for a script, with the key, stall and quota checks
+    /// `message` would get if it were sent on its own.
+    #[cfg(feature = "scripting")]
+    fn script_write(&mut self, message: Message) -> Result<(), KvStoreError> {
+        self.check_keys(&message)?;
+        if let Some(retry_after) = self.write_stall(&message) {
+            return Err(KvStoreError::WriteStalled(retry_after));
+        }
+        let touched = self.quotas.check(self.engine.reader(), &message)?;
+
+        let result = self.writer().and_then(|writer| match message {
+            Message::Set { key, value, .. } => writer.set(key, value),
+            Message::Remove { key } => writer.remove(key),
+            _ => unreachable!("scripts only set and remove"),
+        });
+        if let Err(err) = self.quotas.update(self.engine.reader(), touched) {
+            error!(self.logger, "Counting quota usage failed: {}", err);
+            self.quotas.reset();
+        }
+        result
+    }
+
+    fn reader(&mut self) -> &mut dyn KvsReader {
+        self.engine.reader()
+    }
+
+    fn engine(&mut self) -> Result<&mut dyn KvsEngine, KvStoreError> {
+        self.engine.engine()
+    }
+
+    fn writer(&mut self) -> Result<&mut dyn KvsWriter, KvStoreError> {
+        match &mut self.engine {
+            ServerEngine::ReadWrite(engine) => Ok(engine.as_mut()),
+            ServerEngine::ReadOnly(_) => Err(KvStoreError::ReadOnly),
+            ServerEngine::Closed(_) => Err(KvStoreError::EngineClosed),
+        }
+    }
+
+    /// Close the engine and open it again from `dir`, or from the directory
+    /// it was last opened from. If `dir` can't be opened, the previous
+    /// directory is opened again and the error returned; if that fails too,
+    /// the server answers with `EngineClosed` until a later reopen succeeds.
+    fn reopen(&mut self, dir: Option<PathBuf>) -> Result<(), KvStoreError> {
+        let Some(reopener) = &mut self.reopener else {
+            return Err(KvStoreError::StringError(
+                "The server can't reopen its engine".to_owned(),
+            ));
+        };
+        if let ServerEngine::ReadWrite(engine) = &mut self.engine {
+            engine.flush()?;
+        }
+        // Engines lock their directory, so close the old one first
+        self.engine = ServerEngine::Closed(ClosedEngine);
+
+        let dir = dir.unwrap_or_else(|| reopener.dir.clone());
+        match open_retrying(&mut reopener.open, &dir) {
+            Ok(engine) => {
+                info!(self.logger, "Reopened the engine from {}", dir.display());
+                self.engine = ServerEngine::ReadWrite(engine);
+                reopener.dir = dir;
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    self.logger,
+                    "Opening {} failed, opening {} again: {}",
+                    dir.display(),
+                    reopener.dir.display(),
+                    err
+                );
+                let engine = open_retrying(&mut reopener.open, &reopener.dir)?;
+                self.engine = ServerEngine::ReadWrite(engine);
+                Err(err)
+            }
+        }
+    }
+
+    /// Serve connections on `addr`, and on `ServerConfig::websocket_addr` and
+    /// `ServerConfig::admin_addr` if set. Connections are served one at a time, whichever listener they
+    /// came in on.
     pub fn listen(&mut self, addr: SocketAddr) -> Result<(), io::Error> {
-        let listener = TcpListener::bind(addr)?;
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve connections on a listener that is already bound, such as one on
+    /// port 0 whose address the caller wants to know first, as `listen`
+    /// does. Returns once `stop_handle` has been used to stop the server.
+    pub fn serve(&mut self, listener: TcpListener) -> Result<(), io::Error> {
+        let addr = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel();
+        #[cfg_attr(
+            not(any(feature = "websocket", feature = "admin-ui")),
+            allow(unused_mut)
+        )]
+        let mut acceptors = vec![accept_on(listener, Transport::Tcp, sender.clone())?];
         info!(self.logger, "Listening on {}", addr);
+        if let Some(websocket_addr) = self.config.websocket_addr {
+            #[cfg(feature = "websocket")]
+            {
+                acceptors.push(accept_on(
+                    TcpListener::bind(websocket_addr)?,
+                    Transport::WebSocket,
+                    sender.clone(),
+                )?);
+                info!(
+                    self.logger,
+                    "Listening for WebSockets on {}", websocket_addr
+                );
+            }
+            #[cfg(not(feature = "websocket"))]
+            return Err(missing_feature("WebSockets", websocket_addr, "websocket"));
+        }
+        if let Some(admin_addr) = self.config.admin_addr {
+            #[cfg(feature = "admin-ui")]
+            {
+                if self.acl.is_none() && !admin_addr.ip().is_loopback() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Refusing to serve the admin UI on {} without an ACL to \
+                             authenticate its users; bind it to a loopback address",
+                            admin_addr
+                        ),
+                    ));
+                }
+                acceptors.push(accept_on(
+                    TcpListener::bind(admin_addr)?,
+                    Transport::Admin,
+                    sender,
+                )?);
+                info!(
+                    self.logger,
+                    "Serving the admin UI on http://{}/", admin_addr
+                );
+            }
+            #[cfg(not(feature = "admin-ui"))]
+            return Err(missing_feature("the admin UI", admin_addr, "admin-ui"));
+        }
 
-        for stream in listener.incoming() {
+        while !self.stopped.load(Ordering::Relaxed) {
+            let (transport, stream) = match incoming.recv_timeout(REAP_INTERVAL) {
+                Ok(incoming) => incoming,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.reap_expired();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
             match stream {
+                // A panic while serving one client must not take the server
+                // down with it; drop that connection and keep accepting
                 Ok(stream) => {
-                    if let Err(e) = self.handle_client(stream) {
-                        error!(self.logger, "Error on serving client: {}", e);
+                    let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.handle_client(transport, stream)
+                    }));
+                    match served {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!(self.logger, "Error on serving client: {}", e),
+                        Err(_) => error!(self.logger, "Panicked while serving client"),
                     }
                 }
                 Err(e) => error!(self.logger, "Connection failed: {}", e),
             }
         }
 
+        // Let go of the listeners so their addresses can be served again
+        drop(incoming);
+        acceptors.into_iter().for_each(Acceptor::close);
+
         Ok(())
     }
 
-    fn handle_client(&mut self, stream: TcpStream) -> Result<(), io::Error> {
+    fn handle_client(&mut self, transport: Transport, stream: TcpStream) -> Result<(), io::Error> {
         info!(self.logger, "Connected to client.");
-        let reader_stream = stream;
-        let writer_stream = reader_stream.try_clone()?;
+        self.user = None;
+        self.pending_value = None;
+        self.compression = None;
+        stream.set_read_timeout(self.config.idle_timeout)?;
+        if let Some(keepalive) = self.config.keepalive {
+            SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+
+        match transport {
+            Transport::Tcp => {
+                let writer_stream = stream.try_clone()?;
+                self.serve_connection(&stream, stream.try_clone()?, writer_stream)
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket => {
+                let (reader, writer) = websocket::accept(stream.try_clone()?)?;
+                self.serve_connection(&stream, reader, writer)
+            }
+            #[cfg(feature = "admin-ui")]
+            Transport::Admin => admin::serve(stream, &mut self.engine, self.acl.as_ref()),
+        }
+    }
+
+    /// Answer the messages read from `reader` on `writer` until the client
+    /// goes away. `stream` is the socket under both.
+    fn serve_connection(
+        &mut self,
+        stream: &TcpStream,
+        reader: impl Read,
+        writer: impl Write + Send + 'static,
+    ) -> Result<(), io::Error> {
+        // Requests log through a copy tagged with their trace id, if any
+        let logger = self.logger.clone();
+        let result = self.serve_messages(stream, reader, writer, &logger);
+        self.logger = logger;
+
+        result
+    }
+
+    fn serve_messages(
+        &mut self,
+        stream: &TcpStream,
+        reader: impl Read,
+        writer: impl Write + Send + 'static,
+        logger: &Logger,
+    ) -> Result<(), io::Error> {
+        let connection = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string());
+
+        let mut message_stream: FrameReader<_, Message> =
+            FrameReader::with_max_len(BufReader::new(reader), self.config.max_frame_len as u64);
+        let mut writer = BufWriter::new(writer);
+
+        loop {
+            let request_start = message_stream.byte_offset();
+            let Some(message) = message_stream.next() else {
+                break;
+            };
+            let message = match message {
+                Ok(message) => message,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    info!(self.logger, "Closing idle connection.");
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            let (trace_id, message) = match message {
+                Message::Traced { trace_id, message } => (Some(trace_id), *message),
+                message => (None, message),
+            };
+            self.logger = match &trace_id {
+                Some(trace_id) => logger.new(o!("trace_id" => trace_id.clone())),
+                None => logger.clone(),
+            };
+
+            match &message {
+                // Keep tokens out of the log
+                Message::Auth { user, .. } => info!(self.logger, "Received auth for {}", user),
+                message => info!(self.logger, "Received message: {:?}", message),
+            }
+
+            if let Err(err) = self.authorize(&message) {
+                info!(self.logger, "{}", err);
+                write_frame(&mut writer, &Response::Denied(err.to_string()))?;
+                continue;
+            }
+
+            if let Some(retry_after) = self.write_stall(&message) {
+                info!(self.logger, "Writes are stalled for {:?}", retry_after);
+                let retry_after_ms = retry_after.as_millis() as u64;
+                write_frame(&mut writer, &Response::Stalled { retry_after_ms })?;
+                continue;
+            }
+
+            if let Some(chaos) = self.config.chaos.as_ref().filter(|_| !message.is_admin()) {
+                if let Some(delay) = chaos.delay() {
+                    info!(self.logger, "Chaos: delaying the request by {:?}", delay);
+                    thread::sleep(delay);
+                }
+                match chaos.fault() {
+                    Some(Fault::Disconnect) => {
+                        info!(self.logger, "Chaos: closing the connection");
+                        break;
+                    }
+                    Some(Fault::Error) => {
+                        info!(self.logger, "Chaos: failing the request");
+                        let response = message.failed("Injected by chaos mode".to_owned());
+                        write_frame(&mut writer, &response)?;
+                        continue;
+                    }
+                    None => {}
+                }
+            }
 
-        let message_stream =
-            Deserializer::from_reader(BufReader::new(reader_stream)).into_iter::<Message>();
-        let mut writer = BufWriter::new(writer_stream);
+            // The connection now belongs to its channels; stop reading from it
+            if let Message::Subscribe { channels } = message {
+                write_frame(&mut writer, &Response::Subscribed(Ok(())))?;
+                self.channels.subscribe(channels, stream, writer)?;
+                break;
+            }
 
-        for message in message_stream {
-            let message = message?;
-            info!(self.logger, "Received message: {:?}", message);
+            let started = Instant::now();
+            let request_len = message_stream.byte_offset() - request_start;
+            let operation = message.operation();
+            let is_admin = message.is_admin();
+            let key = message.key().map(<[u8]>::to_vec);
 
-            let response = self.handle_message(message);
+            let _request = info_span!(
+                "request",
+                operation,
+                key = key.as_deref().map(String::from_utf8_lossy).as_deref(),
+                trace_id = trace_id.as_deref()
+            )
+            .entered();
 
-            info!(self.logger, "Sending response: {:?}", response);
-            serde_json::to_writer(&mut writer, &response)?;
+            let response_len = self.respond(message, &mut writer)?;
 
+            self.rates.record(operation);
+            if !is_admin {
+                let request = Request {
+                    operation,
+                    key: key.as_deref(),
+                    request_len,
+                    response_len,
+                    connection: &connection,
+                    trace_id: trace_id.as_deref(),
+                };
+                self.slow_log.record(request, started.elapsed());
+            }
+            self.reap_expired();
+        }
+
+        if let Ok(writer) = self.writer() {
             writer.flush()?;
         }
 
-        self.engine.flush()?;
+        Ok(())
+    }
+
+    /// Let the engine remove expired keys, between requests.
+    fn reap_expired(&mut self) {
+        let Ok(engine) = self.engine.engine() else {
+            return;
+        };
+
+        match engine.reap_expired() {
+            Ok(0) => {}
+            // The quotas counted the removed keys
+            Ok(_) => self.quotas.reset(),
+            Err(err) => error!(self.logger, "Failed to remove expired keys: {}", err),
+        }
+    }
+
+    /// Answer one message, returning the number of bytes written.
+    fn respond(&mut self, message: Message, writer: &mut impl Write) -> Result<usize, io::Error> {
+        if let Message::Scan {
+            prefix,
+            after,
+            pattern,
+        } = message
+        {
+            return self.stream_scan(prefix, pattern, after, writer);
+        }
+
+        if let Err(err) = self.check_keys(&message) {
+            return self.send_response(message.failed(err.to_string()), writer);
+        }
+        if let Message::Get { key, after } = message {
+            return self.send_value(key, after, writer);
+        }
+
+        let response = info_span!("engine").in_scope(|| self.handle_message(message));
+        self.send_response(response, writer)
+    }
+
+    /// Write `response`, returning the number of bytes written.
+    fn send_response(
+        &mut self,
+        mut response: Response,
+        writer: &mut impl Write,
+    ) -> Result<usize, io::Error> {
+        // Encode before writing so an oversized response is replaced by an
+        // error rather than cut off midway, stopping at the limit so it is
+        // never encoded whole
+        let mut bytes = self.buffers.take();
+        let limit = self.config.max_response_len;
+        let mut capped = CappedBuffer {
+            bytes: &mut bytes,
+            limit,
+            exceeded: false,
+        };
+        match serde_json::to_writer(&mut capped, &response) {
+            Ok(()) => {}
+            Err(_) if capped.exceeded => {
+                let err = KvStoreError::ResponseTooLarge(limit).to_string();
+                error!(self.logger, "{}", err);
+                response = response.into_error(err);
+                bytes.clear();
+                serde_json::to_writer(&mut bytes, &response)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        info!(self.logger, "Sending response: {:?}", response);
+        let written = self.write_encoded(&bytes, writer);
+        self.buffers.put(bytes);
+
+        written
+    }
+
+    /// Write an encoded response, compressed if the connection asked for
+    /// that and it comes out shorter, returning the number of bytes written.
+    fn write_encoded(&self, bytes: &[u8], writer: &mut impl Write) -> Result<usize, io::Error> {
+        let compressed = match self.compression {
+            Some((compression, compress_above)) if bytes.len() >= compress_above => {
+                Some(serde_json::to_vec(&compression.compress(bytes)?)?)
+            }
+            _ => None,
+        };
+        let bytes = match &compressed {
+            Some(compressed) if compressed.len() < bytes.len() => compressed,
+            _ => bytes,
+        };
+
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        Ok(bytes.len())
+    }
+
+    /// Answer a get, copying the value's JSON encoding from the engine into
+    /// the frame instead of decoding it and encoding it again.
+    fn send_value(
+        &mut self,
+        key: Vec<u8>,
+        after: Option<LogPosition>,
+        writer: &mut impl Write,
+    ) -> Result<usize, io::Error> {
+        self.hot_keys.record(&key);
+        let result = info_span!("engine").in_scope(|| {
+            self.wait_until_applied(after)?;
+            self.reader().get_json(key)
+        });
+        let value = match result {
+            Ok(Some(value)) => value,
+            Ok(None) => return self.send_response(Response::Get(Ok(None)), writer),
+            Err(err) => return self.send_response(Response::Get(Err(err.to_string())), writer),
+        };
+
+        let len = GET_VALUE_PREFIX.len() + value.len() + GET_VALUE_SUFFIX.len();
+        let limit = self.config.max_response_len;
+        if len > limit {
+            let err = KvStoreError::ResponseTooLarge(limit).to_string();
+            error!(self.logger, "{}: {} bytes", err, len);
+            return self.send_response(Response::Get(Err(err)), writer);
+        }
+
+        info!(self.logger, "Sending value of {} bytes", value.len());
+        if self
+            .compression
+            .is_some_and(|(_, compress_above)| len >= compress_above)
+        {
+            let mut bytes = self.buffers.take();
+            bytes.extend_from_slice(GET_VALUE_PREFIX);
+            bytes.extend_from_slice(&value);
+            bytes.extend_from_slice(GET_VALUE_SUFFIX);
+            let written = self.write_encoded(&bytes, writer);
+            self.buffers.put(bytes);
+            return written;
+        }
+        writer.write_all(GET_VALUE_PREFIX)?;
+        writer.write_all(&value)?;
+        writer.write_all(GET_VALUE_SUFFIX)?;
+        writer.flush()?;
+
+        Ok(len)
+    }
+
+    /// Write the scan result in bounded chunks. Each chunk is flushed before the
+    /// next one is read from the engine, so a slow client blocks the socket
+    /// write instead of the whole result being buffered in memory.
+    fn stream_scan(
+        &mut self,
+        prefix: Vec<u8>,
+        pattern: Option<String>,
+        after: Option<LogPosition>,
+        writer: &mut impl Write,
+    ) -> Result<usize, io::Error> {
+        let mut start_after: Option<Vec<u8>> = None;
+        let mut sent = 0;
+
+        let pattern = match pattern.as_deref().map(Glob::new).transpose() {
+            Ok(pattern) => pattern,
+            Err(err) => return end_scan(writer, Err(err.to_string())),
+        };
+
+        if let Err(err) = self.wait_until_applied(after) {
+            return end_scan(writer, Err(err.to_string()));
+        }
+
+        loop {
+            let chunk = match info_span!("engine").in_scope(|| match &pattern {
+                Some(pattern) => {
+                    self.reader()
+                        .scan_glob(pattern, start_after.as_deref(), SCAN_CHUNK_LEN)
+                }
+                None => self
+                    .reader()
+                    .scan(&prefix, start_after.as_deref(), SCAN_CHUNK_LEN),
+            }) {
+                Ok(chunk) => chunk,
+                Err(err) => return Ok(sent + end_scan(writer, Err(err.to_string()))?),
+            };
+
+            let is_last = chunk.len() < SCAN_CHUNK_LEN;
+            start_after = chunk.last().map(|(key, _)| key.clone());
+            let chunk: Vec<_> = chunk
+                .into_iter()
+                .filter(|(key, _)| {
+                    !(self.config.reserve_internal_keys && key.starts_with(RESERVED_KEY_PREFIX))
+                })
+                .collect();
+
+            if !chunk.is_empty() {
+                info!(self.logger, "Sending scan chunk of {} entries", chunk.len());
+                let entries = chunk
+                    .into_iter()
+                    .map(|(key, value)| Entry { key, value })
+                    .collect();
+                let mut bytes = self.buffers.take();
+                serde_json::to_writer(&mut bytes, &Response::ScanChunk(entries))?;
+
+                let limit = self.config.max_response_len;
+                if sent + bytes.len() > limit {
+                    self.buffers.put(bytes);
+                    let err = KvStoreError::ResponseTooLarge(limit).to_string();
+                    error!(self.logger, "Aborting scan: {}", err);
+                    return Ok(sent + end_scan(writer, Err(err))?);
+                }
+
+                let written = self.write_encoded(&bytes, writer);
+                self.buffers.put(bytes);
+                sent += written?;
+            }
+
+            if is_last {
+                return Ok(sent + end_scan(writer, Ok(()))?);
+            }
+        }
+    }
+
+    /// Block until the engine has applied writes up to `after`, giving up after
+    /// the configured replica wait.
+    fn wait_until_applied(&mut self, after: Option<LogPosition>) -> Result<(), KvStoreError> {
+        let Some(position) = after else {
+            return Ok(());
+        };
+
+        let deadline = Instant::now() + self.config.replica_wait;
+        while !self.reader().has_applied(position)? {
+            if Instant::now() >= deadline {
+                return Err(KvStoreError::ReplicaBehind);
+            }
+            thread::sleep(SESSION_POLL_INTERVAL);
+        }
 
         Ok(())
     }
 
+    /// Add a part of a value being sent in chunks, returning the key and
+    /// the whole value once the last part is in.
+    fn add_chunk(
+        &mut self,
+        key: Vec<u8>,
+        offset: u64,
+        chunk: String,
+        last: bool,
+    ) -> Result<Option<(Vec<u8>, String)>, KvStoreError> {
+        let mut pending = match self.pending_value.take() {
+            _ if offset == 0 => PendingValue {
+                key,
+                value: String::new(),
+            },
+            Some(pending) if pending.key == key && pending.value.len() as u64 == offset => pending,
+            _ => {
+                return Err(KvStoreError::StringError(
+                    "The chunk doesn't continue the value being sent; start again from offset 0"
+                        .to_owned(),
+                ))
+            }
+        };
+
+        let limit = self.config.max_chunked_value_len;
+        if pending.value.len() + chunk.len() > limit {
+            return Err(KvStoreError::StringError(format!(
+                "Chunked value exceeds the maximum length of {} bytes",
+                limit
+            )));
+        }
+        pending.value.push_str(&chunk);
+
+        if last {
+            return Ok(Some((pending.key, pending.value)));
+        }
+        self.pending_value = Some(pending);
+        Ok(None)
+    }
+
     fn handle_message(&mut self, message: Message) -> Response {
+        if let Err(err) = self.check_keys(&message) {
+            return message.failed(err.to_string());
+        }
+
+        // Wrapped and batched messages are checked one by one as they run
+        let touched = match &message {
+            Message::Traced { .. }
+            | Message::Idempotent { .. }
+            | Message::Batch(_)
+            | Message::SetChunk { .. } => None,
+            message if is_write(message) => {
+                match self.quotas.check(self.engine.reader(), message) {
+                    Ok(touched) => Some(touched),
+                    Err(err) => return message.failed(err.to_string()),
+                }
+            }
+            _ => None,
+        };
+
+        let response = self.run_message(message);
+        if let Some(touched) = touched {
+            if let Err(err) = self.quotas.update(self.engine.reader(), touched) {
+                error!(self.logger, "Counting quota usage failed: {}", err);
+                self.quotas.reset();
+            }
+        }
+        response
+    }
+
+    fn run_message(&mut self, message: Message) -> Response {
         match message {
-            Message::Set { key, value } => {
-                let result = self.engine.set(key, value).map_err(|err| err.to_string());
+            Message::Set { key, value, ttl_ms } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| {
+                        match ttl_ms {
+                            Some(ttl_ms) => {
+                                writer.set_with_ttl(key, value, Duration::from_millis(ttl_ms))?
+                            }
+                            None => writer.set(key, value)?,
+                        }
+                        write_position(writer)
+                    })
+                    .map_err(|err| err.to_string());
                 Response::Set(result)
             }
-            Message::Get { key } => {
-                let result = self.engine.get(key).map_err(|err| err.to_string());
+            Message::SetChunk {
+                key,
+                offset,
+                chunk,
+                last,
+                ttl_ms,
+            } => match self.add_chunk(key, offset, chunk, last) {
+                Ok(Some((key, value))) => {
+                    match self.handle_message(Message::Set { key, value, ttl_ms }) {
+                        Response::Set(result) => Response::SetChunk(result),
+                        response => response,
+                    }
+                }
+                Ok(None) => Response::SetChunk(Ok(None)),
+                Err(err) => Response::SetChunk(Err(err.to_string())),
+            },
+            Message::SetNx { key, value } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| writer.set_nx(key, value))
+                    .map_err(|err| err.to_string());
+                Response::SetNx(result)
+            }
+            Message::Get { key, after } => {
+                self.hot_keys.record(&key);
+                let result = self
+                    .wait_until_applied(after)
+                    .and_then(|()| self.reader().get(key))
+                    .map_err(|err| err.to_string());
                 Response::Get(result)
             }
+            Message::GetRange {
+                key,
+                offset,
+                len,
+                after,
+            } => {
+                self.hot_keys.record(&key);
+                let result = self
+                    .wait_until_applied(after)
+                    .and_then(|()| self.reader().get_range(key, offset as usize, len as usize))
+                    .map_err(|err| err.to_string());
+                Response::GetRange(result)
+            }
             Message::Remove { key } => {
-                let result = self.engine.remove(key).map_err(|err| err.to_string());
+                let result = self
+                    .writer()
+                    .and_then(|writer| {
+                        writer.remove(key)?;
+                        write_position(writer)
+                    })
+                    .map_err(|err| err.to_string());
                 Response::Remove(result)
             }
+            Message::GetSet { key, value } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| writer.get_set(key, value))
+                    .map_err(|err| err.to_string());
+                Response::GetSet(result)
+            }
+            Message::GetDel { key } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| writer.get_del(key))
+                    .map_err(|err| err.to_string());
+                Response::GetDel(result)
+            }
+            Message::Append { key, suffix } => {
+                let result = self
+                    .engine()
+                    .and_then(|engine| engine.append(key, &suffix))
+                    .map_err(|err| err.to_string());
+                Response::Append(result)
+            }
+            Message::Ttl { key } => {
+                let result = self
+                    .reader()
+                    .ttl(&key)
+                    .map(|ttl| ttl.map(|ttl| ttl.as_millis() as u64))
+                    .map_err(|err| err.to_string());
+                Response::Ttl(result)
+            }
+            Message::Expire { key, ttl_ms } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| writer.expire(key, Duration::from_millis(ttl_ms)))
+                    .map_err(|err| err.to_string());
+                Response::Expire(result)
+            }
+            Message::Persist { key } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| writer.persist(key))
+                    .map_err(|err| err.to_string());
+                Response::Persist(result)
+            }
+            Message::Restore { key } => {
+                let result = self
+                    .writer()
+                    .and_then(|writer| {
+                        writer.restore(key)?;
+                        write_position(writer)
+                    })
+                    .map_err(|err| err.to_string());
+                Response::Restore(result)
+            }
+            Message::Traced { message, .. } => self.handle_message(*message),
+            Message::Idempotent {
+                idempotency_key,
+                message,
+            } => {
+                let user = self.user.as_ref().map(|user| user.name.clone());
+                if let Some(response) = self.idempotency.get(user.as_deref(), &idempotency_key) {
+                    info!(
+                        self.logger,
+                        "Answering a retry of {:?} with its first response", idempotency_key
+                    );
+                    return response.clone();
+                }
+
+                let response = self.handle_message(*message);
+                if !response.is_err() {
+                    self.idempotency
+                        .record(user, idempotency_key, response.clone());
+                }
+                response
+            }
+            Message::Batch(messages) => {
+                let responses = messages
+                    .into_iter()
+                    .map(|message| self.handle_message(message))
+                    .collect();
+                Response::Batch(Ok(responses))
+            }
+            #[cfg(feature = "scripting")]
+            Message::Eval { script, args } => {
+                let timeout = self.config.script_timeout;
+                let memory_limit = self.config.script_memory_limit;
+                let result = match self.engine() {
+                    Ok(_) => crate::scripting::eval(self, &script, args, timeout, memory_limit),
+                    Err(err) => Err(err),
+                };
+                Response::Eval(result.map_err(|err| err.to_string()))
+            }
+            #[cfg(not(feature = "scripting"))]
+            Message::Eval { .. } => {
+                Response::Eval(Err("Scripting is not enabled on this server".to_string()))
+            }
+            Message::SlowLogGet { count } => Response::SlowLogGet(Ok(self.slow_log.get(count))),
+            Message::SlowLogReset => {
+                self.slow_log.reset();
+                Response::SlowLogReset(Ok(()))
+            }
+            Message::Quotas => {
+                let result = self.quotas.usage(self.engine.reader());
+                Response::Quotas(result.map_err(|err| err.to_string()))
+            }
+            Message::HotKeys { count } => match self.hot_keys.is_enabled() {
+                true => Response::HotKeys(Ok(self.hot_keys.top(count))),
+                false => Response::HotKeys(Err("The server doesn't sample reads".to_owned())),
+            },
+            Message::Metrics => {
+                let buffer_pool = self.buffers.stats();
+                let rates = self.rates.get();
+                let result = self.engine().map(|engine| {
+                    Box::new(Metrics {
+                        buffer_pool: Some(buffer_pool),
+                        rates: Some(rates),
+                        ..engine.metrics()
+                    })
+                });
+                Response::Metrics(result.map_err(|err| err.to_string()))
+            }
+            Message::CompactionHistory => {
+                let result = self.engine().map(|engine| engine.compaction_history());
+                Response::CompactionHistory(result.map_err(|err| err.to_string()))
+            }
+            Message::Auth { user, token } => {
+                let result = match &self.acl {
+                    Some(acl) => match acl.authenticate(&user, &token) {
+                        Some(user) => {
+                            self.user = Some(user.clone());
+                            Ok(())
+                        }
+                        None => Err("Unknown user or wrong token".to_string()),
+                    },
+                    // Without an ACL every connection may do anything
+                    None => Ok(()),
+                };
+                Response::Auth(result)
+            }
+            Message::ReloadAcl => {
+                let result = match &mut self.acl {
+                    Some(acl) => acl.reload(),
+                    None => Err(KvStoreError::StringError(
+                        "The server has no ACL to reload".to_owned(),
+                    )),
+                };
+                // Pick up changes to the current user's permissions
+                if let (Ok(()), Some(acl), Some(user)) = (&result, &self.acl, &self.user) {
+                    self.user = acl.authenticate(&user.name, &user.token).cloned();
+                }
+                Response::ReloadAcl(result.map_err(|err| err.to_string()))
+            }
+            Message::SetLogLevel { level } => {
+                let result = match (&self.log_level, level.parse::<Level>()) {
+                    (None, _) => Err("The server's log level can't be changed".to_owned()),
+                    (Some(_), Err(())) => Err(format!("Unknown log level {:?}", level)),
+                    (Some(log_level), Ok(level)) => {
+                        log_level.set(level);
+                        warn!(self.logger, "Log level is now {}", level.as_str());
+                        Ok(())
+                    }
+                };
+                Response::SetLogLevel(result)
+            }
+            Message::Reopen { dir } => {
+                let result = self.reopen(dir);
+                self.quotas.reset();
+                Response::Reopen(result.map_err(|err| err.to_string()))
+            }
+            Message::RotateLog => {
+                let result = self.writer().and_then(|writer| writer.rotate_log());
+                Response::RotateLog(result.map_err(|err| err.to_string()))
+            }
+            Message::Analyze { samples, delimiter } => {
+                let result = crate::analyze(self.reader(), samples, delimiter);
+                Response::Analyze(result.map_err(|err| err.to_string()))
+            }
+            Message::Digest { prefix_len } => {
+                let result = crate::digest(self.reader(), prefix_len);
+                Response::Digest(result.map_err(|err| err.to_string()))
+            }
+            Message::MerkleNodes { depth, nodes } => {
+                let result =
+                    MerkleTree::build(self.reader(), depth).and_then(|tree| tree.nodes(&nodes));
+                Response::MerkleNodes(result.map_err(|err| err.to_string()))
+            }
+            Message::Hello {
+                compression,
+                compress_above,
+            } => {
+                let chosen = compression
+                    .into_iter()
+                    .find(|compression| *compression == Compression::Zstd);
+                self.compression = chosen.map(|compression| (compression, compress_above as usize));
+                Response::Hello(Ok(chosen))
+            }
+            Message::Scan { .. } => Response::ScanEnd(Err("Scans must be streamed".to_string())),
+            Message::Enqueue { queue, item } => {
+                let result = self
+                    .engine()
+                    .and_then(|engine| Queue::new(&queue).enqueue(engine, item));
+                Response::Enqueue(result.map_err(|err| err.to_string()))
+            }
+            Message::Dequeue {
+                queue,
+                visibility_ms,
+            } => {
+                let visibility_timeout = Duration::from_millis(visibility_ms);
+                let result = self
+                    .engine()
+                    .and_then(|engine| Queue::new(&queue).dequeue(engine, visibility_timeout));
+                Response::Dequeue(result.map_err(|err| err.to_string()))
+            }
+            Message::Ack { queue, id } => {
+                let result = self
+                    .engine()
+                    .and_then(|engine| Queue::new(&queue).ack(engine, id));
+                Response::Ack(result.map_err(|err| err.to_string()))
+            }
+            Message::Acquire { lock, ttl_ms } => {
+                let ttl = Duration::from_millis(ttl_ms);
+                let result = self
+                    .engine()
+                    .and_then(|engine| Lock::new(&lock).acquire(engine, ttl));
+                Response::Acquire(result.map_err(|err| err.to_string()))
+            }
+            Message::Renew {
+                lock,
+                token,
+                ttl_ms,
+            } => {
+                let ttl = Duration::from_millis(ttl_ms);
+                let result = self
+                    .engine()
+                    .and_then(|engine| Lock::new(&lock).renew(engine, token, ttl));
+                Response::Renew(result.map_err(|err| err.to_string()))
+            }
+            Message::Release { lock, token } => {
+                let result = self
+                    .engine()
+                    .and_then(|engine| Lock::new(&lock).release(engine, token));
+                Response::Release(result.map_err(|err| err.to_string()))
+            }
+            Message::Txn(txn) => {
+                let result = self.engine().and_then(|engine| txn.apply(engine));
+                Response::Txn(result.map_err(|err| err.to_string()))
+            }
+            Message::Subscribe { .. } => Response::Subscribed(Err(
+                "Subscriptions need a connection of their own".to_string(),
+            )),
+            Message::Publish { channel, message } => {
+                let result = self.channels.publish(channel, message);
+                Response::Publish(result.map_err(|err| err.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl crate::scripting::ScriptHost for KvsServer {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>, KvStoreError> {
+        self.check_keys(&Message::Get {
+            key: key.clone(),
+            after: None,
+        })?;
+        self.reader().get(key)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<(), KvStoreError> {
+        self.script_write(Message::Set {
+            key,
+            value,
+            ttl_ms: None,
+        })
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<(), KvStoreError> {
+        self.script_write(Message::Remove { key })
+    }
+}
+
+/// Finish a scan stream, returning the number of bytes written.
+fn end_scan(writer: &mut impl Write, result: Result<(), String>) -> Result<usize, io::Error> {
+    let bytes = serde_json::to_vec(&Response::ScanEnd(result))?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+
+    Ok(bytes.len())
+}
+
+/// Position of the writer's last write, flushed first so that a standby
+/// following the logs can reach it.
+fn write_position(writer: &mut dyn KvsWriter) -> Result<Option<LogPosition>, KvStoreError> {
+    let position = writer.position();
+    if position.is_some() {
+        writer.flush()?;
+    }
+
+    Ok(position)
+}
+
+// Open `dir`, retrying for a while in case the engine that last had it open
+// hasn't let go yet
+fn open_retrying(open: &mut EngineOpener, dir: &Path) -> Result<Box<dyn KvsEngine>, KvStoreError> {
+    let started = Instant::now();
+    loop {
+        match open(dir) {
+            Err(_) if started.elapsed() < REOPEN_TIMEOUT => thread::sleep(REOPEN_RETRY_INTERVAL),
+            result => return result,
         }
     }
 }
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logs::unix_millis;
+
+/// One request that took longer than the slow log threshold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowLogEntry {
+    /// Increases by one for every recorded request, including ones that have
+    /// since been evicted or reset
+    pub id: u64,
+    /// Unix time in milliseconds when the request finished
+    pub timestamp_ms: u64,
+    pub duration_us: u64,
+    pub operation: String,
+    #[serde(with = "crate::encoding")]
+    pub key: Vec<u8>,
+    pub request_len: usize,
+    pub response_len: usize,
+    /// Peer address of the connection the request came in on
+    pub connection: String,
+    /// Trace id the client sent with the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Ring buffer of the most recent slow requests across all connections.
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+/// What the server knows about a finished request.
+pub(crate) struct Request<'a> {
+    pub operation: &'static str,
+    pub key: Option<&'a [u8]>,
+    pub request_len: usize,
+    pub response_len: usize,
+    pub connection: &'a str,
+    pub trace_id: Option<&'a str>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, max_len: usize) -> SlowLog {
+        SlowLog {
+            threshold,
+            max_len,
+            next_id: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Record the request if it took at least the threshold, evicting the
+    /// oldest entry when the log is full.
+    pub fn record(&mut self, request: Request<'_>, duration: Duration) {
+        if duration < self.threshold || self.max_len == 0 {
+            return;
+        }
+
+        if self.entries.len() == self.max_len {
+            self.entries.pop_back();
+        }
+
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            timestamp_ms: unix_millis(),
+            duration_us: duration.as_micros() as u64,
+            operation: request.operation.to_owned(),
+            key: request.key.unwrap_or_default().to_vec(),
+            request_len: request.request_len,
+            response_len: request.response_len,
+            connection: request.connection.to_owned(),
+            trace_id: request.trace_id.map(str::to_owned),
+        });
+        self.next_id += 1;
+    }
+
+    /// Up to `count` entries, newest first.
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        self.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "net")]
+use crate::KvsClient;
+use crate::{KvStoreError, KvsEngine, KvsReader, Result};
+
+// Entries read per scan while walking the keyspace
+const SYNC_PAGE_LEN: usize = 1024;
+// Levels of the Merkle tree descended per exchange in `diverging_leaves`
+const MERKLE_LEVELS_PER_ROUND: u32 = 4;
+
+/// The deepest `MerkleTree`, with 2^20 leaves
+pub const MAX_MERKLE_DEPTH: u32 = 20;
+
+/// A hash of every live entry whose key starts with `prefix`, so two stores
+/// can tell which prefixes they disagree on without sending their entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PrefixDigest {
+    /// The first bytes of the keys, or the whole key if it is shorter
+    #[serde(with = "crate::encoding")]
+    pub prefix: Vec<u8>,
+    pub keys: u64,
+    /// FNV-1a of the entries' keys and values, in key order
+    pub hash: u64,
+}
+
+/// What `sync` changed on its target.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Prefixes on either side
+    pub prefixes: u64,
+    /// Prefixes whose entries differed, and were scanned on both sides
+    pub differing: u64,
+    /// Keys set on the target because they were missing or had another value
+    pub set: u64,
+    /// Keys removed from the target because the source doesn't have them
+    pub removed: u64,
+}
+
+/// One side of a `sync`: a store opened in this process or a server.
+pub enum SyncPeer<'a> {
+    Local(&'a mut dyn KvsEngine),
+    #[cfg(feature = "net")]
+    Remote(&'a mut KvsClient),
+}
+
+impl SyncPeer<'_> {
+    fn digests(&mut self, prefix_len: usize) -> Result<Vec<PrefixDigest>> {
+        match self {
+            SyncPeer::Local(engine) => digest(&mut **engine, prefix_len),
+            #[cfg(feature = "net")]
+            SyncPeer::Remote(client) => client.digest(prefix_len),
+        }
+    }
+
+    // The entries grouped under `prefix`: those starting with it, leaving
+    // out longer keys when `prefix` is a whole key shorter than `prefix_len`
+    fn entries(&mut self, prefix: &[u8], prefix_len: usize) -> Result<Vec<(Vec<u8>, String)>> {
+        let mut entries = Vec::new();
+        match self {
+            SyncPeer::Local(engine) => {
+                let mut start_after: Option<Vec<u8>> = None;
+                loop {
+                    let page = engine.scan(prefix, start_after.as_deref(), SYNC_PAGE_LEN)?;
+                    let done = page.len() < SYNC_PAGE_LEN;
+                    start_after = page.last().map(|(key, _)| key.clone());
+                    entries.extend(page);
+                    if done {
+                        break;
+                    }
+                }
+            }
+            #[cfg(feature = "net")]
+            SyncPeer::Remote(client) => {
+                for entry in client.scan(prefix.to_vec())? {
+                    entries.push(entry?);
+                }
+            }
+        }
+        entries.retain(|(key, _)| group(key, prefix_len) == prefix);
+        Ok(entries)
+    }
+
+    // The hashes of the nodes at `indexes` of the peer's tree of `depth`,
+    // building it in `tree` once for a local store, as a server builds its
+    // tree for each request
+    fn merkle_nodes(
+        &mut self,
+        tree: &mut Option<MerkleTree>,
+        depth: u32,
+        indexes: &[u64],
+    ) -> Result<Vec<u64>> {
+        match self {
+            SyncPeer::Local(engine) => {
+                let tree = match tree {
+                    Some(tree) => tree,
+                    None => tree.insert(MerkleTree::build(&mut **engine, depth)?),
+                };
+                tree.nodes(indexes)
+            }
+            #[cfg(feature = "net")]
+            SyncPeer::Remote(client) => client.merkle_nodes(depth, indexes.to_vec()),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<()> {
+        match self {
+            SyncPeer::Local(engine) => engine.set(key, value),
+            #[cfg(feature = "net")]
+            SyncPeer::Remote(client) => client.set(key, value),
+        }
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        match self {
+            SyncPeer::Local(engine) => engine.remove(key),
+            #[cfg(feature = "net")]
+            SyncPeer::Remote(client) => client.remove(key),
+        }
+    }
+}
+
+fn group(key: &[u8], prefix_len: usize) -> &[u8] {
+    &key[..key.len().min(prefix_len)]
+}
+
+/// Group the live entries by the first `prefix_len` bytes of their keys and
+/// hash each group. Every entry is read once, but only the digests are kept
+/// in memory.
+pub fn digest(reader: &mut dyn KvsReader, prefix_len: usize) -> Result<Vec<PrefixDigest>> {
+    let mut digests: Vec<PrefixDigest> = Vec::new();
+    let mut start_after: Option<Vec<u8>> = None;
+    loop {
+        let page = reader.scan(b"", start_after.as_deref(), SYNC_PAGE_LEN)?;
+        let done = page.len() < SYNC_PAGE_LEN;
+        for (key, value) in &page {
+            let prefix = group(key, prefix_len);
+            // Keys come in order, so a group's entries are contiguous
+            let digest = match digests.last_mut() {
+                Some(digest) if digest.prefix == prefix => digest,
+                _ => {
+                    digests.push(PrefixDigest {
+                        prefix: prefix.to_vec(),
+                        keys: 0,
+                        hash: FNV_OFFSET_BASIS,
+                    });
+                    digests.last_mut().expect("digest was just pushed")
+                }
+            };
+            digest.keys += 1;
+            digest.hash = hash_entry(digest.hash, key, value.as_bytes());
+        }
+        start_after = page.last().map(|(key, _)| key.clone());
+        if done {
+            return Ok(digests);
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+// Fold an entry into `hash` with FNV-1a, length-prefixing the key and value
+// so that entries can't run into each other. Unlike std's hashers it never
+// changes, as digests are compared across processes and versions.
+fn hash_entry(mut hash: u64, key: &[u8], value: &[u8]) -> u64 {
+    let key_len = (key.len() as u64).to_le_bytes();
+    let value_len = (value.len() as u64).to_le_bytes();
+    for part in [&key_len[..], key, &value_len[..], value] {
+        for &byte in part {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Make `target` hold the same live entries as `source`. The two exchange
+/// digests of their keys grouped by the first `prefix_len` bytes, and only
+/// the groups whose digests differ are scanned and copied over, so stores
+/// that mostly agree sync in about the time it takes each to read its own
+/// keys. Expiry times aren't compared or copied.
+pub fn sync(source: &mut SyncPeer, target: &mut SyncPeer, prefix_len: usize) -> Result<SyncReport> {
+    let mut digests: BTreeMap<Vec<u8>, (Option<u64>, Option<u64>)> = BTreeMap::new();
+    for digest in source.digests(prefix_len)? {
+        digests.entry(digest.prefix).or_default().0 = Some(digest.hash);
+    }
+    for digest in target.digests(prefix_len)? {
+        digests.entry(digest.prefix).or_default().1 = Some(digest.hash);
+    }
+
+    let mut report = SyncReport {
+        prefixes: digests.len() as u64,
+        ..SyncReport::default()
+    };
+    for (prefix, (source_hash, target_hash)) in digests {
+        if source_hash == target_hash {
+            continue;
+        }
+        report.differing += 1;
+
+        let mut wanted: BTreeMap<Vec<u8>, String> = match source_hash {
+            Some(_) => source.entries(&prefix, prefix_len)?.into_iter().collect(),
+            None => BTreeMap::new(),
+        };
+        let present = match target_hash {
+            Some(_) => target.entries(&prefix, prefix_len)?,
+            None => Vec::new(),
+        };
+        for (key, value) in present {
+            match wanted.get(&key) {
+                Some(wanted_value) if *wanted_value == value => {
+                    wanted.remove(&key);
+                }
+                Some(_) => {}
+                None => {
+                    target.remove(key)?;
+                    report.removed += 1;
+                }
+            }
+        }
+        for (key, value) in wanted {
+            target.set(key, value)?;
+            report.set += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Hashes of the live entries split into 2^depth leaves by a hash of their
+/// keys, each inner node hashing its two children, so that two stores can
+/// find the leaves they disagree on by comparing a few nodes per level
+/// instead of every entry. Nodes are numbered breadth first: the root is 0
+/// and node i's children are 2i + 1 and 2i + 2. An empty subtree hashes to 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u32,
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Read every live entry once and hash it into its leaf.
+    pub fn build(reader: &mut dyn KvsReader, depth: u32) -> Result<MerkleTree> {
+        if depth > MAX_MERKLE_DEPTH {
+            return Err(KvStoreError::StringError(format!(
+                "Merkle trees are at most {} levels deep",
+                MAX_MERKLE_DEPTH
+            )));
+        }
+        let first_leaf = (1usize << depth) - 1;
+        let mut nodes = vec![0; 2 * first_leaf + 1];
+
+        let mut start_after: Option<Vec<u8>> = None;
+        loop {
+            let page = reader.scan(b"", start_after.as_deref(), SYNC_PAGE_LEN)?;
+            let done = page.len() < SYNC_PAGE_LEN;
+            for (key, value) in &page {
+                let leaf = &mut nodes[first_leaf + MerkleTree::leaf_of(key, depth) as usize];
+                if *leaf == 0 {
+                    *leaf = FNV_OFFSET_BASIS;
+                }
+                *leaf = hash_entry(*leaf, key, value.as_bytes());
+            }
+            start_after = page.last().map(|(key, _)| key.clone());
+            if done {
+                break;
+            }
+        }
+
+        for index in (0..first_leaf).rev() {
+            nodes[index] = match (nodes[2 * index + 1], nodes[2 * index + 2]) {
+                (0, 0) => 0,
+                (left, right) => {
+                    hash_entry(FNV_OFFSET_BASIS, &left.to_le_bytes(), &right.to_le_bytes())
+                }
+            };
+        }
+        Ok(MerkleTree { depth, nodes })
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// The hash of every entry.
+    pub fn root(&self) -> u64 {
+        self.nodes[0]
+    }
+
+    /// The hashes of the nodes at `indexes`.
+    pub fn nodes(&self, indexes: &[u64]) -> Result<Vec<u64>> {
+        indexes
+            .iter()
+            .map(|&index| {
+                self.nodes.get(index as usize).copied().ok_or_else(|| {
+                    KvStoreError::StringError(format!(
+                        "A Merkle tree {} levels deep has no node {}",
+                        self.depth, index
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// The leaf, from 0 to 2^depth - 1, that `key` is hashed into.
+    pub fn leaf_of(key: &[u8], depth: u32) -> u64 {
+        match depth {
+            0 => 0,
+            depth => mix(hash_entry(FNV_OFFSET_BASIS, key, b"")) >> (64 - depth),
+        }
+    }
+}
+
+// The finalizer of MurmurHash3, so that the leaf taken from a key hash's top
+// bits depends on all of them
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ (x >> 33)
+}
+
+/// The leaves, from 0 to 2^depth - 1, of the Merkle trees of `a` and `b`
+/// that hash differently, i.e. hold keys the two disagree on. Trees are
+/// compared from the root down, a few levels per exchange and only below
+/// nodes that differ, so a server is asked about 1 + depth / 4 times
+/// however many keys it holds.
+pub fn diverging_leaves(a: &mut SyncPeer, b: &mut SyncPeer, depth: u32) -> Result<Vec<u64>> {
+    let (mut a_tree, mut b_tree) = (None, None);
+    let mut diverging = vec![0];
+    if a.merkle_nodes(&mut a_tree, depth, &diverging)?
+        == b.merkle_nodes(&mut b_tree, depth, &diverging)?
+    {
+        return Ok(Vec::new());
+    }
+
+    let mut level = 0;
+    while level < depth {
+        let levels = MERKLE_LEVELS_PER_ROUND.min(depth - level);
+        // The descendants `levels` below each diverging node
+        let indexes: Vec<u64> = diverging
+            .iter()
+            .flat_map(|&index| {
+                let first = ((index + 1) << levels) - 1;
+                first..first + (1 << levels)
+            })
+            .collect();
+        let a_nodes = a.merkle_nodes(&mut a_tree, depth, &indexes)?;
+        let b_nodes = b.merkle_nodes(&mut b_tree, depth, &indexes)?;
+        diverging = indexes
+            .into_iter()
+            .zip(a_nodes.into_iter().zip(b_nodes))
+            .filter(|(_, (a_node, b_node))| a_node != b_node)
+            .map(|(index, _)| index)
+            .collect();
+        level += levels;
+    }
+
+    let first_leaf = (1u64 << depth) - 1;
+    Ok(diverging
+        .into_iter()
+        .map(|index| index - first_leaf)
+        .collect())
+}
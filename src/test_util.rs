@@ -0,0 +1,366 @@
+//! Helpers for testing `KvsEngine` implementations, including a conformance
+//! suite of behavior every engine in this crate shares. An engine passes if
+//! each check returns `Ok` without panicking:
+//!
+//! ```ignore
+//! #[test]
+//! fn conformance() -> kvs::Result<()> {
+//!     kvs::test_util::run_conformance_suite::<MyEngine>()
+//! }
+//! ```
+//!
+//! With the `net` feature, `TestServer` runs a real `KvsServer` over an
+//! engine for end-to-end tests of clients, the protocol and durability.
+
+#[cfg(feature = "net")]
+use std::marker::PhantomData;
+#[cfg(feature = "net")]
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+#[cfg(feature = "net")]
+use std::sync::mpsc;
+use std::thread;
+#[cfg(feature = "net")]
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+use crate::{KvStoreError, KvsEngine, Result, MAX_KEY_LEN};
+#[cfg(feature = "net")]
+use crate::{KvsClient, KvsServer, ServerConfig, StopHandle};
+
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(2);
+const REOPEN_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A fresh engine in its own temporary directory. The directory is deleted
+/// when the harness is dropped.
+pub struct EngineHarness<E: KvsEngine> {
+    pub engine: E,
+    dir: TempDir,
+}
+
+impl<E: KvsEngine> EngineHarness<E> {
+    pub fn new() -> Result<EngineHarness<E>> {
+        let dir = TempDir::new()?;
+        let engine = E::open(dir.path().to_path_buf())?;
+
+        Ok(EngineHarness { engine, dir })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().to_path_buf()
+    }
+
+    /// Drop the engine and open a new one on the same directory.
+    pub fn reopen(self) -> Result<EngineHarness<E>> {
+        let EngineHarness { engine, dir } = self;
+        drop(engine);
+        let engine = open_retrying(dir.path().to_path_buf())?;
+
+        Ok(EngineHarness { engine, dir })
+    }
+}
+
+// Sled lets go of its directory lock from background threads shortly after
+// it is dropped, so give the lock a moment to come free
+fn open_retrying<E: KvsEngine>(path: PathBuf) -> Result<E> {
+    let deadline = Instant::now() + REOPEN_TIMEOUT;
+    loop {
+        match E::open(path.clone()) {
+            Ok(engine) => return Ok(engine),
+            Err(_) if Instant::now() < deadline => thread::sleep(REOPEN_RETRY_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A `KvsServer` serving a fresh engine of type `E` from its own temporary
+/// directory, on a thread of its own and a free port on localhost. The
+/// server is stopped, and the directory deleted, when it is dropped.
+///
+/// ```ignore
+/// let server = TestServer::<KvStore>::start()?;
+/// server.client()?.set(b"key".to_vec(), "value".to_owned())?;
+/// let server = server.restart()?;
+/// assert_eq!(server.client()?.get(b"key".to_vec())?, Some("value".to_owned()));
+/// ```
+#[cfg(feature = "net")]
+pub struct TestServer<E: KvsEngine> {
+    addr: SocketAddr,
+    config: ServerConfig,
+    serving: Serving,
+    dir: TempDir,
+    engine: PhantomData<fn() -> E>,
+}
+
+// The thread serving a test server, stopped when dropped
+#[cfg(feature = "net")]
+struct Serving {
+    stop: StopHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "net")]
+impl<E: KvsEngine + 'static> TestServer<E> {
+    pub fn start() -> Result<TestServer<E>> {
+        TestServer::start_with_config(ServerConfig::default())
+    }
+
+    pub fn start_with_config(config: ServerConfig) -> Result<TestServer<E>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        TestServer::serve(listener, config, TempDir::new()?)
+    }
+
+    // Open the engine in `dir` and serve it on `listener` from a new thread.
+    // Engines needn't be `Send`, so the server is made on that thread.
+    fn serve(listener: TcpListener, config: ServerConfig, dir: TempDir) -> Result<TestServer<E>> {
+        let addr = listener.local_addr()?;
+        let (ready, started) = mpsc::channel();
+        let path = dir.path().to_path_buf();
+        let server_config = config.clone();
+        let thread = thread::spawn(move || {
+            let engine = match open_retrying::<E>(path) {
+                Ok(engine) => engine,
+                Err(err) => return drop(ready.send(Err(err))),
+            };
+            let logger = slog::Logger::root(slog::Discard, slog::o!());
+            let mut server = KvsServer::new(logger, engine).with_config(server_config);
+            let _ = ready.send(Ok(server.stop_handle()));
+            let _ = server.serve(listener);
+        });
+
+        let stop = started.recv().map_err(|_| {
+            KvStoreError::StringError("The test server's thread panicked".to_owned())
+        })??;
+        Ok(TestServer {
+            addr,
+            config,
+            serving: Serving {
+                stop,
+                thread: Some(thread),
+            },
+            dir,
+            engine: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The directory the engine is stored in.
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().to_path_buf()
+    }
+
+    /// A new client connected to the server. The server serves one
+    /// connection at a time, so a client's requests wait until every client
+    /// connected before it has been dropped.
+    pub fn client(&self) -> Result<KvsClient> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        Ok(KvsClient::new(logger, self.addr)?)
+    }
+
+    /// Stop the server, close its engine and serve the same directory again
+    /// on the same address, as if the server process had been restarted.
+    /// Waits for connected clients to be dropped first.
+    pub fn restart(self) -> Result<TestServer<E>> {
+        let TestServer {
+            addr,
+            config,
+            serving,
+            dir,
+            ..
+        } = self;
+        drop(serving);
+        TestServer::serve(TcpListener::bind(addr)?, config, dir)
+    }
+}
+
+#[cfg(feature = "net")]
+impl Drop for Serving {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Run every check in the suite against a fresh engine each.
+pub fn run_conformance_suite<E: KvsEngine>() -> Result<()> {
+    persists_across_reopen::<E>()?;
+    removes_missing_key::<E>()?;
+    overwrites_value::<E>()?;
+    stores_large_values::<E>()?;
+    stores_unicode_keys::<E>()?;
+    rejects_invalid_keys::<E>()?;
+    appends_to_values::<E>()?;
+    survives_compaction::<E>()?;
+
+    Ok(())
+}
+
+/// Writes and removes should still be visible after reopening.
+pub fn persists_across_reopen<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    harness.engine.set(b"key1".to_vec(), "value1".to_owned())?;
+    harness.engine.set(b"key2".to_vec(), "value2".to_owned())?;
+    harness.engine.remove(b"key2".to_vec())?;
+
+    let mut harness = harness.reopen()?;
+    assert_eq!(
+        harness.engine.get(b"key1".to_vec())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(harness.engine.get(b"key2".to_vec())?, None);
+
+    Ok(())
+}
+
+/// Removing an absent key should fail with `UnknownKeyError`, also once the
+/// key has already been removed.
+pub fn removes_missing_key<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    assert!(matches!(
+        harness.engine.remove(b"key1".to_vec()),
+        Err(KvStoreError::UnknownKeyError)
+    ));
+
+    harness.engine.set(b"key1".to_vec(), "value1".to_owned())?;
+    harness.engine.remove(b"key1".to_vec())?;
+    assert!(matches!(
+        harness.engine.remove(b"key1".to_vec()),
+        Err(KvStoreError::UnknownKeyError)
+    ));
+
+    Ok(())
+}
+
+/// The last value set for a key should win, before and after reopening.
+pub fn overwrites_value<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    harness.engine.set(b"key1".to_vec(), "value1".to_owned())?;
+    harness.engine.set(b"key1".to_vec(), "value2".to_owned())?;
+    assert_eq!(
+        harness.engine.get(b"key1".to_vec())?,
+        Some("value2".to_owned())
+    );
+
+    let mut harness = harness.reopen()?;
+    assert_eq!(
+        harness.engine.get(b"key1".to_vec())?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+/// Values of a few megabytes should round-trip intact.
+pub fn stores_large_values<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    let value: String = (0..4 * 1024 * 1024)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    harness.engine.set(b"large".to_vec(), value.clone())?;
+
+    let mut harness = harness.reopen()?;
+    assert_eq!(harness.engine.get(b"large".to_vec())?, Some(value));
+
+    Ok(())
+}
+
+/// Keys and values outside ASCII should round-trip, and keys that aren't
+/// valid UTF-8 should too.
+pub fn stores_unicode_keys<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    let entries = [
+        ("ключ".as_bytes().to_vec(), "значение"),
+        ("键".as_bytes().to_vec(), "值"),
+        ("🔑".as_bytes().to_vec(), "🗝️"),
+        (vec![0xff, 0x00, 0xfe], "binary"),
+    ];
+    for (key, value) in &entries {
+        harness.engine.set(key.clone(), value.to_string())?;
+    }
+
+    let mut harness = harness.reopen()?;
+    for (key, value) in entries {
+        assert_eq!(harness.engine.get(key)?, Some(value.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Writing an empty key or one longer than `MAX_KEY_LEN` should fail with
+/// `EmptyKey` or `KeyTooLong`, and leave nothing behind.
+pub fn rejects_invalid_keys<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    assert!(matches!(
+        harness.engine.set(Vec::new(), "value".to_owned()),
+        Err(KvStoreError::EmptyKey)
+    ));
+    assert!(matches!(
+        harness.engine.get_set(Vec::new(), "value".to_owned()),
+        Err(KvStoreError::EmptyKey)
+    ));
+    assert!(matches!(
+        harness.engine.set_with_ttl(
+            vec![b'k'; MAX_KEY_LEN + 1],
+            "value".to_owned(),
+            Duration::from_secs(60)
+        ),
+        Err(KvStoreError::KeyTooLong(_))
+    ));
+    assert!(harness.engine.scan(&[], None, 10)?.is_empty());
+
+    Ok(())
+}
+
+/// Appending should create an absent key and extend an existing value.
+pub fn appends_to_values<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    assert_eq!(harness.engine.append(b"key1".to_vec(), "ab")?, 2);
+    assert_eq!(harness.engine.append(b"key1".to_vec(), "cd")?, 4);
+    assert_eq!(
+        harness.engine.get(b"key1".to_vec())?,
+        Some("abcd".to_owned())
+    );
+
+    Ok(())
+}
+
+/// Enough overwrites and removes to make a log-structured engine compact
+/// should leave exactly the latest values, before and after reopening.
+pub fn survives_compaction<E: KvsEngine>() -> Result<()> {
+    let mut harness = EngineHarness::<E>::new()?;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id).into_bytes();
+            harness.engine.set(key, format!("{:0>200}", iter))?;
+        }
+    }
+    for key_id in 0..50 {
+        harness
+            .engine
+            .remove(format!("key{}", key_id).into_bytes())?;
+    }
+
+    let check = |engine: &mut E| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = match key_id {
+                0..=49 => None,
+                _ => Some(format!("{:0>200}", 99)),
+            };
+            assert_eq!(engine.get(format!("key{}", key_id).into_bytes())?, expected);
+        }
+        Ok(())
+    };
+    check(&mut harness.engine)?;
+
+    let mut harness = harness.reopen()?;
+    check(&mut harness.engine)?;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsEngine, Result, WriteBatch};
+
+/// A set of conditions with an operation list to run if all of them hold and
+/// another to run if any doesn't, in the style of etcd's `Txn`.
+///
+/// Keys carry no version, so conditions compare values. Nothing else runs
+/// on the engine while a transaction does when it is sent to a server. The
+/// writes of the branch that runs are applied as one `WriteBatch`, so either
+/// all of them take effect or, if any fails, none do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Txn {
+    #[serde(default)]
+    pub compare: Vec<Compare>,
+    #[serde(default)]
+    pub success: Vec<TxnOp>,
+    #[serde(default)]
+    pub failure: Vec<TxnOp>,
+}
+
+/// A condition on one key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Compare {
+    /// The key holds exactly this value
+    Value {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        value: String,
+    },
+    Exists {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    Absent {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxnOp {
+    Get {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        value: String,
+    },
+    Remove {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+}
+
+/// The result of one `TxnOp`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxnResult {
+    Get(Option<String>),
+    Set,
+    /// Whether the key was there to remove
+    Remove(bool),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxnResponse {
+    /// Whether every condition held, and so which branch ran
+    pub succeeded: bool,
+    /// One result per operation of the branch that ran, in order
+    pub results: Vec<TxnResult>,
+}
+
+impl Compare {
+    fn holds(&self, engine: &mut dyn KvsEngine) -> Result<bool> {
+        match self {
+            Compare::Value { key, value } => Ok(engine.get(key.clone())?.as_ref() == Some(value)),
+            Compare::Exists { key } => engine.contains(key),
+            Compare::Absent { key } => Ok(!engine.contains(key)?),
+        }
+    }
+}
+
+impl TxnOp {
+    /// Add the operation to `batch`, reading through the writes already in
+    /// it, which `written` tracks by key.
+    fn stage(
+        self,
+        engine: &mut dyn KvsEngine,
+        batch: &mut WriteBatch,
+        written: &mut HashMap<Vec<u8>, Option<String>>,
+    ) -> Result<TxnResult> {
+        match self {
+            TxnOp::Get { key } => match written.get(&key) {
+                Some(value) => Ok(TxnResult::Get(value.clone())),
+                None => Ok(TxnResult::Get(engine.get(key)?)),
+            },
+            TxnOp::Set { key, value } => {
+                written.insert(key.clone(), Some(value.clone()));
+                batch.set(key, value);
+                Ok(TxnResult::Set)
+            }
+            TxnOp::Remove { key } => {
+                let present = match written.get(&key) {
+                    Some(value) => value.is_some(),
+                    None => engine.contains(&key)?,
+                };
+                written.insert(key.clone(), None);
+                batch.remove(key);
+                Ok(TxnResult::Remove(present))
+            }
+        }
+    }
+}
+
+impl Txn {
+    /// Check the conditions and run the branch they pick.
+    pub fn apply(self, engine: &mut dyn KvsEngine) -> Result<TxnResponse> {
+        let mut succeeded = true;
+        for compare in &self.compare {
+            if !compare.holds(engine)? {
+                succeeded = false;
+                break;
+            }
+        }
+
+        let ops = if succeeded {
+            self.success
+        } else {
+            self.failure
+        };
+        let mut batch = WriteBatch::new();
+        let mut written = HashMap::new();
+        let results = ops
+            .into_iter()
+            .map(|op| op.stage(engine, &mut batch, &mut written))
+            .collect::<Result<_>>()?;
+        if !batch.is_empty() {
+            engine.apply(batch)?;
+        }
+
+        Ok(TxnResponse { succeeded, results })
+    }
+}
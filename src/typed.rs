@@ -0,0 +1,112 @@
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvsClient, Result};
+
+/// A view of a `KvsClient` that stores values of type `V` as JSON under
+/// `prefix` followed by a key of type `K`, so that callers deal in their own
+/// types rather than strings. Usually declared with `kvs::typed!`.
+pub struct TypedClient<'a, K: ?Sized, V> {
+    client: &'a mut KvsClient,
+    prefix: &'static str,
+    types: PhantomData<fn(&K) -> V>,
+}
+
+impl<'a, K: Display + ?Sized, V: Serialize + DeserializeOwned> TypedClient<'a, K, V> {
+    pub fn new(client: &'a mut KvsClient, prefix: &'static str) -> TypedClient<'a, K, V> {
+        TypedClient {
+            client,
+            prefix,
+            types: PhantomData,
+        }
+    }
+
+    /// The key a value is stored under: the prefix, then `key` as displayed.
+    pub fn key(&self, key: &K) -> Vec<u8> {
+        format!("{}{}", self.prefix, key).into_bytes()
+    }
+
+    /// Get the value of `key`. A value that isn't JSON of type `V` is a
+    /// `SerdeErr`.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.key(key);
+        match self.client.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = self.key(key);
+        self.client.set(key, serde_json::to_string(value)?)
+    }
+
+    pub fn set_with_ttl(&mut self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        let key = self.key(key);
+        self.client
+            .set_with_ttl(key, serde_json::to_string(value)?, ttl)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        let key = self.key(key);
+        self.client.remove(key)
+    }
+}
+
+/// Declare a typed view of a `KvsClient`: a struct wrapping a
+/// `TypedClient` with the given key and value types, and a key prefix that
+/// defaults to the struct's name and a slash.
+///
+/// ```ignore
+/// kvs::typed! {
+///     pub struct Sessions: key = str, value = SessionData, prefix = "sessions/"
+/// }
+///
+/// let mut sessions = Sessions::new(&mut client);
+/// sessions.set("abc", &session)?;
+/// let session: Option<SessionData> = sessions.get("abc")?;
+/// ```
+#[macro_export]
+macro_rules! typed {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident: key = $key:ty, value = $value:ty $(,)?
+    ) => {
+        $crate::typed! {
+            $(#[$attr])*
+            $vis struct $name: key = $key, value = $value,
+                prefix = concat!(stringify!($name), "/")
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident: key = $key:ty, value = $value:ty, prefix = $prefix:expr $(,)?
+    ) => {
+        $(#[$attr])*
+        $vis struct $name<'a>($crate::TypedClient<'a, $key, $value>);
+
+        impl<'a> $name<'a> {
+            $vis fn new(client: &'a mut $crate::KvsClient) -> $name<'a> {
+                $name($crate::TypedClient::new(client, $prefix))
+            }
+        }
+
+        impl<'a> ::std::ops::Deref for $name<'a> {
+            type Target = $crate::TypedClient<'a, $key, $value>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl<'a> ::std::ops::DerefMut for $name<'a> {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                &mut self.0
+            }
+        }
+    };
+}
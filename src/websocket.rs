@@ -0,0 +1,91 @@
+//! The protocol over WebSocket: each message a client sends holds one or more
+//! protocol frames, and each frame, or run of frames, the server flushes is
+//! sent back as one text message.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use tungstenite::protocol::Role;
+use tungstenite::{Error, Message, WebSocket};
+
+/// The incoming side of a WebSocket connection as a byte stream.
+pub(crate) struct WsReader {
+    socket: WebSocket<TcpStream>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// The outgoing side of a WebSocket connection. Writes are collected and
+/// sent as one message on flush.
+pub(crate) struct WsWriter {
+    socket: WebSocket<TcpStream>,
+    buf: Vec<u8>,
+}
+
+/// Complete the WebSocket handshake on `stream` and split the connection.
+pub(crate) fn accept(stream: TcpStream) -> io::Result<(WsReader, WsWriter)> {
+    let writer_stream = stream.try_clone()?;
+    // The handshake may have buffered the first messages, so the socket it
+    // returns has to do the reading
+    let socket = tungstenite::accept(stream).map_err(io::Error::other)?;
+    let writer = WebSocket::from_raw_socket(writer_stream, Role::Server, None);
+
+    Ok((
+        WsReader {
+            socket,
+            buf: Vec::new(),
+            pos: 0,
+        },
+        WsWriter {
+            socket: writer,
+            buf: Vec::new(),
+        },
+    ))
+}
+
+fn io_error(err: Error) -> io::Error {
+    match err {
+        Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl Read for WsReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let payload = match self.socket.read() {
+                Ok(Message::Text(text)) => text.as_bytes().to_vec(),
+                Ok(Message::Binary(bytes)) => bytes.to_vec(),
+                // Pings are answered by the socket itself
+                Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)) => continue,
+                Ok(Message::Close(_)) | Err(Error::ConnectionClosed | Error::AlreadyClosed) => {
+                    return Ok(0)
+                }
+                Err(err) => return Err(io_error(err)),
+            };
+            self.buf = payload;
+            self.pos = 0;
+        }
+
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Write for WsWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let text = String::from_utf8(std::mem::take(&mut self.buf))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.socket.send(Message::text(text)).map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use kvs::{AsyncKvsEngine, BlockingEngine, KvStore, KvStoreError, KvsEngine, KvsReader, Result};
+use tempfile::TempDir;
+
+const TASKS: usize = 8;
+
+// Tasks sharing a wrapped KvStore should each see their own writes, and the
+// store should hold all of them once they finish.
+#[test]
+fn blocking_engine_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = BlockingEngine::new(KvStore::open(temp_dir.path().to_path_buf())?);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("unable to start a runtime");
+
+    runtime.block_on(async {
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    let key = format!("key{}", task).into_bytes();
+                    engine.set(key.clone(), format!("value{}", task)).await?;
+                    assert_eq!(
+                        engine.get(key.clone()).await?,
+                        Some(format!("value{}", task))
+                    );
+                    if task % 2 == 0 {
+                        engine.remove(key).await?;
+                    }
+                    Ok::<_, KvStoreError>(())
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.expect("task panicked")?;
+        }
+
+        assert!(engine.remove(b"missing".to_vec()).await.is_err());
+        let keys = engine.run(|store| store.scan(b"key", None, TASKS)).await?;
+        assert_eq!(keys.len(), TASKS / 2);
+        Ok(())
+    })
+}
@@ -1,10 +1,14 @@
 use assert_cmd::prelude::*;
+use kvs::protocol::{write_frame, Compression, FrameReader, Message, Response};
+use kvs::{KvsClient, MerkleTree};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -20,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +127,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,18 +138,58 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-client completions` should print a script for each shell without a
+// server to connect to
+#[test]
+fn client_cli_completions() {
+    for (shell, expected) in [
+        ("bash", "complete -F _kvs__client"),
+        ("zsh", "#compdef kvs-client"),
+        ("fish", "complete -c kvs-client"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["completions", shell])
+            .assert()
+            .success()
+            .stdout(contains(expected))
+            .stdout(contains("rotate-log"));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["completions", "tcsh"])
+        .assert()
+        .failure();
+}
+
+// Subcommand help should describe the command and its key, with examples
+#[test]
+fn client_cli_subcommand_help() {
+    for (subcommand, about) in [("get", "Print a key's value"), ("rm", "Remove a key")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args([subcommand, "--help"])
+            .assert()
+            .success()
+            .stdout(contains(about))
+            .stdout(contains("The key, or its bytes in hex"))
+            .stdout(contains("Example"))
+            .stdout(contains(format!("kvs-client {} user:1", subcommand)));
+    }
+}
+
 // `kvs-server -V` should print the version
 #[test]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +201,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -179,15 +224,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -198,15 +244,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -218,19 +265,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -238,7 +286,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -246,7 +294,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -254,7 +302,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -262,7 +310,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -270,7 +318,95 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\nkey2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--glob", "*2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["append", "events", "start", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["append", "events", ",stop", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("10\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "events", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("start,stop\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["expire", "key1", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -278,7 +414,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["mset", "key3=value4", "key4=value5", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -286,7 +422,23 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["mget", "key3", "key1", "key9", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\nvalue2\nKey not found\nvalue5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("KEY=VALUE"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -299,26 +451,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -336,3 +489,2186 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_hex_keys() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "ff00fe", "value1", "--key-hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "FF00FE", "--key-hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // The same characters without --key-hex are a different key
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "ff00fe", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "not-hex", "--key-hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_standby_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary_addr = "127.0.0.1:4007";
+    let standby_addr = "127.0.0.1:4008";
+
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", primary_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", primary_addr])
+        .assert()
+        .success();
+
+    let mut standby = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", standby_addr, "--standby-of"])
+        .arg(temp_dir.path())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", standby_addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", standby_addr])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+
+    // New writes on the primary show up on the standby after a poll
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", primary_addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", standby_addr])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    standby.kill().expect("standby exited before killed");
+    standby.wait().unwrap();
+    primary.kill().expect("primary exited before killed");
+    primary.wait().unwrap();
+}
+
+#[test]
+fn cli_max_response_bytes() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--max-response-bytes", "200"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let large_value = "v".repeat(300);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "large", &large_value, "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "small", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "large", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Response exceeds the limit of 200 bytes"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Response exceeds the limit of 200 bytes"));
+
+    // Other responses are cut off at the limit while they are encoded
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "large", "0", "300", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Response exceeds the limit of 200 bytes"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "large", "0", "10", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("vvvvvvvvvv\n");
+
+    // Responses under the limit are unaffected
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "small", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_slowlog() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--slowlog-threshold-ms", "0"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(["--trace-id", "request-42"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success();
+
+    // Every request is over a zero threshold; newest first, with the trace
+    // id of the requests that sent one
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["slowlog", "get", "--addr", addr])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0][0], "1");
+    assert_eq!(&lines[0][4..6], ["get", "key1"]);
+    assert_eq!(lines[0].len(), 8);
+    assert_eq!(&lines[1][4..6], ["set", "key1"]);
+    assert_eq!(lines[1][8], "request-42");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["slowlog", "reset", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["slowlog", "get", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client restore` should bring back a key removed on a server keeping
+// soft deletes, and fail once there is nothing to restore.
+#[test]
+fn cli_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--soft-delete-secs", "3600"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", "key1", "--addr", addr])
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client enqueue`, `dequeue` and `ack` should pass items through a
+// queue in order.
+#[test]
+fn cli_queue() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (item, id) in [("job1", "0\n"), ("job2", "1\n")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["enqueue", "jobs", item, "--addr", addr])
+            .assert()
+            .success()
+            .stdout(id);
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dequeue", "jobs", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("0\t1\tjob1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ack", "jobs", "0", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dequeue", "jobs", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1\t1\tjob2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dequeue", "jobs", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client acquire` should fail while someone holds the lock, and
+// `release` should free it.
+#[test]
+fn cli_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["acquire", "leader", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["acquire", "leader", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["renew", "leader", "1", "--ttl", "60", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["release", "leader", "2", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["release", "leader", "1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["acquire", "leader", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client txn` should run the branch its conditions pick and print the
+// response.
+#[test]
+fn cli_txn() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let txn = r#"{
+        "compare": [{"Absent": {"key": "key1"}}],
+        "success": [{"Set": {"key": "key1", "value": "value1"}}],
+        "failure": [{"Get": {"key": "key1"}}]
+    }"#;
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["txn", txn, "--addr", addr])
+        .assert()
+        .success()
+        .stdout("{\"succeeded\":true,\"results\":[\"Set\"]}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["txn", txn, "--addr", addr])
+        .assert()
+        .success()
+        .stdout("{\"succeeded\":false,\"results\":[{\"Get\":\"value1\"}]}\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client metrics` should report the same counters and size histograms
+// for either engine, plus cache counters where the engine has an observable
+// cache.
+#[test]
+fn cli_metrics() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4017"), ("sled", "127.0.0.1:4018")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .assert()
+            .success();
+
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["metrics", "--addr", addr])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("reads\t1\n"), "{}", stdout);
+        assert!(stdout.contains("writes\t1\n"), "{}", stdout);
+        assert_eq!(stdout.contains("cache_misses\t1\n"), engine == "kvs");
+        assert!(stdout.contains("key bytes\tsets\n<=4\t1\n"), "{}", stdout);
+        assert!(stdout.contains("value bytes\tsets\n<=8\t1\n"), "{}", stdout);
+        // Each response was encoded into the one pooled buffer
+        assert!(stdout.contains("buffers_allocated\t1\n"), "{}", stdout);
+        assert!(stdout.contains("buffers_pooled\t1\n"), "{}", stdout);
+        assert_eq!(stdout.contains("keys\t1\n"), engine == "kvs");
+        // The set and get made since the server started
+        assert!(
+            stdout.contains("operation\tper_sec_1s\tper_sec_10s\tper_sec_60s\n"),
+            "{}",
+            stdout
+        );
+        assert!(stdout.contains("\nget\t"), "{}", stdout);
+        assert!(stdout.contains("\nset\t"), "{}", stdout);
+        // The get missed the cache and read the record from the log
+        assert_eq!(stdout.contains("record_reads\t1\n"), engine == "kvs");
+        assert_eq!(stdout.contains("record bytes\treads\n"), engine == "kvs");
+
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["metrics", "--prometheus", "--addr", addr])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.contains("# TYPE kvs_reads_total counter\nkvs_reads_total 1\n"),
+            "{}",
+            stdout
+        );
+        assert_eq!(
+            stdout.contains("kvs_record_read_disk_seconds_bucket{le=\"+Inf\"} 1\n"),
+            engine == "kvs"
+        );
+
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
+
+// `kvs-server` should pass its sled options through to sled, and reject them
+// for the kvs engine.
+#[test]
+fn cli_sled_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--sled-cache-mb", "16", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "sled",
+            "--sled-cache-mb",
+            "16",
+            "--sled-flush-every-ms",
+            "0",
+            "--sled-mode",
+            "high-throughput",
+            "--sled-compression",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client analyze` should report the keyspace's sizes and prefixes.
+#[test]
+fn cli_analyze() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "user:1=a", "user:2=b", "order:1=c", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["analyze", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("keys\t3\n"))
+        .stdout(contains("sampled\t3\n"))
+        .stdout(contains("user:\t2\t14\n"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client sync` should copy what differs from another server, and
+// `kvs-doctor sync` between two stopped stores' directories.
+#[test]
+fn cli_sync() {
+    let source_dir = TempDir::new().unwrap();
+    let target_dir = TempDir::new().unwrap();
+    let (source_addr, target_addr) = ("127.0.0.1:4046", "127.0.0.1:4047");
+    let mut source = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", source_addr])
+        .current_dir(&source_dir)
+        .spawn()
+        .unwrap();
+    let mut target = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", target_addr])
+        .current_dir(&target_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "user:1=a", "user:2=b", "--addr", source_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "mset",
+            "user:1=a",
+            "user:2=old",
+            "temp=x",
+            "--addr",
+            target_addr,
+        ])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["sync", "--from", source_addr, "--addr", target_addr])
+        .assert()
+        .success()
+        .stdout(contains("differing\t2\n"))
+        .stdout(contains("set\t1\n"))
+        .stdout(contains("removed\t1\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "user:2", "--addr", target_addr])
+        .assert()
+        .success()
+        .stdout("b\n");
+
+    source.kill().expect("server exited before killed");
+    source.wait().unwrap();
+    target.kill().expect("server exited before killed");
+    target.wait().unwrap();
+
+    let copy_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .arg("sync")
+        .args([source_dir.path(), copy_dir.path()])
+        .assert()
+        .success()
+        .stdout(contains("set\t2\n"));
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .arg("sync")
+        .args([source_dir.path(), copy_dir.path()])
+        .assert()
+        .success()
+        .stdout(contains("differing\t0\n"));
+}
+
+// `kvs-client diff` should print the Merkle leaves of keys two servers
+// disagree on, and nothing once they agree.
+#[test]
+fn cli_diff() {
+    let a_dir = TempDir::new().unwrap();
+    let b_dir = TempDir::new().unwrap();
+    let (a_addr, b_addr) = ("127.0.0.1:4048", "127.0.0.1:4049");
+    let mut a = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", a_addr])
+        .current_dir(&a_dir)
+        .spawn()
+        .unwrap();
+    let mut b = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", b_addr])
+        .current_dir(&b_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (addr, value) in [(a_addr, "a"), (b_addr, "b")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["mset", "same=x", &format!("key={}", value), "--addr", addr])
+            .assert()
+            .success();
+    }
+    let leaf = format!("{}\n", MerkleTree::leaf_of(b"key", 4));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["diff", "--with", b_addr, "--depth", "4", "--addr", a_addr])
+        .assert()
+        .success()
+        .stdout(leaf);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "a", "--addr", b_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["diff", "--with", b_addr, "--addr", a_addr])
+        .assert()
+        .success()
+        .stdout("");
+
+    a.kill().expect("server exited before killed");
+    a.wait().unwrap();
+    b.kill().expect("server exited before killed");
+    b.wait().unwrap();
+}
+
+// `kvs-client get-range` should print just the bytes asked for, cut short at
+// the end of the value, and fail on a range that splits a character.
+#[test]
+fn cli_get_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4050";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "header|body é", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key1", "0", "6", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("header\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key1", "7", "100", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("body é\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key1", "0", "13", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("split a character"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key2", "0", "6", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client rotate-log` should print the new log's generation.
+#[test]
+fn cli_rotate_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4021";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rotate-log", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+    assert!(temp_dir.path().join("2.log").exists());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client compactions` should print a line of stats for each compaction
+// the server's store ran.
+#[test]
+fn cli_compactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4052";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compactions", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // Overwrite one key until over a megabyte is stale
+    let value = "v".repeat(100 * 1024);
+    for _ in 0..15 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", &value, "--addr", addr])
+            .assert()
+            .success();
+    }
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compactions", "--addr", addr])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let fields: Vec<&str> = lines[0].split('\t').collect();
+    assert_eq!(fields.len(), 7);
+    let input_bytes: u64 = fields[2].parse().unwrap();
+    let bytes_written: u64 = fields[3].parse().unwrap();
+    assert!(input_bytes > 1024 * 1024);
+    assert!(bytes_written > 100 * 1024 && bytes_written < input_bytes);
+    assert_eq!(fields[5], "1");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// A server with an ACL should turn away unauthenticated clients and hold
+// each user to its permissions and key prefixes.
+#[test]
+fn cli_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let acl_path = temp_dir.path().join("acl.json");
+    fs::write(
+        &acl_path,
+        r#"{"users": [
+            {"name": "admin", "token": "a", "permissions": ["read", "write", "admin"]},
+            {"name": "reader", "token": "r", "permissions": ["read"], "prefixes": ["public:"]}
+        ]}"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4022";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--acl", acl_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+    let as_admin = ["--user", "admin", "--token", "a"];
+    let as_reader = ["--user", "reader", "--token", "r"];
+
+    client(&["set", "public:key1", "value1"])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    client(&[
+        "set",
+        "public:key1",
+        "value1",
+        "--user",
+        "admin",
+        "--token",
+        "b",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("wrong token"));
+    client(&["set", "public:key1", "value1"])
+        .args(as_admin)
+        .assert()
+        .success();
+    client(&["set", "private:key1", "value1"])
+        .args(as_admin)
+        .assert()
+        .success();
+
+    client(&["get", "public:key1"])
+        .args(as_reader)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "private:key1"])
+        .args(as_reader)
+        .assert()
+        .failure()
+        .stderr(contains("outside the user's prefixes"));
+    client(&["set", "public:key1", "value2"])
+        .args(as_reader)
+        .assert()
+        .failure()
+        .stderr(contains("Write permission"));
+    client(&["reload-acl"])
+        .args(as_reader)
+        .assert()
+        .failure()
+        .stderr(contains("Admin permission"));
+    client(&["reload-acl"]).args(as_admin).assert().success();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// A connection that sends nothing for the idle timeout should be closed, so
+// it doesn't hold up the clients behind it.
+#[test]
+fn cli_idle_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--idle-timeout-secs", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    // The server closed the idle connection rather than the read timing out
+    assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client set key -` should store stdin as the value, and `get --raw`
+// should write it back byte for byte, however large.
+#[test]
+fn cli_stdin_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4024";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "line one\nline two\n\n";
+    // std's Command can't be handed stdin to write
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "-", "--addr", addr])
+        .write_stdin(value)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--raw", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(value);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--raw", "--addr", addr])
+        .assert()
+        .failure()
+        .stdout(is_empty());
+
+    // Large values are copied into the response still JSON-encoded
+    let large = "\"quoted\" \\ ключ\t🔑\n".repeat(8 * 1024);
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "large", "-", "--addr", addr])
+        .write_stdin(large.clone())
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "large", "--raw", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(large);
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// A client built only on `kvs::protocol` should be able to talk to the
+// server directly.
+#[test]
+fn protocol_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut responses: FrameReader<_, Response> = FrameReader::new(stream.try_clone().unwrap());
+    let set = Message::Set {
+        key: b"key1".to_vec(),
+        value: "value1".to_owned(),
+        ttl_ms: None,
+    };
+    write_frame(&mut stream, &set).unwrap();
+    assert!(matches!(
+        responses.next().unwrap().unwrap(),
+        Response::Set(Ok(_))
+    ));
+
+    let get = Message::Get {
+        key: b"key1".to_vec(),
+        after: None,
+    };
+    write_frame(&mut stream, &get).unwrap();
+    match responses.next().unwrap().unwrap() {
+        Response::Get(Ok(value)) => assert_eq!(value, Some("value1".to_owned())),
+        response => panic!("Expected a get response, got {:?}", response),
+    }
+
+    drop(stream);
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// After a `Hello`, the server should compress responses over the client's
+// threshold, and leave smaller ones alone.
+#[test]
+fn cli_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4051";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "abcdefgh".repeat(512);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut responses: FrameReader<_, Response> = FrameReader::new(stream.try_clone().unwrap());
+    let hello = Message::Hello {
+        compression: vec![Compression::Zstd],
+        compress_above: 256,
+    };
+    write_frame(&mut stream, &hello).unwrap();
+    match responses.next().unwrap().unwrap() {
+        Response::Hello(Ok(chosen)) => assert_eq!(chosen, Some(Compression::Zstd)),
+        response => panic!("Expected a hello response, got {:?}", response),
+    }
+    for (key, value) in [("small", "value1"), ("large", value.as_str())] {
+        let set = Message::Set {
+            key: key.as_bytes().to_vec(),
+            value: value.to_owned(),
+            ttl_ms: None,
+        };
+        write_frame(&mut stream, &set).unwrap();
+        assert!(matches!(
+            responses.next().unwrap().unwrap(),
+            Response::Set(Ok(_))
+        ));
+    }
+
+    let get = |key: &str| Message::Get {
+        key: key.as_bytes().to_vec(),
+        after: None,
+    };
+    write_frame(&mut stream, &get("small")).unwrap();
+    match responses.next().unwrap().unwrap() {
+        Response::Get(Ok(got)) => assert_eq!(got, Some("value1".to_owned())),
+        response => panic!("Expected a get response, got {:?}", response),
+    }
+    write_frame(&mut stream, &get("large")).unwrap();
+    match responses.next().unwrap().unwrap() {
+        Response::Compressed { compression, data } => {
+            assert!(data.len() < value.len());
+            match compression.decompress(&data).unwrap() {
+                Response::Get(Ok(got)) => assert_eq!(got, Some(value.clone())),
+                response => panic!("Expected a get response, got {:?}", response),
+            }
+        }
+        response => panic!("Expected a compressed response, got {:?}", response),
+    }
+    drop(responses);
+    drop(stream);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "large", "--addr", addr, "--compress-above", "64"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "large", "8", "16", "--addr", addr])
+        .args(["--compress-above", "8"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("abcdefghabcdefgh\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// The server should reject empty keys, and keys under the reserved prefix
+// when started with `--reserve-internal-keys`, which also hides them from
+// scans.
+#[test]
+fn cli_key_validation() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4030";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--reserve-internal-keys"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Keys must not be empty"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "__kvs_version", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("reserved for internal metadata"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "__kvs_version", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("reserved for internal metadata"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "kvs_version", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // Queues and locks keep their keys under the prefix themselves
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["enqueue", "jobs", "item", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["acquire", "leader", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // ...and scans leave them out
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("kvs_version\tvalue\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--glob", "*", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("kvs_version\tvalue\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-doctor check` should report a torn log tail and fail, and `--fix`
+// should truncate it.
+#[test]
+fn doctor_check() {
+    use kvs::{KvStore, KvsEngine, KvsWriter};
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).unwrap();
+    store.set(b"key1".to_vec(), "value1".to_owned()).unwrap();
+    drop(store);
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))
+        .unwrap();
+    log.write_all(br#"{"Set":{"key":"key2","val"#).unwrap();
+    drop(log);
+
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("unreadable bytes from offset"));
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .args(["check", "--fix"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Repaired:"));
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .arg("check")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Live keys: 1"))
+        .stdout(contains("Index: valid"));
+}
+
+// `kvs-doctor dump` should print one line per record, from an offset if
+// given, and fail at an unreadable record.
+#[test]
+fn doctor_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("1.log");
+    fs::write(
+        &log,
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Remove":{"key":"key1"}}{"Set":{"#,
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .arg("dump")
+        .arg(&log)
+        .assert()
+        .failure()
+        .stdout(contains(r#"0       39  set            6 B"#))
+        .stdout(contains(r#"39       25  rm"#))
+        .stderr(contains("Unreadable record at offset 64 after 2 records"));
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .args(["dump", "--offset", "39"])
+        .arg(&log)
+        .assert()
+        .failure()
+        .stdout(contains(r#""key1""#).count(1));
+
+    fs::write(&log, r#"{"Set":{"key":"key1","value":"value1"}}"#).unwrap();
+    Command::cargo_bin("kvs-doctor")
+        .unwrap()
+        .arg("dump")
+        .arg(&log)
+        .assert()
+        .success()
+        .stdout(contains("1 records, ending at offset 39"));
+}
+
+// The server should speak the protocol over WebSocket, with one frame per
+// text message.
+#[cfg(feature = "websocket")]
+#[test]
+fn websocket_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4026";
+    let websocket_addr = "127.0.0.1:4027";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--websocket-addr", websocket_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect(websocket_addr).unwrap();
+    let (mut socket, _) = tungstenite::client(format!("ws://{}/", websocket_addr), stream).unwrap();
+    let mut send = |message: &Message| -> Response {
+        let text = serde_json::to_string(message).unwrap();
+        socket.send(tungstenite::Message::text(text)).unwrap();
+        let reply = socket.read().unwrap();
+        serde_json::from_str(reply.to_text().unwrap()).unwrap()
+    };
+
+    let set = Message::Set {
+        key: b"key1".to_vec(),
+        value: "value1".to_owned(),
+        ttl_ms: None,
+    };
+    assert!(matches!(send(&set), Response::Set(Ok(_))));
+    let get = Message::Get {
+        key: b"key1".to_vec(),
+        after: None,
+    };
+    match send(&get) {
+        Response::Get(Ok(value)) => assert_eq!(value, Some("value1".to_owned())),
+        response => panic!("Expected a get response, got {:?}", response),
+    }
+    socket.close(None).unwrap();
+    drop(socket);
+
+    // The plain listener still works alongside it
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// The admin UI should list keys with value previews and run compaction
+// from its button, but not for pages of other origins or host names.
+#[cfg(feature = "admin-ui")]
+#[test]
+fn admin_ui() {
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4028";
+    let admin_addr = "127.0.0.1:4029";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--admin-addr", "4029"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "user/1", "<b>alice</b>", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let http = |request: &str| {
+        let mut stream = TcpStream::connect(admin_addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let keys = http("GET /keys?prefix=user%2F HTTP/1.1\r\nHost: localhost:4029\r\n\r\n");
+    assert!(keys.starts_with("HTTP/1.1 200 OK"));
+    assert!(keys.contains("<a href=\"/key?key=user%2F1\">user/1</a>"));
+    assert!(keys.contains("&lt;b&gt;alice&lt;/b&gt;"));
+
+    let compact = http(
+        "POST /compact HTTP/1.1\r\nHost: 127.0.0.1:4029\r\n\
+         Origin: http://127.0.0.1:4029\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert!(compact.starts_with("HTTP/1.1 303 See Other"));
+    assert!(compact.contains("Location: /?notice=Compacted%20the%20logs"));
+
+    let overview = http("GET /?notice=Compacted%20the%20logs HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(overview.contains("Compacted the logs"));
+    assert!(overview.contains("Writes"));
+
+    let metrics = http("GET /metrics HTTP/1.1\r\nHost: 127.0.0.1:4029\r\n\r\n");
+    assert!(metrics.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(metrics.contains("# TYPE kvs_writes_total counter\nkvs_writes_total 1\n"));
+
+    let missing = http("GET /nowhere HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found"));
+
+    // Forms on other sites, and names rebound to the server, are refused
+    let cross_origin = http(
+        "POST /rotate HTTP/1.1\r\nHost: 127.0.0.1:4029\r\n\
+         Origin: http://evil.example\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert!(cross_origin.starts_with("HTTP/1.1 403 Forbidden"));
+    let cross_site = http(
+        "POST /rotate HTTP/1.1\r\nHost: 127.0.0.1:4029\r\n\
+         Sec-Fetch-Site: cross-site\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert!(cross_site.starts_with("HTTP/1.1 403 Forbidden"));
+    let rebound = http("GET /keys HTTP/1.1\r\nHost: evil.example:4029\r\n\r\n");
+    assert!(rebound.starts_with("HTTP/1.1 403 Forbidden"));
+    assert!(!temp_dir.path().join("4.log").exists());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // Without an ACL, the UI isn't served beyond loopback
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--admin-addr", "0.0.0.0:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("without an ACL"));
+}
+
+// On a server with an ACL, the admin UI should only answer users with the
+// admin permission.
+#[cfg(feature = "admin-ui")]
+#[test]
+fn admin_ui_acl() {
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let acl_path = temp_dir.path().join("acl.json");
+    fs::write(
+        &acl_path,
+        r#"{"users": [
+            {"name": "admin", "token": "a", "permissions": ["read", "write", "admin"]},
+            {"name": "reader", "token": "r", "permissions": ["read"]}
+        ]}"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4053";
+    let admin_addr = "127.0.0.1:4054";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--admin-addr", admin_addr])
+        .args(["--acl", acl_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let http = |request: &str| {
+        let mut stream = TcpStream::connect(admin_addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // admin:a and reader:r
+    let anonymous = http("GET /keys HTTP/1.1\r\nHost: kvs.example\r\n\r\n");
+    assert!(anonymous.starts_with("HTTP/1.1 401 Unauthorized"));
+    assert!(anonymous.contains("WWW-Authenticate: Basic"));
+    let reader = http(
+        "GET /keys HTTP/1.1\r\nHost: kvs.example\r\nAuthorization: Basic cmVhZGVyOnI=\r\n\r\n",
+    );
+    assert!(reader.starts_with("HTTP/1.1 401 Unauthorized"));
+    let wrong_token = http(
+        "POST /compact HTTP/1.1\r\nHost: kvs.example\r\nAuthorization: Basic YWRtaW46cg==\r\n\r\n",
+    );
+    assert!(wrong_token.starts_with("HTTP/1.1 401 Unauthorized"));
+    let admin = http(
+        "GET /keys HTTP/1.1\r\nHost: kvs.example\r\nAuthorization: Basic YWRtaW46YQ==\r\n\r\n",
+    );
+    assert!(admin.starts_with("HTTP/1.1 200 OK"));
+    let compact = http(
+        "POST /compact HTTP/1.1\r\nHost: kvs.example\r\nAuthorization: Basic YWRtaW46YQ==\r\n\
+         Origin: http://kvs.example\r\n\r\n",
+    );
+    assert!(compact.starts_with("HTTP/1.1 303 See Other"));
+    let cross_origin = http(
+        "POST /compact HTTP/1.1\r\nHost: kvs.example\r\nAuthorization: Basic YWRtaW46YQ==\r\n\
+         Origin: http://evil.example\r\n\r\n",
+    );
+    assert!(cross_origin.starts_with("HTTP/1.1 403 Forbidden"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client eval` should run a script against the store and print its
+// result, hold its writes to the checks a client's own writes get, and stop
+// a runaway or memory-hungry script.
+#[cfg(feature = "scripting")]
+#[test]
+fn cli_eval() {
+    let temp_dir = TempDir::new().unwrap();
+    let quotas = temp_dir.path().join("quotas.json");
+    fs::write(&quotas, r#"{"quotas": [{"prefix": "a/", "max_keys": 1}]}"#).unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--script-timeout-ms", "100"])
+        .args(["--script-memory-mb", "16"])
+        .args(["--reserve-internal-keys", "--quotas"])
+        .arg(&quotas)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let increment = r#"
+        local count = tonumber(kvs.get(ARGV[1]) or "0") + 1
+        kvs.set(ARGV[1], tostring(count))
+        return count
+    "#;
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", increment, "counter", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", increment, "counter", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "eval",
+            "return tostring(kvs.remove('missing'))",
+            "--addr",
+            addr,
+        ])
+        .assert()
+        .success()
+        .stdout("false\n");
+    // Only strings, numbers and nil can be returned
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", "return {}", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", "kvs.set('__kvs_version', 'x')", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("reserved for internal metadata"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", "kvs.remove('')", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Keys must not be empty"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "eval",
+            "kvs.set('a/1', 'x') kvs.set('a/2', 'x')",
+            "--addr",
+            addr,
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("Quota exceeded"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", "while true do end", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("timed out"));
+    for hog in [
+        "return string.rep('x', 1 << 30)",
+        "local t = {} for i = 1, 1e9 do t[i] = i end",
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["eval", hog, "--addr", addr])
+            .assert()
+            .failure()
+            .stderr(contains("out of memory"));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["eval", "return os.exit()", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "counter", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// Messages published to a channel should reach its subscribers and no one
+// else.
+#[test]
+fn cli_pubsub() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut subscriber = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["subscribe", "news", "alerts", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = subscriber.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["publish", "news", "hello", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["publish", "sports", "ignored", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("0\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["publish", "alerts", "fire", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), "news\thello");
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), "alerts\tfire");
+
+    subscriber.kill().unwrap();
+    subscriber.wait().unwrap();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// A request retried with the same idempotency key should get the first
+// response back without running again.
+#[test]
+fn cli_idempotency_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4031";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let append = |idempotency_key: &str, extra: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["append", "events", "start,", "--addr", addr])
+            .args(["--idempotency-key", idempotency_key])
+            .args(extra)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    append("request-1", &[]).stdout("6\n");
+    append("request-1", &["--trace-id", "retry"]).stdout("6\n");
+    append("request-2", &[]).stdout("12\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "events", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("start,start,\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client reopen` should make the server close its engine and open it
+// again, from another directory if given, falling back to the old one if
+// the new one can't be opened, all without dropping its listener.
+#[test]
+fn cli_reopen() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4032"), ("sled", "127.0.0.1:4033")] {
+        let temp_dir = TempDir::new().unwrap();
+        let other_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(args)
+                .args(["--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+        };
+        let dir = |dir: &TempDir| dir.path().to_str().unwrap().to_owned();
+
+        client(&["set", "key1", "value1"]).success();
+        client(&["reopen"]).success();
+        client(&["get", "key1"]).success().stdout("value1\n");
+
+        client(&["reopen", &dir(&other_dir)]).success();
+        client(&["get", "key1"])
+            .success()
+            .stdout(contains("Key not found"));
+        client(&["set", "key2", "value2"]).success();
+
+        let not_a_dir = temp_dir.path().join("file");
+        fs::write(&not_a_dir, "").unwrap();
+        client(&["reopen", not_a_dir.to_str().unwrap()]).failure();
+        client(&["get", "key2"]).success().stdout("value2\n");
+
+        client(&["reopen", &dir(&temp_dir)]).success();
+        client(&["get", "key1"]).success().stdout("value1\n");
+
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
+
+// `kvs-client hotkeys` should list the keys read most, with their read
+// counts, when the server samples reads.
+#[test]
+fn cli_hotkeys() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4034";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--hotkeys-sample-rate", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    client(&["set", "warm", "value"]);
+    client(&["set", "hot", "value"]);
+    client(&["get", "warm"]);
+    for _ in 0..3 {
+        client(&["get", "hot"]);
+    }
+
+    let output = client(&["hotkeys", "--top", "2"])
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let lines: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert_eq!(lines[0][..2], ["hot", "3"]);
+    assert_eq!(lines[1][..2], ["warm", "1"]);
+    assert!(lines[0][2].parse::<u64>().unwrap() >= lines[1][2].parse::<u64>().unwrap());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// Writes that would take a prefix over its quota should fail, and
+// `kvs-client quotas` should print each prefix's usage.
+#[test]
+fn cli_quotas() {
+    let temp_dir = TempDir::new().unwrap();
+    let quotas = temp_dir.path().join("quotas.json");
+    fs::write(
+        &quotas,
+        r#"{"quotas": [{"prefix": "a/", "max_keys": 2}, {"prefix": "b/", "max_bytes": 10}]}"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4035";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--quotas"])
+        .arg(&quotas)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["set", "a/1", "x"]).success();
+    client(&["set", "a/2", "x"]).success();
+    client(&["set", "a/3", "x"])
+        .failure()
+        .stderr(contains("Quota exceeded"));
+    // Overwriting a key doesn't add one
+    client(&["set", "a/2", "y"]).success();
+    client(&["set", "b/1", "12345678"])
+        .failure()
+        .stderr(contains("Quota exceeded"));
+    client(&["set", "b/1", "123"]).success();
+    client(&["rm", "a/1"]).success();
+    client(&["set", "a/3", "x"]).success();
+
+    let output = client(&["quotas"]).success().get_output().stdout.clone();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "a/\t2\t2\t8\t-\nb/\t1\t-\t6\t10\n"
+    );
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// A server in chaos mode should delay, fail and drop client requests as its
+// chaos file says, while still answering admin requests
+#[test]
+fn cli_chaos() {
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = |addr: &str, chaos: &str| {
+        let chaos_file = temp_dir.path().join(format!("chaos-{}.json", &addr[10..]));
+        fs::write(&chaos_file, chaos).unwrap();
+        let server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--chaos"])
+            .arg(&chaos_file)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        server
+    };
+    let client = |addr: &str, args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    let failing = "127.0.0.1:4038";
+    let mut server = start_server(
+        failing,
+        r#"{"delay_probability": 1, "delay_ms": [300, 300], "error_probability": 1}"#,
+    );
+    let started = Instant::now();
+    client(failing, &["set", "key", "value"])
+        .failure()
+        .stderr(contains("Injected by chaos mode"));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    client(failing, &["metrics"]).success();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let dropping = "127.0.0.1:4039";
+    let mut server = start_server(dropping, r#"{"disconnect_probability": 1}"#);
+    client(dropping, &["get", "key"])
+        .failure()
+        .stderr(contains("The server closed the connection"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let invalid = temp_dir.path().join("invalid.json");
+    fs::write(&invalid, r#"{"error_probability": 2}"#).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4040", "--chaos"])
+        .arg(&invalid)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("from 0 to 1"));
+}
+
+// A client given fallback addresses should use them while the primary is
+// down, send reads there when the primary fails mid-session, and go back to
+// the primary once it returns.
+#[test]
+fn cli_failover() {
+    let primary_dir = TempDir::new().unwrap();
+    let fallback_dir = TempDir::new().unwrap();
+    let (primary_addr, fallback_addr) = ("127.0.0.1:4036", "127.0.0.1:4037");
+    let start_server = |addr: &str, dir: &TempDir| {
+        let server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        server
+    };
+
+    let mut fallback = start_server(fallback_addr, &fallback_dir);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "fallback", "--addr", primary_addr])
+        .args(["--fallback-addr", fallback_addr])
+        .current_dir(&fallback_dir)
+        .assert()
+        .success();
+
+    let mut primary = start_server(primary_addr, &primary_dir);
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let addrs = vec![primary_addr, fallback_addr];
+    let mut client = KvsClient::builder(logger)
+        .probe_interval(Duration::ZERO)
+        .connect_failover(addrs)
+        .unwrap();
+    assert_eq!(client.server_index(), Some(0));
+    client.set(b"key".to_vec(), "primary".to_owned()).unwrap();
+
+    primary.kill().expect("server exited before killed");
+    primary.wait().unwrap();
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some("fallback".to_owned())
+    );
+    assert_eq!(client.server_index(), Some(1));
+
+    let mut primary = start_server(primary_addr, &primary_dir);
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some("primary".to_owned())
+    );
+    assert_eq!(client.server_index(), Some(0));
+
+    for server in [&mut primary, &mut fallback] {
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
+
+// A server should drop a client sending a frame over its limit without
+// reading it all, while a client sends longer values in parts.
+#[test]
+fn cli_max_frame_len() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4041";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--max-frame-bytes", "1024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let oversized = Message::Set {
+        key: b"key".to_vec(),
+        value: "x".repeat(4096),
+        ttl_ms: None,
+    };
+    // The server may close the connection before the whole frame is written
+    let _ = write_frame(&mut writer, &oversized);
+    let mut responses: FrameReader<_, Response> = FrameReader::new(stream);
+    assert!(responses.next().is_none_or(|response| response.is_err()));
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut client = KvsClient::builder(logger)
+        .chunk_len(256)
+        .connect(addr)
+        .unwrap();
+    let value = "é".repeat(5000);
+    client.set(b"key".to_vec(), value.clone()).unwrap();
+    assert_eq!(client.get(b"key".to_vec()).unwrap(), Some(value));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+kvs::typed! {
+    struct Sessions: key = str, value = Session, prefix = "sessions/"
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Session {
+    user: String,
+    visits: u32,
+}
+
+// A typed client should store values as JSON under its prefix, and fail on
+// values of another shape.
+#[test]
+fn cli_typed_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4042";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut client = KvsClient::new(logger, addr).unwrap();
+    let session = Session {
+        user: "ada".to_owned(),
+        visits: 3,
+    };
+    let mut sessions = Sessions::new(&mut client);
+    sessions.set("abc", &session).unwrap();
+    assert_eq!(sessions.get("abc").unwrap(), Some(session));
+    assert_eq!(sessions.get("missing").unwrap(), None);
+    sessions.remove("abc").unwrap();
+    assert_eq!(sessions.get("abc").unwrap(), None);
+
+    client
+        .set(b"sessions/bad".to_vec(), "not json".to_owned())
+        .unwrap();
+    assert!(matches!(
+        Sessions::new(&mut client).get("bad"),
+        Err(kvs::KvStoreError::SerdeErr(_))
+    ));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client log-level` and SIGUSR1 should change what a running server
+// logs.
+#[test]
+fn cli_log_level() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4043";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--log-level", "warn"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    client(&["set", "quiet", "1"]).success();
+    client(&["log-level", "info"]).success();
+    client(&["set", "loud", "2"]).success();
+    client(&["log-level", "loudest"])
+        .failure()
+        .stderr(contains("Unknown log level"));
+
+    #[cfg(unix)]
+    {
+        Command::new("kill")
+            .args(["-USR1", &server.id().to_string()])
+            .assert()
+            .success();
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    let mut stderr = String::new();
+    server
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(stderr.matches("Received message: Set {").count(), 1);
+    assert!(stderr.contains("Log level is now INFO"), "{}", stderr);
+    #[cfg(unix)]
+    assert!(stderr.contains("Log level is now DEBUG"), "{}", stderr);
+}
+
+// `kvs-load` should run the requested number of requests against a server
+// and report throughput and latency percentiles.
+#[test]
+fn cli_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4044";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let output = Command::cargo_bin("kvs-load")
+        .unwrap()
+        .args(["--addr", addr, "--keys", "50", "--value-bytes", "10..100"])
+        .args(["--requests", "201", "--concurrency", "2", "--prefill"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("requests\t201\n"), "{}", stdout);
+    assert!(stdout.contains("errors\t0\n"), "{}", stdout);
+    assert!(stdout.contains("p99_us\t"), "{}", stdout);
+
+    Command::cargo_bin("kvs-load")
+        .unwrap()
+        .args(["--addr", addr, "--value-bytes", "100..10"])
+        .assert()
+        .failure()
+        .stderr(contains("is longer than"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// `kvs-client --addr` should accept a hostname as well as an IP address.
+#[test]
+fn cli_client_hostname() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4045"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "localhost:4045"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "localhost:4045"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "no-such-host.invalid:4045"])
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
@@ -0,0 +1,13 @@
+use kvs::test_util::run_conformance_suite;
+use kvs::{KvStore, Result};
+
+#[test]
+fn kv_store_conformance() -> Result<()> {
+    run_conformance_suite::<KvStore>()
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_conformance() -> Result<()> {
+    run_conformance_suite::<kvs::SledKvsEngine>()
+}
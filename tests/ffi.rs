@@ -0,0 +1,78 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+
+use kvs::ffi::*;
+use tempfile::TempDir;
+
+// Set, get, remove and reopen a store through the C ABI, as a C caller
+// would.
+#[test]
+fn ffi_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+
+    unsafe {
+        let mut store = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &mut store), KvsStatus::Ok);
+
+        let key = b"key1";
+        let value = "value1";
+        assert_eq!(
+            kvs_set(
+                store,
+                key.as_ptr(),
+                key.len(),
+                value.as_ptr() as *const c_char,
+                value.len()
+            ),
+            KvsStatus::Ok
+        );
+        assert_eq!(kvs_close(store), KvsStatus::Ok);
+
+        let mut store = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &mut store), KvsStatus::Ok);
+        let mut found = ptr::null_mut();
+        let mut found_len = 0;
+        assert_eq!(
+            kvs_get(store, key.as_ptr(), key.len(), &mut found, &mut found_len),
+            KvsStatus::Ok
+        );
+        assert_eq!(
+            slice::from_raw_parts(found as *const u8, found_len),
+            b"value1"
+        );
+        assert_eq!(CStr::from_ptr(found).to_str().unwrap(), "value1");
+        kvs_free_value(found, found_len);
+
+        assert_eq!(kvs_remove(store, key.as_ptr(), key.len()), KvsStatus::Ok);
+        assert_eq!(
+            kvs_get(store, key.as_ptr(), key.len(), &mut found, &mut found_len),
+            KvsStatus::NotFound
+        );
+        assert!(found.is_null());
+        assert_eq!(
+            kvs_remove(store, key.as_ptr(), key.len()),
+            KvsStatus::NotFound
+        );
+
+        let invalid = [0xff, 0xfe];
+        assert_eq!(
+            kvs_set(
+                store,
+                key.as_ptr(),
+                key.len(),
+                invalid.as_ptr() as *const c_char,
+                invalid.len()
+            ),
+            KvsStatus::InvalidArgument
+        );
+        assert!(CStr::from_ptr(kvs_last_error())
+            .to_str()
+            .unwrap()
+            .contains("UTF-8"));
+
+        assert_eq!(kvs_close(store), KvsStatus::Ok);
+    }
+}
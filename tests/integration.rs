@@ -0,0 +1,392 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use kvs::test_util::TestServer;
+use kvs::{
+    EngineMetrics, Glob, KvStore, KvStoreError, KvsClient, KvsEngine, KvsReader, KvsWriter,
+    Metrics, Result, ServerConfig, WriteBatch,
+};
+
+const CLIENTS: usize = 4;
+const KEYS_PER_CLIENT: usize = 100;
+
+fn key(client: usize, key: usize) -> Vec<u8> {
+    format!("client{}/key{:03}", client, key).into_bytes()
+}
+
+// Clients writing at once should each read their own writes back, and every
+// acknowledged write should still be there after the server restarts.
+fn concurrent_writes_survive_restart<E: KvsEngine + 'static>() -> Result<()> {
+    let server = TestServer::<E>::start()?;
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..CLIENTS)
+            .map(|client| {
+                let server = &server;
+                scope.spawn(move || -> Result<()> {
+                    let mut kvs = server.client()?;
+                    for n in 0..KEYS_PER_CLIENT {
+                        kvs.set(key(client, n), format!("value{}", n))?;
+                        assert_eq!(kvs.get(key(client, n))?, Some(format!("value{}", n)));
+                    }
+                    // Overwrite the even keys and remove every tenth
+                    for n in (0..KEYS_PER_CLIENT).step_by(2) {
+                        kvs.set(key(client, n), format!("new{}", n))?;
+                    }
+                    for n in (0..KEYS_PER_CLIENT).step_by(10) {
+                        kvs.remove(key(client, n))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let server = server.restart()?;
+    let mut kvs = server.client()?;
+    for client in 0..CLIENTS {
+        for n in 0..KEYS_PER_CLIENT {
+            let expected = match n {
+                n if n % 10 == 0 => None,
+                n if n % 2 == 0 => Some(format!("new{}", n)),
+                n => Some(format!("value{}", n)),
+            };
+            assert_eq!(kvs.get(key(client, n))?, expected);
+        }
+    }
+
+    let scanned = kvs
+        .scan(b"client1/".to_vec())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(scanned.len(), KEYS_PER_CLIENT - KEYS_PER_CLIENT / 10);
+    assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    Ok(())
+}
+
+// A failed request should get an error response and leave the connection
+// usable, and restarts should keep serving on the same address.
+fn errors_keep_the_connection<E: KvsEngine + 'static>() -> Result<()> {
+    let mut server = TestServer::<E>::start()?;
+    for round in 0..3 {
+        let addr = server.addr();
+        {
+            let mut kvs = server.client()?;
+            assert!(kvs.remove(b"missing".to_vec()).is_err());
+            assert!(kvs.set(Vec::new(), "value".to_owned()).is_err());
+
+            let created = kvs.set_nx(b"once".to_vec(), "first".to_owned())?;
+            assert_eq!(created, round == 0);
+            assert!(!kvs.set_nx(b"once".to_vec(), "second".to_owned())?);
+            assert_eq!(
+                kvs.get_many(vec![b"once".to_vec(), b"missing".to_vec()])?,
+                vec![Some("first".to_owned()), None]
+            );
+        }
+        server = server.restart()?;
+        assert_eq!(server.addr(), addr);
+    }
+
+    Ok(())
+}
+
+// A client given a hostname should resolve it when it connects and again
+// when it reconnects, keeping its session with the server.
+#[test]
+fn reconnect_resolves_the_hostname() -> Result<()> {
+    let server = TestServer::<KvStore>::start()?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut kvs = KvsClient::new(logger, format!("localhost:{}", server.addr().port()))?;
+
+    kvs.set(b"key".to_vec(), "value".to_owned())?;
+    for _ in 0..3 {
+        kvs.reconnect()?;
+        assert_eq!(kvs.get(b"key".to_vec())?, Some("value".to_owned()));
+    }
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    assert!(KvsClient::new(logger, "no-such-host.invalid:4000").is_err());
+
+    Ok(())
+}
+
+// Keys set with set_many should read back with get_many, absent keys as
+// None, even when the values together, or one of them, are longer than the
+// server takes in a frame.
+#[test]
+fn set_many_fits_the_frame_limit() -> Result<()> {
+    let server = TestServer::<KvStore>::start_with_config(ServerConfig {
+        max_frame_len: 64 * 1024,
+        ..ServerConfig::default()
+    })?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut kvs = KvsClient::builder(logger)
+        .chunk_len(16 * 1024)
+        .connect(server.addr())?;
+
+    let entries: Vec<(Vec<u8>, String)> = (0..20)
+        .map(|n| (key(0, n), format!("{}", n).repeat(n * 1000)))
+        .collect();
+    kvs.set_many(entries.clone())?;
+
+    let mut keys: Vec<Vec<u8>> = entries.iter().map(|(key, _)| key.clone()).collect();
+    keys.insert(3, b"missing".to_vec());
+    let mut expected: Vec<Option<String>> =
+        entries.into_iter().map(|(_, value)| Some(value)).collect();
+    expected.insert(3, None);
+    assert_eq!(kvs.get_many(keys)?, expected);
+
+    Ok(())
+}
+
+// A KvStore that panics on a get of "panic", and finds no log reader for
+// "unread", as a bug in a request path would
+struct FaultyEngine(KvStore);
+
+impl KvsReader for FaultyEngine {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<String>> {
+        match key.as_slice() {
+            b"panic" => panic!("a bug in a request path"),
+            b"unread" => Err(KvStoreError::MissingLogReader(7)),
+            _ => self.0.get(key),
+        }
+    }
+    fn scan(
+        &mut self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.0.scan(prefix, start_after, limit)
+    }
+    fn scan_glob(
+        &mut self,
+        pattern: &Glob,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        self.0.scan_glob(pattern, start_after, limit)
+    }
+    fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        self.0.contains(key)
+    }
+    fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.0.ttl(key)
+    }
+}
+
+impl KvsWriter for FaultyEngine {
+    fn set(&mut self, key: Vec<u8>, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+    fn set_nx(&mut self, key: Vec<u8>, value: String) -> Result<bool> {
+        self.0.set_nx(key, value)
+    }
+    fn set_with_ttl(&mut self, key: Vec<u8>, value: String, ttl: Duration) -> Result<()> {
+        self.0.set_with_ttl(key, value, ttl)
+    }
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        self.0.apply(batch)
+    }
+    fn get_set(&mut self, key: Vec<u8>, value: String) -> Result<Option<String>> {
+        self.0.get_set(key, value)
+    }
+    fn get_del(&mut self, key: Vec<u8>) -> Result<Option<String>> {
+        self.0.get_del(key)
+    }
+    fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.0.expire(key, ttl)
+    }
+    fn persist(&mut self, key: Vec<u8>) -> Result<()> {
+        self.0.persist(key)
+    }
+    fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.0.flush()
+    }
+}
+
+impl EngineMetrics for FaultyEngine {
+    fn metrics(&self) -> Metrics {
+        self.0.metrics()
+    }
+}
+
+impl KvsEngine for FaultyEngine {
+    fn open(path: PathBuf) -> Result<FaultyEngine> {
+        KvStore::open(path).map(FaultyEngine)
+    }
+}
+
+// A request that panics should cost only its own connection, and a missing
+// log reader only its own request: either way the server keeps serving.
+#[test]
+fn faults_are_isolated() -> Result<()> {
+    let server = TestServer::<FaultyEngine>::start()?;
+    {
+        let mut kvs = server.client()?;
+        kvs.set(b"key".to_vec(), "value".to_owned())?;
+        assert!(matches!(
+            kvs.get(b"panic".to_vec()),
+            Err(KvStoreError::IoErr(_))
+        ));
+    }
+
+    let mut kvs = server.client()?;
+    assert_eq!(kvs.get(b"key".to_vec())?, Some("value".to_owned()));
+    let err = kvs.get(b"unread".to_vec()).unwrap_err();
+    assert!(err.to_string().contains("No reader for log generation 7"));
+    assert_eq!(kvs.get(b"key".to_vec())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A trace id the client sends should be recorded with its requests in the
+// slow log, and not with the requests sent after it is cleared.
+#[test]
+fn trace_ids_reach_the_slow_log() -> Result<()> {
+    let server = TestServer::<KvStore>::start_with_config(ServerConfig {
+        slowlog_threshold: Duration::ZERO,
+        ..ServerConfig::default()
+    })?;
+    let mut kvs = server.client()?;
+
+    kvs.set_trace_id(Some("trace-1234".to_owned()));
+    kvs.set(b"traced".to_vec(), "value".to_owned())?;
+    kvs.set_trace_id(None);
+    kvs.set(b"untraced".to_vec(), "value".to_owned())?;
+
+    let entries = kvs.slowlog_get(10)?;
+    let trace_id = |key: &[u8]| {
+        let entry = entries.iter().find(|entry| entry.key == key).unwrap();
+        entry.trace_id.clone()
+    };
+    assert_eq!(trace_id(b"traced"), Some("trace-1234".to_owned()));
+    assert_eq!(trace_id(b"untraced"), None);
+
+    Ok(())
+}
+
+// Responses encoded into a reused buffer should carry only their own bytes,
+// whether the response before was longer or shorter.
+#[test]
+fn reused_buffers_stay_correct() -> Result<()> {
+    let server = TestServer::<KvStore>::start_with_config(ServerConfig {
+        buffer_pool_len: 1,
+        ..ServerConfig::default()
+    })?;
+    let mut kvs = server.client()?;
+    let values = [
+        "a".repeat(10_000),
+        "b".to_owned(),
+        "c".repeat(500),
+        String::new(),
+    ];
+    for (n, value) in values.iter().enumerate() {
+        kvs.set(key(0, n), value.clone())?;
+    }
+
+    for n in [0, 1, 0, 2, 3, 2, 1] {
+        assert_eq!(kvs.get(key(0, n))?, Some(values[n].clone()));
+    }
+    assert_eq!(kvs.get(key(0, 9))?, None);
+
+    let pool = kvs.metrics()?.buffer_pool.unwrap();
+    assert!(pool.taken > pool.allocated);
+
+    Ok(())
+}
+
+// After reads and writes, the server's Metrics should report non-zero rates
+// for both, and none for operations that weren't made.
+#[test]
+fn request_rates() -> Result<()> {
+    let server = TestServer::<KvStore>::start()?;
+    let mut kvs = server.client()?;
+    for n in 0..5 {
+        kvs.set(key(0, n), "value".to_owned())?;
+    }
+    for n in 0..10 {
+        kvs.get(key(0, n))?;
+    }
+
+    let rates = kvs.metrics()?.rates.unwrap();
+    for operation in ["get", "set"] {
+        let rate = rates[operation];
+        assert!(rate.per_sec_1s > 0.0, "{}: {:?}", operation, rate);
+        assert!(rate.per_sec_10s > 0.0, "{}: {:?}", operation, rate);
+        assert!(rate.per_sec_60s > 0.0, "{}: {:?}", operation, rate);
+    }
+    assert!(rates["get"].per_sec_60s > rates["set"].per_sec_60s);
+    assert!(!rates.contains_key("remove"));
+
+    Ok(())
+}
+
+// Keys and values of known sizes should be counted in the power-of-two
+// buckets of the server's Metrics, each length in the smallest bucket that
+// holds it.
+fn size_histograms<E: KvsEngine + 'static>() -> Result<()> {
+    let server = TestServer::<E>::start()?;
+    let mut kvs = server.client()?;
+    for (key_len, value_len) in [(1, 0), (2, 16), (3, 17), (4, 1024), (5, 1025)] {
+        kvs.set(vec![b'k'; key_len], "v".repeat(value_len))?;
+    }
+
+    let metrics = kvs.metrics()?;
+    let counts = |buckets: Vec<kvs::Bucket>| -> Vec<(u64, u64)> {
+        buckets
+            .into_iter()
+            .map(|bucket| (bucket.up_to, bucket.count))
+            .collect()
+    };
+    assert_eq!(
+        counts(metrics.key_lens.buckets()),
+        [(1, 1), (2, 1), (4, 2), (8, 1)]
+    );
+    assert_eq!(
+        counts(metrics.value_lens.buckets()),
+        [(1, 1), (16, 1), (32, 1), (1024, 1), (2048, 1)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn kv_store_concurrent_writes_survive_restart() -> Result<()> {
+    concurrent_writes_survive_restart::<KvStore>()
+}
+
+#[test]
+fn kv_store_errors_keep_the_connection() -> Result<()> {
+    errors_keep_the_connection::<KvStore>()
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_concurrent_writes_survive_restart() -> Result<()> {
+    concurrent_writes_survive_restart::<kvs::SledKvsEngine>()
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_errors_keep_the_connection() -> Result<()> {
+    errors_keep_the_connection::<kvs::SledKvsEngine>()
+}
+
+#[test]
+fn kv_store_size_histograms() -> Result<()> {
+    size_histograms::<KvStore>()
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_size_histograms() -> Result<()> {
+    size_histograms::<kvs::SledKvsEngine>()
+}
@@ -1,4 +1,19 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::log_inspect::{self, Command};
+use kvs::{
+    analyze, check_logs, digest, diverging_leaves, repair_logs, sync, Bucket, CompactionTransform,
+    CompactionWindow, Compare, EngineMetrics, Glob, IndexState, KeydirCheck, KvStore,
+    KvStoreConfig, KvStoreError, KvStoreSnapshot, KvStoreStandby, KvsEngine, KvsReader, KvsWriter,
+    Lock, LogEncoding, MerkleTree, NegativeCaching, Queue, RemoteTier, Result, StoreEvent,
+    SyncPeer, Txn, TxnOp, TxnResult, VersionRetention, WriteBatch, MAX_KEY_LEN, MAX_MERKLE_DEPTH,
+    RESERVED_KEY_PREFIX,
+};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -7,21 +22,21 @@ use walkdir::WalkDir;
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new()
         .expect("unable to create temporary working directory")
-        .into_path();
+        .keep();
 
     let mut store = KvStore::open(temp_dir.clone())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir.clone())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -31,20 +46,20 @@ fn get_stored_value() -> Result<()> {
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new()
         .expect("unable to create temporary working directory")
-        .into_path();
+        .keep();
     let mut store = KvStore::open(temp_dir.clone())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    store.set(b"key1".to_vec(), "value2".to_owned())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir)?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), "value3".to_owned())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -54,16 +69,146 @@ fn overwrite_value() -> Result<()> {
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new()
         .expect("unable to create temporary working directory")
-        .into_path();
+        .keep();
     let mut store = KvStore::open(temp_dir.clone())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir.clone())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    Ok(())
+}
+
+// Should round-trip keys that aren't valid UTF-8 or contain newlines
+#[test]
+fn binary_keys() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+
+    store.set(vec![0xff, 0x00, 0xfe], "value1".to_owned())?;
+    store.set(b"multi\nline".to_vec(), "value2".to_owned())?;
+    assert_eq!(
+        store.get(vec![0xff, 0x00, 0xfe])?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get(b"multi\nline".to_vec())?,
+        Some("value2".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir)?;
+    assert_eq!(
+        store.get(vec![0xff, 0x00, 0xfe])?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get(b"multi\nline".to_vec())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get(vec![0xff])?, None);
+
+    Ok(())
+}
+
+// Should page through keys with a prefix in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+
+    for key_id in 0..10 {
+        store.set(format!("user:{}", key_id).into_bytes(), key_id.to_string())?;
+    }
+    store.set(b"other".to_vec(), "value".to_owned())?;
+    store.remove(b"user:5".to_vec())?;
+
+    let first = store.scan(b"user:", None, 4)?;
+    let keys: Vec<&[u8]> = first.iter().map(|(key, _)| key.as_slice()).collect();
+    assert_eq!(keys, [&b"user:0"[..], b"user:1", b"user:2", b"user:3"]);
+
+    let rest = store.scan(b"user:", Some(b"user:3"), 100)?;
+    let values: Vec<&str> = rest.iter().map(|(_, value)| value.as_str()).collect();
+    assert_eq!(values, ["4", "6", "7", "8", "9"]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir)?;
+    assert_eq!(store.scan(b"user:", None, 100)?.len(), 9);
+    assert_eq!(store.scan(b"missing", None, 100)?, []);
+
+    Ok(())
+}
+
+// Should answer reads through a read-only handle
+// Glob scans should return the keys matching the pattern, in key order
+#[test]
+fn scan_glob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.keep())?;
+
+    for key in [
+        "user:1:settings",
+        "user:2:settings",
+        "user:2:profile",
+        "user:10:settings",
+        "users",
+        "admin:1:settings",
+    ] {
+        store.set(key.as_bytes().to_vec(), key.to_owned())?;
+    }
+
+    let scan_keys = |store: &mut KvStore, pattern: &str| -> Result<Vec<String>> {
+        let entries = store.scan_glob(&Glob::new(pattern)?, None, 100)?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect())
+    };
+
+    assert_eq!(
+        scan_keys(&mut store, "user:*:settings")?,
+        ["user:10:settings", "user:1:settings", "user:2:settings"]
+    );
+    assert_eq!(scan_keys(&mut store, "user:?:*")?.len(), 3);
+    assert_eq!(
+        scan_keys(&mut store, "*:[12]:settings")?,
+        ["admin:1:settings", "user:1:settings", "user:2:settings"]
+    );
+    assert_eq!(scan_keys(&mut store, "user:[^1]:*")?.len(), 2);
+    assert_eq!(scan_keys(&mut store, "users")?, ["users"]);
+    assert_eq!(scan_keys(&mut store, "user\\*")?, Vec::<String>::new());
+    assert_eq!(scan_keys(&mut store, "*")?.len(), 6);
+
+    // Paging resumes after the last key returned
+    let page = store.scan_glob(&Glob::new("user:*")?, None, 2)?;
+    let rest = store.scan_glob(&Glob::new("user:*")?, Some(&page[1].0), 100)?;
+    assert_eq!(page.len() + rest.len(), 4);
+
+    assert!(Glob::new("user:[12").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn read_only_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.keep())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+
+    let reader: &mut dyn KvsReader = &mut store;
+    assert!(reader.contains(b"key1")?);
+    assert!(!reader.contains(b"key2")?);
+    assert_eq!(reader.get(b"key1".to_vec())?, Some("value1".to_owned()));
 
     Ok(())
 }
@@ -71,18 +216,303 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.into_path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let mut store = KvStore::open(temp_dir.keep())?;
+    assert!(store.remove(b"key1".to_vec()).is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.into_path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let mut store = KvStore::open(temp_dir.keep())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    assert!(store.remove(b"key1".to_vec()).is_ok());
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    Ok(())
+}
+
+// set_nx should only write keys that are absent
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.keep())?;
+
+    assert!(store.set_nx(b"key1".to_vec(), "value1".to_owned())?);
+    assert!(!store.set_nx(b"key1".to_vec(), "value2".to_owned())?);
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+
+    store.remove(b"key1".to_vec())?;
+    assert!(store.set_nx(b"key1".to_vec(), "value3".to_owned())?);
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value3".to_owned()));
+
+    // An expired key counts as absent
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(store.set_nx(b"key2".to_vec(), "value2".to_owned())?);
+    assert_eq!(store.ttl(b"key2")?, None);
+
+    Ok(())
+}
+
+// get_set and get_del should return the value they replaced or removed
+#[test]
+fn get_set_and_get_del() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+
+    assert_eq!(store.get_set(b"key1".to_vec(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.get_set(b"key1".to_vec(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value2".to_owned()));
+
+    assert_eq!(store.get_del(b"key1".to_vec())?, Some("value2".to_owned()));
+    assert_eq!(store.get_del(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+
+    store.set(b"key2".to_vec(), "value1".to_owned())?;
+    store.get_del(b"key2".to_vec())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir)?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    Ok(())
+}
+
+// append should create absent keys, extend existing ones and keep their
+// expiry
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+
+    assert_eq!(store.append(b"key1".to_vec(), "a")?, 1);
+    assert_eq!(store.append(b"key1".to_vec(), "bc")?, 3);
+    assert_eq!(store.get(b"key1".to_vec())?, Some("abc".to_owned()));
+
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.append(b"key2".to_vec(), "!")?, 6);
+    assert!(store.ttl(b"key2")?.is_some());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("abc".to_owned()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some("value!".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a ttl should read as absent once it has passed, also after
+// reopening the store
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+
+    store.set_with_ttl(
+        b"short".to_vec(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        b"long".to_vec(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set(b"plain".to_vec(), "value3".to_owned())?;
+
+    assert_eq!(store.get(b"short".to_vec())?, Some("value1".to_owned()));
+    assert!(store.ttl(b"long")?.unwrap() > Duration::from_secs(59));
+    assert_eq!(store.ttl(b"plain")?, None);
+    assert!(store.ttl(b"missing").is_err());
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert!(!store.contains(b"short")?);
+    assert!(store.ttl(b"short").is_err());
+    assert!(store.remove(b"short".to_vec()).is_err());
+    assert_eq!(store.scan(b"", None, 10)?.len(), 2);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir)?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert_eq!(store.get(b"long".to_vec())?, Some("value2".to_owned()));
+    assert!(store.ttl(b"long")?.is_some());
+
+    Ok(())
+}
+
+// The reaper should remove expired keys, soonest expiry first, no faster than
+// its rate, writing a tombstone for each
+#[test]
+fn reap_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let config = KvStoreConfig {
+        reap_keys_per_sec: Some(2),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(path.to_path_buf(), config)?;
+
+    for i in 0..3 {
+        store.set_with_ttl(
+            format!("short{}", i).into_bytes(),
+            "value".to_owned(),
+            Duration::from_millis(50 + i),
+        )?;
+    }
+    store.set_with_ttl(
+        b"long".to_vec(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set(b"plain".to_vec(), "value".to_owned())?;
+    assert_eq!(store.reap_expired()?, 0);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.reap_expired()?, 2);
+    assert_eq!(store.reap_expired()?, 0);
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.reap_expired()?, 1);
+    assert_eq!(store.metrics().reaped, 3);
+    // Each tombstone counts as a write, like any other remove
+    assert_eq!(store.metrics().writes, 8);
+    assert_eq!(store.scan(b"", None, 10)?.len(), 2);
+    drop(store);
+
+    let check = check_logs(path, None, None)?;
+    let records: u64 = check.logs.iter().map(|log| log.records).sum();
+    assert_eq!((records, check.live_keys), (8, 2));
+
+    Ok(())
+}
+
+// A store keeping versions should read old values back by version, up to
+// its retention, across removes, compaction and reopening
+#[test]
+fn keep_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        keep_versions: Some(VersionRetention {
+            max_versions: Some(2),
+            max_age: None,
+        }),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+
+    for i in 1..=4 {
+        store.set(b"key".to_vec(), format!("value{}", i))?;
+    }
+    let versions = store.versions(b"key");
+    assert_eq!(versions.len(), 3);
+    assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(
+        store.get_version(b"key", versions[0])?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get_version(b"key", versions[2])?,
+        Some("value4".to_owned())
+    );
+
+    store.remove(b"key".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, None);
+    assert_eq!(store.versions(b"key"), versions[1..]);
+    store.compact()?;
+    assert_eq!(
+        store.get_version(b"key", versions[2])?,
+        Some("value4".to_owned())
+    );
+    store.save_index()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert_eq!(store.get(b"key".to_vec())?, None);
+    assert_eq!(store.versions(b"key"), versions[1..]);
+    assert_eq!(
+        store.get_version(b"key", versions[1])?,
+        Some("value3".to_owned())
+    );
+    drop(store);
+
+    // Versions older than the window are dropped, and none are kept once
+    // the store stops keeping them
+    let config = KvStoreConfig {
+        keep_versions: Some(VersionRetention {
+            max_versions: None,
+            max_age: Some(Duration::from_millis(200)),
+        }),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert_eq!(store.versions(b"key"), versions[1..]);
+    store.set(b"key".to_vec(), "value5".to_owned())?;
+    store.set(b"key".to_vec(), "value6".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    store.compact()?;
+    assert_eq!(store.versions(b"key").len(), 1);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let current = store.versions(b"key");
+    assert_eq!(current.len(), 1);
+    assert_eq!(
+        store.get_version(b"key", current[0])?,
+        Some("value6".to_owned())
+    );
+
+    Ok(())
+}
+
+// An expiry can be attached to or removed from an existing key
+#[test]
+fn expire_and_persist() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    assert!(store
+        .expire(b"missing".to_vec(), Duration::from_secs(1))
+        .is_err());
+    assert!(store.persist(b"missing".to_vec()).is_err());
+
+    store.expire(b"key1".to_vec(), Duration::from_millis(200))?;
+    store.expire(b"key2".to_vec(), Duration::from_millis(200))?;
+    store.persist(b"key2".to_vec())?;
+    assert_eq!(store.ttl(b"key2")?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    drop(store);
+    let mut store = KvStore::open(temp_dir)?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some("value2".to_owned()));
+
+    // A plain set clears the expiry
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        "value1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set(b"key1".to_vec(), "value3".to_owned())?;
+    assert_eq!(store.ttl(b"key1")?, None);
+
     Ok(())
 }
 
@@ -92,7 +522,7 @@ fn remove_key() -> Result<()> {
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new()
         .expect("unable to create temporary working directory")
-        .into_path();
+        .keep();
     let mut store = KvStore::open(temp_dir.clone())?;
 
     let dir_size = || {
@@ -109,7 +539,7 @@ fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
+            let key = format!("key{}", key_id).into_bytes();
             let value = format!("{}", iter);
             store.set(key, value)?;
         }
@@ -125,7 +555,7 @@ fn compaction() -> Result<()> {
         // reopen and check content
         let mut store = KvStore::open(temp_dir.clone())?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
+            let key = format!("key{}", key_id).into_bytes();
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
@@ -133,3 +563,2387 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compaction should save a bloom filter next to the log it writes, which
+// rules the log out for keys it doesn't hold, before and after a reopen, and
+// delete it along with the log
+#[test]
+fn segment_bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let key = |key_id: usize| format!("key{}", key_id).into_bytes();
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    for key_id in 0..1000 {
+        store.set(key(key_id), "value".to_owned())?;
+    }
+    assert!(store.compact()?);
+    assert!(path.join("2.bloom").exists());
+
+    let check_filter = |store: &KvStore| {
+        for key_id in 0..1000 {
+            assert!(store.candidate_logs(&key(key_id)).contains(&2));
+        }
+        let false_positives = (1000..2000)
+            .filter(|&key_id| store.candidate_logs(&key(key_id)).contains(&2))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    };
+    check_filter(&store);
+    // The active log has no filter
+    assert_eq!(store.candidate_logs(b"missing"), vec![3]);
+    drop(store);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    check_filter(&store);
+    assert!(store.compact()?);
+    assert!(!path.join("2.bloom").exists());
+    assert!(path.join("5.bloom").exists());
+
+    Ok(())
+}
+
+// A compaction budget should slow compaction down to the configured rate
+// without changing what it keeps.
+#[test]
+fn throttled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(256 * 1024),
+        compaction_bytes_per_sec: Some(1024 * 1024),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+
+    let value = |iter: usize| format!("{:0>1000}", iter);
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id).into_bytes(), value(0))?;
+    }
+
+    // About 500 KB of live data has to be copied at 1 MB/s once the
+    // overwrites cross the threshold
+    let started = std::time::Instant::now();
+    for key_id in 0..300 {
+        store.set(format!("key{}", key_id).into_bytes(), value(1))?;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    for key_id in 0..500 {
+        let expected = if key_id < 300 { value(1) } else { value(0) };
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(expected)
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should wait until stale bytes make up the configured share of
+// the logs, however many of them there are.
+#[test]
+fn stale_ratio_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_stale_ratio: 0.4,
+        compaction_min_stale: 64 * 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    let events = store.subscribe();
+    let compacted = || {
+        events
+            .try_iter()
+            .any(|event| matches!(event, StoreEvent::CompactionFinished(_)))
+    };
+
+    let value = |iter: usize| format!("{:0>1000}", iter);
+    for key_id in 0..400 {
+        store.set(format!("key{}", key_id).into_bytes(), value(0))?;
+    }
+
+    // About 100 KB stale against 500 KB of logs
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id).into_bytes(), value(1))?;
+    }
+    assert!(!compacted());
+
+    // 40% of the logs are stale once about 270 KB are
+    for key_id in 100..400 {
+        store.set(format!("key{}", key_id).into_bytes(), value(1))?;
+    }
+    assert!(compacted());
+    for key_id in 0..400 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(value(1))
+        );
+    }
+
+    Ok(())
+}
+
+// A range get should return just the bytes asked for, cut short at the end
+// of the value, from the log and from the cache alike
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let value = format!("header|{}é", "x".repeat(1000));
+    store.set(b"key".to_vec(), value.clone())?;
+
+    for _ in 0..2 {
+        assert_eq!(
+            store.get_range(b"key".to_vec(), 0, 6)?,
+            Some("header".to_owned())
+        );
+        assert_eq!(
+            store.get_range(b"key".to_vec(), 1006, 100)?,
+            Some("xé".to_owned())
+        );
+        assert_eq!(
+            store.get_range(b"key".to_vec(), 2000, 10)?,
+            Some(String::new())
+        );
+        // The last byte asked for is the first of the "é"
+        assert!(store
+            .get_range(b"key".to_vec(), 0, value.len() - 1)
+            .is_err());
+        // Read the whole value into the cache for the second round
+        store.get(b"key".to_vec())?;
+    }
+    assert_eq!(store.get_range(b"absent".to_vec(), 0, 6)?, None);
+
+    Ok(())
+}
+
+// A range of a large value should match the same range of the whole value,
+// escapes and multi-byte characters included, and one near the start should
+// read only that far into the record
+#[test]
+fn get_range_large_value() -> Result<()> {
+    for log_encoding in [LogEncoding::Compact, LogEncoding::Lines] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig {
+            log_encoding: Some(log_encoding),
+            ..KvStoreConfig::default()
+        };
+        let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+        let value = "head \"quoted\" \\ tab\t é 😀 \u{1}|".repeat(10_000);
+        store.set(b"key".to_vec(), value.clone())?;
+
+        // Each 32-byte repeat has its "é" at 22 and its "😀" at 25, so some of
+        // these split a character
+        let ranges = vec![
+            (0, 4),
+            (5, 10),
+            (13, 9),
+            (22, 1),
+            (23, 2),
+            (0, 26),
+            (25, 4),
+            (32 * 5000 + 13, 40),
+            (value.len() - 7, 100),
+        ];
+        for (offset, len) in ranges {
+            let range = store.get_range(b"key".to_vec(), offset, len);
+            match value.get(offset..(offset + len).min(value.len())) {
+                Some(expected) => assert_eq!(range?, Some(expected.to_owned()), "{}", offset),
+                None => assert!(range.is_err(), "{}", offset),
+            }
+        }
+        assert_eq!(
+            store.get_range(b"key".to_vec(), value.len() + 10, 10)?,
+            Some(String::new())
+        );
+
+        let before = store.metrics().bytes_read;
+        store.get_range(b"key".to_vec(), 0, 4)?;
+        assert!(store.metrics().bytes_read - before < 100);
+    }
+
+    Ok(())
+}
+
+// A compaction transform should rewrite or drop each live value as
+// compaction copies it, for reads straight after and after a reopen, and a
+// dropped key's old versions shouldn't come back as its value
+#[test]
+fn compaction_transform() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let config = KvStoreConfig {
+        keep_versions: Some(VersionRetention {
+            max_versions: Some(2),
+            max_age: None,
+        }),
+        compaction_transform: Some(CompactionTransform::new(|key, value| {
+            if key.starts_with(b"drop") {
+                None
+            } else {
+                Some(value.replace("v1:", "v2:"))
+            }
+        })),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(path.to_path_buf(), config.clone())?;
+    store.set(b"key".to_vec(), "v1:value".to_owned())?;
+    store.set(b"drop".to_vec(), "old".to_owned())?;
+    store.set(b"drop".to_vec(), "new".to_owned())?;
+    // Cache the value compaction is about to rewrite
+    assert_eq!(store.get(b"key".to_vec())?, Some("v1:value".to_owned()));
+
+    assert!(store.compact()?);
+    assert_eq!(store.get(b"key".to_vec())?, Some("v2:value".to_owned()));
+    assert_eq!(store.get(b"drop".to_vec())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some("v2:value".to_owned()));
+    assert_eq!(store.get(b"drop".to_vec())?, None);
+
+    Ok(())
+}
+
+// Outside its compaction windows a store should leave compaction to manual
+// calls, unless its load is light enough.
+#[test]
+fn compaction_windows() -> Result<()> {
+    let window: CompactionWindow = "22:30-02:00".parse()?;
+    let at = |hours: u64, minutes: u64| (hours * 60 + minutes) * 60_000 + 3 * 86_400_000;
+    assert!(window.contains(at(23, 0)));
+    assert!(window.contains(at(1, 59)));
+    assert!(!window.contains(at(2, 0)));
+    assert!(!window.contains(at(12, 0)));
+    assert!("24:00-01:00".parse::<CompactionWindow>().is_err());
+    assert!("02:00".parse::<CompactionWindow>().is_err());
+
+    // A window an hour from now, so it isn't open while the test runs
+    let minute = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+        % (24 * 60);
+    let later = |offset: u64| {
+        let minute = (minute + offset) % (24 * 60);
+        ((minute / 60) as u32, (minute % 60) as u32)
+    };
+    let closed = CompactionWindow::new(later(60), later(120))?;
+
+    let fill = |store: &mut KvStore| -> Result<bool> {
+        let events = store.subscribe();
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store.set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{:0>1000}", iter),
+                )?;
+            }
+        }
+        store.reap_expired()?;
+        Ok(events
+            .try_iter()
+            .any(|event| matches!(event, StoreEvent::CompactionFinished(_))))
+    };
+    let config = KvStoreConfig {
+        compaction_min_stale: 64 * 1024,
+        compaction_windows: vec![closed],
+        ..KvStoreConfig::default()
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    assert!(!fill(&mut store)?);
+    assert!(store.compact()?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_max_ops_per_sec: Some(1_000_000),
+        ..config
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert!(fill(&mut store)?);
+
+    Ok(())
+}
+
+// Reads of records from the logs should be counted, with their lengths and
+// the time spent on disk and decoding, and cache hits left out
+#[test]
+fn read_path_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id).into_bytes(), "x".repeat(100))?;
+    }
+    for key_id in 0..3 {
+        store.get(format!("key{}", key_id).into_bytes())?;
+    }
+    store.get(b"key0".to_vec())?;
+
+    let read_path = store.metrics().read_path.expect("kvs tracks its reads");
+    assert_eq!(read_path.reads, 3);
+    assert!(read_path.seeks >= 1);
+    assert!(read_path.record_bytes > 300);
+    assert_eq!(read_path.record_lens.buckets().len(), 1);
+    assert_eq!(read_path.record_lens.buckets()[0].count, 3);
+    assert!(read_path.disk_nanos_total > 0);
+    assert!(read_path.decode_nanos_total > 0);
+
+    let text = store.metrics().to_prometheus();
+    assert!(text.contains("# TYPE kvs_record_reads_total counter\nkvs_record_reads_total 3\n"));
+    assert!(text.contains("# TYPE kvs_record_read_bytes histogram\n"));
+    assert!(text.contains("kvs_record_read_bytes_bucket{le=\"1\"} 0\n"));
+    assert!(text.contains("kvs_record_read_bytes_bucket{le=\"+Inf\"} 3\n"));
+    assert!(text.contains("kvs_record_read_decode_seconds_count 3\n"));
+
+    Ok(())
+}
+
+// With a remote tier, keys the store doesn't have should be fetched from it
+// and kept, misses remembered for a while, and writes passed on to it with
+// their expiries, loaded and reaped keys included
+#[test]
+fn remote_tier() -> Result<()> {
+    type Remote = HashMap<Vec<u8>, (String, Option<u64>)>;
+    let remote: Arc<Mutex<Remote>> = Arc::new(Mutex::new(HashMap::new()));
+    remote
+        .lock()
+        .unwrap()
+        .insert(b"remote".to_vec(), ("value1".to_owned(), None));
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tier = {
+        let (fetch_from, store_to) = (remote.clone(), remote.clone());
+        let fetches = fetches.clone();
+        RemoteTier::new()
+            .read_through(move |key| {
+                fetches.fetch_add(1, AtomicOrdering::SeqCst);
+                let remote = fetch_from.lock().unwrap();
+                Ok(remote.get(key).map(|(value, _)| value.clone()))
+            })
+            .write_through(move |key, value, expires_at| {
+                if key == b"readonly" {
+                    return Err(KvStoreError::StringError("read-only key".to_owned()));
+                }
+                let mut remote = store_to.lock().unwrap();
+                match value {
+                    Some(value) => remote.insert(key.to_vec(), (value.to_owned(), expires_at)),
+                    None => remote.remove(key),
+                };
+                Ok(())
+            })
+            .negative_caching(NegativeCaching {
+                ttl: Duration::from_secs(60),
+                max_keys: 10,
+            })
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        remote: Some(tier),
+        reap_keys_per_sec: Some(1000),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+
+    // Fetched once, then served locally
+    assert_eq!(store.get(b"remote".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(store.get(b"remote".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(fetches.load(AtomicOrdering::SeqCst), 1);
+
+    // A miss is remembered until the key is written
+    assert_eq!(store.get(b"missing".to_vec())?, None);
+    assert_eq!(store.get(b"missing".to_vec())?, None);
+    assert_eq!(fetches.load(AtomicOrdering::SeqCst), 2);
+    store.set(b"missing".to_vec(), "value2".to_owned())?;
+    assert_eq!(
+        remote.lock().unwrap().get(&b"missing"[..]),
+        Some(&("value2".to_owned(), None))
+    );
+    assert_eq!(store.get(b"missing".to_vec())?, Some("value2".to_owned()));
+
+    store.remove(b"remote".to_vec())?;
+    assert!(!remote.lock().unwrap().contains_key(&b"remote"[..]));
+
+    // A write the remote refuses isn't applied locally either
+    assert!(store
+        .set(b"readonly".to_vec(), "value3".to_owned())
+        .is_err());
+    assert_eq!(store.get(b"readonly".to_vec())?, None);
+
+    let expiry = |remote: &Arc<Mutex<Remote>>, key: &[u8]| {
+        remote
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|(_, expires_at)| *expires_at)
+    };
+    store.set_with_ttl(
+        b"leased".to_vec(),
+        "value4".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert!(matches!(expiry(&remote, b"leased"), Some(Some(_))));
+    store.persist(b"leased".to_vec())?;
+    assert_eq!(expiry(&remote, b"leased"), Some(None));
+
+    store.bulk_load(vec![(b"loaded".to_vec(), "value5".to_owned())])?;
+    assert_eq!(expiry(&remote, b"loaded"), Some(None));
+    // A failed load leaves the store as it was, but the remote keeps what
+    // it took before the failure
+    assert!(store
+        .bulk_load(vec![
+            (b"partial".to_vec(), "value6".to_owned()),
+            (b"readonly".to_vec(), "value6".to_owned()),
+        ])
+        .is_err());
+    assert_eq!(expiry(&remote, b"partial"), Some(None));
+    assert!(store.scan(b"partial", None, 10)?.is_empty());
+    assert_eq!(expiry(&remote, b"readonly"), None);
+
+    store.set_with_ttl(
+        b"brief".to_vec(),
+        "value7".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert!(expiry(&remote, b"brief").is_some());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.reap_expired()?, 1);
+    assert_eq!(expiry(&remote, b"brief"), None);
+    drop(store);
+
+    // Fetched values persist in the store on their own
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get(b"missing".to_vec())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"remote".to_vec())?, None);
+
+    Ok(())
+}
+
+// A crash during compaction should leave the store with either the old logs
+// or the compacted ones: logs written but not committed are thrown away on
+// open, and a committed compaction is finished
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let log_gens = |dir: &Path| -> Vec<u64> {
+        let mut log_gens: Vec<u64> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        log_gens.sort_unstable();
+        log_gens
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let crashed = temp_dir.path().join("crashed");
+    let mut store = KvStore::open(crashed.clone())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key1".to_vec(), "value2".to_owned())?;
+    store.set(b"key2".to_vec(), "value3".to_owned())?;
+    store.remove(b"key2".to_vec())?;
+    drop(store);
+    let old_log_gens = log_gens(&crashed);
+
+    // Compact a copy of the store to get the compacted log
+    let compacted = temp_dir.path().join("compacted");
+    std::fs::create_dir(&compacted)?;
+    for entry in std::fs::read_dir(&crashed)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), compacted.join(entry.file_name()))?;
+    }
+    let mut store = KvStore::open(compacted.clone())?;
+    store.compact()?;
+    drop(store);
+    let compact_log_gen = log_gens(&compacted)
+        .into_iter()
+        .find(|log_gen| log_gen > old_log_gens.last().unwrap())
+        .unwrap();
+
+    // The crashed store got as far as writing the compacted log and
+    // committing it, and had started an uncommitted one besides
+    std::fs::copy(
+        compacted.join(format!("{}.log", compact_log_gen)),
+        crashed.join(format!("{}.log.tmp", compact_log_gen)),
+    )?;
+    std::fs::write(crashed.join("99.log.tmp"), "torn")?;
+    std::fs::write(crashed.join("99.bloom"), "torn")?;
+    let manifest_path = crashed.join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)?;
+    manifest["compaction"] = serde_json::json!({
+        "written": [compact_log_gen],
+        "retired": old_log_gens,
+    });
+    std::fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+
+    let mut store = KvStore::open(crashed.clone())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    let recovered_log_gens = log_gens(&crashed);
+    assert!(recovered_log_gens.contains(&compact_log_gen));
+    assert!(old_log_gens
+        .iter()
+        .all(|log_gen| !recovered_log_gens.contains(log_gen)));
+    assert!(!crashed.join("99.log.tmp").exists());
+    assert!(!crashed.join("99.bloom").exists());
+    assert!(!String::from_utf8_lossy(&std::fs::read(&manifest_path)?).contains("compaction"));
+
+    Ok(())
+}
+
+// A store with more logs than `max_open_logs` should keep only that many
+// files open, reopening the others as they are read
+#[test]
+fn max_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_open_logs: 2,
+        read_cache_bytes: 0,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    for key_id in 0..5 {
+        store.set(
+            format!("key{}", key_id).into_bytes(),
+            format!("value{}", key_id),
+        )?;
+        store.rotate_log()?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    for _ in 0..2 {
+        for key_id in 0..5 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes())?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    let metrics = store.metrics();
+    assert!(metrics.open_log_misses >= Some(5), "{:?}", metrics);
+
+    // Linux lists the process's open files under /proc
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        let open_logs = fds
+            .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+            .filter(|target| target.starts_with(temp_dir.path()))
+            .filter(|target| target.extension().is_some_and(|ext| ext == "log"))
+            .count();
+        // The readers, and the writer of the active log
+        assert!(open_logs <= 3, "{} logs open", open_logs);
+    }
+
+    Ok(())
+}
+
+// Logs should record when they were started, and once the oldest is past
+// `compact_after` a compaction should purge even a little stale data
+#[test]
+fn compact_after_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compact_after: Some(Duration::from_millis(500)),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    let events = store.subscribe();
+    let compacted = || {
+        events
+            .try_iter()
+            .any(|event| matches!(event, StoreEvent::CompactionFinished(_)))
+    };
+
+    let opened_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let started_at = store.log_started_at().clone();
+    assert_eq!(started_at.len(), 1);
+    assert!(started_at
+        .values()
+        .all(|&at| at.abs_diff(opened_at) < 5_000));
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    store.remove(b"key2".to_vec())?;
+    store.reap_expired()?;
+    assert!(!compacted());
+
+    thread::sleep(Duration::from_millis(600));
+    store.reap_expired()?;
+    assert!(compacted());
+    let compacted_at = store.log_started_at().clone();
+    assert!(compacted_at
+        .keys()
+        .all(|log_gen| !started_at.contains_key(log_gen)));
+
+    // Nothing is left to purge, however old the logs get
+    thread::sleep(Duration::from_millis(600));
+    store.reap_expired()?;
+    assert!(!compacted());
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    for (log_gen, at) in &compacted_at {
+        assert_eq!(store.log_started_at().get(log_gen), Some(at));
+    }
+
+    Ok(())
+}
+
+// Logs past the cold age should move to the cold directory, stay readable
+// there, and keep their live data there through compaction.
+#[test]
+fn cold_tier() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = |dir: &TempDir| {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let mut store = KvStore::open(hot_dir.path().to_path_buf())?;
+    for key_id in 0..100 {
+        store.set(format!("cold{}", key_id).into_bytes(), "old".to_owned())?;
+    }
+    drop(store);
+
+    let config = KvStoreConfig {
+        compaction_threshold: Some(64 * 1024),
+        cold_dir: Some(cold_dir.path().to_path_buf()),
+        cold_after: Duration::ZERO,
+        ..KvStoreConfig::default()
+    };
+    // Every sealed log is old enough with a zero age
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config.clone())?;
+    assert_eq!(log_count(&hot_dir), 1);
+    assert!(log_count(&cold_dir) >= 1);
+    assert_eq!(store.get(b"cold0".to_vec())?, Some("old".to_owned()));
+    drop(store);
+
+    // Overwrite hot keys until compaction runs, with logs now staying hot
+    let config = KvStoreConfig {
+        cold_after: Duration::from_secs(3600),
+        ..config
+    };
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(
+                format!("hot{}", key_id).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config)?;
+    for key_id in 0..100 {
+        let key = format!("cold{}", key_id).into_bytes();
+        assert_eq!(store.get(key)?, Some("old".to_owned()));
+    }
+    for key_id in 0..10 {
+        let key = format!("hot{}", key_id).into_bytes();
+        assert_eq!(store.get(key)?, Some(format!("{:0>100}", 99)));
+    }
+    // Compaction kept the cold keys out of the hot directory
+    assert!(log_count(&hot_dir) > 1);
+    let hot_logs = WalkDir::new(hot_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("log".as_ref()));
+    for path in hot_logs {
+        let contents = std::fs::read_to_string(path).expect("unable to read log");
+        assert!(!contents.contains("cold"));
+    }
+
+    Ok(())
+}
+
+// Compaction should keep a key's versions in order when they are spread
+// over hot and cold logs, with the newest still current after a reopen
+#[test]
+fn versions_across_tiers() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        keep_versions: Some(VersionRetention {
+            max_versions: Some(10),
+            max_age: None,
+        }),
+        cold_dir: Some(cold_dir.path().to_path_buf()),
+        cold_after: Duration::from_secs(3600),
+        ..KvStoreConfig::default()
+    };
+
+    // One version per log
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config.clone())?;
+    for i in 1..=3 {
+        store.set(b"key".to_vec(), format!("value{}", i))?;
+        store.rotate_log()?;
+    }
+    drop(store);
+
+    // Age the logs of the later versions so they move to the cold tier,
+    // leaving the first version hot
+    let two_hours_ago = SystemTime::now() - Duration::from_secs(7200);
+    for log_gen in [2, 3] {
+        let log = hot_dir.path().join(format!("{}.log", log_gen));
+        OpenOptions::new()
+            .write(true)
+            .open(log)?
+            .set_modified(two_hours_ago)?;
+    }
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config.clone())?;
+    assert!(cold_dir.path().join("3.log").exists());
+    assert!(hot_dir.path().join("1.log").exists());
+
+    let versions = store.versions(b"key");
+    assert_eq!(versions.len(), 3);
+    store.compact()?;
+    assert_eq!(store.versions(b"key"), versions);
+    assert_eq!(store.get(b"key".to_vec())?, Some("value3".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with_config(hot_dir.path().to_path_buf(), config)?;
+    assert_eq!(store.versions(b"key"), versions);
+    assert_eq!(store.get(b"key".to_vec())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get_version(b"key", versions[0])?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Preloading should count the keys it found, and cached values should never
+// outlive the writes and compactions that replace them.
+#[test]
+fn preload_read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    for key_id in 0..50 {
+        store.set(format!("hot{}", key_id).into_bytes(), "value".to_owned())?;
+    }
+    store.set(b"other".to_vec(), "value".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.preload_prefix(b"hot")?, 50);
+    assert_eq!(
+        store.preload(vec![b"other".to_vec(), b"missing".to_vec()])?,
+        1
+    );
+
+    store.set(b"hot0".to_vec(), "changed".to_owned())?;
+    store.remove(b"hot1".to_vec())?;
+    assert_eq!(store.get(b"hot0".to_vec())?, Some("changed".to_owned()));
+    assert_eq!(store.get(b"hot1".to_vec())?, None);
+
+    // Compaction moves the records the cached values were read from
+    for iter in 0..200 {
+        for key_id in 40..50 {
+            store.set(
+                format!("hot{}", key_id).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+    }
+    assert_eq!(store.get(b"hot2".to_vec())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get(b"hot49".to_vec())?,
+        Some(format!("{:0>100}", 199))
+    );
+    assert_eq!(store.scan(b"hot", None, 100)?.len(), 49);
+
+    Ok(())
+}
+
+// Short values should be served from memory from the moment they are
+// written, even with the read cache off, and never outlive their records.
+#[test]
+fn inline_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        read_cache_bytes: 0,
+        inline_value_len: 8,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    store.set(b"short".to_vec(), "value".to_owned())?;
+    store.set(b"long".to_vec(), "a longer value".to_owned())?;
+
+    assert_eq!(store.get(b"short".to_vec())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get(b"long".to_vec())?,
+        Some("a longer value".to_owned())
+    );
+    let metrics = store.metrics();
+    assert_eq!(metrics.cache_hits, Some(1));
+    assert_eq!(metrics.cache_misses, Some(1));
+
+    store.set(b"short".to_vec(), "changed".to_owned())?;
+    assert_eq!(store.get(b"short".to_vec())?, Some("changed".to_owned()));
+    store.remove(b"short".to_vec())?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert_eq!(store.metrics().cache_hits, Some(2));
+
+    // Compaction moves the records the inline values were written to
+    store.set(b"short".to_vec(), "value".to_owned())?;
+    store.set(b"long".to_vec(), "replaced".to_owned())?;
+    assert!(store.compact()?);
+    assert_eq!(store.get(b"short".to_vec())?, Some("value".to_owned()));
+    assert_eq!(store.metrics().cache_hits, Some(3));
+    drop(store);
+
+    // After a reopen each value is kept from its first read
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert_eq!(store.get(b"short".to_vec())?, Some("value".to_owned()));
+    assert_eq!(store.get(b"short".to_vec())?, Some("value".to_owned()));
+    let metrics = store.metrics();
+    assert_eq!(metrics.cache_hits, Some(1));
+    assert_eq!(metrics.cache_misses, Some(1));
+
+    Ok(())
+}
+
+// A keydir entry that no longer matches its log should be repointed at the
+// key's record by rescanning the log, or dropped if the log has none.
+#[test]
+fn reindex_on_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        read_cache_bytes: 0,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    store.set(b"key3".to_vec(), "value3".to_owned())?;
+    store.flush()?;
+
+    let logs: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    // Shift every record along, as if the log were rewritten under the store
+    for log in &logs {
+        let mut shifted = b"          ".to_vec();
+        shifted.extend(std::fs::read(log)?);
+        std::fs::write(log, shifted)?;
+    }
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_json(b"key2".to_vec())?,
+        Some(b"\"value2\"".to_vec())
+    );
+    assert_eq!(store.scan(b"key", None, 10)?.len(), 3);
+
+    // With the records gone, the key can't be read and is dropped
+    for log in &logs {
+        let len = std::fs::metadata(log)?.len() as usize;
+        std::fs::write(log, " ".repeat(len))?;
+    }
+    assert!(store.get(b"key3".to_vec()).is_err());
+    assert_eq!(store.get(b"key3".to_vec())?, None);
+
+    Ok(())
+}
+
+// With soft deletes on, a removed key should be restorable across a reopen,
+// but not after the retention window or a compaction.
+#[test]
+fn soft_delete_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        soft_delete_retention: Some(Duration::from_secs(3600)),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    store.remove(b"key1".to_vec())?;
+    store.remove(b"key2".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+
+    store.restore(b"key1".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    assert!(store.restore(b"key1".to_vec()).is_err());
+    assert!(store.restore(b"missing".to_vec()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    store.restore(b"key2".to_vec())?;
+    assert_eq!(store.get(b"key2".to_vec())?, Some("value2".to_owned()));
+
+    // Compaction discards the removed values
+    store.remove(b"key2".to_vec())?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id + 10).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+    }
+    assert!(store.restore(b"key2".to_vec()).is_err());
+    drop(store);
+
+    let config = KvStoreConfig {
+        soft_delete_retention: Some(Duration::ZERO),
+        ..config
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    store.remove(b"key1".to_vec())?;
+    assert!(store.restore(b"key1".to_vec()).is_err());
+
+    // Without soft deletes nothing is restorable
+    let mut store = KvStore::open(TempDir::new().unwrap().path().to_path_buf())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.remove(b"key1".to_vec())?;
+    assert!(store.restore(b"key1".to_vec()).is_err());
+
+    Ok(())
+}
+
+// Bulk-loaded records should be readable at once and after a reopen, replace
+// existing values, and be replaced by later writes.
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set(b"key00001".to_vec(), "old".to_owned())?;
+    store.set(b"other".to_vec(), "value".to_owned())?;
+
+    let before = store.metrics();
+    let records =
+        (0..10_000).map(|id| (format!("key{:05}", id).into_bytes(), format!("value{}", id)));
+    assert_eq!(store.bulk_load(records)?, 10_000);
+    let metrics = store.metrics();
+    assert_eq!(metrics.writes - before.writes, 10_000);
+    assert!(metrics.bytes_written - before.bytes_written > 10_000 * 14);
+    assert_eq!(metrics.key_lens.max(), Some(8));
+    let loaded_values: u64 = metrics
+        .value_lens
+        .buckets()
+        .iter()
+        .map(|bucket| bucket.count)
+        .sum();
+    assert_eq!(loaded_values, 10_002);
+    assert_eq!(store.get(b"key00001".to_vec())?, Some("value1".to_owned()));
+    assert_eq!(store.get(b"other".to_vec())?, Some("value".to_owned()));
+
+    store.set(b"key00002".to_vec(), "new".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get(b"key00002".to_vec())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get(b"key09999".to_vec())?,
+        Some("value9999".to_owned())
+    );
+    assert_eq!(store.scan(b"key", None, 20_000)?.len(), 10_000);
+
+    Ok(())
+}
+
+// Writes of an empty or overlong key should fail without touching the store.
+#[test]
+fn key_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+
+    assert!(matches!(
+        store.set(Vec::new(), "value".to_owned()),
+        Err(KvStoreError::EmptyKey)
+    ));
+    assert!(matches!(
+        store.set_nx(Vec::new(), "value".to_owned()),
+        Err(KvStoreError::EmptyKey)
+    ));
+    assert!(matches!(
+        store.set(vec![b'k'; MAX_KEY_LEN + 1], "value".to_owned()),
+        Err(KvStoreError::KeyTooLong(len)) if len == MAX_KEY_LEN + 1
+    ));
+    store.set(vec![b'k'; MAX_KEY_LEN], "value".to_owned())?;
+
+    let records = vec![
+        (b"key1".to_vec(), "value1".to_owned()),
+        (Vec::new(), "value".to_owned()),
+    ];
+    assert!(matches!(
+        store.bulk_load(records),
+        Err(KvStoreError::EmptyKey)
+    ));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.scan(&[], None, 10)?.len(), 1);
+
+    Ok(())
+}
+
+// Writes should stall once stale data passes the limit and compaction can't
+// reclaim it, and resume once it can.
+#[test]
+fn write_stall() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_min_logs: usize::MAX,
+        stall_stale_bytes: Some(1000),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    // Compaction writes the next log generation; a directory in its place
+    // makes it fail
+    let blocker = temp_dir.path().join("2.log");
+    std::fs::create_dir(&blocker)?;
+
+    let mut stalled = None;
+    for i in 0..100 {
+        match store.set(b"key".to_vec(), format!("value{:0>50}", i)) {
+            Ok(()) => {}
+            Err(KvStoreError::WriteStalled(retry_after)) => {
+                stalled = Some(retry_after);
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let retry_after = stalled.expect("writes never stalled");
+    assert!(store.write_stall().is_some());
+    assert!(matches!(
+        store.remove(b"key".to_vec()),
+        Err(KvStoreError::WriteStalled(_))
+    ));
+    // Reads carry on
+    assert!(store.get(b"key".to_vec())?.is_some());
+
+    std::fs::remove_dir(&blocker)?;
+    thread::sleep(retry_after);
+    assert_eq!(store.write_stall(), None);
+    store.set(b"key".to_vec(), "value".to_owned())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// get_json should return the value as a JSON string, whether it is decoded or
+// copied from the log still encoded.
+#[test]
+fn get_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+
+    let small = "\"quoted\" \\ ключ\t🔑".to_owned();
+    let large = small.repeat(8 * 1024);
+    store.set(b"small".to_vec(), small.clone())?;
+    store.set(b"large".to_vec(), large.clone())?;
+
+    for (key, value) in [(b"small", &small), (b"large", &large)] {
+        let json = store.get_json(key.to_vec())?.expect("value is set");
+        assert_eq!(serde_json::from_slice::<String>(&json).unwrap(), *value);
+    }
+    assert_eq!(store.get_json(b"missing".to_vec())?, None);
+
+    // Also once the large value has been read into the cache
+    assert_eq!(store.get(b"large".to_vec())?, Some(large.clone()));
+    let json = store.get_json(b"large".to_vec())?.expect("value is set");
+    assert_eq!(serde_json::from_slice::<String>(&json).unwrap(), large);
+
+    Ok(())
+}
+
+// Subscribers should see each compaction start and finish, with the logs it
+// created and deleted in between.
+#[test]
+fn compaction_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let events = store.subscribe();
+
+    let mut received = Vec::new();
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+        received.extend(events.try_iter());
+        if !received.is_empty() {
+            break;
+        }
+    }
+
+    assert!(
+        matches!(received[0], StoreEvent::CompactionStarted { stale_bytes } if stale_bytes > 1024 * 1024)
+    );
+    assert_eq!(
+        received
+            .iter()
+            .filter(|event| matches!(event, StoreEvent::SegmentCreated { .. }))
+            .count(),
+        2
+    );
+    assert!(received
+        .iter()
+        .any(|event| matches!(event, StoreEvent::SegmentDeleted { .. })));
+    match received.last() {
+        Some(StoreEvent::CompactionFinished(stats)) => {
+            assert_eq!(stats.keys, 100);
+            assert!(stats.bytes_written > 100 * 100);
+            assert!(stats.input_bytes > stats.stale_bytes);
+            assert!(stats.write_amplification() < 0.1);
+            assert_eq!(store.compaction_history(), vec![stats.clone()]);
+        }
+        event => panic!("Expected compaction to finish, got {:?}", event),
+    }
+
+    // A dropped receiver doesn't stop the store
+    drop(events);
+    store.bulk_load(vec![(b"key".to_vec(), "value".to_owned())])?;
+
+    Ok(())
+}
+
+// The store should keep the stats of only its latest compactions, oldest
+// first.
+#[test]
+fn compaction_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_history_len: 2,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert!(store.compaction_history().is_empty());
+
+    for keys in 1..=3 {
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id).into_bytes(), "value".to_owned())?;
+            store.set(format!("key{}", key_id).into_bytes(), "value".to_owned())?;
+        }
+        store.compact()?;
+    }
+
+    let history = store.compaction_history();
+    assert_eq!(
+        history.iter().map(|stats| stats.keys).collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+    for stats in &history {
+        assert!(stats.input_bytes > stats.bytes_written);
+        assert!(stats.write_amplification() > 0.0);
+    }
+    assert!(history[0].finished_at_ms <= history[1].finished_at_ms);
+
+    Ok(())
+}
+
+// `log_inspect` should read every record of a store's logs as stored, batch
+// headers and overwritten values included, and flag the ones that don't
+// decode or authenticate.
+#[test]
+fn log_inspect() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let mut store = KvStore::open(path.to_path_buf())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key1".to_vec(), "value2".to_owned())?;
+    store.remove(b"key1".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), "value3".to_owned())
+        .set(b"key3".to_vec(), "value4".to_owned());
+    store.apply(batch)?;
+    drop(store);
+
+    // Tear the last record, as a crash mid-write would
+    let records = log_inspect::open(path, None, None)?.collect::<Result<Vec<_>>>()?;
+    let log = path.join(format!("{}.log", records[0].log_gen));
+    let file = OpenOptions::new().write(true).open(&log)?;
+    let len = file.metadata()?.len() - 3;
+    file.set_len(len)?;
+    drop(file);
+
+    let records = log_inspect::open(path, None, None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 6);
+    assert!(matches!(
+        &records[0].command,
+        Some(Command::Set { key, value, .. }) if key == b"key1" && value == "value1"
+    ));
+    assert!(matches!(
+        &records[1].command,
+        Some(Command::Set { value, .. }) if value == "value2"
+    ));
+    assert!(matches!(
+        &records[2].command,
+        Some(Command::Remove { key, .. }) if key == b"key1"
+    ));
+    assert!(matches!(
+        &records[3].command,
+        Some(Command::Batch { ops: 2 })
+    ));
+    assert!(matches!(
+        &records[4].command,
+        Some(Command::Set { key, .. }) if key == b"key2"
+    ));
+    assert!(records[..5].iter().all(|record| record.checksum_ok));
+    assert_eq!(records[5].command, None);
+    assert!(!records[5].checksum_ok);
+    for pair in records.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+    }
+    assert_eq!(records[5].offset + records[5].len, len);
+
+    Ok(())
+}
+
+// `log_inspect` should need a sealed store's keys, and flag its records as
+// bad when given the wrong ones.
+#[cfg(feature = "encryption")]
+#[test]
+fn log_inspect_sealed() -> Result<()> {
+    use kvs::log_inspect::RawRecord;
+    use kvs::Keyring;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let config = KvStoreConfig {
+        encryption: Some(Keyring::new(1, [7; 32])),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(path.to_path_buf(), config)?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        log_inspect::open(path, None, None),
+        Err(KvStoreError::Encryption(_))
+    ));
+    let records = log_inspect::open(path, None, Some(&Keyring::new(1, [7; 32])))?
+        .collect::<Result<Vec<_>>>()?;
+    assert!(matches!(
+        &records[..],
+        [RawRecord {
+            command: Some(Command::Set { .. }),
+            checksum_ok: true,
+            ..
+        }]
+    ));
+    let records = log_inspect::open(path, None, Some(&Keyring::new(1, [8; 32])))?
+        .collect::<Result<Vec<_>>>()?;
+    assert!(matches!(
+        &records[..],
+        [RawRecord {
+            command: None,
+            checksum_ok: false,
+            ..
+        }]
+    ));
+
+    Ok(())
+}
+
+// Opening from a saved keydir index should replay the writes made after it,
+// and fall back to the logs once compaction has made the index stale.
+#[test]
+fn keydir_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        index_interval: Some(4 * 1024),
+        soft_delete_retention: Some(Duration::from_secs(3600)),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id).into_bytes(), "value".to_owned())?;
+    }
+    store.remove(b"key0".to_vec())?;
+    store.set(b"key1".to_vec(), "changed".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("keydir.index").exists());
+
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    assert_eq!(store.get(b"key0".to_vec())?, None);
+    assert_eq!(store.get(b"key1".to_vec())?, Some("changed".to_owned()));
+    assert_eq!(store.get(b"key999".to_vec())?, Some("value".to_owned()));
+    assert_eq!(store.scan(b"key", None, 2000)?.len(), 999);
+    store.restore(b"key0".to_vec())?;
+    drop(store);
+
+    // Saved by hand, then compacted without saving again
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.save_index()?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get(b"key0".to_vec())?, Some(format!("{:0>100}", 199)));
+    assert_eq!(store.get(b"key999".to_vec())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A standby should follow flushed writes, removes and compactions of the
+// primary, and take over with the same data when promoted.
+#[test]
+fn standby_follows_primary() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    store.flush()?;
+
+    // Poll manually so the test doesn't depend on timing
+    let mut standby = KvStoreStandby::open(temp_dir.clone(), Duration::from_secs(3600))?;
+    assert_eq!(standby.get(b"key1".to_vec())?, Some("value1".to_owned()));
+
+    store.set(b"key3".to_vec(), "value3".to_owned())?;
+    store.remove(b"key1".to_vec())?;
+    store.flush()?;
+    standby.catch_up()?;
+    assert_eq!(standby.get(b"key1".to_vec())?, None);
+    assert_eq!(standby.get(b"key3".to_vec())?, Some("value3".to_owned()));
+
+    // Overwrite until the primary compacts away its first log
+    let mut iter = 0;
+    while temp_dir.join("1.log").exists() {
+        iter += 1;
+        store.set(format!("key{}", iter % 100).into_bytes(), iter.to_string())?;
+    }
+    store.remove(b"key2".to_vec())?;
+    store.flush()?;
+    standby.catch_up()?;
+    assert_eq!(standby.get(b"key2".to_vec())?, None);
+    assert_eq!(standby.scan(b"key", None, 1000)?.len(), 99);
+
+    drop(store);
+    let mut store = standby.promote()?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(
+        store.get(format!("key{}", iter % 100).into_bytes())?,
+        Some(iter.to_string())
+    );
+    store.set(b"key1".to_vec(), "value4".to_owned())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A standby should report a write's position as applied only once it can
+// read that write
+#[test]
+fn standby_session_position() -> Result<()> {
+    let temp_dir = TempDir::new()
+        .expect("unable to create temporary working directory")
+        .keep();
+    let mut store = KvStore::open(temp_dir.clone())?;
+    let mut standby = KvStoreStandby::open(temp_dir, Duration::from_secs(60))?;
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    let position = store.position().expect("kvs engine hands out positions");
+    assert!(store.has_applied(position)?);
+
+    // Not flushed yet, so the standby can't have seen it
+    assert!(!standby.has_applied(position)?);
+
+    store.flush()?;
+    assert!(standby.has_applied(position)?);
+    assert_eq!(standby.get(b"key1".to_vec())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Every mutation should take the next sequence number, which the store's
+// positions report, and numbering should carry on across reopens, with or
+// without an index, and compactions that drop the latest records
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.latest_sequence(), 0);
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    assert_eq!(store.latest_sequence(), 2);
+    store.remove(b"key2".to_vec())?;
+    assert_eq!(store.latest_sequence(), 3);
+    // A failed remove writes nothing
+    assert!(store.remove(b"key2".to_vec()).is_err());
+    assert_eq!(store.latest_sequence(), 3);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key3".to_vec(), "value3".to_owned())
+        .remove(b"key1".to_vec());
+    store.apply(batch)?;
+    assert_eq!(store.latest_sequence(), 5);
+    store.bulk_load([(b"key4".to_vec(), "value4".to_owned())])?;
+    assert_eq!(store.latest_sequence(), 6);
+    let position = store.position().expect("kvs engine hands out positions");
+    assert_eq!(position.sequence(), Some(6));
+    drop(store);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.latest_sequence(), 6);
+    store.set(b"key5".to_vec(), "value5".to_owned())?;
+    assert_eq!(store.latest_sequence(), 7);
+    store.save_index()?;
+    drop(store);
+
+    // The index covers every write, so none are replayed
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.latest_sequence(), 7);
+
+    // The latest mutation is a remove, which compaction drops
+    store.remove(b"key5".to_vec())?;
+    assert!(store.compact()?);
+    assert_eq!(store.latest_sequence(), 8);
+    store.save_index()?;
+    drop(store);
+    fs::remove_file(path.join("keydir.index"))?;
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.latest_sequence(), 8);
+    store.set(b"key6".to_vec(), "value6".to_owned())?;
+    store.flush()?;
+    assert_eq!(store.latest_sequence(), 9);
+
+    // A promoted standby carries on from the records it followed
+    let standby = KvStoreStandby::open(path.to_path_buf(), Duration::from_secs(60))?;
+    drop(store);
+    let mut store = standby.promote()?;
+    store.set(b"key7".to_vec(), "value7".to_owned())?;
+    assert_eq!(store.latest_sequence(), 10);
+
+    Ok(())
+}
+
+// A snapshot view should keep returning the data as of when it was taken,
+// through later writes and compactions
+#[test]
+fn snapshot_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.keep())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id).into_bytes(), "before".to_owned())?;
+    }
+    let mut snapshot: KvStoreSnapshot = store.snapshot_view()?;
+
+    store.remove(b"key0".to_vec())?;
+    store.set(b"new".to_vec(), "value".to_owned())?;
+    // Enough overwrites to compact away the logs the snapshot points into
+    for iter in 0..200 {
+        for key_id in 1..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter),
+            )?;
+        }
+    }
+
+    assert_eq!(snapshot.get(b"key0".to_vec())?, Some("before".to_owned()));
+    assert_eq!(snapshot.get(b"key1".to_vec())?, Some("before".to_owned()));
+    assert_eq!(snapshot.get(b"new".to_vec())?, None);
+    let entries = snapshot.scan(b"key", None, 1000)?;
+    assert_eq!(entries.len(), 100);
+    assert!(entries.iter().all(|(_, value)| value == "before"));
+
+    assert_eq!(store.get(b"key0".to_vec())?, None);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(format!("{:0>100}", 199)));
+
+    Ok(())
+}
+
+// A snapshot view should open no more than `max_open_logs` files of its
+// own, and still read the logs compaction deletes after it was taken
+#[test]
+fn snapshot_view_max_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_open_logs: 2,
+        read_cache_bytes: 0,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    for key_id in 0..8 {
+        store.set(
+            format!("key{}", key_id).into_bytes(),
+            format!("value{}", key_id),
+        )?;
+        store.rotate_log()?;
+    }
+
+    let mut snapshot = store.snapshot_view()?;
+    for key_id in 0..8 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    // Linux lists the process's open files under /proc
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        let open_logs = fds
+            .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+            .filter(|target| target.starts_with(temp_dir.path()))
+            .filter(|target| target.extension().is_some_and(|ext| ext == "log"))
+            .count();
+        // The store's readers and writer, and the snapshot's readers
+        assert!(open_logs <= 5, "{} logs open", open_logs);
+    }
+
+    for key_id in 0..8 {
+        store.set(format!("key{}", key_id).into_bytes(), "after".to_owned())?;
+    }
+    store.compact()?;
+    for key_id in 0..8 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Queue items should come out in order, be handed out again once their
+// visibility timeout passes without an ack, and survive a reopen
+#[test]
+fn queue() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let jobs = Queue::new("jobs");
+    let visibility = Duration::from_millis(300);
+
+    assert_eq!(jobs.dequeue(&mut store, visibility)?, None);
+    assert_eq!(jobs.enqueue(&mut store, "first".to_owned())?, 0);
+    assert_eq!(jobs.enqueue(&mut store, "second".to_owned())?, 1);
+    // Other queues are kept apart
+    Queue::new("other").enqueue(&mut store, "other".to_owned())?;
+
+    let first = jobs.dequeue(&mut store, visibility)?.unwrap();
+    assert_eq!(
+        (first.id, first.item.as_str(), first.deliveries),
+        (0, "first", 1)
+    );
+    let second = jobs.dequeue(&mut store, visibility)?.unwrap();
+    assert_eq!((second.id, second.item.as_str()), (1, "second"));
+    assert_eq!(jobs.dequeue(&mut store, visibility)?, None);
+
+    jobs.ack(&mut store, second.id)?;
+    thread::sleep(Duration::from_millis(400));
+    let redelivered = jobs.dequeue(&mut store, visibility)?.unwrap();
+    assert_eq!((redelivered.id, redelivered.deliveries), (0, 2));
+    assert_eq!(jobs.dequeue(&mut store, visibility)?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(jobs.enqueue(&mut store, "third".to_owned())?, 2);
+    thread::sleep(Duration::from_millis(400));
+    jobs.ack(&mut store, 2)?;
+    let redelivered = jobs.dequeue(&mut store, visibility)?.unwrap();
+    assert_eq!((redelivered.id, redelivered.deliveries), (0, 3));
+    jobs.ack(&mut store, 0)?;
+    assert_eq!(jobs.dequeue(&mut store, visibility)?, None);
+
+    Ok(())
+}
+
+// A lock should have one holder at a time, free up when its lease expires or
+// is released, and hand out a larger fencing token on every acquire
+#[test]
+fn lock_leases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let lock = Lock::new("leader");
+    let ttl = Duration::from_millis(300);
+
+    let first = lock.acquire(&mut store, ttl)?.unwrap();
+    assert_eq!(lock.acquire(&mut store, ttl)?, None);
+    // Other locks are independent
+    assert!(Lock::new("other").acquire(&mut store, ttl)?.is_some());
+
+    thread::sleep(Duration::from_millis(200));
+    lock.renew(&mut store, first, ttl)?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(lock.acquire(&mut store, ttl)?, None);
+
+    thread::sleep(Duration::from_millis(200));
+    let second = lock.acquire(&mut store, ttl)?.unwrap();
+    assert!(second > first);
+    assert!(lock.renew(&mut store, first, ttl).is_err());
+    assert!(lock.release(&mut store, first).is_err());
+
+    lock.release(&mut store, second)?;
+    assert!(lock.release(&mut store, second).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let third = lock.acquire(&mut store, ttl)?.unwrap();
+    assert!(third > second);
+
+    Ok(())
+}
+
+// Queue and lock keys should sit under the reserved prefix, unaffected by
+// look-alike keys outside it, and a lock should refuse to wrap its fence
+#[test]
+fn queue_and_lock_keys_reserved() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let look_alikes = [
+        b"\0queue\0jobs\0item\x0000000000000000000000".to_vec(),
+        b"\0queue\0jobs\0tail".to_vec(),
+        b"\0lock\0leader\0fence".to_vec(),
+    ];
+    store.set(
+        look_alikes[0].clone(),
+        r#"{"item":"fake","visible_at":0,"deliveries":0}"#.to_owned(),
+    )?;
+    store.set(look_alikes[1].clone(), "5".to_owned())?;
+    store.set(look_alikes[2].clone(), u64::MAX.to_string())?;
+
+    let jobs = Queue::new("jobs");
+    assert_eq!(jobs.enqueue(&mut store, "new".to_owned())?, 0);
+    let item = jobs.dequeue(&mut store, Duration::from_secs(60))?.unwrap();
+    assert_eq!((item.id, item.item.as_str()), (0, "new"));
+    let leader = Lock::new("leader");
+    assert_eq!(
+        leader.acquire(&mut store, Duration::from_secs(60))?,
+        Some(1)
+    );
+
+    for (key, _) in store.scan(b"", None, 100)? {
+        assert!(
+            key.starts_with(RESERVED_KEY_PREFIX) || look_alikes.contains(&key),
+            "{:?}",
+            key
+        );
+    }
+
+    let mut fence_key = RESERVED_KEY_PREFIX.to_vec();
+    fence_key.extend_from_slice(b"\0lock\0follower\0fence");
+    store.set(fence_key.clone(), u64::MAX.to_string())?;
+    let follower = Lock::new("follower");
+    assert!(follower
+        .acquire(&mut store, Duration::from_secs(60))
+        .is_err());
+    assert_eq!(store.get(fence_key)?, Some(u64::MAX.to_string()));
+
+    Ok(())
+}
+
+// A transaction should run its success branch only when every condition
+// holds, and its failure branch otherwise, as one unit
+#[test]
+fn txn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set(b"owner".to_vec(), "a".to_owned())?;
+
+    let claim = |owner: &str| Txn {
+        compare: vec![
+            Compare::Value {
+                key: b"owner".to_vec(),
+                value: "a".to_owned(),
+            },
+            Compare::Absent {
+                key: b"claimed".to_vec(),
+            },
+        ],
+        success: vec![
+            TxnOp::Set {
+                key: b"claimed".to_vec(),
+                value: owner.to_owned(),
+            },
+            TxnOp::Remove {
+                key: b"owner".to_vec(),
+            },
+        ],
+        failure: vec![TxnOp::Get {
+            key: b"claimed".to_vec(),
+        }],
+    };
+
+    let response = claim("b").apply(&mut store)?;
+    assert!(response.succeeded);
+    assert_eq!(
+        response.results,
+        vec![TxnResult::Set, TxnResult::Remove(true)]
+    );
+    assert_eq!(store.get(b"owner".to_vec())?, None);
+
+    let response = claim("c").apply(&mut store)?;
+    assert!(!response.succeeded);
+    assert_eq!(response.results, vec![TxnResult::Get(Some("b".to_owned()))]);
+    assert_eq!(store.get(b"claimed".to_vec())?, Some("b".to_owned()));
+
+    // Reads see the branch's own writes, and a bad key anywhere in it
+    // leaves every write undone
+    let staged = |last_key: Vec<u8>| Txn {
+        compare: Vec::new(),
+        success: vec![
+            TxnOp::Set {
+                key: b"step".to_vec(),
+                value: "1".to_owned(),
+            },
+            TxnOp::Get {
+                key: b"step".to_vec(),
+            },
+            TxnOp::Remove {
+                key: b"claimed".to_vec(),
+            },
+            TxnOp::Remove { key: last_key },
+        ],
+        failure: Vec::new(),
+    };
+    assert!(staged(Vec::new()).apply(&mut store).is_err());
+    assert_eq!(store.get(b"step".to_vec())?, None);
+    assert_eq!(store.get(b"claimed".to_vec())?, Some("b".to_owned()));
+
+    let response = staged(b"claimed".to_vec()).apply(&mut store)?;
+    assert_eq!(
+        response.results,
+        vec![
+            TxnResult::Set,
+            TxnResult::Get(Some("1".to_owned())),
+            TxnResult::Remove(true),
+            TxnResult::Remove(false),
+        ]
+    );
+    assert_eq!(store.get(b"claimed".to_vec())?, None);
+
+    Ok(())
+}
+
+// A write batch should apply all of its writes, keep them across a reopen,
+// and leave nothing behind if a crash tears it or one of its keys is invalid
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let mut store = KvStore::open(path.to_path_buf())?;
+    store.set(b"from".to_vec(), "10".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"to".to_vec(), "5".to_owned())
+        .remove(b"from".to_vec())
+        .remove(b"absent".to_vec())
+        .set(b"to".to_vec(), "10".to_owned());
+    assert_eq!(batch.len(), 4);
+    store.apply(batch)?;
+    assert_eq!(store.get(b"to".to_vec())?, Some("10".to_owned()));
+    assert_eq!(store.get(b"from".to_vec())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"a".to_vec(), "1".to_owned())
+        .set(Vec::new(), "2".to_owned());
+    assert!(matches!(store.apply(batch), Err(KvStoreError::EmptyKey)));
+    assert_eq!(store.get(b"a".to_vec())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.get(b"to".to_vec())?, Some("10".to_owned()));
+    assert_eq!(store.get(b"from".to_vec())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"a".to_vec(), "1".to_owned())
+        .set(b"b".to_vec(), "2".to_owned());
+    store.apply(batch)?;
+    drop(store);
+
+    // Tear the batch's last record, as a crash mid-write would
+    let log = OpenOptions::new().write(true).open(path.join("2.log"))?;
+    log.set_len(log.metadata()?.len() - 3)?;
+    drop(log);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.get(b"a".to_vec())?, None);
+    assert_eq!(store.get(b"b".to_vec())?, None);
+    assert_eq!(store.get(b"to".to_vec())?, Some("10".to_owned()));
+
+    Ok(())
+}
+
+// The store should count its reads, writes and cache use
+#[test]
+fn metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    store.remove(b"key2".to_vec())?;
+    store.get(b"key1".to_vec())?;
+    store.get(b"key1".to_vec())?;
+    store.get(b"key2".to_vec())?;
+    store.flush()?;
+
+    let metrics = store.metrics();
+    assert_eq!(metrics.writes, 3);
+    assert_eq!(metrics.reads, 3);
+    assert_eq!(metrics.flushes, 1);
+    assert_eq!(metrics.cache_hits, Some(1));
+    assert_eq!(metrics.cache_misses, Some(1));
+    assert!(metrics.bytes_read > 0);
+    assert!(metrics.bytes_written > metrics.bytes_read);
+
+    store.set(b"key3".to_vec(), "x".repeat(100_000))?;
+    let metrics = store.metrics();
+    let counts = |buckets: Vec<Bucket>| -> Vec<(u64, u64)> {
+        buckets
+            .into_iter()
+            .map(|bucket| (bucket.up_to, bucket.count))
+            .collect()
+    };
+    assert_eq!(counts(metrics.key_lens.buckets()), [(4, 3)]);
+    assert_eq!(
+        counts(metrics.value_lens.buckets()),
+        [(8, 2), (128 * 1024, 1)]
+    );
+    assert_eq!(metrics.value_lens.max(), Some(128 * 1024));
+
+    Ok(())
+}
+
+// The memory estimate should grow with the keys and cached values held
+#[test]
+fn memory_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+
+    let empty = store.memory_usage();
+    assert_eq!(empty.keys, 0);
+    assert_eq!(empty.keydir_bytes, 0);
+    assert_eq!(empty.cache_bytes, 0);
+
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i).into_bytes(), "value".to_owned())?;
+    }
+    let written = store.memory_usage();
+    assert_eq!(written.keys, 1000);
+    // At least the keys themselves and a pointer to each
+    assert!(written.keydir_bytes >= 1000 * (7 + 16));
+    assert_eq!(written.cache_bytes, 0);
+
+    store.get(b"key0001".to_vec())?;
+    let read = store.memory_usage();
+    assert!(read.cache_bytes >= ("key0001".len() + "value".len()) as u64);
+    assert_eq!(store.metrics().memory, Some(read));
+
+    store.remove(b"key0001".to_vec())?;
+    assert_eq!(store.memory_usage().keys, 999);
+
+    Ok(())
+}
+
+// Sampling should cover every key when asked for more samples than there are
+// keys, and group them by prefix
+#[test]
+fn analyze_sample() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    for key_id in 0..30 {
+        store.set(format!("user:{}", key_id).into_bytes(), "x".repeat(100))?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("session:{}", key_id).into_bytes(), "x".repeat(1000))?;
+    }
+
+    let sample = analyze(&mut store, 1000, b':')?;
+    assert_eq!((sample.keys, sample.sampled), (40, 40));
+    assert_eq!(
+        sample
+            .key_lens
+            .iter()
+            .map(|bucket| bucket.count)
+            .sum::<u64>(),
+        40
+    );
+    assert_eq!(sample.value_lens.len(), 2);
+    assert_eq!(
+        (sample.value_lens[0].up_to, sample.value_lens[0].count),
+        (128, 30)
+    );
+    assert_eq!(
+        (sample.value_lens[1].up_to, sample.value_lens[1].count),
+        (1024, 10)
+    );
+    assert_eq!(sample.top_prefixes_by_keys[0].prefix, b"user:".to_vec());
+    assert_eq!(sample.top_prefixes_by_keys[0].keys, 30);
+    assert_eq!(sample.top_prefixes_by_bytes[0].prefix, b"session:".to_vec());
+
+    let sample = analyze(&mut store, 5, b':')?;
+    assert_eq!((sample.keys, sample.sampled), (40, 5));
+
+    Ok(())
+}
+
+// Syncing should copy only what differs, leave matching prefixes unread, and
+// leave the two stores with equal digests
+#[test]
+fn sync_stores() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path().to_path_buf())?;
+    let mut target = KvStore::open(target_dir.path().to_path_buf())?;
+    for key_id in 0..20 {
+        let key = format!("user:{}", key_id).into_bytes();
+        source.set(key.clone(), "same".to_owned())?;
+        target.set(key, "same".to_owned())?;
+    }
+    source.set(b"item:1".to_vec(), "new".to_owned())?;
+    target.set(b"item:1".to_vec(), "old".to_owned())?;
+    source.set(b"item:2".to_vec(), "added".to_owned())?;
+    target.set(b"item:3".to_vec(), "removed".to_owned())?;
+    target.set(b"gone".to_vec(), "removed".to_owned())?;
+    // Shorter than the prefix, so it groups alone, apart from "item:*"
+    source.set(b"ite".to_vec(), "short".to_owned())?;
+
+    let report = sync(
+        &mut SyncPeer::Local(&mut source),
+        &mut SyncPeer::Local(&mut target),
+        4,
+    )?;
+    assert_eq!(report.prefixes, 4);
+    assert_eq!(report.differing, 3);
+    assert_eq!((report.set, report.removed), (3, 2));
+
+    assert_eq!(target.get(b"item:1".to_vec())?, Some("new".to_owned()));
+    assert_eq!(target.get(b"item:2".to_vec())?, Some("added".to_owned()));
+    assert_eq!(target.get(b"item:3".to_vec())?, None);
+    assert_eq!(target.get(b"gone".to_vec())?, None);
+    assert_eq!(target.get(b"ite".to_vec())?, Some("short".to_owned()));
+    assert_eq!(digest(&mut source, 4)?, digest(&mut target, 4)?);
+
+    let report = sync(
+        &mut SyncPeer::Local(&mut source),
+        &mut SyncPeer::Local(&mut target),
+        4,
+    )?;
+    assert_eq!((report.differing, report.set, report.removed), (0, 0, 0));
+
+    Ok(())
+}
+
+// Comparing Merkle trees should find exactly the leaves of the keys two
+// stores disagree on
+#[test]
+fn merkle_diverging_leaves() -> Result<()> {
+    let a_dir = TempDir::new().expect("unable to create temporary working directory");
+    let b_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut a = KvStore::open(a_dir.path().to_path_buf())?;
+    let mut b = KvStore::open(b_dir.path().to_path_buf())?;
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id).into_bytes();
+        a.set(key.clone(), "value".to_owned())?;
+        b.set(key, "value".to_owned())?;
+    }
+
+    let depth = 10;
+    assert_eq!(
+        MerkleTree::build(&mut a, depth)?,
+        MerkleTree::build(&mut b, depth)?
+    );
+    let leaves = diverging_leaves(
+        &mut SyncPeer::Local(&mut a),
+        &mut SyncPeer::Local(&mut b),
+        depth,
+    )?;
+    assert!(leaves.is_empty());
+
+    a.set(b"key7".to_vec(), "changed".to_owned())?;
+    b.remove(b"key300".to_vec())?;
+    b.set(b"extra".to_vec(), "value".to_owned())?;
+    let mut expected: Vec<u64> = [&b"key7"[..], b"key300", b"extra"]
+        .iter()
+        .map(|key| MerkleTree::leaf_of(key, depth))
+        .collect();
+    expected.sort_unstable();
+    expected.dedup();
+    let leaves = diverging_leaves(
+        &mut SyncPeer::Local(&mut a),
+        &mut SyncPeer::Local(&mut b),
+        depth,
+    )?;
+    assert_eq!(leaves, expected);
+    assert_ne!(
+        MerkleTree::build(&mut a, depth)?.root(),
+        MerkleTree::build(&mut b, depth)?.root()
+    );
+
+    assert!(MerkleTree::build(&mut a, MAX_MERKLE_DEPTH + 1).is_err());
+    Ok(())
+}
+
+// Rotating should seal the active log behind a new one, on demand and once
+// the active log is older than `rotate_every`
+#[test]
+fn rotate_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    // Nothing to seal yet
+    assert_eq!(store.rotate_log()?, Some(1));
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    assert_eq!(store.rotate_log()?, Some(2));
+    assert_eq!(log_count(), 2);
+    store.set(b"key2".to_vec(), "value2".to_owned())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value1".to_owned()));
+    drop(store);
+
+    let config = KvStoreConfig {
+        rotate_every: Some(Duration::from_millis(200)),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path().to_path_buf(), config)?;
+    let logs = log_count();
+    store.set(b"key3".to_vec(), "value3".to_owned())?;
+    assert_eq!(log_count(), logs);
+    thread::sleep(Duration::from_millis(300));
+    store.set(b"key4".to_vec(), "value4".to_owned())?;
+    assert_eq!(log_count(), logs + 1);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path().to_path_buf())?;
+    for key_id in 1..=4 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Checking should find a torn record and an index that points past it, and
+// repairing should drop the torn record and rebuild the index
+#[test]
+fn check_and_repair_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key1".to_vec(), "value2".to_owned())?;
+    store.set(b"key2".to_vec(), "value3".to_owned())?;
+    store.save_index()?;
+    drop(store);
+
+    let check = check_logs(path, None, None)?;
+    assert!(check.is_healthy());
+    assert_eq!(check.index, IndexState::Valid);
+    assert_eq!((check.logs[0].records, check.live_keys), (3, 2));
+    assert!(check.logs[0].stale_len() > 0);
+
+    // Tear the last record, as a crash mid-write would
+    let log = OpenOptions::new().write(true).open(path.join("1.log"))?;
+    log.set_len(log.metadata()?.len() - 3)?;
+    drop(log);
+
+    let check = check_logs(path, None, None)?;
+    assert!(!check.is_healthy());
+    assert_eq!(check.logs[0].records, 2);
+    assert!(check.logs[0].error.is_some());
+    assert_eq!(check.index, IndexState::Invalid);
+
+    let repaired = repair_logs(path, None, None)?;
+    assert!(repaired.is_healthy());
+    assert_eq!(repaired.index, IndexState::Valid);
+    assert_eq!(repaired.logs[0].torn_len(), 0);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    Ok(())
+}
+
+// A store created with line-delimited records should write one record per
+// line, keep that encoding across reopens and compaction, and refuse to be
+// reopened with another
+#[test]
+fn line_log_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let lines = KvStoreConfig {
+        log_encoding: Some(LogEncoding::Lines),
+        ..KvStoreConfig::default()
+    };
+
+    let mut store = KvStore::open_with_config(path.to_path_buf(), lines.clone())?;
+    store.set(b"key1".to_vec(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), "line\nbreak".to_owned())?;
+    store.remove(b"key1".to_vec())?;
+    drop(store);
+
+    let log = std::fs::read_to_string(path.join("1.log"))?;
+    let records: Vec<&str> = log.lines().filter(|line| !line.is_empty()).collect();
+    assert_eq!(records.len(), 3);
+    assert!(records[2].starts_with(r#"{"Remove""#));
+
+    let err = KvStore::open_with_config(
+        path.to_path_buf(),
+        KvStoreConfig {
+            log_encoding: Some(LogEncoding::Compact),
+            ..KvStoreConfig::default()
+        },
+    );
+    assert!(err.is_err());
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some("line\nbreak".to_owned()));
+    store.compact()?;
+    store.set(b"key3".to_vec(), "value3".to_owned())?;
+    store.save_index()?;
+    drop(store);
+
+    let check = check_logs(path, None, None)?;
+    assert!(check.is_healthy());
+    assert_eq!(check.log_encoding, Some(LogEncoding::Lines));
+    assert_eq!(check.index, IndexState::Valid);
+    assert_eq!(check.live_keys, 2);
+
+    let mut store = KvStore::open_with_config(path.to_path_buf(), lines)?;
+    assert_eq!(store.get(b"key2".to_vec())?, Some("line\nbreak".to_owned()));
+    assert_eq!(store.get(b"key3".to_vec())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Opening with a keydir check should read back the records the saved index
+// points to and refuse to open if one doesn't match
+#[test]
+fn verify_keydir_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let verify = |verify_keydir| KvStoreConfig {
+        verify_keydir,
+        ..KvStoreConfig::default()
+    };
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    for key_id in 0..100 {
+        store.set(
+            format!("key{:0>3}", key_id).into_bytes(),
+            "value".to_owned(),
+        )?;
+    }
+    store.set(b"key042".to_vec(), "changed".to_owned())?;
+    store.save_index()?;
+    drop(store);
+
+    KvStore::open_with_config(path.to_path_buf(), verify(KeydirCheck::Full))?;
+    KvStore::open_with_config(path.to_path_buf(), verify(KeydirCheck::Sample(10)))?;
+
+    // Rewrite a key in place, where the index still points
+    let log = std::fs::read_to_string(path.join("1.log"))?;
+    std::fs::write(path.join("1.log"), log.replacen("key007", "key700", 1))?;
+
+    KvStore::open(path.to_path_buf())?;
+    match KvStore::open_with_config(path.to_path_buf(), verify(KeydirCheck::Full)) {
+        Err(KvStoreError::InconsistentKeydir(report)) => {
+            assert!(report.starts_with("1 of 100 entries checked"), "{}", report);
+            assert!(report.contains("loaded from the saved index"), "{}", report);
+            assert!(
+                report.contains(r#"key "key007" -> log 1"#)
+                    && report.contains(r#"the record sets key "key700""#),
+                "{}",
+                report
+            );
+        }
+        other => panic!(
+            "expected an inconsistent keydir, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+
+    Ok(())
+}
+
+// Values and keys written with a keyring never reach the disk in the clear,
+// and compaction moves the records onto the active key
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    use kvs::Keyring;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let encrypted = |keys: Keyring| KvStoreConfig {
+        encryption: Some(keys),
+        ..KvStoreConfig::default()
+    };
+    let on_disk = |needle: &[u8]| {
+        WalkDir::new(path).into_iter().any(|entry| {
+            let entry = entry.unwrap();
+            entry.file_type().is_file()
+                && std::fs::read(entry.path())
+                    .unwrap()
+                    .windows(needle.len())
+                    .any(|window| window == needle)
+        })
+    };
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    store.set(b"plain-key".to_vec(), "plain-value".to_owned())?;
+    drop(store);
+
+    let mut store =
+        KvStore::open_with_config(path.to_path_buf(), encrypted(Keyring::new(1, [7; 32])))?;
+    store.set(b"secret-key".to_vec(), "secret-value".to_owned())?;
+    assert_eq!(
+        store.get(b"plain-key".to_vec())?,
+        Some("plain-value".to_owned())
+    );
+    store.save_index()?;
+    drop(store);
+    assert!(!on_disk(b"secret"));
+    assert!(on_disk(b"plain-value"));
+
+    assert!(matches!(
+        KvStore::open(path.to_path_buf()),
+        Err(KvStoreError::Encryption(_))
+    ));
+    assert!(matches!(
+        KvStore::open_with_config(path.to_path_buf(), encrypted(Keyring::new(2, [9; 32]))),
+        Err(KvStoreError::Encryption(_))
+    ));
+    assert!(matches!(
+        check_logs(path, None, None),
+        Err(KvStoreError::Encryption(_))
+    ));
+    let check = check_logs(path, None, Some(&Keyring::new(1, [7; 32])))?;
+    assert!(check.is_healthy());
+    assert_eq!(check.live_keys, 2);
+    assert_eq!(check.index, IndexState::Valid);
+
+    // Rotate to a new key, keeping the old one until compaction
+    let rotated = Keyring::new(2, [9; 32]).with_retired_key(1, [7; 32]);
+    let mut store = KvStore::open_with_config(path.to_path_buf(), encrypted(rotated))?;
+    assert_eq!(
+        store.get(b"secret-key".to_vec())?,
+        Some("secret-value".to_owned())
+    );
+    store.compact()?;
+    drop(store);
+    assert!(!on_disk(b"plain"));
+
+    let mut store =
+        KvStore::open_with_config(path.to_path_buf(), encrypted(Keyring::new(2, [9; 32])))?;
+    assert_eq!(
+        store.get(b"secret-key".to_vec())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(
+        store.get(b"plain-key".to_vec())?,
+        Some("plain-value".to_owned())
+    );
+
+    Ok(())
+}
+
+// A sealed record only opens where it was written, and once a store is
+// encrypted it only reads plain records from the logs it had before
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_binds_record_locations() -> Result<()> {
+    use kvs::{read_log, Keyring};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let keys = Keyring::new(1, [7; 32]);
+
+    let mut store = KvStore::open(path.to_path_buf())?;
+    store.set(b"plain-key".to_vec(), "plain-value".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_config(
+        path.to_path_buf(),
+        KvStoreConfig {
+            encryption: Some(keys.clone()),
+            ..KvStoreConfig::default()
+        },
+    )?;
+    store.set(b"key-a".to_vec(), "value-a".to_owned())?;
+    store.set(b"key-b".to_vec(), "value-b".to_owned())?;
+    drop(store);
+
+    let plain_log = path.join("1.log");
+    let sealed_log = path.join("2.log");
+    let plain = read_log(&plain_log, 0, Some(&keys))?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(plain[0].key, b"plain-key");
+    let sealed = read_log(&sealed_log, 0, Some(&keys))?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(sealed.len(), 2);
+    let range =
+        |record: &kvs::LogRecord| record.offset as usize..(record.offset + record.len) as usize;
+
+    // Swap the two sealed records: each still decrypts, but not where it
+    // now is
+    let bytes = fs::read(&sealed_log)?;
+    let (a, b) = (range(&sealed[0]), range(&sealed[1]));
+    assert_eq!(a.len(), b.len());
+    let mut swapped = bytes.clone();
+    swapped[a.clone()].copy_from_slice(&bytes[b.clone()]);
+    swapped[b].copy_from_slice(&bytes[a]);
+    fs::write(&sealed_log, &swapped)?;
+    assert!(matches!(
+        read_log(&sealed_log, 0, Some(&keys))?.next(),
+        Some(Err(KvStoreError::Encryption(_)))
+    ));
+    fs::write(&sealed_log, &bytes)?;
+
+    // A plain record is refused in a log written after the switch
+    let mut appended = bytes.clone();
+    appended.extend_from_slice(&fs::read(&plain_log)?[range(&plain[0])]);
+    fs::write(&sealed_log, &appended)?;
+    let records: Vec<_> = read_log(&sealed_log, 0, Some(&keys))?.collect();
+    assert!(records[..2].iter().all(|record| record.is_ok()));
+    assert!(matches!(records[2], Err(KvStoreError::Encryption(_))));
+    assert!(!check_logs(path, None, Some(&keys))?.is_healthy());
+    fs::write(&sealed_log, &bytes)?;
+
+    let mut store = KvStore::open_with_config(
+        path.to_path_buf(),
+        KvStoreConfig {
+            encryption: Some(keys),
+            ..KvStoreConfig::default()
+        },
+    )?;
+    assert_eq!(
+        store.get(b"plain-key".to_vec())?,
+        Some("plain-value".to_owned())
+    );
+    assert_eq!(store.get(b"key-b".to_vec())?, Some("value-b".to_owned()));
+
+    Ok(())
+}